@@ -0,0 +1,65 @@
+//! Prints the telemetry records from a usb serial device or a recorded capture, one per line
+
+use std::{fs::File, io::BufReader, process::ExitCode};
+
+use latency_host::decoder::TelemetryReader;
+use latency_proto::record::Record;
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: decode <device or capture file>");
+        return ExitCode::FAILURE;
+    };
+    let file = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("failed to open {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut reader = TelemetryReader::new(BufReader::new(file));
+    for record in reader.by_ref() {
+        match record {
+            Ok(Record::Send(r)) => println!(
+                "send {} us: peer {} ch {} rssi {} dBm | {} us ({} us last attempt), {} retranmisisons",
+                r.timestamp_us,
+                r.peer,
+                r.channel,
+                r.rssi,
+                r.total_us,
+                r.time_elapsed_us,
+                r.retranmisisons
+            ),
+            Ok(Record::Receive(r)) => println!(
+                "recv {} us: peer {} ch {} rssi {} dBm | id {}, {} bytes",
+                r.timestamp_us, r.peer, r.channel, r.rssi, r.id, r.len
+            ),
+            Ok(Record::Pattern {
+                timestamp_us,
+                peer,
+                counters: c,
+            }) => println!(
+                "pattern {timestamp_us} us: peer {peer} | {} verified, {} wrong length, {} misdelivered, {} corrupt",
+                c.verified, c.wrong_length, c.misdelivered, c.corrupt
+            ),
+            Ok(Record::Battery {
+                timestamp_us,
+                peer,
+                millivolts,
+                percent,
+            }) => println!("battery {timestamp_us} us: peer {peer} | {millivolts} mV, {percent}%"),
+            Ok(Record::Dropped(n)) => println!("device dropped {n} records"),
+            Ok(other) => println!("{other:?}"),
+            Err(e) => {
+                eprintln!("read failed: {e}");
+                break;
+            }
+        }
+    }
+    let stats = reader.stats();
+    eprintln!(
+        "{} frames, {} lost, {} crc errors, {} malformed",
+        stats.frames, stats.lost_frames, stats.crc_errors, stats.malformed
+    );
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,323 @@
+//! Drives latency runs on the firmware over usb serial and exports the results.
+//!
+//! ```text
+//! latency <device|sim> status
+//! latency <device|sim> stop
+//! latency <device|sim> counters
+//! latency <device|sim> run [--size N] [--interval-us N] [--count N] [--driver polled|trad]
+//!                          [--rate nrf1m|nrf2m|ble1m|ble2m] [--channel N] [--pattern]
+//!                          [--csv PATH] [--json PATH]
+//! latency <device|sim> ping [--size N] [--interval-us N] [--count N] [--driver polled|trad]
+//!                           [--rate nrf1m|nrf2m|ble1m|ble2m] [--channel N]
+//! latency <device|sim> stream [--size N] [--duration-ms N] [--driver polled|trad]
+//!                             [--rate nrf1m|nrf2m|ble1m|ble2m] [--channel N]
+//! latency <device|sim> sweep [--sizes S] [--intervals S] [--bursts S] [--drivers D,..]
+//!                            [--rates R,..] [--repeats N] [--csv PATH]
+//! ```
+//!
+//! Sweeps are written as `start:end:step` for a linear range, `start:end:*factor` for a geometric
+//! one or a single value.
+//!
+//! Passing `sim` as the device runs against [`SimDevice`] instead of real hardware.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    process::{Command, ExitCode},
+};
+
+use latency_host::{
+    client::Client,
+    report::{self, summarize},
+    sim::SimDevice,
+};
+use latency_proto::{
+    control::{DataRate, Driver, RunParams},
+    plan::{Plan, Sweep},
+};
+
+const USAGE: &str =
+    "usage: latency <device|sim> <status|stop|counters|run|ping|stream|sweep> [options]
+
+run options:
+  --size N           payload length in bytes (0..=32)
+  --interval-us N    delay between sends
+  --count N          number of packets, 0 runs until stopped
+  --driver D         polled or trad
+  --rate R           nrf1m, nrf2m, ble1m or ble2m
+  --channel N        frequency offset from 2400 MHz
+  --pattern          fill payloads with test patterns the receiver verifies,
+                     needs a size of at least 7
+  --csv PATH         write every send result as csv
+  --json PATH        write params, summary and send results as json
+
+ping takes the same options except --csv and --json, the size is padded up to
+the 13 bytes a ping needs
+
+stream sends back to back for a fixed time and takes --size, --driver, --rate
+and --channel from the run options, the size defaults to 32
+  --duration-ms N    how long to stream, 5000 by default
+
+sweep options:
+  --sizes S          payload lengths, start:end:step, start:end:*factor or N
+  --intervals S      delays between bursts
+  --bursts S         packets sent back to back per burst
+  --drivers D,..     polled and/or trad, cells for the other driver are skipped
+  --rates R,..       data rates to sweep over
+  --repeats N        bursts sent for every cell
+  --csv PATH         write every cell summary as csv";
+
+struct RunArgs {
+    params: RunParams,
+    /// Only used by stream
+    duration_ms: u32,
+    csv: Option<String>,
+    json: Option<String>,
+}
+
+struct SweepArgs {
+    plan: Plan,
+    csv: Option<String>,
+}
+
+fn parse_driver(value: &str) -> Result<Driver, String> {
+    match value {
+        "polled" => Ok(Driver::Polled),
+        "trad" => Ok(Driver::Trad),
+        _ => Err(format!("unknown driver {value}")),
+    }
+}
+
+fn parse_rate(value: &str) -> Result<DataRate, String> {
+    match value {
+        "nrf1m" => Ok(DataRate::Nrf1Mbit),
+        "nrf2m" => Ok(DataRate::Nrf2Mbit),
+        "ble1m" => Ok(DataRate::Ble1Mbit),
+        "ble2m" => Ok(DataRate::Ble2Mbit),
+        _ => Err(format!("unknown data rate {value}")),
+    }
+}
+
+fn parse_sweep(value: &str) -> Result<Sweep, String> {
+    let invalid = || format!("invalid sweep {value}");
+    let number = |s: &str| s.parse::<u32>().map_err(|_| invalid());
+    let parts: Vec<&str> = value.split(':').collect();
+    match parts[..] {
+        [single] => Ok(Sweep::single(number(single)?)),
+        [start, end, step] => match step.strip_prefix('*') {
+            Some(factor) => Ok(Sweep::geometric(
+                number(start)?,
+                number(end)?,
+                number(factor)?,
+            )),
+            None => Ok(Sweep::linear(number(start)?, number(end)?, number(step)?)),
+        },
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_sweep_args(args: &[String]) -> Result<SweepArgs, String> {
+    let mut res = SweepArgs {
+        plan: Plan::default_sweep(Driver::Polled),
+        csv: None,
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        let p = &mut res.plan;
+        match flag.as_str() {
+            "--sizes" => p.payload_len = parse_sweep(value)?,
+            "--intervals" => p.interval_us = parse_sweep(value)?,
+            "--bursts" => p.burst_len = parse_sweep(value)?,
+            "--repeats" => {
+                p.repeats = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for {flag}: {value}"))?
+            }
+            "--drivers" => {
+                p.drivers = 0;
+                for d in value.split(',') {
+                    p.drivers |= 1 << parse_driver(d)? as u8;
+                }
+            }
+            "--rates" => {
+                p.data_rates = 0;
+                for r in value.split(',') {
+                    p.data_rates |= 1 << parse_rate(r)? as u8;
+                }
+            }
+            "--csv" => res.csv = Some(value.clone()),
+            _ => return Err(format!("unknown option {flag}")),
+        }
+    }
+    if res.plan.is_empty() {
+        return Err("sweep has no cells".into());
+    }
+    Ok(res)
+}
+
+fn parse_run_args(args: &[String], params: RunParams) -> Result<RunArgs, String> {
+    let mut res = RunArgs {
+        params,
+        duration_ms: 5000,
+        csv: None,
+        json: None,
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if flag == "--pattern" {
+            res.params.pattern = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        let number = || {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("invalid value for {flag}: {value}"))
+        };
+        let p = &mut res.params;
+        match flag.as_str() {
+            "--size" => p.payload_len = number()?.try_into().map_err(|_| "size too large")?,
+            "--interval-us" => p.interval_us = number()?,
+            "--count" => p.count = number()?,
+            "--duration-ms" => res.duration_ms = number()?,
+            "--channel" => p.channel = number()?.try_into().map_err(|_| "channel too large")?,
+            "--driver" => p.driver = parse_driver(value)?,
+            "--rate" => p.data_rate = parse_rate(value)?,
+            "--csv" => res.csv = Some(value.clone()),
+            "--json" => res.json = Some(value.clone()),
+            _ => return Err(format!("unknown option {flag}")),
+        }
+    }
+    Ok(res)
+}
+
+fn run_defaults() -> RunParams {
+    RunParams {
+        count: 1000,
+        interval_us: 1000,
+        ..Default::default()
+    }
+}
+
+fn open_device(path: &str) -> io::Result<File> {
+    // The tty has to be raw or the line discipline mangles the binary stream
+    let status = Command::new("stty")
+        .args(["-F", path, "raw", "-echo"])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("stty failed on {path}")));
+    }
+    OpenOptions::new().read(true).write(true).open(path)
+}
+
+fn execute<T: Read + Write>(
+    mut client: Client<T>,
+    command: &str,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        "status" => {
+            let status = client.status()?;
+            println!(
+                "driver {:?}, {}",
+                status.driver,
+                if status.running { "running" } else { "idle" }
+            );
+            println!("{:?}", status.params);
+        }
+        "stop" => client.stop()?,
+        "counters" => report::write_counters_table(io::stdout(), &client.counters()?)?,
+        "run" => {
+            let run = parse_run_args(args, run_defaults())?;
+            let mut samples = Vec::new();
+            let totals = client.run(run.params, |s| samples.push(*s))?;
+            let summary = summarize(&samples);
+            let stream = client.stream_stats();
+            report::write_table(io::stdout(), &summary)?;
+            println!(
+                "device sent {} with {} retranmisisons, {} frames lost on usb",
+                totals.sent, totals.retranmisisons, stream.lost_frames
+            );
+            if let Some(path) = run.csv {
+                report::write_csv(io::BufWriter::new(File::create(path)?), &samples)?;
+            }
+            if let Some(path) = run.json {
+                report::write_json(
+                    io::BufWriter::new(File::create(path)?),
+                    &run.params,
+                    &summary,
+                    &stream,
+                    &samples,
+                )?;
+            }
+        }
+        "ping" => {
+            let run = parse_run_args(args, run_defaults())?;
+            let mut samples = Vec::new();
+            let totals = client.ping(run.params, |s| samples.push(*s))?;
+            println!("round trip, ping received and echoed by the application");
+            report::write_table(io::stdout(), &report::summarize_pings(&samples))?;
+            println!("ack, ping acked by the link layer");
+            report::write_table(io::stdout(), &report::summarize_ping_acks(&samples))?;
+            println!("{} of {} pings came back", samples.len(), totals.sent);
+        }
+        "stream" => {
+            let run = parse_run_args(
+                args,
+                RunParams {
+                    payload_len: 32,
+                    ..Default::default()
+                },
+            )?;
+            let summary = client.stream(run.params, run.duration_ms)?;
+            report::write_stream_table(io::stdout(), &summary)?;
+        }
+        "sweep" => {
+            let sweep = parse_sweep_args(args)?;
+            let (cells, skipped) = client.run_plan(sweep.plan)?;
+            report::write_cells_table(io::stdout(), &cells)?;
+            println!(
+                "{} of {} cells run, {skipped} skipped for needing the other driver",
+                cells.len(),
+                sweep.plan.len()
+            );
+            if let Some(path) = sweep.csv {
+                report::write_cells_csv(io::BufWriter::new(File::create(path)?), &cells)?;
+            }
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (Some(device), Some(command)) = (args.first(), args.get(1)) else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    let res = if device == "sim" {
+        let driver = match parse_run_args(&args[2..], run_defaults()) {
+            Ok(run) => run.params.driver,
+            Err(_) => Driver::Polled,
+        };
+        execute(Client::new(SimDevice::new(driver)), command, &args[2..])
+    } else {
+        match open_device(device) {
+            Ok(file) => execute(Client::new(file), command, &args[2..]),
+            Err(e) => Err(e.into()),
+        }
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Request/response layer on top of the telemetry stream. Works over anything that is
+//! `Read + Write`, the usb serial device in practice or [`SimDevice`](crate::sim::SimDevice) when
+//! there's no hardware around.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+};
+
+use latency_proto::{
+    control::{Command, NackReason, RunParams},
+    frame::{self, Message},
+    plan::{CellSummary, Plan},
+    record::{PeerCounters, PingRecord, Record, SendRecord, Status},
+    stream::StreamSummary,
+};
+
+use crate::decoder::{FrameReader, StreamStats};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The device rejected the command
+    Nack(NackReason),
+    /// The stream ended while waiting for a reply
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::Nack(reason) => write!(f, "device rejected the command: {reason:?}"),
+            Error::Closed => write!(f, "device closed the stream"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Totals reported by the device at the end of a run
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunTotals {
+    pub sent: u32,
+    pub retranmisisons: u32,
+}
+
+pub struct Client<T> {
+    reader: FrameReader<T>,
+    seq: u16,
+    /// Records that arrived while waiting for a reply
+    pending: VecDeque<Record>,
+}
+
+impl<T: Read + Write> Client<T> {
+    pub fn new(io: T) -> Self {
+        Self {
+            reader: FrameReader::new(io),
+            seq: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn stream_stats(&self) -> StreamStats {
+        self.reader.stats()
+    }
+
+    fn send(&mut self, command: &Command) -> Result<(), Error> {
+        let mut buf = [0u8; frame::MAX_FRAME_SIZE];
+        let len = frame::encode(self.seq, command, &mut buf).expect("commands always fit a frame");
+        self.seq = self.seq.wrapping_add(1);
+        let io = self.reader.get_mut();
+        io.write_all(&buf[..len])?;
+        io.flush()?;
+        Ok(())
+    }
+
+    fn read_record(&mut self) -> Result<Record, Error> {
+        match self.reader.next_frame::<Record>()? {
+            Some(frame) => Ok(frame.message),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Next record from the device, including ones queued up while waiting for replies
+    pub fn next_record(&mut self) -> Result<Record, Error> {
+        match self.pending.pop_front() {
+            Some(record) => Ok(record),
+            None => self.read_record(),
+        }
+    }
+
+    /// Sends the command and waits for the device to accept or reject it
+    fn command(&mut self, command: Command) -> Result<(), Error> {
+        self.send(&command)?;
+        loop {
+            match self.read_record()? {
+                Record::Ack(kind) if kind == command.kind() => return Ok(()),
+                Record::Nack {
+                    command: kind,
+                    reason,
+                } if kind == command.kind() => return Err(Error::Nack(reason)),
+                other => self.pending.push_back(other),
+            }
+        }
+    }
+
+    pub fn status(&mut self) -> Result<Status, Error> {
+        self.send(&Command::Status)?;
+        loop {
+            match self.read_record()? {
+                Record::Status(status) => return Ok(status),
+                other => self.pending.push_back(other),
+            }
+        }
+    }
+
+    /// Link counters of every peer that saw traffic since the device booted
+    pub fn counters(&mut self) -> Result<Vec<(u8, PeerCounters)>, Error> {
+        self.command(Command::Counters)?;
+        // The counters arrive before the ack so they're all in the pending queue by now, along
+        // with anything else that came in meanwhile
+        let mut res = Vec::new();
+        self.pending.retain(|record| match record {
+            Record::Counters { peer, counters, .. } => {
+                res.push((*peer, *counters));
+                false
+            }
+            _ => true,
+        });
+        Ok(res)
+    }
+
+    pub fn configure(&mut self, params: RunParams) -> Result<(), Error> {
+        self.command(Command::Configure(params))
+    }
+
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.command(Command::Start)
+    }
+
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.command(Command::Stop)
+    }
+
+    /// Configures and starts a run, handing every send result to `on_send` until the device
+    /// reports the run as done
+    pub fn run(
+        &mut self,
+        params: RunParams,
+        mut on_send: impl FnMut(&SendRecord),
+    ) -> Result<RunTotals, Error> {
+        self.configure(params)?;
+        self.start()?;
+        loop {
+            match self.next_record()? {
+                Record::Send(record) => on_send(&record),
+                Record::RunDone {
+                    sent,
+                    retranmisisons,
+                } => {
+                    return Ok(RunTotals {
+                        sent,
+                        retranmisisons,
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Configures and starts a ping run, handing every round trip to `on_ping` until the device
+    /// reports the run as done. Pings whose pong never arrived are only missing from the results.
+    pub fn ping(
+        &mut self,
+        params: RunParams,
+        mut on_ping: impl FnMut(&PingRecord),
+    ) -> Result<RunTotals, Error> {
+        self.configure(params)?;
+        self.command(Command::Ping)?;
+        loop {
+            match self.next_record()? {
+                Record::Ping(record) => on_ping(&record),
+                Record::RunDone {
+                    sent,
+                    retranmisisons,
+                } => {
+                    return Ok(RunTotals {
+                        sent,
+                        retranmisisons,
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Configures the link and sends packets back to back for `duration_ms`. Stopping the run
+    /// early still returns the summary up to that point
+    pub fn stream(&mut self, params: RunParams, duration_ms: u32) -> Result<StreamSummary, Error> {
+        self.configure(params)?;
+        self.command(Command::Stream { duration_ms })?;
+        loop {
+            if let Record::StreamDone(summary) = self.next_record()? {
+                return Ok(summary);
+            }
+        }
+    }
+
+    /// Runs a benchmark plan and returns the summary of every cell the firmware ran, along with
+    /// the number of cells it skipped for needing a different driver
+    pub fn run_plan(&mut self, plan: Plan) -> Result<(Vec<CellSummary>, u16), Error> {
+        self.command(Command::RunPlan(plan))?;
+        let mut results = Vec::new();
+        loop {
+            match self.next_record()? {
+                Record::CellResult(summary) => results.push(summary),
+                Record::PlanDone { skipped, .. } => return Ok((results, skipped)),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use latency_proto::control::Driver;
+
+    use super::*;
+    use crate::sim::SimDevice;
+
+    fn client() -> Client<SimDevice> {
+        Client::new(SimDevice::new(Driver::Polled))
+    }
+
+    #[test]
+    fn run_round_trip() {
+        let mut client = client();
+        let params = RunParams {
+            count: 50,
+            payload_len: 16,
+            channel: 42,
+            ..Default::default()
+        };
+        let mut sends = Vec::new();
+        let totals = client.run(params, |r| sends.push(*r)).unwrap();
+        assert_eq!(sends.len(), 50);
+        assert_eq!(totals.sent, 50);
+        assert_eq!(
+            totals.retranmisisons,
+            sends.iter().map(|r| r.retranmisisons as u32).sum::<u32>()
+        );
+        assert!(sends.iter().all(|r| r.channel == 42));
+        assert!(sends
+            .windows(2)
+            .all(|w| w[0].timestamp_us < w[1].timestamp_us));
+
+        let status = client.status().unwrap();
+        assert!(!status.running);
+        assert_eq!(status.params, params);
+        let counters = client.counters().unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].1.acks_received, 50);
+
+        let stats = client.stream_stats();
+        assert_eq!(stats.lost_frames, 0);
+        assert_eq!(stats.crc_errors + stats.malformed, 0);
+    }
+
+    #[test]
+    fn rejected_commands() {
+        let mut client = client();
+        let wrong_driver = RunParams {
+            driver: Driver::Trad,
+            ..Default::default()
+        };
+        assert!(matches!(
+            client.configure(wrong_driver),
+            Err(Error::Nack(NackReason::WrongDriver))
+        ));
+        let too_long = RunParams {
+            payload_len: 33,
+            ..Default::default()
+        };
+        assert!(matches!(
+            client.configure(too_long),
+            Err(Error::Nack(NackReason::InvalidParams))
+        ));
+        assert!(matches!(
+            client.stop(),
+            Err(Error::Nack(NackReason::NotRunning))
+        ));
+    }
+
+    #[test]
+    fn endless_run_until_stopped() {
+        let mut client = client();
+        client.configure(RunParams::default()).unwrap();
+        client.start().unwrap();
+        assert!(client.status().unwrap().running);
+        assert!(matches!(
+            client.configure(RunParams::default()),
+            Err(Error::Nack(NackReason::Busy))
+        ));
+        client.stop().unwrap();
+        assert!(matches!(
+            client.next_record().unwrap(),
+            Record::RunDone { sent: 0, .. }
+        ));
+        assert!(!client.status().unwrap().running);
+    }
+
+    #[test]
+    fn plan_skips_other_driver() {
+        let mut client = client();
+        let mut plan = Plan::default_sweep(Driver::Polled);
+        plan.drivers = 0b11;
+        plan.repeats = 2;
+        let (cells, skipped) = client.run_plan(plan).unwrap();
+        assert_eq!(cells.len() + skipped as usize, plan.len());
+        assert_eq!(cells.len(), skipped as usize);
+        assert!(cells.iter().all(|c| c.cell.driver == Driver::Polled));
+        assert!(cells.iter().all(|c| c.count == 2 * c.cell.burst_len as u32));
+    }
+
+    #[test]
+    fn ping_and_stream() {
+        let mut client = client();
+        let params = RunParams {
+            count: 10,
+            ..Default::default()
+        };
+        let mut pings = Vec::new();
+        client.ping(params, |p| pings.push(*p)).unwrap();
+        assert_eq!(
+            pings.iter().map(|p| p.seq).collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert!(pings.iter().all(|p| p.rtt_us > p.ack_us));
+
+        let summary = client.stream(RunParams::default(), 100).unwrap();
+        assert!(summary.packets > 0);
+        assert!(summary.duration_us >= 100_000);
+    }
+
+    #[test]
+    fn closed_stream() {
+        let mut client = Client::new(io::Cursor::new(Vec::new()));
+        assert!(matches!(client.status(), Err(Error::Closed)));
+    }
+}
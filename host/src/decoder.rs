@@ -0,0 +1,190 @@
+use std::io::{self, Read};
+
+use latency_proto::{
+    frame::{DecodeError, Frame, FrameDecoder, Message},
+    record::Record,
+};
+
+/// Counters describing how healthy the stream was
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StreamStats {
+    pub frames: u64,
+    /// Frames missing according to gaps in the sequence numbers
+    pub lost_frames: u64,
+    pub crc_errors: u64,
+    /// Frames that failed to decode for any reason other than a bad crc
+    pub malformed: u64,
+}
+
+/// Decodes frames from a byte stream such as the usb serial device or a recorded capture
+pub struct FrameReader<R> {
+    inner: R,
+    decoder: FrameDecoder,
+    next_seq: Option<u16>,
+    buf: [u8; 512],
+    pos: usize,
+    len: usize,
+    stats: StreamStats,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            decoder: FrameDecoder::new(),
+            next_seq: None,
+            buf: [0; 512],
+            pos: 0,
+            len: 0,
+            stats: StreamStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        self.stats
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Reads until the next valid frame, returning `None` at the end of the stream. Corrupt frames
+    /// are counted in [`Self::stats`] and skipped.
+    pub fn next_frame<M: Message>(&mut self) -> io::Result<Option<Frame<M>>> {
+        loop {
+            if self.pos == self.len {
+                self.len = match self.inner.read(&mut self.buf) {
+                    Ok(0) => return Ok(None),
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                self.pos = 0;
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            match self.decoder.push::<M>(byte) {
+                None => {}
+                Some(Ok(frame)) => {
+                    self.stats.frames += 1;
+                    if let Some(expected) = self.next_seq {
+                        self.stats.lost_frames += frame.seq.wrapping_sub(expected) as u64;
+                    }
+                    self.next_seq = Some(frame.seq.wrapping_add(1));
+                    return Ok(Some(frame));
+                }
+                Some(Err(DecodeError::Crc)) => self.stats.crc_errors += 1,
+                Some(Err(_)) => self.stats.malformed += 1,
+            }
+        }
+    }
+}
+
+/// Iterates over the telemetry records in a stream
+pub struct TelemetryReader<R>(pub FrameReader<R>);
+
+impl<R: Read> TelemetryReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self(FrameReader::new(inner))
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        self.0.stats()
+    }
+}
+
+impl<R: Read> Iterator for TelemetryReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .next_frame::<Record>()
+            .map(|f| f.map(|f| f.message))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use latency_proto::frame;
+
+    use super::*;
+
+    fn capture(frames: &[(u16, Record)]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for (seq, record) in frames {
+            let mut buf = [0u8; frame::MAX_FRAME_SIZE];
+            let len = frame::encode(*seq, record, &mut buf).unwrap();
+            stream.extend_from_slice(&buf[..len]);
+        }
+        stream
+    }
+
+    /// Hands the stream out a few bytes per read like a serial port does
+    struct Chunked<'a>(&'a [u8]);
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(5);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn counts_gaps_and_corruption() {
+        let mut stream = capture(&[(0, Record::Ack(1)), (1, Record::Dropped(4))]);
+        let corrupt = stream.len();
+        stream.extend(capture(&[(2, Record::Ack(2))]));
+        // Flip a bit in seq 2
+        stream[corrupt + 5] ^= 1;
+        stream.extend(capture(&[(5, Record::Ack(5)), (6, Record::Dropped(0))]));
+        // Garbage that never ends in a delimiter
+        stream.extend([0x55; 3]);
+
+        let mut reader = TelemetryReader::new(Chunked(&stream));
+        let records: Vec<Record> = reader.by_ref().map(Result::unwrap).collect();
+        assert_eq!(
+            records,
+            [
+                Record::Ack(1),
+                Record::Dropped(4),
+                Record::Ack(5),
+                Record::Dropped(0)
+            ]
+        );
+        let stats = reader.stats();
+        assert_eq!(stats.frames, 4);
+        // Seq 2 was corrupted, 3 and 4 never made it
+        assert_eq!(stats.lost_frames, 3);
+        assert_eq!(stats.crc_errors, 1);
+        assert_eq!(stats.malformed, 0);
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let stream = capture(&[(u16::MAX, Record::Ack(0)), (0, Record::Ack(1))]);
+        let mut reader = TelemetryReader::new(stream.as_slice());
+        assert_eq!(reader.by_ref().count(), 2);
+        assert_eq!(reader.stats().lost_frames, 0);
+    }
+
+    #[test]
+    fn unknown_kind_is_malformed() {
+        let mut stream = vec![0];
+        // A frame of kind 0xEE with a valid crc
+        let raw = [0u8, 0, 0xEE];
+        let crc = latency_proto::crc::crc16(&raw).to_le_bytes();
+        let mut encoded = [0u8; 16];
+        let len =
+            latency_proto::cobs::encode(&[raw.as_slice(), &crc].concat(), &mut encoded).unwrap();
+        stream.extend_from_slice(&encoded[..len]);
+        stream.push(0);
+        stream.extend(capture(&[(1, Record::Ack(9))]));
+        let mut reader = TelemetryReader::new(stream.as_slice());
+        assert_eq!(reader.next().unwrap().unwrap(), Record::Ack(9));
+        assert!(reader.next().is_none());
+        assert_eq!(reader.stats().malformed, 1);
+    }
+}
@@ -0,0 +1,7 @@
+//! Host side tools for the latency test firmware. Needs std, build it for the host target with
+//! `cargo make host` rather than the default thumbv7em target.
+
+pub mod client;
+pub mod decoder;
+pub mod report;
+pub mod sim;
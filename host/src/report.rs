@@ -0,0 +1,395 @@
+//! Summaries and exports of the send results collected during a run
+
+use std::io::{self, Write};
+
+use latency_proto::{
+    control::RunParams,
+    plan::CellSummary,
+    record::{PeerCounters, PingRecord, SendRecord},
+    stream::StreamSummary,
+};
+
+use crate::decoder::StreamStats;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub min_us: u32,
+    pub max_us: u32,
+    pub mean_us: f64,
+    pub p50_us: u32,
+    pub p90_us: u32,
+    pub p99_us: u32,
+    pub p999_us: u32,
+    /// Mean absolute difference between consecutive latencies
+    pub jitter_us: f64,
+    pub mean_retranmisisons: f64,
+}
+
+/// Nearest rank percentile of already sorted values
+fn percentile(sorted: &[u32], q: f64) -> u32 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Summary of latencies in the order they were measured, along with the retransmissions each
+/// needed
+fn summarize_latencies(latencies: &[u32], retranmisisons: impl Iterator<Item = u16>) -> Summary {
+    if latencies.is_empty() {
+        return Summary::default();
+    }
+    let mut sorted = latencies.to_vec();
+    sorted.sort_unstable();
+    let n = latencies.len() as f64;
+    let jitter = latencies
+        .windows(2)
+        .map(|w| w[0].abs_diff(w[1]) as f64)
+        .sum::<f64>()
+        / (n - 1.0).max(1.0);
+    Summary {
+        count: latencies.len(),
+        min_us: sorted[0],
+        max_us: sorted[sorted.len() - 1],
+        mean_us: sorted.iter().map(|&v| v as f64).sum::<f64>() / n,
+        p50_us: percentile(&sorted, 0.5),
+        p90_us: percentile(&sorted, 0.9),
+        p99_us: percentile(&sorted, 0.99),
+        p999_us: percentile(&sorted, 0.999),
+        jitter_us: jitter,
+        mean_retranmisisons: retranmisisons.map(|r| r as f64).sum::<f64>() / n,
+    }
+}
+
+/// Summary of the full send latencies, from the send call until the ack
+pub fn summarize(samples: &[SendRecord]) -> Summary {
+    let latencies: Vec<u32> = samples.iter().map(|s| s.total_us).collect();
+    summarize_latencies(&latencies, samples.iter().map(|s| s.retranmisisons))
+}
+
+/// Summary of the application level round trips
+pub fn summarize_pings(samples: &[PingRecord]) -> Summary {
+    let latencies: Vec<u32> = samples.iter().map(|s| s.rtt_us).collect();
+    summarize_latencies(&latencies, samples.iter().map(|s| s.retranmisisons))
+}
+
+/// Summary of the link level ack times of the pings, to compare against the round trips
+pub fn summarize_ping_acks(samples: &[PingRecord]) -> Summary {
+    let latencies: Vec<u32> = samples.iter().map(|s| s.ack_us).collect();
+    summarize_latencies(&latencies, samples.iter().map(|s| s.retranmisisons))
+}
+
+pub fn write_csv(mut w: impl Write, samples: &[SendRecord]) -> io::Result<()> {
+    writeln!(
+        w,
+        "timestamp_us,peer,channel,rssi,retranmisisons,time_elapsed_us,total_us,first_transmit_us"
+    )?;
+    for s in samples {
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{},{}",
+            s.timestamp_us,
+            s.peer,
+            s.channel,
+            s.rssi,
+            s.retranmisisons,
+            s.time_elapsed_us,
+            s.total_us,
+            s.first_transmit_us
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_json(
+    mut w: impl Write,
+    params: &RunParams,
+    summary: &Summary,
+    stream: &StreamStats,
+    samples: &[SendRecord],
+) -> io::Result<()> {
+    writeln!(w, "{{")?;
+    writeln!(
+        w,
+        "  \"params\": {{\"payload_len\": {}, \"interval_us\": {}, \"count\": {}, \"driver\": \"{:?}\", \"data_rate\": \"{:?}\", \"channel\": {}}},",
+        params.payload_len,
+        params.interval_us,
+        params.count,
+        params.driver,
+        params.data_rate,
+        params.channel
+    )?;
+    writeln!(
+        w,
+        "  \"summary\": {{\"count\": {}, \"min_us\": {}, \"max_us\": {}, \"mean_us\": {:.1}, \"p50_us\": {}, \"p90_us\": {}, \"p99_us\": {}, \"p999_us\": {}, \"jitter_us\": {:.1}, \"mean_retranmisisons\": {:.3}}},",
+        summary.count,
+        summary.min_us,
+        summary.max_us,
+        summary.mean_us,
+        summary.p50_us,
+        summary.p90_us,
+        summary.p99_us,
+        summary.p999_us,
+        summary.jitter_us,
+        summary.mean_retranmisisons
+    )?;
+    writeln!(
+        w,
+        "  \"stream\": {{\"frames\": {}, \"lost_frames\": {}, \"crc_errors\": {}, \"malformed\": {}}},",
+        stream.frames, stream.lost_frames, stream.crc_errors, stream.malformed
+    )?;
+    writeln!(w, "  \"samples\": [")?;
+    for (i, s) in samples.iter().enumerate() {
+        let sep = if i + 1 == samples.len() { "" } else { "," };
+        writeln!(
+            w,
+            "    {{\"timestamp_us\": {}, \"peer\": {}, \"channel\": {}, \"rssi\": {}, \"retranmisisons\": {}, \"time_elapsed_us\": {}, \"total_us\": {}, \"first_transmit_us\": {}}}{sep}",
+            s.timestamp_us,
+            s.peer,
+            s.channel,
+            s.rssi,
+            s.retranmisisons,
+            s.time_elapsed_us,
+            s.total_us,
+            s.first_transmit_us
+        )?;
+    }
+    writeln!(w, "  ]")?;
+    writeln!(w, "}}")
+}
+
+pub fn write_table(mut w: impl Write, summary: &Summary) -> io::Result<()> {
+    writeln!(
+        w,
+        "{:>8} {:>8} {:>8} {:>9} {:>8} {:>8} {:>8} {:>8} {:>9} {:>8}",
+        "count", "min", "max", "mean", "p50", "p90", "p99", "p99.9", "jitter", "retx"
+    )?;
+    writeln!(
+        w,
+        "{:>8} {:>8} {:>8} {:>9.1} {:>8} {:>8} {:>8} {:>8} {:>9.1} {:>8.3}",
+        summary.count,
+        summary.min_us,
+        summary.max_us,
+        summary.mean_us,
+        summary.p50_us,
+        summary.p90_us,
+        summary.p99_us,
+        summary.p999_us,
+        summary.jitter_us,
+        summary.mean_retranmisisons
+    )
+}
+
+pub fn write_cells_table(mut w: impl Write, cells: &[CellSummary]) -> io::Result<()> {
+    writeln!(
+        w,
+        "{:>5} {:>7} {:>9} {:>5} {:>9} {:>6} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7}",
+        "cell",
+        "driver",
+        "rate",
+        "size",
+        "interval",
+        "burst",
+        "count",
+        "mean",
+        "p50",
+        "p99",
+        "p99.9",
+        "max",
+        "retx"
+    )?;
+    for s in cells {
+        writeln!(
+            w,
+            "{:>5} {:>7} {:>9} {:>5} {:>9} {:>6} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7.3}",
+            s.cell.index,
+            format!("{:?}", s.cell.driver),
+            format!("{:?}", s.cell.data_rate),
+            s.cell.payload_len,
+            s.cell.interval_us,
+            s.cell.burst_len,
+            s.count,
+            s.mean_us,
+            s.p50_us,
+            s.p99_us,
+            s.p999_us,
+            s.max_us,
+            s.retranmisisons_milli as f64 / 1000.0
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_cells_csv(mut w: impl Write, cells: &[CellSummary]) -> io::Result<()> {
+    writeln!(
+        w,
+        "cell,driver,data_rate,payload_len,interval_us,burst_len,count,lost,min_us,mean_us,p50_us,p90_us,p99_us,p999_us,max_us,jitter_us,mean_retranmisisons"
+    )?;
+    for s in cells {
+        writeln!(
+            w,
+            "{},{:?},{:?},{},{},{},{},{},{},{},{},{},{},{},{},{},{:.3}",
+            s.cell.index,
+            s.cell.driver,
+            s.cell.data_rate,
+            s.cell.payload_len,
+            s.cell.interval_us,
+            s.cell.burst_len,
+            s.count,
+            s.lost,
+            s.min_us,
+            s.mean_us,
+            s.p50_us,
+            s.p90_us,
+            s.p99_us,
+            s.p999_us,
+            s.max_us,
+            s.jitter_us,
+            s.retranmisisons_milli as f64 / 1000.0
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_stream_table(mut w: impl Write, s: &StreamSummary) -> io::Result<()> {
+    writeln!(
+        w,
+        "{:>7} {:>9} {:>5} {:>8} {:>6} {:>8} {:>9} {:>8} {:>7} {:>7} {:>7} {:>7} {:>7}",
+        "driver",
+        "rate",
+        "size",
+        "packets",
+        "lost",
+        "pkt/s",
+        "kbit/s",
+        "retry",
+        "mean",
+        "p50",
+        "p99",
+        "max",
+        "jitter"
+    )?;
+    writeln!(
+        w,
+        "{:>7} {:>9} {:>5} {:>8} {:>6} {:>8.1} {:>9.1} {:>8} {:>7} {:>7} {:>7} {:>7} {:>7}",
+        format!("{:?}", s.driver),
+        format!("{:?}", s.data_rate),
+        s.payload_len,
+        s.packets,
+        s.lost,
+        s.packets_per_sec(),
+        s.goodput_kbps(),
+        s.retry_rate()
+            .map_or("-".into(), |rate| format!("{:.4}", rate)),
+        s.mean_us,
+        s.p50_us,
+        s.p99_us,
+        s.max_us,
+        s.jitter_us
+    )
+}
+
+pub fn write_counters_table(mut w: impl Write, counters: &[(u8, PeerCounters)]) -> io::Result<()> {
+    writeln!(
+        w,
+        "{:>4} {:>8} {:>8} {:>8} {:>8} {:>8} {:>6} {:>6} {:>6} {:>10} {:>10} {:>7}",
+        "peer",
+        "sent",
+        "recv",
+        "acks tx",
+        "acks rx",
+        "timeouts",
+        "crc",
+        "dups",
+        "type",
+        "bytes",
+        "radio us",
+        "per"
+    )?;
+    for (peer, c) in counters {
+        writeln!(
+            w,
+            "{:>4} {:>8} {:>8} {:>8} {:>8} {:>8} {:>6} {:>6} {:>6} {:>10} {:>10} {:>7}",
+            peer,
+            c.frames_sent,
+            c.frames_received,
+            c.acks_sent,
+            c.acks_received,
+            c.ack_timeouts,
+            c.crc_failures,
+            c.duplicates,
+            c.unknown_type,
+            c.bytes_on_air,
+            c.radio_on_us,
+            c.packet_error_rate()
+                .map_or("-".into(), |per| format!("{:.4}", per))
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(total_us: u32, retranmisisons: u16) -> SendRecord {
+        SendRecord {
+            timestamp_us: total_us as u64,
+            peer: 1,
+            channel: 80,
+            rssi: -50,
+            retranmisisons,
+            time_elapsed_us: total_us / 2,
+            total_us,
+            first_transmit_us: 3,
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let samples: Vec<SendRecord> = (1..=100).map(|i| send(i * 10, (i % 2) as u16)).collect();
+        let s = summarize(&samples);
+        assert_eq!(s.count, 100);
+        assert_eq!((s.min_us, s.max_us), (10, 1000));
+        assert_eq!(s.mean_us, 505.0);
+        assert_eq!(
+            (s.p50_us, s.p90_us, s.p99_us, s.p999_us),
+            (500, 900, 990, 1000)
+        );
+        assert_eq!(s.jitter_us, 10.0);
+        assert_eq!(s.mean_retranmisisons, 0.5);
+        assert_eq!(summarize(&[]), Summary::default());
+    }
+
+    #[test]
+    fn csv() {
+        let mut out = Vec::new();
+        write_csv(&mut out, &[send(400, 1), send(250, 0)]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].split(',').count(), 8);
+        assert_eq!(lines[1], "400,1,80,-50,1,200,400,3");
+    }
+
+    #[test]
+    fn json_has_every_sample() {
+        let samples = [send(400, 1), send(250, 0)];
+        let mut out = Vec::new();
+        write_json(
+            &mut out,
+            &RunParams::default(),
+            &summarize(&samples),
+            &StreamStats::default(),
+            &samples,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("\"total_us\"").count(), 2);
+        // No trailing comma after the last sample
+        assert!(out.contains("\"first_transmit_us\": 3}\n  ]"));
+        assert_eq!(out.matches('{').count(), out.matches('}').count());
+    }
+}
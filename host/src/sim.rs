@@ -0,0 +1,310 @@
+//! In memory stand in for the firmware. It speaks the same protocol as the device and makes up
+//! plausible send results, so the client and reports can be exercised without hardware.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+};
+
+use latency_proto::{
+    control::{Command, Driver, NackReason, RunParams, MAX_PAYLOAD_LEN},
+    frame::{self, FrameDecoder, Message},
+    pattern,
+    plan::{Cell, CellSummary, Plan},
+    record::{PeerCounters, PingRecord, Record, SendRecord, Status},
+    stream::StreamSummary,
+};
+
+use crate::report::summarize;
+
+pub struct SimDevice {
+    driver: Driver,
+    params: RunParams,
+    running: bool,
+    decoder: FrameDecoder,
+    out: VecDeque<u8>,
+    seq: u16,
+    rng: u32,
+    time_us: u64,
+    sent: u32,
+    retranmisisons: u32,
+    counters: PeerCounters,
+}
+
+impl SimDevice {
+    pub fn new(driver: Driver) -> Self {
+        Self {
+            driver,
+            params: RunParams {
+                driver,
+                ..Default::default()
+            },
+            running: false,
+            decoder: FrameDecoder::new(),
+            out: VecDeque::new(),
+            seq: 0,
+            rng: 0x1234_5678,
+            time_us: 0,
+            sent: 0,
+            retranmisisons: 0,
+            counters: PeerCounters::default(),
+        }
+    }
+
+    fn emit(&mut self, record: Record) {
+        let mut buf = [0u8; frame::MAX_FRAME_SIZE];
+        let len = frame::encode(self.seq, &record, &mut buf).expect("records always fit a frame");
+        self.seq = self.seq.wrapping_add(1);
+        self.out.extend(&buf[..len]);
+    }
+
+    fn random(&mut self) -> u32 {
+        // xorshift32
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+
+    fn send_one(&mut self) {
+        let record = self.simulate_send(self.params.payload_len, self.params.interval_us);
+        self.count(&record);
+        self.emit(Record::Send(record));
+    }
+
+    fn count(&mut self, record: &SendRecord) {
+        self.sent += 1;
+        self.retranmisisons += record.retranmisisons as u32;
+        let attempts = record.retranmisisons as u32 + 1;
+        let frame = 1 + 5 + 1 + 2 + self.params.payload_len as u64 + 2;
+        let c = &mut self.counters;
+        c.frames_sent += attempts;
+        c.acks_received += 1;
+        c.ack_timeouts += record.retranmisisons as u32;
+        c.bytes_on_air += frame * attempts as u64 + 12;
+        // The radio turns on for the ramp up, 40 us before it's ready
+        c.radio_on_us += record.total_us.saturating_sub(record.first_transmit_us - 40) as u64;
+    }
+
+    fn simulate_send(&mut self, payload_len: u8, interval_us: u32) -> SendRecord {
+        let retranmisisons = if self.random().is_multiple_of(20) {
+            1 + self.random() % 3
+        } else {
+            0
+        };
+        // Roughly the on air time of the packet plus ack with some scheduling noise on top
+        let air_us = 200 + payload_len as u32 * 8;
+        let time_elapsed_us = air_us + self.random() % 60;
+        let setup_us = 5 + self.random() % 10;
+        // A failed attempt sends the packet and then waits out the 300 us ack deadline
+        let total_us = setup_us + retranmisisons * (air_us / 2 + 300) + time_elapsed_us;
+        self.time_us += total_us as u64 + interval_us as u64;
+        let rssi = -40 - (self.random() % 10) as i8;
+        SendRecord {
+            timestamp_us: self.time_us,
+            peer: 0,
+            channel: self.params.channel,
+            rssi,
+            retranmisisons: retranmisisons as u16,
+            time_elapsed_us,
+            total_us,
+            // Setup plus the fast ramp up
+            first_transmit_us: setup_us + 40,
+        }
+    }
+
+    fn ping_one(&mut self, seq: u32) {
+        let ping = self.simulate_send(self.params.payload_len, 0);
+        self.count(&ping);
+        // The receiver's executor picks the ping up some time after acking it
+        let scheduling_us = match self.driver {
+            Driver::Polled => 20 + self.random() % 40,
+            Driver::Trad => 40 + self.random() % 120,
+        };
+        let pong = self.simulate_send(self.params.payload_len, self.params.interval_us);
+        self.emit(Record::Ping(PingRecord {
+            timestamp_us: pong.timestamp_us,
+            seq,
+            rtt_us: ping.total_us + scheduling_us + pong.total_us,
+            ack_us: ping.total_us,
+            retranmisisons: ping.retranmisisons,
+            rssi: pong.rssi,
+        }));
+    }
+
+    fn simulate_cell(&mut self, cell: Cell, repeats: u32) -> CellSummary {
+        let samples: Vec<SendRecord> = (0..repeats * cell.burst_len as u32)
+            .map(|_| self.simulate_send(cell.payload_len, cell.interval_us))
+            .collect();
+        let s = summarize(&samples);
+        CellSummary {
+            cell,
+            count: s.count as u32,
+            lost: 0,
+            min_us: s.min_us,
+            mean_us: s.mean_us as u32,
+            p50_us: s.p50_us,
+            p90_us: s.p90_us,
+            p99_us: s.p99_us,
+            p999_us: s.p999_us,
+            max_us: s.max_us,
+            jitter_us: s.jitter_us as u32,
+            retranmisisons_milli: (s.mean_retranmisisons * 1000.0) as u32,
+        }
+    }
+
+    fn simulate_stream(&mut self, duration_ms: u32) -> StreamSummary {
+        let start = self.time_us;
+        let end = start + duration_ms as u64 * 1000;
+        let mut samples = Vec::new();
+        while self.time_us < end {
+            let record = self.simulate_send(self.params.payload_len, 0);
+            self.count(&record);
+            samples.push(record);
+        }
+        let s = summarize(&samples);
+        StreamSummary {
+            driver: self.driver,
+            data_rate: self.params.data_rate,
+            payload_len: self.params.payload_len,
+            duration_us: (self.time_us - start) as u32,
+            packets: s.count as u32,
+            lost: 0,
+            retranmisisons: samples.iter().map(|r| r.retranmisisons as u32).sum(),
+            mean_us: s.mean_us as u32,
+            p50_us: s.p50_us,
+            p99_us: s.p99_us,
+            max_us: s.max_us,
+            jitter_us: s.jitter_us as u32,
+        }
+    }
+
+    fn run_plan(&mut self, plan: &Plan) {
+        let mut cells = 0;
+        let mut skipped = 0;
+        for cell in plan.cells() {
+            if cell.driver == self.driver {
+                let summary = self.simulate_cell(cell, plan.repeats);
+                self.emit(Record::CellResult(summary));
+                cells += 1;
+            } else {
+                skipped += 1;
+            }
+        }
+        self.emit(Record::PlanDone { cells, skipped });
+    }
+
+    fn finish(&mut self) {
+        self.running = false;
+        self.emit(Record::RunDone {
+            sent: self.sent,
+            retranmisisons: self.retranmisisons,
+        });
+    }
+
+    fn handle(&mut self, command: Command) {
+        let kind = command.kind();
+        let nack = |reason| Record::Nack {
+            command: kind,
+            reason,
+        };
+        match command {
+            Command::Counters => {
+                if !self.counters.is_empty() {
+                    self.emit(Record::Counters {
+                        timestamp_us: self.time_us,
+                        peer: 0,
+                        counters: self.counters,
+                    });
+                }
+                self.emit(Record::Ack(kind));
+            }
+            Command::Status => self.emit(Record::Status(Status {
+                driver: self.driver,
+                running: self.running,
+                params: self.params,
+            })),
+            _ if self.running && command != Command::Stop => self.emit(nack(NackReason::Busy)),
+            Command::Configure(params) => {
+                if params.driver != self.driver {
+                    self.emit(nack(NackReason::WrongDriver));
+                } else if params.payload_len > MAX_PAYLOAD_LEN
+                    || (params.pattern && (params.payload_len as usize) < pattern::HEADER_SIZE)
+                {
+                    self.emit(nack(NackReason::InvalidParams));
+                } else {
+                    self.params = params;
+                    self.emit(Record::Ack(kind));
+                }
+            }
+            Command::Start => {
+                self.emit(Record::Ack(kind));
+                self.running = true;
+                self.sent = 0;
+                self.retranmisisons = 0;
+                // Everything happens instantly here, so a finite run completes right away and
+                // an endless one waits for stop
+                if self.params.count > 0 {
+                    for _ in 0..self.params.count {
+                        self.send_one();
+                    }
+                    self.finish();
+                }
+            }
+            Command::Ping => {
+                self.emit(Record::Ack(kind));
+                self.running = true;
+                self.sent = 0;
+                self.retranmisisons = 0;
+                if self.params.count > 0 {
+                    for seq in 0..self.params.count {
+                        self.ping_one(seq);
+                    }
+                    self.finish();
+                }
+            }
+            Command::RunPlan(plan) => {
+                self.emit(Record::Ack(kind));
+                self.run_plan(&plan);
+            }
+            Command::Stream { duration_ms } => {
+                self.emit(Record::Ack(kind));
+                let summary = self.simulate_stream(duration_ms);
+                self.emit(Record::StreamDone(summary));
+            }
+            Command::Stop => {
+                if self.running {
+                    self.emit(Record::Ack(kind));
+                    self.finish();
+                } else {
+                    self.emit(nack(NackReason::NotRunning));
+                }
+            }
+        }
+    }
+}
+
+impl Write for SimDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            if let Some(Ok(frame)) = self.decoder.push::<Command>(b) {
+                self.handle(frame.message);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for SimDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.out.len());
+        for (dst, src) in buf.iter_mut().zip(self.out.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
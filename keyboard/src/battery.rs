@@ -0,0 +1,315 @@
+//! Battery level of the halves.
+//!
+//! A half samples its supply with the SAADC, turns the raw sample into millivolts with a
+//! [`Scale`], smooths it with a [`Filter`] and maps it onto a discharge [`Curve`]. The voltage of a
+//! cell sags while the radio is transmitting and recovers after, so single samples jump around by
+//! more than a few percent. [`Monitor`] puts these together and decides when a level is worth
+//! sending: once per interval, so a dongle that missed one hears about it again, and right away
+//! when it moved by a step or more since the last one sent.
+
+/// Supply voltage and the charge left it stands for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Level {
+    pub millivolts: u16,
+    pub percent: u8,
+}
+
+/// Turns raw SAADC samples into millivolts at the battery
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Scale {
+    /// Input that reads as the largest sample, the reference divided by the gain
+    pub full_scale_mv: u32,
+    pub resolution_bits: u8,
+    /// Divider in front of the input, 1 if the battery is sampled directly
+    pub divider: u32,
+}
+
+impl Scale {
+    /// VDDH / 5 on the nrf52840 with the internal 0.6 V reference, a gain of 1/6 and 12 bits
+    pub const VDDH_DIV5: Self = Self {
+        full_scale_mv: 3600,
+        resolution_bits: 12,
+        divider: 5,
+    };
+
+    /// Negative samples, from noise around 0 V, read as 0
+    pub fn millivolts(&self, raw: i16) -> u16 {
+        let raw = raw.max(0) as u64;
+        let mv = (raw * self.full_scale_mv as u64 * self.divider as u64) >> self.resolution_bits;
+        mv.min(u16::MAX as u64) as u16
+    }
+}
+
+/// Discharge curve as points of millivolts and percent, linear in between
+#[derive(Clone, Copy, Debug)]
+pub struct Curve<'a> {
+    /// Rising in both voltage and percent
+    points: &'a [(u16, u8)],
+}
+
+impl<'a> Curve<'a> {
+    /// Single cell lithium polymer, at the low currents a keyboard half draws
+    pub const LIPO: Curve<'static> = Curve::new(&[
+        (3300, 0),
+        (3500, 5),
+        (3600, 10),
+        (3650, 20),
+        (3700, 30),
+        (3750, 40),
+        (3800, 50),
+        (3900, 65),
+        (4000, 80),
+        (4100, 90),
+        (4200, 100),
+    ]);
+
+    pub const fn new(points: &'a [(u16, u8)]) -> Self {
+        Self { points }
+    }
+
+    /// Below the first point is its percent, above the last one its percent, 0 without points
+    pub fn percent(&self, millivolts: u16) -> u8 {
+        let Some(&(first_mv, first)) = self.points.first() else {
+            return 0;
+        };
+        if millivolts <= first_mv {
+            return first;
+        }
+        for pair in self.points.windows(2) {
+            let &[(lo_mv, lo), (hi_mv, hi)] = pair else {
+                continue;
+            };
+            if millivolts > hi_mv {
+                continue;
+            }
+            let span = hi_mv.saturating_sub(lo_mv).max(1) as u32;
+            let into = millivolts.saturating_sub(lo_mv) as u32;
+            return lo + ((hi.saturating_sub(lo) as u32 * into + span / 2) / span) as u8;
+        }
+        self.points.last().map_or(first, |&(_, percent)| percent)
+    }
+}
+
+/// Exponential moving average over millivolts. Each sample moves the average by
+/// 1 / 2^`shift` of its distance, the first one is taken as is.
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    /// Average with `shift` bits of fraction, `None` until the first sample
+    avg: Option<u32>,
+    shift: u8,
+}
+
+impl Filter {
+    pub const fn new(shift: u8) -> Self {
+        Self { avg: None, shift }
+    }
+
+    pub fn add(&mut self, millivolts: u16) -> u16 {
+        let avg = match self.avg {
+            // Rounded like the average handed out, so it settles on the samples exactly
+            Some(avg) => avg - ((avg + self.half()) >> self.shift) + millivolts as u32,
+            None => (millivolts as u32) << self.shift,
+        };
+        self.avg = Some(avg);
+        self.average().unwrap_or(millivolts)
+    }
+
+    pub fn average(&self) -> Option<u16> {
+        self.avg
+            .map(|avg| ((avg + self.half()) >> self.shift) as u16)
+    }
+
+    fn half(&self) -> u32 {
+        (1 << self.shift) >> 1
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MonitorConfig {
+    /// Samples averaged, as the filter shift
+    pub filter_shift: u8,
+    /// Longest a level goes unsent
+    pub interval_us: u64,
+    /// Change in percent that's sent right away
+    pub step: u8,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            filter_shift: 3,
+            interval_us: 60_000_000,
+            step: 5,
+        }
+    }
+}
+
+/// Half side, filters samples and picks the levels that go out
+pub struct Monitor<'a> {
+    curve: Curve<'a>,
+    filter: Filter,
+    config: MonitorConfig,
+    /// Last level handed out and when
+    sent: Option<(Level, u64)>,
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(curve: Curve<'a>, config: MonitorConfig) -> Self {
+        Self {
+            curve,
+            filter: Filter::new(config.filter_shift),
+            config,
+            sent: None,
+        }
+    }
+
+    /// Filtered level, `None` before the first sample
+    pub fn level(&self) -> Option<Level> {
+        self.filter.average().map(|millivolts| Level {
+            millivolts,
+            percent: self.curve.percent(millivolts),
+        })
+    }
+
+    /// Adds a sample taken at `now_us`, returning the level if it's due to be sent
+    pub fn sample(&mut self, millivolts: u16, now_us: u64) -> Option<Level> {
+        self.filter.add(millivolts);
+        let level = self.level()?;
+        let due = self.sent.is_none_or(|(sent, at_us)| {
+            now_us.saturating_sub(at_us) >= self.config.interval_us
+                || sent.percent.abs_diff(level.percent) >= self.config.step
+        });
+        if !due {
+            return None;
+        }
+        self.sent = Some((level, now_us));
+        Some(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const SECOND_US: u64 = 1_000_000;
+
+    #[test]
+    fn scale_vddh() {
+        let scale = Scale::VDDH_DIV5;
+        assert_eq!(scale.millivolts(0), 0);
+        assert_eq!(scale.millivolts(-3), 0);
+        // 3.7 V is 740 mV at the input
+        assert_eq!(scale.millivolts(842), 3700);
+        assert_eq!(scale.millivolts(4095), 17_995);
+        let scale = Scale {
+            divider: 100,
+            ..scale
+        };
+        assert_eq!(scale.millivolts(i16::MAX), u16::MAX);
+    }
+
+    #[test]
+    fn curve_interpolates_between_points() {
+        let curve = Curve::LIPO;
+        assert_eq!(curve.percent(0), 0);
+        assert_eq!(curve.percent(3300), 0);
+        assert_eq!(curve.percent(3400), 3);
+        assert_eq!(curve.percent(3725), 35);
+        assert_eq!(curve.percent(3800), 50);
+        assert_eq!(curve.percent(3950), 73);
+        assert_eq!(curve.percent(4200), 100);
+        assert_eq!(curve.percent(4350), 100);
+        assert_eq!(Curve::new(&[]).percent(3800), 0);
+        assert_eq!(Curve::new(&[(3000, 40)]).percent(3800), 40);
+    }
+
+    #[test]
+    fn curve_never_falls_with_voltage() {
+        let percents: Vec<u8> = (3000..4400).map(|mv| Curve::LIPO.percent(mv)).collect();
+        assert!(percents.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn filter_takes_the_first_sample_and_settles_on_steps() {
+        let mut filter = Filter::new(3);
+        assert_eq!(filter.average(), None);
+        assert_eq!(filter.add(4000), 4000);
+        assert_eq!(filter.add(4000), 4000);
+        // An eighth of the way towards a new sample
+        assert_eq!(filter.add(3800), 3975);
+        let mut last = 3975;
+        for _ in 0..100 {
+            let avg = filter.add(3800);
+            assert!(avg <= last);
+            last = avg;
+        }
+        assert_eq!(last, 3800);
+        assert_eq!(Filter::new(0).add(1234), 1234);
+    }
+
+    #[test]
+    fn filter_smooths_noise() {
+        let mut filter = Filter::new(3);
+        for i in 0..200 {
+            let avg = filter.add(if i % 2 == 0 { 3750 } else { 3850 });
+            if i > 20 {
+                assert!((3790..=3810).contains(&avg), "{avg}");
+            }
+        }
+    }
+
+    /// A cell at 3.8 V that sags by 150 mV whenever a sample lands on a transmission
+    fn sagging(i: u64) -> u16 {
+        if i % 4 == 3 {
+            3650
+        } else {
+            3800
+        }
+    }
+
+    #[test]
+    fn monitor_sends_on_interval_and_steps() {
+        let config = MonitorConfig::default();
+        let mut monitor = Monitor::new(Curve::LIPO, config);
+        assert_eq!(monitor.level(), None);
+        let first = monitor.sample(3800, 0).unwrap();
+        assert_eq!(
+            first,
+            Level {
+                millivolts: 3800,
+                percent: 50
+            }
+        );
+        assert_eq!(monitor.sample(3800, SECOND_US), None);
+        assert_eq!(monitor.sample(3800, config.interval_us), Some(first));
+        // A big enough drop goes out right away
+        let mut sent = None;
+        for i in 1..60 {
+            sent = sent.or(monitor.sample(3700, config.interval_us + i * SECOND_US));
+        }
+        let sent = sent.unwrap();
+        assert!(sent.percent <= 45, "{sent:?}");
+        assert_eq!(monitor.level().unwrap().percent, 30);
+    }
+
+    #[test]
+    fn radio_sag_does_not_flood_the_link() {
+        let config = MonitorConfig::default();
+        let mut monitor = Monitor::new(Curve::LIPO, config);
+        let mut sent = Vec::new();
+        // Ten minutes of a sample a second
+        for i in 0..600 {
+            if let Some(level) = monitor.sample(sagging(i), i * SECOND_US) {
+                sent.push((i, level.percent));
+            }
+        }
+        assert!(sent.iter().all(|(_, p)| (40..=50).contains(p)), "{sent:?}");
+        // Once the average has settled below the first sample only the interval sends are left
+        let settled: Vec<_> = sent.iter().filter(|(i, _)| *i >= 30).collect();
+        assert!(settled.len() <= 10, "{sent:?}");
+    }
+}
@@ -0,0 +1,185 @@
+//! Combos, several keys pressed together standing in for another one.
+//!
+//! A combo presses a position of its own, usually in a row past the physical matrix, so the keymap
+//! decides what it does on every layer. A press of a key that's part of some combo is held back
+//! until the keys held back are exactly a combo, which then goes down instead of them, or until
+//! they can't become one anymore: the combo term ran out, one of them was released or some other
+//! key changed that doesn't take them closer to a combo. Then they come out as they were, at the
+//! time they happened. A combo comes up as soon as any of its keys is released and the releases of
+//! the others are swallowed. Keys that aren't part of any combo are never delayed.
+
+use crate::matrix::{KeyChange, KeyPos};
+
+/// Keys a combo can have at most, longer ones never fire
+pub const MAX_KEYS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Combo<'a> {
+    pub keys: &'a [KeyPos],
+    /// Position pressed while the combo is held
+    pub output: KeyPos,
+}
+
+impl Combo<'_> {
+    fn contains(&self, pos: KeyPos) -> bool {
+        self.keys.len() <= MAX_KEYS && self.keys.contains(&pos)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ComboConfig {
+    /// How long after the first key the others may come for the keys to count as a combo
+    pub term_us: u32,
+}
+
+impl Default for ComboConfig {
+    fn default() -> Self {
+        Self { term_us: 50_000 }
+    }
+}
+
+pub struct Combos<'a, const N: usize> {
+    combos: &'a [Combo<'a>; N],
+    config: ComboConfig,
+    /// Presses held back, oldest first
+    pending: [(KeyPos, u64); MAX_KEYS],
+    pending_len: usize,
+    /// Keys of each fired combo that are still down, one bit per key
+    down: [u8; N],
+    /// Fired combos whose output is still pressed
+    pressed: [bool; N],
+}
+
+impl<'a, const N: usize> Combos<'a, N> {
+    pub fn new(combos: &'a [Combo<'a>; N], config: ComboConfig) -> Self {
+        Self {
+            combos,
+            config,
+            pending: [(KeyPos { row: 0, col: 0 }, 0); MAX_KEYS],
+            pending_len: 0,
+            down: [0; N],
+            pressed: [false; N],
+        }
+    }
+
+    pub fn config(&self) -> &ComboConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: ComboConfig) {
+        self.config = config;
+    }
+
+    /// When the keys held back come out as they are, `None` if there aren't any
+    pub fn next_deadline(&self) -> Option<u64> {
+        (self.pending_len > 0).then(|| self.pending[0].1 + self.config.term_us as u64)
+    }
+
+    /// Takes a key change that happened at `at_us`. Changes that go on to the keymap are handed
+    /// to `out` with the time they happened, possibly along with ones held back earlier.
+    pub fn event(&mut self, change: KeyChange, at_us: u64, mut out: impl FnMut(KeyChange, u64)) {
+        self.tick(at_us, &mut out);
+        if change.pressed && self.pend(change.pos, at_us, &mut out) {
+            return;
+        }
+        self.resolve(&mut out);
+        if change.pressed {
+            if !self.pend(change.pos, at_us, &mut out) {
+                out(change, at_us);
+            }
+            return;
+        }
+        let mut swallowed = false;
+        for (i, combo) in self.combos.iter().enumerate() {
+            let Some(key) = combo.keys.iter().position(|&k| k == change.pos) else {
+                continue;
+            };
+            if self.down[i] & (1 << key) == 0 {
+                continue;
+            }
+            self.down[i] &= !(1 << key);
+            swallowed = true;
+            if core::mem::take(&mut self.pressed[i]) {
+                out(
+                    KeyChange {
+                        pos: combo.output,
+                        pressed: false,
+                    },
+                    at_us,
+                );
+            }
+        }
+        if !swallowed {
+            out(change, at_us);
+        }
+    }
+
+    /// Lets the keys held back out once the combo term has passed at `now_us`
+    pub fn tick(&mut self, now_us: u64, mut out: impl FnMut(KeyChange, u64)) {
+        if self
+            .next_deadline()
+            .is_some_and(|deadline| now_us >= deadline)
+        {
+            self.resolve(&mut out);
+        }
+    }
+
+    fn pending(&self) -> impl Iterator<Item = KeyPos> + '_ {
+        self.pending[..self.pending_len].iter().map(|&(pos, _)| pos)
+    }
+
+    /// Combos the keys held back could still become
+    fn candidates(&self) -> impl Iterator<Item = (usize, &Combo<'a>)> + '_ {
+        self.combos
+            .iter()
+            .enumerate()
+            .filter(|(_, combo)| self.pending().all(|pos| combo.contains(pos)))
+    }
+
+    /// Holds back a press if it keeps the keys held back on their way to some combo, firing the
+    /// combo once it's complete and can't grow into a longer one
+    fn pend(&mut self, pos: KeyPos, at_us: u64, out: &mut impl FnMut(KeyChange, u64)) -> bool {
+        if self.pending_len == MAX_KEYS || !self.candidates().any(|(_, combo)| combo.contains(pos))
+        {
+            return false;
+        }
+        self.pending[self.pending_len] = (pos, at_us);
+        self.pending_len += 1;
+        if self
+            .candidates()
+            .all(|(_, combo)| combo.keys.len() == self.pending_len)
+        {
+            self.resolve(out);
+        }
+        true
+    }
+
+    /// Fires the combo the keys held back make up, or lets them out as they are
+    fn resolve(&mut self, out: &mut impl FnMut(KeyChange, u64)) {
+        if self.pending_len == 0 {
+            return;
+        }
+        let complete = self
+            .candidates()
+            .find(|(_, combo)| combo.keys.len() == self.pending_len)
+            .map(|(i, _)| i);
+        let pending = &self.pending[..self.pending_len];
+        self.pending_len = 0;
+        let Some(i) = complete else {
+            for &(pos, at_us) in pending {
+                out(KeyChange { pos, pressed: true }, at_us);
+            }
+            return;
+        };
+        let combo = &self.combos[i];
+        self.down[i] = (1u16 << combo.keys.len()).wrapping_sub(1) as u8;
+        self.pressed[i] = true;
+        out(
+            KeyChange {
+                pos: combo.output,
+                pressed: true,
+            },
+            pending[pending.len() - 1].1,
+        );
+    }
+}
@@ -0,0 +1,422 @@
+//! Debouncing of raw key changes.
+//!
+//! Every strategy trades noise immunity for latency, so each debounced change carries the time it
+//! was held back: from the first raw edge of the transition until it was reported. Eager modes
+//! report the first edge right away and then ignore the key (or with [`Scope::Global`] the whole
+//! matrix) for the debounce time. Defer modes wait until the key (or the whole matrix) has been
+//! quiet for the debounce time. Press and release can use different modes and times.
+//!
+//! Everything is driven by timestamps passed in by the caller, so recorded traces replay the same
+//! on the host as on the keyboard.
+
+use crate::matrix::{KeyChange, KeyPos};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// Report the first edge, then ignore the key for the debounce time
+    Eager,
+    /// Report once the key has been stable for the debounce time
+    Defer,
+}
+
+/// What a debounce timer covers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scope {
+    /// Every key has its own timer
+    PerKey,
+    /// One timer for the whole matrix, any raw change restarts it
+    Global,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DebounceConfig {
+    pub press: Mode,
+    pub release: Mode,
+    pub press_us: u32,
+    pub release_us: u32,
+    pub scope: Scope,
+}
+
+impl DebounceConfig {
+    /// Waits out 5 ms of quiet on each key in both directions
+    pub const SYM_DEFER_PK: Self = Self::symmetric(Mode::Defer, 5000, Scope::PerKey);
+    /// Waits out 5 ms of quiet on the whole matrix in both directions
+    pub const SYM_DEFER_G: Self = Self::symmetric(Mode::Defer, 5000, Scope::Global);
+    /// Reports every edge right away and ignores the key for 5 ms after
+    pub const SYM_EAGER_PK: Self = Self::symmetric(Mode::Eager, 5000, Scope::PerKey);
+    /// Reports presses right away and waits out 5 ms of quiet before releases
+    pub const ASYM_EAGER_DEFER_PK: Self = Self {
+        press: Mode::Eager,
+        release: Mode::Defer,
+        press_us: 5000,
+        release_us: 5000,
+        scope: Scope::PerKey,
+    };
+    /// Passes raw changes straight through
+    pub const NONE: Self = Self::symmetric(Mode::Eager, 0, Scope::PerKey);
+
+    pub const fn symmetric(mode: Mode, us: u32, scope: Scope) -> Self {
+        Self {
+            press: mode,
+            release: mode,
+            press_us: us,
+            release_us: us,
+            scope,
+        }
+    }
+
+    fn mode(&self, pressed: bool) -> Mode {
+        if pressed {
+            self.press
+        } else {
+            self.release
+        }
+    }
+
+    fn time_us(&self, pressed: bool) -> u64 {
+        if pressed {
+            self.press_us as u64
+        } else {
+            self.release_us as u64
+        }
+    }
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self::SYM_DEFER_PK
+    }
+}
+
+/// A change that made it through, along with the latency debouncing added to it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Debounced {
+    pub change: KeyChange,
+    /// From the first raw edge of the transition until it was reported
+    pub added_us: u32,
+}
+
+/// Totals over everything a [`Debouncer`] has seen
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct DebounceStats {
+    /// Changes reported
+    pub reported: u32,
+    /// Transitions that settled back to the reported state, glitches or noise
+    pub suppressed: u32,
+    pub added_sum_us: u64,
+    pub added_max_us: u32,
+}
+
+impl DebounceStats {
+    pub fn mean_added_us(&self) -> Option<u32> {
+        (self.reported > 0).then(|| (self.added_sum_us / self.reported as u64) as u32)
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+struct KeyState {
+    /// State as reported
+    stable: bool,
+    /// Latest raw state
+    raw: bool,
+    /// When the raw state last changed
+    raw_since: u64,
+    /// When the raw state first left `stable` in the current transition, kept through bounces
+    /// back to `stable` until the transition is reported or dropped
+    edge_since: Option<u64>,
+    /// Eager modes ignore the key until then
+    locked_until: u64,
+}
+
+impl KeyState {
+    fn pending(&self) -> bool {
+        self.raw != self.stable || self.edge_since.is_some()
+    }
+}
+
+pub struct Debouncer<const ROWS: usize, const COLS: usize> {
+    config: DebounceConfig,
+    keys: [[KeyState; COLS]; ROWS],
+    /// When any raw state last changed, for the global scope
+    raw_since: u64,
+    /// Eager modes ignore the whole matrix until then with the global scope
+    locked_until: u64,
+    stats: DebounceStats,
+}
+
+impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> {
+    pub fn new(config: DebounceConfig) -> Self {
+        Self {
+            config,
+            keys: [[KeyState::default(); COLS]; ROWS],
+            raw_since: 0,
+            locked_until: 0,
+            stats: DebounceStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &DebounceConfig {
+        &self.config
+    }
+
+    /// Switches strategy. Transitions in progress are judged by the new one from here on
+    pub fn set_config(&mut self, config: DebounceConfig) {
+        self.config = config;
+    }
+
+    pub fn stats(&self) -> DebounceStats {
+        self.stats
+    }
+
+    /// When the pending transition of `key` can be reported, or dropped if it settled back
+    fn due(&self, key: &KeyState) -> u64 {
+        let pressed = !key.stable;
+        let t = self.config.time_us(pressed);
+        match (self.config.mode(pressed), self.config.scope) {
+            (Mode::Eager, Scope::PerKey) => key.locked_until,
+            (Mode::Eager, Scope::Global) => self.locked_until,
+            (Mode::Defer, Scope::PerKey) => key.raw_since + t,
+            (Mode::Defer, Scope::Global) => self.raw_since + t,
+        }
+    }
+
+    fn report(&mut self, pos: KeyPos, now_us: u64) -> Debounced {
+        let pressed = self.keys[pos.row as usize][pos.col as usize].raw;
+        let lock = now_us + self.config.time_us(pressed);
+        let eager = self.config.mode(pressed) == Mode::Eager;
+        let key = &mut self.keys[pos.row as usize][pos.col as usize];
+        let added_us = now_us.saturating_sub(key.edge_since.take().unwrap_or(now_us));
+        let added_us = added_us.min(u32::MAX as u64) as u32;
+        key.stable = pressed;
+        if eager {
+            key.locked_until = lock;
+            if self.config.scope == Scope::Global {
+                self.locked_until = lock;
+            }
+        }
+        self.stats.reported += 1;
+        self.stats.added_sum_us += added_us as u64;
+        self.stats.added_max_us = self.stats.added_max_us.max(added_us);
+        Debounced {
+            change: KeyChange { pos, pressed },
+            added_us,
+        }
+    }
+
+    /// Feeds a raw change seen at `now_us`. Returns it right away if the strategy lets it
+    /// through, otherwise it's held back until [`Debouncer::poll`] reports or drops it.
+    /// Positions outside the matrix are ignored.
+    pub fn update(&mut self, change: KeyChange, now_us: u64) -> Option<Debounced> {
+        let (row, col) = (change.pos.row as usize, change.pos.col as usize);
+        let key = self.keys.get_mut(row)?.get_mut(col)?;
+        if key.raw == change.pressed {
+            return None;
+        }
+        key.raw = change.pressed;
+        key.raw_since = now_us;
+        self.raw_since = now_us;
+        if key.raw == key.stable {
+            return None;
+        }
+        key.edge_since.get_or_insert(now_us);
+        let key = *key;
+        (self.config.mode(key.raw) == Mode::Eager && now_us >= self.due(&key))
+            .then(|| self.report(change.pos, now_us))
+    }
+
+    /// Reports the next held back change that's due at `now_us` and drops transitions that
+    /// settled back. Call until it returns `None` whenever [`Debouncer::next_deadline`] passes.
+    pub fn poll(&mut self, now_us: u64) -> Option<Debounced> {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let key = self.keys[row][col];
+                if !key.pending() || now_us < self.due(&key) {
+                    continue;
+                }
+                if key.raw == key.stable {
+                    self.keys[row][col].edge_since = None;
+                    self.stats.suppressed += 1;
+                    continue;
+                }
+                let pos = KeyPos {
+                    row: row as u8,
+                    col: col as u8,
+                };
+                return Some(self.report(pos, now_us));
+            }
+        }
+        None
+    }
+
+    /// Earliest time a held back change becomes due or a settled one gets dropped, `None` if
+    /// nothing is held back
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.keys
+            .iter()
+            .flatten()
+            .filter(|key| key.pending())
+            .map(|key| self.due(key))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const A: KeyPos = KeyPos { row: 0, col: 0 };
+    const B: KeyPos = KeyPos { row: 1, col: 2 };
+
+    /// Raw edges of one key recorded off a worn switch: a press bouncing for 1.1 ms, a release
+    /// bouncing for 0.4 ms and a 50 µs glitch from noise on the line
+    const TRACE: &[(u64, bool)] = &[
+        (0, true),
+        (150, false),
+        (300, true),
+        (900, false),
+        (1_100, true),
+        (50_000, false),
+        (50_200, true),
+        (50_400, false),
+        (80_000, true),
+        (80_050, false),
+    ];
+
+    fn trace(pos: KeyPos, edges: &[(u64, bool)]) -> Vec<(u64, KeyChange)> {
+        edges
+            .iter()
+            .map(|&(t, pressed)| (t, KeyChange { pos, pressed }))
+            .collect()
+    }
+
+    /// Replays raw changes, polling at every deadline in between like the firmware does. Returns
+    /// the reported changes with the time they came out.
+    fn replay(
+        debouncer: &mut Debouncer<2, 3>,
+        changes: &[(u64, KeyChange)],
+    ) -> Vec<(u64, KeyChange, u32)> {
+        let mut res = Vec::new();
+        let drain = |debouncer: &mut Debouncer<2, 3>, until: u64, res: &mut Vec<_>| {
+            while let Some(deadline) = debouncer.next_deadline().filter(|&d| d <= until) {
+                while let Some(d) = debouncer.poll(deadline) {
+                    res.push((deadline, d.change, d.added_us));
+                }
+            }
+        };
+        for &(t, change) in changes {
+            drain(debouncer, t, &mut res);
+            if let Some(d) = debouncer.update(change, t) {
+                res.push((t, d.change, d.added_us));
+            }
+        }
+        drain(debouncer, u64::MAX, &mut res);
+        res
+    }
+
+    fn press(pos: KeyPos) -> KeyChange {
+        KeyChange { pos, pressed: true }
+    }
+
+    fn release(pos: KeyPos) -> KeyChange {
+        KeyChange {
+            pos,
+            pressed: false,
+        }
+    }
+
+    #[test]
+    fn defer_waits_out_the_bounces() {
+        let mut debouncer = Debouncer::new(DebounceConfig::SYM_DEFER_PK);
+        let out = replay(&mut debouncer, &trace(A, TRACE));
+        assert_eq!(out, [(6_100, press(A), 6_100), (55_400, release(A), 5_400)]);
+        let stats = debouncer.stats();
+        assert_eq!(stats.reported, 2);
+        assert_eq!(stats.suppressed, 1);
+        assert_eq!(stats.added_max_us, 6_100);
+        assert_eq!(stats.mean_added_us(), Some(5_750));
+    }
+
+    #[test]
+    fn eager_reports_first_edges_and_lets_glitches_through() {
+        let mut debouncer = Debouncer::new(DebounceConfig::SYM_EAGER_PK);
+        let out = replay(&mut debouncer, &trace(A, TRACE));
+        assert_eq!(
+            out,
+            [
+                (0, press(A), 0),
+                (50_000, release(A), 0),
+                (80_000, press(A), 0),
+                // The release came in while the key was locked
+                (85_000, release(A), 4_950),
+            ]
+        );
+        // Both bounce bursts settled back before the lock ran out
+        assert_eq!(debouncer.stats().suppressed, 2);
+    }
+
+    #[test]
+    fn asymmetric_presses_fast_and_releases_clean() {
+        let mut debouncer = Debouncer::new(DebounceConfig::ASYM_EAGER_DEFER_PK);
+        let out = replay(&mut debouncer, &trace(A, TRACE));
+        assert_eq!(
+            out,
+            [
+                (0, press(A), 0),
+                (55_400, release(A), 5_400),
+                (80_000, press(A), 0),
+                (85_050, release(A), 5_000),
+            ]
+        );
+        assert_eq!(debouncer.stats().suppressed, 1);
+    }
+
+    #[test]
+    fn no_debouncing_passes_every_edge() {
+        let mut debouncer = Debouncer::new(DebounceConfig::NONE);
+        let out = replay(&mut debouncer, &trace(A, TRACE));
+        let expected: Vec<_> = trace(A, TRACE)
+            .into_iter()
+            .map(|(t, change)| (t, change, 0))
+            .collect();
+        assert_eq!(out, expected);
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+
+    #[test]
+    fn keys_bounce_independently_per_key() {
+        let mut changes = trace(A, &[(0, true)]);
+        changes.extend(trace(B, &[(1_000, true), (3_000, false)]));
+        let mut debouncer = Debouncer::new(DebounceConfig::SYM_DEFER_PK);
+        assert_eq!(replay(&mut debouncer, &changes), [(5_000, press(A), 5_000)]);
+    }
+
+    #[test]
+    fn global_scope_waits_for_the_whole_matrix() {
+        let mut changes = trace(A, &[(0, true)]);
+        changes.extend(trace(B, &[(1_000, true), (3_000, false)]));
+        let mut debouncer = Debouncer::new(DebounceConfig::SYM_DEFER_G);
+        assert_eq!(replay(&mut debouncer, &changes), [(8_000, press(A), 8_000)]);
+        assert_eq!(debouncer.stats().suppressed, 1);
+
+        // An eager report locks every key
+        let mut debouncer =
+            Debouncer::new(DebounceConfig::symmetric(Mode::Eager, 5_000, Scope::Global));
+        let changes = [(0, press(A)), (1_000, press(B))];
+        assert_eq!(
+            replay(&mut debouncer, &changes),
+            [(0, press(A), 0), (5_000, press(B), 4_000)]
+        );
+    }
+
+    #[test]
+    fn positions_outside_the_matrix_are_ignored() {
+        let mut debouncer = Debouncer::<2, 3>::new(DebounceConfig::NONE);
+        assert_eq!(debouncer.update(press(KeyPos { row: 2, col: 0 }), 0), None);
+        assert_eq!(debouncer.update(press(KeyPos { row: 0, col: 3 }), 0), None);
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+}
@@ -0,0 +1,774 @@
+//! Key events as they go over the radio.
+//!
+//! Every payload starts with a header byte holding the format version in the high nibble and the
+//! message kind in the low one. Key halves send deltas, the keys that changed since the last
+//! payload, each under a wrapping sequence number. Now and then, after a send that went unacked or
+//! when the receiver asks, they send the full state of the matrix as a bitmap instead. A receiver
+//! that sees a gap in the sequence numbers asks for the full state, and applying it releases any
+//! key whose release got lost. A delta also says how long ago its changes happened, so a receiver
+//! taking in more than one half can put them back in order.
+//!
+//! Halves with a pointing device send its buttons and the motion since their previous pointer
+//! payload, see [`crate::pointer`], and ones with media keys send every consumer usage they hold
+//! whenever that changes. Both are sent again while anything is held, the same as the full state.
+//! Battery powered halves send their [`Level`] now and then, only the latest one matters.
+//!
+//! ```text
+//! delta        header seq count age_us:u16 (row | pressed << 7, col) * count
+//! full state   header seq rows cols bitmap, row major, lsb first
+//! request      header last seq
+//! pointer      header seq buttons x:i16 y:i16 wheel:i8 pan:i8
+//! consumer     header count usage:u16 * count
+//! battery      header millivolts:u16 percent
+//! ```
+
+use crate::{
+    battery::Level,
+    matrix::{KeyChange, KeyPos},
+    pointer::Motion,
+};
+
+pub const VERSION: u8 = 2;
+/// Largest payload the radio carries
+pub const MAX_LEN: usize = 32;
+/// Most changes a single delta carries, more go out as a full state
+pub const MAX_CHANGES: usize = (MAX_LEN - DELTA_HEADER) / 2;
+/// Most consumer usages a half can hold at once
+pub const MAX_CONSUMER_USAGES: usize = (MAX_LEN - 2) / 2;
+
+const DELTA_HEADER: usize = 5;
+
+const DELTA: u8 = 1;
+const FULL_STATE: u8 = 2;
+const STATE_REQUEST: u8 = 3;
+const POINTER: u8 = 4;
+const CONSUMER: u8 = 5;
+const BATTERY: u8 = 6;
+
+const PRESSED: u8 = 0x80;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// Shorter or longer than its header says
+    WrongLength,
+    /// Written by a different format version
+    Version(u8),
+    UnknownKind(u8),
+    /// A key or matrix size out of range
+    Malformed,
+}
+
+/// Key changes in a delta. Only the length is checked when decoding, keys outside the matrix
+/// are up to the receiver.
+#[derive(Clone, Debug)]
+pub struct Changes<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for Changes<'_> {
+    type Item = KeyChange;
+
+    fn next(&mut self) -> Option<KeyChange> {
+        let (&[row, col], rest) = self.bytes.split_first_chunk::<2>()?;
+        self.bytes = rest;
+        Some(KeyChange {
+            pos: KeyPos {
+                row: row & !PRESSED,
+                col,
+            },
+            pressed: row & PRESSED != 0,
+        })
+    }
+}
+
+/// Pressed keys of a full state
+#[derive(Clone, Copy, Debug)]
+pub struct Bitmap<'a> {
+    pub rows: u8,
+    pub cols: u8,
+    bits: &'a [u8],
+}
+
+impl Bitmap<'_> {
+    pub fn is_pressed(&self, pos: KeyPos) -> bool {
+        if pos.row >= self.rows || pos.col >= self.cols {
+            return false;
+        }
+        let i = pos.row as usize * self.cols as usize + pos.col as usize;
+        self.bits[i / 8] & (1 << (i % 8)) != 0
+    }
+}
+
+/// Consumer page usages held by a half
+#[derive(Clone, Debug)]
+pub struct Usages<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for Usages<'_> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        let (&usage, rest) = self.bytes.split_first_chunk::<2>()?;
+        self.bytes = rest;
+        Some(u16::from_le_bytes(usage))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Message<'a> {
+    Delta {
+        seq: u8,
+        /// From the oldest change until the payload was written, saturating
+        age_us: u16,
+        changes: Changes<'a>,
+    },
+    FullState {
+        seq: u8,
+        state: Bitmap<'a>,
+    },
+    /// Sent back by the receiver, `last_seq` is the last sequence number it applied
+    StateRequest {
+        last_seq: u8,
+    },
+    Pointer {
+        seq: u8,
+        buttons: u8,
+        /// Since the previous pointer payload
+        motion: Motion,
+    },
+    Consumer {
+        usages: Usages<'a>,
+    },
+    Battery {
+        level: Level,
+    },
+}
+
+fn bitmap_len(rows: u8, cols: u8) -> usize {
+    (rows as usize * cols as usize).div_ceil(8)
+}
+
+pub fn decode(payload: &[u8]) -> Result<Message<'_>, DecodeError> {
+    let (&header, body) = payload.split_first().ok_or(DecodeError::WrongLength)?;
+    if header >> 4 != VERSION {
+        return Err(DecodeError::Version(header >> 4));
+    }
+    match header & 0x0F {
+        DELTA => {
+            let [seq, count, age_0, age_1, ref changes @ ..] = *body else {
+                return Err(DecodeError::WrongLength);
+            };
+            if changes.len() != count as usize * 2 {
+                return Err(DecodeError::WrongLength);
+            }
+            Ok(Message::Delta {
+                seq,
+                age_us: u16::from_le_bytes([age_0, age_1]),
+                changes: Changes { bytes: changes },
+            })
+        }
+        FULL_STATE => {
+            let [seq, rows, cols, ref bits @ ..] = *body else {
+                return Err(DecodeError::WrongLength);
+            };
+            if rows >= PRESSED {
+                return Err(DecodeError::Malformed);
+            }
+            if bits.len() != bitmap_len(rows, cols) {
+                return Err(DecodeError::WrongLength);
+            }
+            Ok(Message::FullState {
+                seq,
+                state: Bitmap { rows, cols, bits },
+            })
+        }
+        STATE_REQUEST => match *body {
+            [last_seq] => Ok(Message::StateRequest { last_seq }),
+            _ => Err(DecodeError::WrongLength),
+        },
+        POINTER => match *body {
+            [seq, buttons, x_0, x_1, y_0, y_1, wheel, pan] => Ok(Message::Pointer {
+                seq,
+                buttons,
+                motion: Motion {
+                    x: i16::from_le_bytes([x_0, x_1]) as i32,
+                    y: i16::from_le_bytes([y_0, y_1]) as i32,
+                    wheel: wheel as i8 as i32,
+                    pan: pan as i8 as i32,
+                },
+            }),
+            _ => Err(DecodeError::WrongLength),
+        },
+        CONSUMER => {
+            let [count, ref usages @ ..] = *body else {
+                return Err(DecodeError::WrongLength);
+            };
+            if usages.len() != count as usize * 2 {
+                return Err(DecodeError::WrongLength);
+            }
+            Ok(Message::Consumer {
+                usages: Usages { bytes: usages },
+            })
+        }
+        BATTERY => match *body {
+            [mv_0, mv_1, percent] => Ok(Message::Battery {
+                level: Level {
+                    millivolts: u16::from_le_bytes([mv_0, mv_1]),
+                    percent,
+                },
+            }),
+            _ => Err(DecodeError::WrongLength),
+        },
+        kind => Err(DecodeError::UnknownKind(kind)),
+    }
+}
+
+/// Writes a delta, `None` if `buf` is too small or a row doesn't fit in 7 bits
+pub fn encode_delta(seq: u8, age_us: u16, changes: &[KeyChange], buf: &mut [u8]) -> Option<usize> {
+    let len = DELTA_HEADER + changes.len() * 2;
+    let buf = buf.get_mut(..len)?;
+    buf[0] = VERSION << 4 | DELTA;
+    buf[1] = seq;
+    buf[2] = u8::try_from(changes.len()).ok()?;
+    buf[3..5].copy_from_slice(&age_us.to_le_bytes());
+    for (change, out) in changes.iter().zip(buf[DELTA_HEADER..].chunks_exact_mut(2)) {
+        if change.pos.row >= PRESSED {
+            return None;
+        }
+        out[0] = change.pos.row | if change.pressed { PRESSED } else { 0 };
+        out[1] = change.pos.col;
+    }
+    Some(len)
+}
+
+/// Writes the state of a `ROWS` by `COLS` matrix, `None` if `buf` is too small
+pub fn encode_full_state<const ROWS: usize, const COLS: usize>(
+    seq: u8,
+    state: &[[bool; COLS]; ROWS],
+    buf: &mut [u8],
+) -> Option<usize> {
+    let rows = u8::try_from(ROWS).ok().filter(|&rows| rows < PRESSED)?;
+    let cols = u8::try_from(COLS).ok()?;
+    let len = 4 + bitmap_len(rows, cols);
+    let buf = buf.get_mut(..len)?;
+    buf[0] = VERSION << 4 | FULL_STATE;
+    buf[1] = seq;
+    buf[2] = rows;
+    buf[3] = cols;
+    let bits = &mut buf[4..];
+    bits.fill(0);
+    for (i, &pressed) in state.iter().flatten().enumerate() {
+        if pressed {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+    Some(len)
+}
+
+pub fn encode_state_request(last_seq: u8, buf: &mut [u8]) -> Option<usize> {
+    let buf = buf.get_mut(..2)?;
+    buf[0] = VERSION << 4 | STATE_REQUEST;
+    buf[1] = last_seq;
+    Some(2)
+}
+
+/// Writes a pointer payload, `None` if `buf` is too small or the motion doesn't fit
+pub fn encode_pointer(seq: u8, buttons: u8, motion: &Motion, buf: &mut [u8]) -> Option<usize> {
+    let buf = buf.get_mut(..9)?;
+    buf[0] = VERSION << 4 | POINTER;
+    buf[1] = seq;
+    buf[2] = buttons;
+    buf[3..5].copy_from_slice(&i16::try_from(motion.x).ok()?.to_le_bytes());
+    buf[5..7].copy_from_slice(&i16::try_from(motion.y).ok()?.to_le_bytes());
+    buf[7] = i8::try_from(motion.wheel).ok()? as u8;
+    buf[8] = i8::try_from(motion.pan).ok()? as u8;
+    Some(9)
+}
+
+/// Writes the consumer usages a half holds, `None` if `buf` is too small
+pub fn encode_consumer(usages: &[u16], buf: &mut [u8]) -> Option<usize> {
+    let len = 2 + usages.len() * 2;
+    let buf = buf.get_mut(..len)?;
+    buf[0] = VERSION << 4 | CONSUMER;
+    buf[1] = u8::try_from(usages.len()).ok()?;
+    for (usage, out) in usages.iter().zip(buf[2..].chunks_exact_mut(2)) {
+        out.copy_from_slice(&usage.to_le_bytes());
+    }
+    Some(len)
+}
+
+/// Writes a battery payload, `None` if `buf` is too small
+pub fn encode_battery(level: &Level, buf: &mut [u8]) -> Option<usize> {
+    let buf = buf.get_mut(..4)?;
+    buf[0] = VERSION << 4 | BATTERY;
+    buf[1..3].copy_from_slice(&level.millivolts.to_le_bytes());
+    buf[3] = level.percent;
+    Some(4)
+}
+
+/// Key half side, tracks the matrix state and picks what goes into the next payload
+pub struct Sender<const ROWS: usize, const COLS: usize> {
+    state: [[bool; COLS]; ROWS],
+    seq: u8,
+    /// Deltas sent since the last full state
+    deltas: u8,
+    /// Send a full state after this many deltas, 0 for only when asked
+    full_state_every: u8,
+    full_state_pending: bool,
+}
+
+impl<const ROWS: usize, const COLS: usize> Sender<ROWS, COLS> {
+    pub fn new(full_state_every: u8) -> Self {
+        Self {
+            state: [[false; COLS]; ROWS],
+            seq: 0,
+            deltas: 0,
+            full_state_every,
+            // The receiver might hold keys from before a reset
+            full_state_pending: true,
+        }
+    }
+
+    pub fn state(&self) -> &[[bool; COLS]; ROWS] {
+        &self.state
+    }
+
+    /// Makes the next payload a full state. Call when a send went unacked or the receiver sent
+    /// a [`Message::StateRequest`].
+    pub fn request_full_state(&mut self) {
+        self.full_state_pending = true;
+    }
+
+    /// Applies `changes`, the oldest of which happened `age_us` ago, and writes the payload that
+    /// carries them. Keys outside the matrix aren't tracked. `None` if `buf` can't even hold the
+    /// full state.
+    pub fn encode(&mut self, changes: &[KeyChange], age_us: u16, buf: &mut [u8]) -> Option<usize> {
+        for change in changes {
+            if let Some(key) = self
+                .state
+                .get_mut(change.pos.row as usize)
+                .and_then(|row| row.get_mut(change.pos.col as usize))
+            {
+                *key = change.pressed;
+            }
+        }
+        let full_state_due = self.full_state_every != 0 && self.deltas >= self.full_state_every;
+        if !self.full_state_pending && !full_state_due && changes.len() <= MAX_CHANGES {
+            if let Some(len) = encode_delta(self.seq.wrapping_add(1), age_us, changes, buf) {
+                self.seq = self.seq.wrapping_add(1);
+                // Keeps counting with full states only on request
+                self.deltas = self.deltas.saturating_add(1);
+                return Some(len);
+            }
+        }
+        self.encode_full_state(buf)
+    }
+
+    /// Writes the full state without any change, for refreshing it on a timer while keys are
+    /// held so a lost release doesn't leave them stuck
+    pub fn encode_full_state(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let len = encode_full_state(self.seq.wrapping_add(1), &self.state, buf)?;
+        self.seq = self.seq.wrapping_add(1);
+        self.deltas = 0;
+        self.full_state_pending = false;
+        Some(len)
+    }
+}
+
+/// Receiver side of one key half, turns payloads back into key changes
+pub struct Receiver<const ROWS: usize, const COLS: usize> {
+    state: [[bool; COLS]; ROWS],
+    /// Last sequence number applied, `None` until anything was
+    last_seq: Option<u8>,
+    /// A delta went missing since the last full state
+    out_of_sync: bool,
+}
+
+impl<const ROWS: usize, const COLS: usize> Default for Receiver<ROWS, COLS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Receiver<ROWS, COLS> {
+    pub fn new() -> Self {
+        Self {
+            state: [[false; COLS]; ROWS],
+            last_seq: None,
+            out_of_sync: true,
+        }
+    }
+
+    pub fn is_pressed(&self, pos: KeyPos) -> bool {
+        self.state
+            .get(pos.row as usize)
+            .and_then(|row| row.get(pos.col as usize))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Whether the sender should be asked for the full state, see [`Receiver::encode_request`]
+    pub fn needs_full_state(&self) -> bool {
+        self.out_of_sync
+    }
+
+    pub fn encode_request(&self, buf: &mut [u8]) -> Option<usize> {
+        encode_state_request(self.last_seq.unwrap_or(0), buf)
+    }
+
+    fn set(&mut self, pos: KeyPos, pressed: bool, on_change: &mut impl FnMut(KeyChange)) {
+        let Some(key) = self
+            .state
+            .get_mut(pos.row as usize)
+            .and_then(|row| row.get_mut(pos.col as usize))
+        else {
+            return;
+        };
+        if *key != pressed {
+            *key = pressed;
+            on_change(KeyChange { pos, pressed });
+        }
+    }
+
+    /// Releases every held key and forgets the sender, for when it went away. Whatever it sends
+    /// next has to be a full state to get back in sync.
+    pub fn release_all(&mut self, mut on_change: impl FnMut(KeyChange)) {
+        for row in 0..ROWS as u8 {
+            for col in 0..COLS as u8 {
+                self.set(KeyPos { row, col }, false, &mut on_change);
+            }
+        }
+        self.last_seq = None;
+        self.out_of_sync = true;
+    }
+
+    /// Applies a payload, handing every key that actually changed to `on_change`. Repeats of the
+    /// last delta are ignored and a full state releases keys the deltas missed. Nothing is
+    /// applied if the payload doesn't decode, isn't about keys or is a full state of a different
+    /// size.
+    pub fn receive(
+        &mut self,
+        payload: &[u8],
+        mut on_change: impl FnMut(KeyChange),
+    ) -> Result<(), DecodeError> {
+        match decode(payload)? {
+            Message::Delta { seq, changes, .. } => {
+                if self.last_seq == Some(seq) {
+                    return Ok(());
+                }
+                if self.last_seq.is_none_or(|last| seq != last.wrapping_add(1)) {
+                    self.out_of_sync = true;
+                }
+                self.last_seq = Some(seq);
+                for change in changes {
+                    self.set(change.pos, change.pressed, &mut on_change);
+                }
+                Ok(())
+            }
+            Message::FullState { seq, state } => {
+                if state.rows as usize != ROWS || state.cols as usize != COLS {
+                    return Err(DecodeError::Malformed);
+                }
+                self.last_seq = Some(seq);
+                self.out_of_sync = false;
+                for row in 0..ROWS as u8 {
+                    for col in 0..COLS as u8 {
+                        let pos = KeyPos { row, col };
+                        self.set(pos, state.is_pressed(pos), &mut on_change);
+                    }
+                }
+                Ok(())
+            }
+            Message::StateRequest { .. }
+            | Message::Pointer { .. }
+            | Message::Consumer { .. }
+            | Message::Battery { .. } => Err(DecodeError::Malformed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// xorshift, enough to shake out panics without pulling in a fuzzer
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn byte(&mut self) -> u8 {
+            self.next() as u8
+        }
+    }
+
+    fn change(row: u8, col: u8, pressed: bool) -> KeyChange {
+        KeyChange {
+            pos: KeyPos { row, col },
+            pressed,
+        }
+    }
+
+    /// One valid payload of every kind
+    fn samples() -> Vec<Vec<u8>> {
+        let mut res = Vec::new();
+        let mut buf = [0u8; MAX_LEN];
+        let changes = [change(0, 1, true), change(3, 13, false), change(4, 0, true)];
+        let len = encode_delta(7, 1234, &changes, &mut buf).unwrap();
+        res.push(buf[..len].to_vec());
+        let mut state = [[false; 14]; 5];
+        state[2][9] = true;
+        state[4][13] = true;
+        let len = encode_full_state(8, &state, &mut buf).unwrap();
+        res.push(buf[..len].to_vec());
+        let len = encode_state_request(9, &mut buf).unwrap();
+        res.push(buf[..len].to_vec());
+        let motion = Motion {
+            x: -300,
+            y: 12,
+            wheel: -1,
+            pan: 2,
+        };
+        let len = encode_pointer(10, 0b101, &motion, &mut buf).unwrap();
+        res.push(buf[..len].to_vec());
+        let len = encode_consumer(&[0xE9, 0xCD], &mut buf).unwrap();
+        res.push(buf[..len].to_vec());
+        let level = Level {
+            millivolts: 3900,
+            percent: 76,
+        };
+        let len = encode_battery(&level, &mut buf).unwrap();
+        res.push(buf[..len].to_vec());
+        res
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = samples();
+        let Ok(Message::Delta {
+            seq: 7,
+            age_us: 1234,
+            changes,
+        }) = decode(&samples[0])
+        else {
+            panic!("not a delta");
+        };
+        assert_eq!(
+            changes.collect::<Vec<_>>(),
+            [change(0, 1, true), change(3, 13, false), change(4, 0, true)]
+        );
+        let Ok(Message::FullState { seq: 8, state }) = decode(&samples[1]) else {
+            panic!("not a full state");
+        };
+        assert_eq!((state.rows, state.cols), (5, 14));
+        let pressed: Vec<_> = (0..5)
+            .flat_map(|row| (0..14).map(move |col| KeyPos { row, col }))
+            .filter(|&pos| state.is_pressed(pos))
+            .collect();
+        assert_eq!(
+            pressed,
+            [KeyPos { row: 2, col: 9 }, KeyPos { row: 4, col: 13 }]
+        );
+        assert!(!state.is_pressed(KeyPos { row: 5, col: 0 }));
+        assert!(matches!(
+            decode(&samples[2]),
+            Ok(Message::StateRequest { last_seq: 9 })
+        ));
+        let Ok(Message::Pointer {
+            seq: 10,
+            buttons: 0b101,
+            motion,
+        }) = decode(&samples[3])
+        else {
+            panic!("not a pointer payload");
+        };
+        assert_eq!(
+            (motion.x, motion.y, motion.wheel, motion.pan),
+            (-300, 12, -1, 2)
+        );
+        let Ok(Message::Consumer { usages }) = decode(&samples[4]) else {
+            panic!("not a consumer payload");
+        };
+        assert_eq!(usages.collect::<Vec<_>>(), [0xE9, 0xCD]);
+        let Ok(Message::Battery { level }) = decode(&samples[5]) else {
+            panic!("not a battery payload");
+        };
+        assert_eq!((level.millivolts, level.percent), (3900, 76));
+    }
+
+    #[test]
+    fn encoders_reject_what_does_not_fit() {
+        let mut buf = [0u8; MAX_LEN];
+        assert_eq!(encode_delta(0, 0, &[change(0x80, 0, true)], &mut buf), None);
+        assert_eq!(
+            encode_delta(0, 0, &[change(0, 0, true); 14], &mut buf),
+            None
+        );
+        assert_eq!(
+            encode_full_state(0, &[[false; 1]; 128], &mut [0u8; 64]),
+            None
+        );
+        assert_eq!(encode_full_state(0, &[[false; 16]; 16], &mut buf), None);
+        let motion = Motion {
+            x: 40_000,
+            ..Default::default()
+        };
+        assert_eq!(encode_pointer(0, 0, &motion, &mut buf), None);
+        assert_eq!(
+            encode_battery(
+                &Level {
+                    millivolts: 0,
+                    percent: 0
+                },
+                &mut buf[..3]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        for sample in samples() {
+            for len in 0..sample.len() {
+                assert_eq!(
+                    decode(&sample[..len]).err(),
+                    Some(DecodeError::WrongLength),
+                    "{sample:?} cut to {len}"
+                );
+            }
+            let mut long = sample.clone();
+            long.push(0);
+            assert_eq!(decode(&long).err(), Some(DecodeError::WrongLength));
+        }
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut payload = samples().remove(0);
+        payload[0] = 1 << 4 | DELTA;
+        assert_eq!(decode(&payload).err(), Some(DecodeError::Version(1)));
+        payload[0] = VERSION << 4 | 0x0F;
+        assert_eq!(decode(&payload).err(), Some(DecodeError::UnknownKind(0x0F)));
+        // 128 rows can't be addressed by a delta
+        assert_eq!(
+            decode(&[VERSION << 4 | FULL_STATE, 0, 0x80, 1]).err(),
+            Some(DecodeError::Malformed)
+        );
+    }
+
+    /// Corrupted and random payloads must never panic, only decode to something or fail
+    #[test]
+    fn corrupt_payloads_do_not_panic() {
+        let mut rng = Rng(0x1234_5678);
+        let mut receiver = Receiver::<5, 14>::new();
+        let mut inputs = Vec::new();
+        for sample in samples() {
+            for _ in 0..2000 {
+                let mut payload = sample.clone();
+                for _ in 0..1 + rng.next() % 3 {
+                    let i = rng.next() as usize % payload.len();
+                    payload[i] ^= 1 << (rng.next() % 8);
+                }
+                inputs.push(payload);
+            }
+        }
+        for _ in 0..20_000 {
+            let len = rng.next() as usize % (MAX_LEN + 1);
+            let mut payload: Vec<u8> = (0..len).map(|_| rng.byte()).collect();
+            // Keep most of them past the version check
+            if len > 0 && !rng.next().is_multiple_of(4) {
+                payload[0] = VERSION << 4 | (payload[0] & 0x0F);
+            }
+            inputs.push(payload);
+        }
+        for payload in inputs {
+            if let Ok(message) = decode(&payload) {
+                match message {
+                    Message::Delta { changes, .. } => {
+                        changes.count();
+                    }
+                    Message::FullState { state, .. } => {
+                        for row in 0..=state.rows {
+                            for col in 0..=state.cols {
+                                state.is_pressed(KeyPos { row, col });
+                            }
+                        }
+                    }
+                    Message::Consumer { usages } => {
+                        usages.count();
+                    }
+                    _ => {}
+                }
+            }
+            let _ = receiver.receive(&payload, |change| {
+                assert!(change.pos.row < 5 && change.pos.col < 14);
+            });
+        }
+    }
+
+    #[test]
+    fn receiver_recovers_from_a_lost_delta() {
+        let mut sender = Sender::<2, 3>::new(0);
+        let mut receiver = Receiver::<2, 3>::new();
+        let mut buf = [0u8; MAX_LEN];
+        let mut seen = Vec::new();
+
+        let len = sender.encode(&[change(0, 0, true)], 0, &mut buf).unwrap();
+        receiver.receive(&buf[..len], |c| seen.push(c)).unwrap();
+        assert!(!receiver.needs_full_state());
+        // The release is lost
+        sender.encode(&[change(0, 0, false)], 0, &mut buf).unwrap();
+        let len = sender.encode(&[change(1, 2, true)], 0, &mut buf).unwrap();
+        receiver.receive(&buf[..len], |c| seen.push(c)).unwrap();
+        assert!(receiver.needs_full_state());
+        assert!(receiver.is_pressed(KeyPos { row: 0, col: 0 }));
+
+        sender.request_full_state();
+        let len = sender.encode(&[], 0, &mut buf).unwrap();
+        receiver.receive(&buf[..len], |c| seen.push(c)).unwrap();
+        assert!(!receiver.needs_full_state());
+        assert_eq!(
+            seen,
+            [change(0, 0, true), change(1, 2, true), change(0, 0, false)]
+        );
+    }
+
+    #[test]
+    fn sender_without_periodic_full_states_keeps_sending_deltas() {
+        let mut sender = Sender::<2, 3>::new(0);
+        let mut buf = [0u8; MAX_LEN];
+        // The first payload after a reset is always a full state
+        sender.encode(&[], 0, &mut buf).unwrap();
+        for i in 0..1000u32 {
+            let len = sender
+                .encode(&[change(0, 0, i.is_multiple_of(2))], 0, &mut buf)
+                .unwrap();
+            assert!(matches!(decode(&buf[..len]), Ok(Message::Delta { .. })));
+        }
+    }
+
+    #[test]
+    fn sender_sends_full_states_periodically() {
+        let mut sender = Sender::<2, 3>::new(3);
+        let mut buf = [0u8; MAX_LEN];
+        let mut kinds = Vec::new();
+        for _ in 0..9 {
+            let len = sender.encode(&[change(1, 1, true)], 0, &mut buf).unwrap();
+            kinds.push(buf[..len][0] & 0x0F);
+        }
+        assert_eq!(
+            kinds,
+            [FULL_STATE, DELTA, DELTA, DELTA, FULL_STATE, DELTA, DELTA, DELTA, FULL_STATE]
+        );
+    }
+}
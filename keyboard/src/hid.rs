@@ -0,0 +1,568 @@
+//! USB HID keyboard reports.
+//!
+//! [`KeyReport`] holds the usages currently down and turns them into either report. The boot
+//! report is the fixed 8 byte layout every BIOS understands: modifiers, a reserved byte and up to
+//! six keys, with every slot set to ErrorRollOver once more than six are down. The NKRO report is
+//! the modifiers followed by one bit for each usage below the modifiers, laid out by
+//! [`NKRO_DESCRIPTOR`]. Both interfaces take the lock LEDs as a one byte output report.
+//!
+//! Mouse and consumer control share an interface of their own and are told apart by the report id
+//! in front of each report, see [`MOUSE_CONSUMER_DESCRIPTOR`]. [`MouseReport`] holds the motion
+//! not reported yet and hands it out as far as a report can carry it, [`ConsumerReport`] is the
+//! consumer usages held, as an array.
+
+use crate::pointer::Motion;
+
+/// Modifiers, reserved and six keys
+pub const BOOT_REPORT_LEN: usize = 8;
+/// Usages covered by the NKRO bitmap, everything below the modifiers
+pub const NKRO_USAGES: usize = 0xE0;
+/// Modifiers followed by the bitmap
+pub const NKRO_REPORT_LEN: usize = 1 + NKRO_USAGES / 8;
+const BOOT_KEYS: usize = 6;
+pub const MOUSE_REPORT_ID: u8 = 1;
+pub const CONSUMER_REPORT_ID: u8 = 2;
+/// Report id, buttons, x and y, wheel and pan
+pub const MOUSE_REPORT_LEN: usize = 8;
+/// Consumer usages reported at once
+pub const CONSUMER_KEYS: usize = 4;
+/// Report id and the usages
+pub const CONSUMER_REPORT_LEN: usize = 1 + 2 * CONSUMER_KEYS;
+
+const ERROR_ROLL_OVER: u8 = 0x01;
+/// First usage that's an actual key, the ones below are error codes
+const FIRST_KEY: u8 = 0x04;
+const LEFT_CTRL: u8 = 0xE0;
+const RIGHT_GUI: u8 = 0xE7;
+
+/// Report descriptor of the boot interface, the layout the boot protocol fixes
+#[rustfmt::skip]
+pub const BOOT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0, //   Usage Minimum (Left Control)
+    0x29, 0xE7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x01, //   Input (Constant)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x05, //   Report Count (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0x95, 0x03, //   Report Count (3)
+    0x91, 0x01, //   Output (Constant)
+    0x05, 0x07, //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xDF, //   Usage Maximum (0xDF)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xDF, 0x00, // Logical Maximum (0xDF)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x06, //   Report Count (6)
+    0x81, 0x00, //   Input (Data, Array, Absolute)
+    0xC0,       // End Collection
+];
+
+/// Report descriptor of the NKRO interface
+#[rustfmt::skip]
+pub const NKRO_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0, //   Usage Minimum (Left Control)
+    0x29, 0xE7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xDF, //   Usage Maximum (0xDF)
+    0x96, 0xE0, 0x00, // Report Count (224)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x95, 0x05, //   Report Count (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0x95, 0x03, //   Report Count (3)
+    0x91, 0x01, //   Output (Constant)
+    0xC0,       // End Collection
+];
+
+/// Report descriptor of the mouse and consumer control interface
+#[rustfmt::skip]
+pub const MOUSE_CONSUMER_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xA1, 0x01, // Collection (Application)
+    0x85, MOUSE_REPORT_ID, //   Report ID
+    0x09, 0x01, //   Usage (Pointer)
+    0xA1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Buttons)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x05, //     Usage Maximum (5)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x05, //     Report Count (5)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x75, 0x03, //     Report Size (3)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x01, //     Input (Constant)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x16, 0x01, 0x80, // Logical Minimum (-32767)
+    0x26, 0xFF, 0x7F, // Logical Maximum (32767)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7F, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0x05, 0x0C, //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02, // Usage (AC Pan)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xC0,       //   End Collection
+    0xC0,       // End Collection
+    0x05, 0x0C, // Usage Page (Consumer)
+    0x09, 0x01, // Usage (Consumer Control)
+    0xA1, 0x01, // Collection (Application)
+    0x85, CONSUMER_REPORT_ID, //   Report ID
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x03, // Logical Maximum (0x3FF)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03, // Usage Maximum (0x3FF)
+    0x75, 0x10, //   Report Size (16)
+    0x95, 0x04, //   Report Count (4)
+    0x81, 0x00, //   Input (Data, Array, Absolute)
+    0xC0,       // End Collection
+];
+
+/// Which report carries the keys
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReportMode {
+    /// Six keys at most, works before the OS is up
+    Boot,
+    /// Every key, needs a host that parses the report descriptor
+    Nkro,
+}
+
+/// Usages currently down. Keys that share a usage aren't told apart, releasing either releases it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyReport {
+    modifiers: u8,
+    keys: [u8; NKRO_USAGES / 8],
+}
+
+impl Default for KeyReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyReport {
+    pub const fn new() -> Self {
+        Self {
+            modifiers: 0,
+            keys: [0; NKRO_USAGES / 8],
+        }
+    }
+
+    /// Presses or releases a keyboard page usage. Error codes and reserved usages are ignored
+    pub fn set(&mut self, usage: u8, pressed: bool) {
+        let (byte, bit) = match usage {
+            LEFT_CTRL..=RIGHT_GUI => (&mut self.modifiers, usage - LEFT_CTRL),
+            FIRST_KEY..LEFT_CTRL => (&mut self.keys[usage as usize / 8], usage % 8),
+            _ => return,
+        };
+        if pressed {
+            *byte |= 1 << bit;
+        } else {
+            *byte &= !(1 << bit);
+        }
+    }
+
+    pub fn is_pressed(&self, usage: u8) -> bool {
+        match usage {
+            LEFT_CTRL..=RIGHT_GUI => self.modifiers & (1 << (usage - LEFT_CTRL)) != 0,
+            FIRST_KEY..LEFT_CTRL => self.keys[usage as usize / 8] & (1 << (usage % 8)) != 0,
+            _ => false,
+        }
+    }
+
+    pub fn modifiers(&self) -> u8 {
+        self.modifiers
+    }
+
+    /// Held usages below the modifiers, lowest first
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        (FIRST_KEY..LEFT_CTRL).filter(|&usage| self.is_pressed(usage))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::new()
+    }
+
+    /// Usages held in either report, for layering reports kept apart
+    pub fn union(&self, other: &Self) -> Self {
+        let mut res = *self;
+        res.modifiers |= other.modifiers;
+        for (byte, other) in res.keys.iter_mut().zip(other.keys) {
+            *byte |= other;
+        }
+        res
+    }
+
+    pub fn boot(&self) -> [u8; BOOT_REPORT_LEN] {
+        let mut res = [0; BOOT_REPORT_LEN];
+        res[0] = self.modifiers;
+        let slots = &mut res[2..];
+        if self.keys().nth(BOOT_KEYS).is_some() {
+            slots.fill(ERROR_ROLL_OVER);
+        } else {
+            for (slot, usage) in slots.iter_mut().zip(self.keys()) {
+                *slot = usage;
+            }
+        }
+        res
+    }
+
+    pub fn nkro(&self) -> [u8; NKRO_REPORT_LEN] {
+        let mut res = [0; NKRO_REPORT_LEN];
+        res[0] = self.modifiers;
+        res[1..].copy_from_slice(&self.keys);
+        res
+    }
+
+    /// Boot and NKRO report with the keys in the one `mode` picks and nothing held in the other,
+    /// so a host reading both doesn't see every key twice
+    pub fn reports(&self, mode: ReportMode) -> ([u8; BOOT_REPORT_LEN], [u8; NKRO_REPORT_LEN]) {
+        match mode {
+            ReportMode::Boot => (self.boot(), [0; NKRO_REPORT_LEN]),
+            ReportMode::Nkro => ([0; BOOT_REPORT_LEN], self.nkro()),
+        }
+    }
+}
+
+/// Mouse buttons and the motion not reported yet
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct MouseReport {
+    pub buttons: u8,
+    motion: Motion,
+}
+
+impl MouseReport {
+    pub const fn new() -> Self {
+        Self {
+            buttons: 0,
+            motion: Motion::ZERO,
+        }
+    }
+
+    pub fn add(&mut self, motion: Motion) {
+        self.motion.add(motion);
+    }
+
+    /// Whether motion is left over for another report
+    pub fn has_motion(&self) -> bool {
+        !self.motion.is_zero()
+    }
+
+    /// Takes as much of the motion as one report carries
+    pub fn take(&mut self) -> [u8; MOUSE_REPORT_LEN] {
+        let motion = self.motion.take(i16::MAX as i32, i8::MAX as i32);
+        let mut res = [0; MOUSE_REPORT_LEN];
+        res[0] = MOUSE_REPORT_ID;
+        res[1] = self.buttons & 0x1F;
+        res[2..4].copy_from_slice(&(motion.x as i16).to_le_bytes());
+        res[4..6].copy_from_slice(&(motion.y as i16).to_le_bytes());
+        res[6] = motion.wheel as i8 as u8;
+        res[7] = motion.pan as i8 as u8;
+        res
+    }
+}
+
+/// Consumer usages held, past [`CONSUMER_KEYS`] they're dropped
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ConsumerReport {
+    usages: [u16; CONSUMER_KEYS],
+}
+
+impl ConsumerReport {
+    pub const fn new() -> Self {
+        Self {
+            usages: [0; CONSUMER_KEYS],
+        }
+    }
+
+    pub fn from_usages(usages: impl IntoIterator<Item = u16>) -> Self {
+        let mut res = Self::new();
+        for (slot, usage) in res.usages.iter_mut().zip(usages) {
+            *slot = usage;
+        }
+        res
+    }
+
+    pub fn report(&self) -> [u8; CONSUMER_REPORT_LEN] {
+        let mut res = [0; CONSUMER_REPORT_LEN];
+        res[0] = CONSUMER_REPORT_ID;
+        for (usage, out) in self.usages.iter().zip(res[1..].chunks_exact_mut(2)) {
+            out.copy_from_slice(&usage.to_le_bytes());
+        }
+        res
+    }
+}
+
+/// Lock LEDs as set by the host
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Leds(pub u8);
+
+impl Leds {
+    /// Parses the output report, `None` if it isn't the single byte both interfaces declare
+    pub fn from_report(data: &[u8]) -> Option<Self> {
+        match *data {
+            [bits] => Some(Self(bits & 0x1F)),
+            _ => None,
+        }
+    }
+
+    pub fn num_lock(&self) -> bool {
+        self.0 & 0x01 != 0
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.0 & 0x02 != 0
+    }
+
+    pub fn scroll_lock(&self) -> bool {
+        self.0 & 0x04 != 0
+    }
+
+    pub fn compose(&self) -> bool {
+        self.0 & 0x08 != 0
+    }
+
+    pub fn kana(&self) -> bool {
+        self.0 & 0x10 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{collections::BTreeMap, vec::Vec};
+
+    use super::*;
+
+    const A: u8 = 0x04;
+    const Z: u8 = 0x1D;
+    const LEFT_SHIFT: u8 = 0xE1;
+
+    /// Input and output bits of every report id a descriptor declares
+    #[derive(Default, Debug)]
+    struct Layout {
+        input_bits: BTreeMap<u8, u32>,
+        output_bits: BTreeMap<u8, u32>,
+    }
+
+    /// Walks the short items of a report descriptor, enough to size its reports
+    fn layout(descriptor: &[u8]) -> Layout {
+        let mut res = Layout::default();
+        let (mut size, mut count, mut id) = (0, 0, 0u8);
+        let mut depth = 0;
+        let mut rest = descriptor;
+        while let Some((&prefix, tail)) = rest.split_first() {
+            let len = [0, 1, 2, 4][(prefix & 3) as usize];
+            let data = &tail[..len];
+            rest = &tail[len..];
+            let value = data.iter().rev().fold(0u32, |acc, &b| acc << 8 | b as u32);
+            match prefix & 0xFC {
+                0x74 => size = value,
+                0x94 => count = value,
+                0x84 => id = value as u8,
+                0x80 => *res.input_bits.entry(id).or_default() += size * count,
+                0x90 => *res.output_bits.entry(id).or_default() += size * count,
+                0xA0 => depth += 1,
+                0xC0 => depth -= 1,
+                _ => {}
+            }
+        }
+        assert_eq!(depth, 0, "unbalanced collections");
+        res
+    }
+
+    #[test]
+    fn descriptors_match_report_lengths() {
+        let boot = layout(BOOT_DESCRIPTOR);
+        assert_eq!(boot.input_bits[&0], BOOT_REPORT_LEN as u32 * 8);
+        assert_eq!(boot.output_bits[&0], 8);
+
+        let nkro = layout(NKRO_DESCRIPTOR);
+        assert_eq!(nkro.input_bits[&0], NKRO_REPORT_LEN as u32 * 8);
+        assert_eq!(nkro.output_bits[&0], 8);
+
+        // Report ids aren't counted in the descriptor but go in front of every report
+        let shared = layout(MOUSE_CONSUMER_DESCRIPTOR);
+        assert_eq!(
+            shared.input_bits.keys().copied().collect::<Vec<_>>(),
+            [MOUSE_REPORT_ID, CONSUMER_REPORT_ID]
+        );
+        assert_eq!(
+            shared.input_bits[&MOUSE_REPORT_ID],
+            (MOUSE_REPORT_LEN as u32 - 1) * 8
+        );
+        assert_eq!(
+            shared.input_bits[&CONSUMER_REPORT_ID],
+            (CONSUMER_REPORT_LEN as u32 - 1) * 8
+        );
+        assert!(shared.output_bits.is_empty());
+    }
+
+    #[test]
+    fn set_and_release() {
+        let mut report = KeyReport::new();
+        assert!(report.is_empty());
+        report.set(A, true);
+        report.set(LEFT_SHIFT, true);
+        assert!(report.is_pressed(A));
+        assert!(report.is_pressed(LEFT_SHIFT));
+        assert_eq!(report.modifiers(), 0b10);
+        assert_eq!(report.keys().collect::<Vec<_>>(), [A]);
+        report.set(A, false);
+        report.set(LEFT_SHIFT, false);
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn error_codes_and_reserved_usages_are_ignored() {
+        let mut report = KeyReport::new();
+        for usage in [0x00, ERROR_ROLL_OVER, 0x02, 0x03, 0xE8, 0xFF] {
+            report.set(usage, true);
+            assert!(!report.is_pressed(usage));
+        }
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn boot_report_lists_keys_lowest_first() {
+        let mut report = KeyReport::new();
+        for usage in [Z, A, 0x10] {
+            report.set(usage, true);
+        }
+        report.set(LEFT_CTRL, true);
+        assert_eq!(report.boot(), [0x01, 0, A, 0x10, Z, 0, 0, 0]);
+    }
+
+    #[test]
+    fn boot_report_rolls_over_past_six_keys() {
+        let mut report = KeyReport::new();
+        report.set(RIGHT_GUI, true);
+        for usage in A..A + 6 {
+            report.set(usage, true);
+        }
+        assert_eq!(report.boot(), [0x80, 0, 4, 5, 6, 7, 8, 9]);
+        report.set(Z, true);
+        assert_eq!(report.boot(), [0x80, 0, 1, 1, 1, 1, 1, 1]);
+        // The keys are all still there in the NKRO report
+        assert_eq!(
+            report.nkro()[1..]
+                .iter()
+                .map(|b| b.count_ones())
+                .sum::<u32>(),
+            7
+        );
+    }
+
+    #[test]
+    fn nkro_report_is_a_bitmap() {
+        let mut report = KeyReport::new();
+        for usage in [A, Z, 0xDF] {
+            report.set(usage, true);
+        }
+        report.set(LEFT_SHIFT, true);
+        let nkro = report.nkro();
+        let mut expected = [0u8; NKRO_REPORT_LEN];
+        expected[0] = 0b10;
+        expected[1] = 1 << 4;
+        expected[1 + 3] = 1 << 5;
+        expected[NKRO_REPORT_LEN - 1] = 1 << 7;
+        assert_eq!(nkro, expected);
+    }
+
+    #[test]
+    fn only_the_active_mode_carries_keys() {
+        let mut report = KeyReport::new();
+        report.set(A, true);
+        let (boot, nkro) = report.reports(ReportMode::Boot);
+        assert_eq!(boot, report.boot());
+        assert_eq!(nkro, [0; NKRO_REPORT_LEN]);
+        let (boot, nkro) = report.reports(ReportMode::Nkro);
+        assert_eq!(boot, [0; BOOT_REPORT_LEN]);
+        assert_eq!(nkro, report.nkro());
+    }
+
+    #[test]
+    fn mouse_motion_is_split_over_reports() {
+        let mut mouse = MouseReport::new();
+        mouse.buttons = 0xFF;
+        mouse.add(Motion {
+            x: 40_000,
+            y: -5,
+            wheel: -200,
+            pan: 1,
+        });
+        let first = mouse.take();
+        assert_eq!(first[..2], [MOUSE_REPORT_ID, 0x1F]);
+        assert_eq!(i16::from_le_bytes([first[2], first[3]]), i16::MAX);
+        assert_eq!(i16::from_le_bytes([first[4], first[5]]), -5);
+        assert_eq!(first[6] as i8, -127);
+        assert_eq!(first[7] as i8, 1);
+        assert!(mouse.has_motion());
+
+        let second = mouse.take();
+        assert_eq!(i16::from_le_bytes([second[2], second[3]]), 7_233);
+        assert_eq!(second[4..6], [0, 0]);
+        assert_eq!(second[6] as i8, -73);
+        assert!(!mouse.has_motion());
+        // Buttons stay in reports without motion
+        assert_eq!(mouse.take()[..4], [MOUSE_REPORT_ID, 0x1F, 0, 0]);
+    }
+
+    #[test]
+    fn consumer_report_drops_extra_usages() {
+        let report = ConsumerReport::from_usages([0xE9, 0xEA, 0xCD, 0xB5, 0xB6]);
+        assert_eq!(
+            report.report(),
+            [CONSUMER_REPORT_ID, 0xE9, 0, 0xEA, 0, 0xCD, 0, 0xB5, 0]
+        );
+        assert_eq!(ConsumerReport::new().report()[1..], [0; 8]);
+    }
+
+    #[test]
+    fn leds_from_output_report() {
+        let leds = Leds::from_report(&[0xFF]).unwrap();
+        assert_eq!(leds, Leds(0x1F));
+        assert!(leds.num_lock() && leds.caps_lock() && leds.scroll_lock());
+        assert!(leds.compose() && leds.kana());
+        let leds = Leds::from_report(&[0x02]).unwrap();
+        assert!(leds.caps_lock() && !leds.num_lock());
+        assert_eq!(Leds::from_report(&[]), None);
+        assert_eq!(Leds::from_report(&[1, 2]), None);
+    }
+}
@@ -0,0 +1,697 @@
+//! Layered keymap turning key positions into usages.
+//!
+//! A keymap is a const table of [`Layer`]s. Layer 0 is always active, the others come and go
+//! through momentary, toggle and one-shot layer keys, and a key resolves on the highest active
+//! layer that isn't [`Action::Trans`] there. What a key resolved to on press is remembered, so its
+//! release undoes exactly that even if the layers changed in between.
+//!
+//! Mod-tap and layer-tap keys are a tap when released within the tapping term and a hold once it
+//! runs out. While one is undecided every later change is held back and replayed after the
+//! decision, so keys rolled over it come out in order and on the right layer. A tap is only
+//! decided by the key's release, so the tap usage goes down and would come up in the same report.
+//! Its release is held back for [`Keymap::tick`] instead, which lets it out
+//! [`KeymapConfig::tap_release_us`] after the first tick that saw it.
+
+use crate::matrix::{KeyChange, KeyPos};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Does nothing
+    No,
+    /// Falls through to the next active layer below
+    Trans,
+    /// A keyboard page usage, see [`crate::keycode`]
+    Key(u8),
+    /// Layer is active while held
+    Momentary(u8),
+    /// Layer flips on press
+    Toggle(u8),
+    /// Layer is active for the next key press, or while held if other keys are pressed meanwhile
+    OneShot(u8),
+    /// `tap` when tapped, the modifier usage `hold` when held
+    ModTap { tap: u8, hold: u8 },
+    /// `tap` when tapped, momentary `layer` when held
+    LayerTap { tap: u8, layer: u8 },
+    /// Plays a stored macro on press, see [`crate::macros`]
+    Macro(u8),
+}
+
+pub type Layer<const ROWS: usize, const COLS: usize> = [[Action; COLS]; ROWS];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeymapConfig {
+    /// How long a mod-tap or layer-tap key has to be held to count as a hold
+    pub tapping_term_us: u32,
+    /// How long a tapped usage stays down, counted from the first tick after the tap
+    pub tap_release_us: u32,
+}
+
+impl Default for KeymapConfig {
+    fn default() -> Self {
+        Self {
+            tapping_term_us: 200_000,
+            tap_release_us: 10_000,
+        }
+    }
+}
+
+/// A usage going down or up
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UsageChange {
+    pub usage: u8,
+    pub pressed: bool,
+}
+
+/// What a key change comes out of the keymap as
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Output {
+    Usage(UsageChange),
+    /// A macro key went down
+    Macro(u8),
+}
+
+/// What a held key resolved to on press
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Held {
+    Nothing,
+    Usage(u8),
+    /// Tap usage of a tap-hold key, released through [`Keymap::tick`]
+    Tap(u8),
+    Momentary(u8),
+    OneShot(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct OneShot {
+    layer: u8,
+    /// The one-shot key is still down
+    held: bool,
+    /// Another key was pressed while it was down
+    used: bool,
+}
+
+/// A tap-hold key waiting for its decision
+#[derive(Clone, Copy, Debug)]
+struct Pending {
+    pos: KeyPos,
+    action: Action,
+    at_us: u64,
+}
+
+/// Release of a tap usage waiting to come out
+#[derive(Clone, Copy, Debug)]
+struct TapRelease {
+    usage: u8,
+    /// When it comes out, or when it was deferred until a tick has seen it
+    at_us: u64,
+    ticked: bool,
+}
+
+/// Changes held back while a tap-hold key is undecided, a hold is decided early once it's full
+const BUFFER: usize = 16;
+/// Tap releases held back at once, the oldest comes out early once it's full
+const TAPS: usize = 4;
+
+pub struct Keymap<'a, const ROWS: usize, const COLS: usize, const LAYERS: usize> {
+    layers: &'a [Layer<ROWS, COLS>; LAYERS],
+    config: KeymapConfig,
+    /// Layers flipped on by toggle keys, one bit each
+    toggled: u32,
+    /// Momentary and layer-tap keys holding each layer
+    momentary: [u8; LAYERS],
+    oneshot: Option<OneShot>,
+    held: [[Held; COLS]; ROWS],
+    pending: Option<Pending>,
+    buffer: [(KeyChange, u64); BUFFER],
+    buffered: usize,
+    taps: [Option<TapRelease>; TAPS],
+}
+
+impl<'a, const ROWS: usize, const COLS: usize, const LAYERS: usize> Keymap<'a, ROWS, COLS, LAYERS> {
+    pub fn new(layers: &'a [Layer<ROWS, COLS>; LAYERS], config: KeymapConfig) -> Self {
+        const { assert!(LAYERS <= 32, "layers are tracked in a u32") };
+        let nothing = KeyChange {
+            pos: KeyPos { row: 0, col: 0 },
+            pressed: false,
+        };
+        Self {
+            layers,
+            config,
+            toggled: 0,
+            momentary: [0; LAYERS],
+            oneshot: None,
+            held: [[Held::Nothing; COLS]; ROWS],
+            pending: None,
+            buffer: [(nothing, 0); BUFFER],
+            buffered: 0,
+            taps: [None; TAPS],
+        }
+    }
+
+    pub fn config(&self) -> &KeymapConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: KeymapConfig) {
+        self.config = config;
+    }
+
+    pub fn is_layer_active(&self, layer: u8) -> bool {
+        let l = layer as usize;
+        l == 0
+            || (l < LAYERS
+                && (self.toggled & (1 << l) != 0
+                    || self.momentary[l] > 0
+                    || self.oneshot.is_some_and(|o| o.layer == layer)))
+    }
+
+    /// Highest active layer
+    pub fn top_layer(&self) -> u8 {
+        (0..LAYERS as u8)
+            .rev()
+            .find(|&l| self.is_layer_active(l))
+            .unwrap_or(0)
+    }
+
+    /// Action of `pos` on the highest active layer that has one
+    pub fn resolve(&self, pos: KeyPos) -> Action {
+        let (row, col) = (pos.row as usize, pos.col as usize);
+        if row >= ROWS || col >= COLS {
+            return Action::No;
+        }
+        (0..LAYERS)
+            .rev()
+            .filter(|&l| self.is_layer_active(l as u8))
+            .map(|l| self.layers[l][row][col])
+            .find(|&action| action != Action::Trans)
+            .unwrap_or(Action::No)
+    }
+
+    /// When the undecided tap-hold key turns into a hold or a tap release is due, `None` if
+    /// there's neither
+    pub fn next_deadline(&self) -> Option<u64> {
+        let taps = self.taps.iter().flatten().map(|t| t.at_us);
+        self.hold_deadline().into_iter().chain(taps).min()
+    }
+
+    /// When the undecided tap-hold key turns into a hold
+    fn hold_deadline(&self) -> Option<u64> {
+        self.pending
+            .map(|p| p.at_us + self.config.tapping_term_us as u64)
+    }
+
+    /// Takes a key change that happened at `at_us`. Usages that go down or up and macros that start
+    /// as a result are handed to `out`, possibly along with ones held back from earlier changes.
+    pub fn event(&mut self, change: KeyChange, at_us: u64, mut out: impl FnMut(Output)) {
+        self.handle(change, at_us, &mut out);
+    }
+
+    /// Decides an undecided tap-hold key as a hold once the tapping term has passed at `now_us`
+    /// and releases tapped usages that are due. Call once per report, after the events.
+    pub fn tick(&mut self, now_us: u64, mut out: impl FnMut(Output)) {
+        self.expire(now_us, &mut out);
+        let tap_release_us = self.config.tap_release_us as u64;
+        for slot in self.taps.iter_mut() {
+            let Some(tap) = slot else {
+                continue;
+            };
+            if !tap.ticked {
+                // The press goes out with this tick's report
+                tap.ticked = true;
+                tap.at_us = now_us + tap_release_us;
+            } else if now_us >= tap.at_us {
+                out(Output::Usage(UsageChange {
+                    usage: tap.usage,
+                    pressed: false,
+                }));
+                *slot = None;
+            }
+        }
+    }
+
+    /// Holds back the release of a tapped usage until a later [`Keymap::tick`]
+    fn defer_release(&mut self, usage: u8, at_us: u64, out: &mut dyn FnMut(Output)) {
+        let slot = match self.taps.iter().position(Option::is_none) {
+            Some(free) => free,
+            None => {
+                let (oldest, _) = self
+                    .taps
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, t)| t.map(|t| (!t.ticked, t.at_us)))
+                    .unwrap();
+                self.release_tap(oldest, out);
+                oldest
+            }
+        };
+        self.taps[slot] = Some(TapRelease {
+            usage,
+            at_us,
+            ticked: false,
+        });
+    }
+
+    fn release_tap(&mut self, slot: usize, out: &mut dyn FnMut(Output)) {
+        if let Some(tap) = self.taps[slot].take() {
+            out(Output::Usage(UsageChange {
+                usage: tap.usage,
+                pressed: false,
+            }));
+        }
+    }
+
+    fn expire(&mut self, now_us: u64, out: &mut dyn FnMut(Output)) {
+        if self
+            .hold_deadline()
+            .is_some_and(|deadline| now_us >= deadline)
+        {
+            self.decide(true, out);
+        }
+    }
+
+    fn handle(&mut self, change: KeyChange, at_us: u64, out: &mut dyn FnMut(Output)) {
+        self.expire(at_us, out);
+        let Some(pending) = self.pending else {
+            self.process(change, at_us, out);
+            return;
+        };
+        if change.pos == pending.pos && !change.pressed {
+            self.decide(false, out);
+            self.process(change, at_us, out);
+        } else if self.buffered == BUFFER {
+            self.decide(true, out);
+            self.handle(change, at_us, out);
+        } else {
+            self.buffer[self.buffered] = (change, at_us);
+            self.buffered += 1;
+        }
+    }
+
+    /// Resolves the pending tap-hold key and replays what was held back behind it. A tap goes
+    /// down here and comes up when the key's release is processed.
+    fn decide(&mut self, hold: bool, out: &mut dyn FnMut(Output)) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let held = match (pending.action, hold) {
+            (Action::ModTap { hold, .. }, true) => {
+                self.press(hold, out);
+                Held::Usage(hold)
+            }
+            (Action::LayerTap { layer, .. }, true) => {
+                if let Some(count) = self.momentary.get_mut(layer as usize) {
+                    *count += 1;
+                }
+                Held::Momentary(layer)
+            }
+            (Action::ModTap { tap, .. } | Action::LayerTap { tap, .. }, false) => {
+                self.press(tap, out);
+                Held::Tap(tap)
+            }
+            _ => Held::Nothing,
+        };
+        self.held[pending.pos.row as usize][pending.pos.col as usize] = held;
+        let (buffer, buffered) = (self.buffer, self.buffered);
+        self.buffered = 0;
+        for &(change, at_us) in &buffer[..buffered] {
+            // A replayed change can start another undecided key, the rest queues up behind it
+            self.handle(change, at_us, out);
+        }
+    }
+
+    fn process(&mut self, change: KeyChange, at_us: u64, out: &mut dyn FnMut(Output)) {
+        let (row, col) = (change.pos.row as usize, change.pos.col as usize);
+        if row >= ROWS || col >= COLS {
+            return;
+        }
+        if !change.pressed {
+            match core::mem::replace(&mut self.held[row][col], Held::Nothing) {
+                Held::Nothing => {}
+                Held::Usage(usage) => out(Output::Usage(UsageChange {
+                    usage,
+                    pressed: false,
+                })),
+                Held::Tap(usage) => self.defer_release(usage, at_us, out),
+                Held::Momentary(layer) => {
+                    if let Some(count) = self.momentary.get_mut(layer as usize) {
+                        *count = count.saturating_sub(1);
+                    }
+                }
+                Held::OneShot(layer) => match &mut self.oneshot {
+                    Some(o) if o.layer == layer && !o.used => o.held = false,
+                    Some(o) if o.layer == layer => self.oneshot = None,
+                    _ => {}
+                },
+            }
+            return;
+        }
+        let action = self.resolve(change.pos);
+        if !matches!(action, Action::OneShot(_)) {
+            // The key just resolved was the one the one-shot layer was waiting for
+            match &mut self.oneshot {
+                Some(o) if o.held => o.used = true,
+                Some(_) => self.oneshot = None,
+                None => {}
+            }
+        }
+        self.held[row][col] = match action {
+            Action::No | Action::Trans => Held::Nothing,
+            Action::Macro(id) => {
+                out(Output::Macro(id));
+                Held::Nothing
+            }
+            Action::Key(usage) => {
+                self.press(usage, out);
+                Held::Usage(usage)
+            }
+            Action::Momentary(layer) => {
+                if let Some(count) = self.momentary.get_mut(layer as usize) {
+                    *count += 1;
+                }
+                Held::Momentary(layer)
+            }
+            Action::Toggle(layer) => {
+                if (layer as usize) < LAYERS {
+                    self.toggled ^= 1 << layer;
+                }
+                Held::Nothing
+            }
+            Action::OneShot(layer) => {
+                self.oneshot = Some(OneShot {
+                    layer,
+                    held: true,
+                    used: false,
+                });
+                Held::OneShot(layer)
+            }
+            Action::ModTap { .. } | Action::LayerTap { .. } => {
+                self.pending = Some(Pending {
+                    pos: change.pos,
+                    action,
+                    at_us,
+                });
+                Held::Nothing
+            }
+        };
+    }
+
+    /// Presses `usage`. A release of it that's still held back is dropped, the usage stays down
+    /// for the new press instead of coming up under it.
+    fn press(&mut self, usage: u8, out: &mut dyn FnMut(Output)) {
+        for slot in self.taps.iter_mut() {
+            if slot.is_some_and(|t| t.usage == usage) {
+                *slot = None;
+            }
+        }
+        out(Output::Usage(UsageChange {
+            usage,
+            pressed: true,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{collections::BTreeSet, vec, vec::Vec};
+
+    use super::*;
+
+    const A: u8 = 0x04;
+    const B: u8 = 0x05;
+    const C: u8 = 0x06;
+    const X: u8 = 0x1B;
+    const LEFT_SHIFT: u8 = 0xE1;
+    const FRAME_US: u64 = 1000;
+
+    const LAYERS: [Layer<1, 7>; 2] = [
+        [[
+            Action::Key(A),
+            Action::ModTap {
+                tap: B,
+                hold: LEFT_SHIFT,
+            },
+            Action::LayerTap { tap: C, layer: 1 },
+            Action::Momentary(1),
+            Action::Toggle(1),
+            Action::OneShot(1),
+            Action::Key(B),
+        ]],
+        [[
+            Action::Key(X),
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::Trans,
+            Action::No,
+        ]],
+    ];
+
+    const KEY_A: u8 = 0;
+    const MOD_TAP: u8 = 1;
+    const LAYER_TAP: u8 = 2;
+    const MOMENTARY: u8 = 3;
+    const TOGGLE: u8 = 4;
+    const ONE_SHOT: u8 = 5;
+    const KEY_B: u8 = 6;
+
+    fn keymap() -> Keymap<'static, 1, 7, 2> {
+        Keymap::new(&LAYERS, KeymapConfig::default())
+    }
+
+    fn down(at_us: u64, col: u8) -> (u64, KeyChange) {
+        let pos = KeyPos { row: 0, col };
+        (at_us, KeyChange { pos, pressed: true })
+    }
+
+    fn up(at_us: u64, col: u8) -> (u64, KeyChange) {
+        let pos = KeyPos { row: 0, col };
+        (
+            at_us,
+            KeyChange {
+                pos,
+                pressed: false,
+            },
+        )
+    }
+
+    /// Applies an output to the usages held, which like the report don't tell keys apart
+    fn apply(held: &mut BTreeSet<u8>, output: Output) {
+        if let Output::Usage(u) = output {
+            if u.pressed {
+                held.insert(u.usage);
+            } else {
+                held.remove(&u.usage);
+            }
+        }
+    }
+
+    /// Runs the keymap the way the dongle does: every frame the changes that came in are applied,
+    /// then it ticks and the report goes out if it changed. Returns every report the host sees
+    /// with the frame it went out in.
+    fn run(
+        keymap: &mut Keymap<'_, 1, 7, 2>,
+        changes: &[(u64, KeyChange)],
+        until_us: u64,
+    ) -> Vec<(u64, Vec<u8>)> {
+        let mut held = BTreeSet::new();
+        let mut reports = Vec::new();
+        let mut last = Vec::new();
+        let mut changes = changes.iter().peekable();
+        for now_us in (0..=until_us).step_by(FRAME_US as usize) {
+            while let Some(&(at_us, change)) = changes.next_if(|(at_us, _)| *at_us <= now_us) {
+                keymap.event(change, at_us, |o| apply(&mut held, o));
+            }
+            keymap.tick(now_us, |o| apply(&mut held, o));
+            let report: Vec<u8> = held.iter().copied().collect();
+            if report != last {
+                reports.push((now_us, report.clone()));
+                last = report;
+            }
+        }
+        reports
+    }
+
+    #[test]
+    fn plain_keys() {
+        let changes = [down(1_200, KEY_A), up(40_500, KEY_A)];
+        assert_eq!(
+            run(&mut keymap(), &changes, 100_000),
+            [(2_000, vec![A]), (41_000, vec![])]
+        );
+    }
+
+    #[test]
+    fn quick_tap_reaches_the_host() {
+        // Press and release land in the same frame
+        let changes = [down(1_100, MOD_TAP), up(1_600, MOD_TAP)];
+        let mut keymap = keymap();
+        assert_eq!(
+            run(&mut keymap, &changes, 100_000),
+            [(2_000, vec![B]), (12_000, vec![])]
+        );
+        assert_eq!(keymap.next_deadline(), None);
+    }
+
+    #[test]
+    fn layer_tap_tap() {
+        let changes = [down(0, LAYER_TAP), up(90_000, LAYER_TAP)];
+        assert_eq!(
+            run(&mut keymap(), &changes, 200_000),
+            [(90_000, vec![C]), (100_000, vec![])]
+        );
+    }
+
+    #[test]
+    fn hold_after_the_tapping_term() {
+        let changes = [
+            down(0, MOD_TAP),
+            down(250_000, KEY_A),
+            up(260_000, KEY_A),
+            up(300_000, MOD_TAP),
+        ];
+        assert_eq!(
+            run(&mut keymap(), &changes, 400_000),
+            [
+                (200_000, vec![LEFT_SHIFT]),
+                (250_000, vec![A, LEFT_SHIFT]),
+                (260_000, vec![LEFT_SHIFT]),
+                (300_000, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn roll_over_a_tap_keeps_the_order() {
+        // A goes down while the mod-tap is undecided and is replayed behind the tap
+        let changes = [
+            down(0, MOD_TAP),
+            down(50_000, KEY_A),
+            up(80_000, MOD_TAP),
+            up(120_000, KEY_A),
+        ];
+        assert_eq!(
+            run(&mut keymap(), &changes, 200_000),
+            [(80_000, vec![A, B]), (90_000, vec![A]), (120_000, vec![])]
+        );
+    }
+
+    #[test]
+    fn layer_tap_hold_switches_layers() {
+        let changes = [
+            down(0, LAYER_TAP),
+            down(250_000, KEY_A),
+            up(260_000, KEY_A),
+            up(270_000, LAYER_TAP),
+            down(280_000, KEY_A),
+            up(290_000, KEY_A),
+        ];
+        assert_eq!(
+            run(&mut keymap(), &changes, 300_000),
+            [
+                (250_000, vec![X]),
+                (260_000, vec![]),
+                (280_000, vec![A]),
+                (290_000, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn release_undoes_what_the_press_resolved_to() {
+        let mut keymap = keymap();
+        let changes = [
+            down(0, MOMENTARY),
+            down(10_000, KEY_A),
+            up(20_000, MOMENTARY),
+            up(30_000, KEY_A),
+        ];
+        assert_eq!(
+            run(&mut keymap, &changes, 40_000),
+            [(10_000, vec![X]), (30_000, vec![])]
+        );
+        assert_eq!(keymap.top_layer(), 0);
+    }
+
+    #[test]
+    fn toggle_and_one_shot() {
+        let mut keymap = keymap();
+        let changes = [down(0, TOGGLE), up(5_000, TOGGLE)];
+        run(&mut keymap, &changes, 10_000);
+        assert!(keymap.is_layer_active(1));
+        assert_eq!(keymap.resolve(KeyPos { row: 0, col: KEY_B }), Action::No);
+        run(&mut keymap, &changes, 10_000);
+        assert!(!keymap.is_layer_active(1));
+
+        // Tapped, the one-shot layer applies to the next key only
+        let changes = [
+            down(0, ONE_SHOT),
+            up(5_000, ONE_SHOT),
+            down(10_000, KEY_A),
+            up(15_000, KEY_A),
+            down(20_000, KEY_A),
+            up(25_000, KEY_A),
+        ];
+        assert_eq!(
+            run(&mut keymap, &changes, 30_000),
+            [
+                (10_000, vec![X]),
+                (15_000, vec![]),
+                (20_000, vec![A]),
+                (25_000, vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn pressing_a_tapped_usage_again_keeps_it_down() {
+        let changes = [
+            down(0, MOD_TAP),
+            up(500, MOD_TAP),
+            down(3_000, KEY_B),
+            up(30_000, KEY_B),
+        ];
+        // The held back tap release doesn't come up under the second press
+        assert_eq!(
+            run(&mut keymap(), &changes, 50_000),
+            [(1_000, vec![B]), (30_000, vec![])]
+        );
+    }
+
+    #[test]
+    fn back_to_back_taps_stay_taps() {
+        // The second tap-hold key goes down in the same frame as the first one's release, which
+        // is still held back then
+        let changes = [
+            down(0, MOD_TAP),
+            up(20_100, MOD_TAP),
+            down(20_500, LAYER_TAP),
+            up(40_000, LAYER_TAP),
+        ];
+        assert_eq!(
+            run(&mut keymap(), &changes, 100_000),
+            [
+                (21_000, vec![B]),
+                (31_000, vec![]),
+                (40_000, vec![C]),
+                (50_000, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn many_quick_taps() {
+        let mut changes = Vec::new();
+        for i in 0..6 {
+            let at_us = i * 100;
+            changes.push(down(at_us, MOD_TAP));
+            changes.push(up(at_us + 50, MOD_TAP));
+        }
+        // Every tap but the first is merged into the same frame, nothing ends up stuck
+        let reports = run(&mut keymap(), &changes, 50_000);
+        assert_eq!(reports.first(), Some(&(1_000, vec![B])));
+        assert_eq!(reports.last().map(|r| &r.1), Some(&vec![]));
+    }
+}
@@ -0,0 +1,18 @@
+//! Keyboard logic shared by the split halves and the dongle. Only depends on `embedded-hal`
+//! traits, and on embassy-usb's driver traits with the `usb` feature, so it builds for the
+//! firmware as well as for the host with mock pins and a mock usb driver.
+#![no_std]
+
+pub mod battery;
+pub mod combo;
+pub mod debounce;
+pub mod event;
+pub mod hid;
+pub mod keycode;
+pub mod keymap;
+pub mod macros;
+pub mod matrix;
+pub mod pointer;
+pub mod split;
+#[cfg(feature = "usb")]
+pub mod usb;
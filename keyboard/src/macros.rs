@@ -0,0 +1,351 @@
+//! Macros, stored sequences of key presses, releases and delays.
+//!
+//! A macro is kept as bytes so it can live in flash, one step after another, each an op byte
+//! followed by a usage or a little endian delay in milliseconds. [`MacroPlayer`] plays one back
+//! without ever waiting itself: every call to [`MacroPlayer::poll`] makes at most one change, and
+//! changes are spaced out so each lands in a report of its own, so it runs from the same loop that
+//! receives key events. Its usages belong in a report apart from the keys, merged with
+//! [`KeyReport::union`](crate::hid::KeyReport::union) for the host, so a macro releasing a
+//! modifier or being stopped doesn't release a key that's physically held.
+//!
+//! ```text
+//! press    1 usage
+//! release  2 usage
+//! tap      3 usage
+//! delay    4 ms:u16
+//! ```
+
+use crate::keymap::UsageChange;
+
+/// Longest macro in bytes
+pub const MAX_LEN: usize = 64;
+
+const PRESS: u8 = 1;
+const RELEASE: u8 = 2;
+const TAP: u8 = 3;
+const DELAY: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Step {
+    Press(u8),
+    Release(u8),
+    /// Press and release
+    Tap(u8),
+    /// Wait this many milliseconds
+    Delay(u16),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// Longer than [`MAX_LEN`]
+    TooLong,
+    UnknownStep(u8),
+    /// The last step is cut short
+    Truncated,
+}
+
+/// Steps of a macro, checked when decoding
+#[derive(Clone, Debug)]
+pub struct Steps<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for Steps<'_> {
+    type Item = Step;
+
+    fn next(&mut self) -> Option<Step> {
+        let (step, len) = next_step(self.bytes).ok()??;
+        self.bytes = &self.bytes[len..];
+        Some(step)
+    }
+}
+
+/// First step of `bytes` and its length, `None` if there are none left
+fn next_step(bytes: &[u8]) -> Result<Option<(Step, usize)>, DecodeError> {
+    let step = match *bytes {
+        [] => return Ok(None),
+        [PRESS, usage, ..] => (Step::Press(usage), 2),
+        [RELEASE, usage, ..] => (Step::Release(usage), 2),
+        [TAP, usage, ..] => (Step::Tap(usage), 2),
+        [DELAY, ms_0, ms_1, ..] => (Step::Delay(u16::from_le_bytes([ms_0, ms_1])), 3),
+        [PRESS | RELEASE | TAP | DELAY, ..] => return Err(DecodeError::Truncated),
+        [op, ..] => return Err(DecodeError::UnknownStep(op)),
+    };
+    Ok(Some(step))
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Steps<'_>, DecodeError> {
+    if bytes.len() > MAX_LEN {
+        return Err(DecodeError::TooLong);
+    }
+    let mut rest = bytes;
+    while let Some((_, len)) = next_step(rest)? {
+        rest = &rest[len..];
+    }
+    Ok(Steps { bytes })
+}
+
+/// Writes `steps` into `buf`, `None` if they don't fit or come out longer than [`MAX_LEN`]
+pub fn encode(steps: &[Step], buf: &mut [u8]) -> Option<usize> {
+    let limit = MAX_LEN.min(buf.len());
+    let mut len = 0;
+    for step in steps {
+        let (bytes, n) = match *step {
+            Step::Press(usage) => ([PRESS, usage, 0], 2),
+            Step::Release(usage) => ([RELEASE, usage, 0], 2),
+            Step::Tap(usage) => ([TAP, usage, 0], 2),
+            Step::Delay(ms) => {
+                let [ms_0, ms_1] = ms.to_le_bytes();
+                ([DELAY, ms_0, ms_1], 3)
+            }
+        };
+        if len + n > limit {
+            return None;
+        }
+        buf[len..len + n].copy_from_slice(&bytes[..n]);
+        len += n;
+    }
+    Some(len)
+}
+
+/// Plays back one macro at a time
+pub struct MacroPlayer {
+    bytes: [u8; MAX_LEN],
+    len: usize,
+    /// Offset of the next step
+    at: usize,
+    /// Nothing happens before then
+    wait_until: u64,
+    /// Usage of a tap that still has to come up
+    tap: Option<u8>,
+    /// Usages the macro holds down, one bit each
+    held: [u8; 32],
+    step_us: u64,
+}
+
+impl MacroPlayer {
+    /// Changes come at least `step_us` apart, which should be long enough for the host to see
+    /// each of them
+    pub fn new(step_us: u64) -> Self {
+        Self {
+            bytes: [0; MAX_LEN],
+            len: 0,
+            at: 0,
+            wait_until: 0,
+            tap: None,
+            held: [0; 32],
+            step_us,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.at < self.len || self.tap.is_some() || self.held != [0; 32]
+    }
+
+    /// Starts playing `bytes` at `now_us`, after releasing whatever the previous macro still holds
+    pub fn play(
+        &mut self,
+        bytes: &[u8],
+        now_us: u64,
+        mut out: impl FnMut(UsageChange),
+    ) -> Result<(), DecodeError> {
+        decode(bytes)?;
+        let held = self.held != [0; 32];
+        self.stop(&mut out);
+        self.bytes[..bytes.len()].copy_from_slice(bytes);
+        self.len = bytes.len();
+        // Keys just released come back up in a report of their own before anything goes down
+        self.wait_until = if held { now_us + self.step_us } else { now_us };
+        Ok(())
+    }
+
+    /// Stops playing and releases everything the macro holds
+    pub fn stop(&mut self, mut out: impl FnMut(UsageChange)) {
+        self.at = 0;
+        self.len = 0;
+        self.tap = None;
+        for usage in 0..=u8::MAX {
+            self.set(usage, false, &mut out);
+        }
+    }
+
+    /// When the next change is due, `None` if nothing is playing
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.is_playing().then_some(self.wait_until)
+    }
+
+    /// Makes the next change if it's due at `now_us`. Whatever the macro leaves pressed is
+    /// released once it's done.
+    pub fn poll(&mut self, now_us: u64, mut out: impl FnMut(UsageChange)) {
+        while self.is_playing() && now_us >= self.wait_until {
+            if let Some(usage) = self.tap.take() {
+                self.set(usage, false, &mut out);
+                self.wait_until = now_us + self.step_us;
+                return;
+            }
+            let Ok(Some((step, len))) = next_step(&self.bytes[self.at..self.len]) else {
+                self.stop(&mut out);
+                self.wait_until = now_us + self.step_us;
+                return;
+            };
+            self.at += len;
+            match step {
+                Step::Press(usage) => self.set(usage, true, &mut out),
+                Step::Release(usage) => self.set(usage, false, &mut out),
+                Step::Tap(usage) => {
+                    self.set(usage, true, &mut out);
+                    self.tap = Some(usage);
+                }
+                Step::Delay(ms) => {
+                    self.wait_until = now_us + ms as u64 * 1000;
+                    continue;
+                }
+            }
+            self.wait_until = now_us + self.step_us;
+            return;
+        }
+    }
+
+    fn set(&mut self, usage: u8, pressed: bool, out: &mut impl FnMut(UsageChange)) {
+        let (byte, bit) = (&mut self.held[usage as usize / 8], 1 << (usage % 8));
+        if (*byte & bit != 0) == pressed {
+            return;
+        }
+        *byte ^= bit;
+        out(UsageChange { usage, pressed });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::hid::KeyReport;
+
+    const A: u8 = 0x04;
+    const B: u8 = 0x05;
+    const LEFT_CTRL: u8 = 0xE0;
+    const STEP_US: u64 = 10_000;
+
+    fn bytes(steps: &[Step]) -> Vec<u8> {
+        let mut buf = [0u8; MAX_LEN];
+        let len = encode(steps, &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    /// Polls every millisecond until the macro is done, returning each change with its time
+    fn play_all(player: &mut MacroPlayer, report: &mut KeyReport) -> Vec<(u64, UsageChange)> {
+        let mut res = Vec::new();
+        let mut now_us = 0;
+        while player.is_playing() {
+            player.poll(now_us, |u| {
+                report.set(u.usage, u.pressed);
+                res.push((now_us, u));
+            });
+            now_us += 1000;
+        }
+        res
+    }
+
+    fn change(usage: u8, pressed: bool) -> UsageChange {
+        UsageChange { usage, pressed }
+    }
+
+    #[test]
+    fn round_trip() {
+        let steps = [
+            Step::Press(LEFT_CTRL),
+            Step::Tap(A),
+            Step::Delay(300),
+            Step::Release(LEFT_CTRL),
+        ];
+        let encoded = bytes(&steps);
+        assert_eq!(encoded.len(), 9);
+        assert_eq!(decode(&encoded).unwrap().collect::<Vec<_>>(), steps);
+    }
+
+    #[test]
+    fn bad_macros_are_rejected() {
+        assert_eq!(decode(&[PRESS]).err(), Some(DecodeError::Truncated));
+        assert_eq!(
+            decode(&[TAP, A, DELAY, 1]).err(),
+            Some(DecodeError::Truncated)
+        );
+        assert_eq!(decode(&[9, A]).err(), Some(DecodeError::UnknownStep(9)));
+        assert_eq!(
+            decode(&[TAP; MAX_LEN + 2]).err(),
+            Some(DecodeError::TooLong)
+        );
+        assert_eq!(encode(&[Step::Delay(1); 22], &mut [0u8; 128]), None);
+        assert_eq!(encode(&[Step::Tap(A); 2], &mut [0u8; 3]), None);
+    }
+
+    #[test]
+    fn changes_are_spaced_out() {
+        let mut player = MacroPlayer::new(STEP_US);
+        let mut report = KeyReport::new();
+        let steps = bytes(&[Step::Tap(A), Step::Delay(50), Step::Press(B)]);
+        player.play(&steps, 0, |_| panic!("nothing held")).unwrap();
+        assert_eq!(
+            play_all(&mut player, &mut report),
+            [
+                (0, change(A, true)),
+                (10_000, change(A, false)),
+                (70_000, change(B, true)),
+                // Left down by the macro, released once it's done
+                (80_000, change(B, false)),
+            ]
+        );
+        assert!(report.is_empty());
+        assert_eq!(player.next_deadline(), None);
+    }
+
+    #[test]
+    fn play_releases_the_previous_macro_first() {
+        let mut player = MacroPlayer::new(STEP_US);
+        player
+            .play(&bytes(&[Step::Press(LEFT_CTRL)]), 0, |_| {})
+            .unwrap();
+        player.poll(0, |_| {});
+        let mut released = Vec::new();
+        player
+            .play(&bytes(&[Step::Tap(A)]), 5_000, |u| released.push(u))
+            .unwrap();
+        assert_eq!(released, [change(LEFT_CTRL, false)]);
+        // The next press waits a step so the release gets a report of its own
+        assert_eq!(player.next_deadline(), Some(15_000));
+    }
+
+    #[test]
+    fn macro_layer_leaves_physical_keys_alone() {
+        // Ctrl is held on the keyboard while a macro presses and releases it
+        let mut keys = KeyReport::new();
+        keys.set(LEFT_CTRL, true);
+        keys.set(B, true);
+        let mut layer = KeyReport::new();
+        let mut player = MacroPlayer::new(STEP_US);
+        let steps = bytes(&[
+            Step::Press(LEFT_CTRL),
+            Step::Tap(A),
+            Step::Release(LEFT_CTRL),
+            Step::Press(B),
+        ]);
+        player.play(&steps, 0, |_| {}).unwrap();
+        let mut now_us = 0;
+        while player.is_playing() {
+            player.poll(now_us, |u| layer.set(u.usage, u.pressed));
+            let sent = keys.union(&layer);
+            assert!(sent.is_pressed(LEFT_CTRL) && sent.is_pressed(B));
+            now_us += 1000;
+        }
+        // Stopping midway doesn't release them either
+        player.play(&steps, now_us, |_| {}).unwrap();
+        player.poll(now_us, |u| layer.set(u.usage, u.pressed));
+        player.stop(|u| layer.set(u.usage, u.pressed));
+        assert!(layer.is_empty());
+        assert_eq!(keys.union(&layer), keys);
+    }
+}
@@ -0,0 +1,533 @@
+//! Key matrix scanning.
+//!
+//! One set of matrix lines is driven one line at a time while the other set is read. Which set is
+//! driven and which way the diodes point decide whether a pressed key reads as high or low, see
+//! [`MatrixConfig::active_low`]. While no key is held every driven line is left active and the
+//! scanner sleeps until any read line changes, so an idle matrix costs nothing but a pin
+//! interrupt. Results come out of [`Matrix::next`] as one [`KeyChange`] at a time.
+
+use embassy_futures::select::select_array;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+/// The set of matrix lines
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Lines {
+    Rows,
+    Columns,
+}
+
+/// Which way current flows through the diode of each switch
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiodeDirection {
+    /// Anode on the column, cathode on the row
+    Col2Row,
+    /// Anode on the row, cathode on the column
+    Row2Col,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MatrixConfig {
+    /// Lines that get driven, the others are read
+    pub driven: Lines,
+    pub diodes: DiodeDirection,
+    /// Time between driving a line and reading the inputs
+    pub settle_us: u32,
+    /// Time between scans while any key is held
+    pub scan_interval_us: u32,
+}
+
+impl Default for MatrixConfig {
+    fn default() -> Self {
+        Self {
+            driven: Lines::Rows,
+            diodes: DiodeDirection::Col2Row,
+            settle_us: 1,
+            scan_interval_us: 1000,
+        }
+    }
+}
+
+impl MatrixConfig {
+    /// Whether a pressed key pulls its read line low. Driven lines are set low when active in
+    /// that case and the read pins need pull ups, otherwise everything is the other way around.
+    pub fn active_low(&self) -> bool {
+        matches!(
+            (self.driven, self.diodes),
+            (Lines::Rows, DiodeDirection::Col2Row) | (Lines::Columns, DiodeDirection::Row2Col)
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyPos {
+    pub row: u8,
+    pub col: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyChange {
+    pub pos: KeyPos,
+    pub pressed: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error<O, I> {
+    Output(O),
+    Input(I),
+}
+
+/// Scanner for a matrix with `OUTS` driven and `INS` read lines
+pub struct Matrix<O, I, const OUTS: usize, const INS: usize> {
+    outputs: [O; OUTS],
+    inputs: [I; INS],
+    config: MatrixConfig,
+    /// Key states from the last scan, indexed by driven and then read line
+    scanned: [[bool; INS]; OUTS],
+    /// Key states as handed out by [`Matrix::next`]
+    reported: [[bool; INS]; OUTS],
+}
+
+impl<O, I, const OUTS: usize, const INS: usize> Matrix<O, I, OUTS, INS>
+where
+    O: OutputPin,
+    I: InputPin,
+{
+    /// Takes the pins with the read ones already pulled as [`MatrixConfig::active_low`] asks for.
+    /// Every driven line starts out inactive.
+    pub fn new(
+        outputs: [O; OUTS],
+        inputs: [I; INS],
+        config: MatrixConfig,
+    ) -> Result<Self, Error<O::Error, I::Error>> {
+        let mut res = Self {
+            outputs,
+            inputs,
+            config,
+            scanned: [[false; INS]; OUTS],
+            reported: [[false; INS]; OUTS],
+        };
+        for i in 0..OUTS {
+            res.drive(i, false)?;
+        }
+        Ok(res)
+    }
+
+    pub fn config(&self) -> &MatrixConfig {
+        &self.config
+    }
+
+    fn pos(&self, out: usize, input: usize) -> KeyPos {
+        let (row, col) = match self.config.driven {
+            Lines::Rows => (out, input),
+            Lines::Columns => (input, out),
+        };
+        KeyPos {
+            row: row as u8,
+            col: col as u8,
+        }
+    }
+
+    fn drive(&mut self, out: usize, active: bool) -> Result<(), Error<O::Error, I::Error>> {
+        let pin = &mut self.outputs[out];
+        let res = if active == self.config.active_low() {
+            pin.set_low()
+        } else {
+            pin.set_high()
+        };
+        res.map_err(Error::Output)
+    }
+
+    /// Whether the key was down in the last scan
+    pub fn is_pressed(&self, pos: KeyPos) -> bool {
+        let (out, input) = match self.config.driven {
+            Lines::Rows => (pos.row as usize, pos.col as usize),
+            Lines::Columns => (pos.col as usize, pos.row as usize),
+        };
+        self.scanned
+            .get(out)
+            .and_then(|ins| ins.get(input))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub fn any_pressed(&self) -> bool {
+        self.scanned.iter().flatten().any(|&pressed| pressed)
+    }
+
+    /// Reads the whole matrix once. Changes are handed out by [`Matrix::next`] afterwards
+    pub async fn scan<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), Error<O::Error, I::Error>> {
+        let active_low = self.config.active_low();
+        // A dropped wait for any key leaves every line driven
+        for out in 0..OUTS {
+            self.drive(out, false)?;
+        }
+        for out in 0..OUTS {
+            self.drive(out, true)?;
+            delay.delay_us(self.config.settle_us).await;
+            for (input, pin) in self.inputs.iter_mut().enumerate() {
+                let low = pin.is_low().map_err(Error::Input)?;
+                self.scanned[out][input] = low == active_low;
+            }
+            self.drive(out, false)?;
+        }
+        Ok(())
+    }
+
+    /// First key whose scanned state hasn't been handed out yet
+    fn take_change(&mut self) -> Option<KeyChange> {
+        for out in 0..OUTS {
+            for input in 0..INS {
+                let pressed = self.scanned[out][input];
+                if pressed != self.reported[out][input] {
+                    self.reported[out][input] = pressed;
+                    return Some(KeyChange {
+                        pos: self.pos(out, input),
+                        pressed,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<O, I, const OUTS: usize, const INS: usize> Matrix<O, I, OUTS, INS>
+where
+    O: OutputPin,
+    I: InputPin + Wait,
+{
+    /// Drives every line and sleeps until any read line goes active
+    async fn wait_for_any_key(&mut self) -> Result<(), Error<O::Error, I::Error>> {
+        for out in 0..OUTS {
+            self.drive(out, true)?;
+        }
+        let active_low = self.config.active_low();
+        let waits = self.inputs.each_mut().map(|pin| async move {
+            if active_low {
+                pin.wait_for_low().await
+            } else {
+                pin.wait_for_high().await
+            }
+        });
+        let (res, _) = select_array(waits).await;
+        for out in 0..OUTS {
+            self.drive(out, false)?;
+        }
+        res.map_err(Error::Input)
+    }
+
+    /// Waits for the next key to be pressed or released. Keeps scanning every
+    /// [`MatrixConfig::scan_interval_us`] while any key is held and sleeps on the pins otherwise.
+    /// Several keys changing in one scan come out of consecutive calls in matrix order. Dropping
+    /// the future loses nothing, so it can race a timer.
+    pub async fn next<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<KeyChange, Error<O::Error, I::Error>> {
+        loop {
+            if let Some(change) = self.take_change() {
+                return Ok(change);
+            }
+            if self.any_pressed() {
+                delay.delay_us(self.config.scan_interval_us).await;
+            } else {
+                self.wait_for_any_key().await?;
+            }
+            self.scan(delay).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::{
+        cell::RefCell,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{rc::Rc, vec::Vec};
+
+    use embedded_hal::digital::{ErrorKind, ErrorType};
+
+    use super::*;
+
+    const OUTS: usize = 3;
+    const INS: usize = 2;
+
+    /// Switches and diodes between the mock pins
+    struct Board {
+        active_low: bool,
+        /// Switch states indexed by driven and then read line
+        keys: [[bool; INS]; OUTS],
+        /// Levels of the driven lines
+        low: [bool; OUTS],
+        fail_reads: bool,
+        delayed_us: u32,
+    }
+
+    impl Board {
+        fn read_low(&self, input: usize) -> bool {
+            let active =
+                (0..OUTS).any(|out| self.low[out] == self.active_low && self.keys[out][input]);
+            active == self.active_low
+        }
+    }
+
+    type Shared = Rc<RefCell<Board>>;
+
+    struct Out(Shared, usize);
+    struct In(Shared, usize);
+    struct Delay(Shared);
+
+    impl ErrorType for Out {
+        type Error = ErrorKind;
+    }
+
+    impl OutputPin for Out {
+        fn set_low(&mut self) -> Result<(), ErrorKind> {
+            self.0.borrow_mut().low[self.1] = true;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), ErrorKind> {
+            self.0.borrow_mut().low[self.1] = false;
+            Ok(())
+        }
+    }
+
+    impl ErrorType for In {
+        type Error = ErrorKind;
+    }
+
+    impl InputPin for In {
+        fn is_high(&mut self) -> Result<bool, ErrorKind> {
+            self.is_low().map(|low| !low)
+        }
+
+        fn is_low(&mut self) -> Result<bool, ErrorKind> {
+            let board = self.0.borrow();
+            if board.fail_reads {
+                return Err(ErrorKind::Other);
+            }
+            Ok(board.read_low(self.1))
+        }
+    }
+
+    /// Level waits that never complete until the level is there, there's no waker to wake
+    struct Level<'a>(&'a Shared, usize, bool);
+
+    impl Future for Level<'_> {
+        type Output = Result<(), ErrorKind>;
+
+        fn poll(self: core::pin::Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.0.borrow().read_low(self.1) == self.2 {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    impl Wait for In {
+        async fn wait_for_high(&mut self) -> Result<(), ErrorKind> {
+            Level(&self.0, self.1, false).await
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), ErrorKind> {
+            Level(&self.0, self.1, true).await
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), ErrorKind> {
+            unimplemented!()
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), ErrorKind> {
+            unimplemented!()
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), ErrorKind> {
+            unimplemented!()
+        }
+    }
+
+    impl DelayNs for Delay {
+        async fn delay_ns(&mut self, ns: u32) {
+            self.0.borrow_mut().delayed_us += ns / 1000;
+        }
+
+        async fn delay_us(&mut self, us: u32) {
+            self.0.borrow_mut().delayed_us += us;
+        }
+    }
+
+    fn poll_once<F: Future>(fut: F) -> Option<F::Output> {
+        match pin!(fut).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(out) => Some(out),
+            Poll::Pending => None,
+        }
+    }
+
+    fn setup(config: MatrixConfig) -> (Shared, Matrix<Out, In, OUTS, INS>, Delay) {
+        let board = Rc::new(RefCell::new(Board {
+            active_low: config.active_low(),
+            keys: [[false; INS]; OUTS],
+            low: [false; OUTS],
+            fail_reads: false,
+            delayed_us: 0,
+        }));
+        let outputs = core::array::from_fn(|i| Out(board.clone(), i));
+        let inputs = core::array::from_fn(|i| In(board.clone(), i));
+        let matrix = Matrix::new(outputs, inputs, config).unwrap();
+        (board.clone(), matrix, Delay(board))
+    }
+
+    fn configs() -> Vec<MatrixConfig> {
+        let mut res = Vec::new();
+        for driven in [Lines::Rows, Lines::Columns] {
+            for diodes in [DiodeDirection::Col2Row, DiodeDirection::Row2Col] {
+                res.push(MatrixConfig {
+                    driven,
+                    diodes,
+                    ..Default::default()
+                });
+            }
+        }
+        res
+    }
+
+    fn change(row: u8, col: u8, pressed: bool) -> KeyChange {
+        KeyChange {
+            pos: KeyPos { row, col },
+            pressed,
+        }
+    }
+
+    #[test]
+    fn active_low_follows_diodes() {
+        let active_low: Vec<bool> = configs().iter().map(|c| c.active_low()).collect();
+        assert_eq!(active_low, [true, false, false, true]);
+    }
+
+    #[test]
+    fn new_leaves_lines_inactive() {
+        for config in configs() {
+            let (board, _, _) = setup(config);
+            assert_eq!(board.borrow().low, [!config.active_low(); OUTS]);
+        }
+    }
+
+    #[test]
+    fn scan_maps_lines_to_positions() {
+        for config in configs() {
+            let (board, mut matrix, mut delay) = setup(config);
+            board.borrow_mut().keys[2][1] = true;
+            poll_once(matrix.scan(&mut delay)).unwrap().unwrap();
+            let pos = match config.driven {
+                Lines::Rows => KeyPos { row: 2, col: 1 },
+                Lines::Columns => KeyPos { row: 1, col: 2 },
+            };
+            assert!(matrix.is_pressed(pos), "{config:?}");
+            assert!(matrix.any_pressed());
+            assert!(!matrix.is_pressed(KeyPos { row: 0, col: 0 }));
+            assert!(!matrix.is_pressed(KeyPos { row: 9, col: 9 }));
+            // Every line is back to inactive after the scan
+            assert_eq!(board.borrow().low, [!config.active_low(); OUTS]);
+        }
+    }
+
+    #[test]
+    fn next_hands_out_changes_in_matrix_order() {
+        let (board, mut matrix, mut delay) = setup(MatrixConfig::default());
+        board.borrow_mut().keys[1][1] = true;
+        board.borrow_mut().keys[0][1] = true;
+        let mut next = || poll_once(matrix.next(&mut delay)).map(Result::unwrap);
+        assert_eq!(next(), Some(change(0, 1, true)));
+        assert_eq!(next(), Some(change(1, 1, true)));
+
+        board.borrow_mut().keys[0][1] = false;
+        assert_eq!(next(), Some(change(0, 1, false)));
+        board.borrow_mut().keys[1][1] = false;
+        assert_eq!(next(), Some(change(1, 1, false)));
+    }
+
+    #[test]
+    fn held_keys_are_rescanned_after_the_interval() {
+        let (board, mut matrix, mut delay) = setup(MatrixConfig::default());
+        board.borrow_mut().keys[2][0] = true;
+        assert_eq!(
+            poll_once(matrix.next(&mut delay)).unwrap(),
+            Ok(change(2, 0, true))
+        );
+        let before = board.borrow().delayed_us;
+        board.borrow_mut().keys[2][0] = false;
+        assert_eq!(
+            poll_once(matrix.next(&mut delay)).unwrap(),
+            Ok(change(2, 0, false))
+        );
+        // One scan interval and a settle time per driven line
+        assert_eq!(board.borrow().delayed_us - before, 1000 + OUTS as u32);
+    }
+
+    #[test]
+    fn idle_matrix_sleeps_with_every_line_driven() {
+        for config in configs() {
+            let (board, mut matrix, mut delay) = setup(config);
+            let mut cx = Context::from_waker(Waker::noop());
+            {
+                let mut next = pin!(matrix.next(&mut delay));
+                for _ in 0..3 {
+                    assert!(next.as_mut().poll(&mut cx).is_pending());
+                }
+                assert_eq!(board.borrow().low, [config.active_low(); OUTS]);
+                assert_eq!(board.borrow().delayed_us, 0);
+
+                board.borrow_mut().keys[1][0] = true;
+                let Poll::Ready(res) = next.as_mut().poll(&mut cx) else {
+                    panic!("key press not noticed");
+                };
+                let pos = match config.driven {
+                    Lines::Rows => KeyPos { row: 1, col: 0 },
+                    Lines::Columns => KeyPos { row: 0, col: 1 },
+                };
+                assert_eq!(res, Ok(KeyChange { pos, pressed: true }));
+            }
+            // The next scan starts from inactive lines
+            assert_eq!(board.borrow().low, [!config.active_low(); OUTS]);
+        }
+    }
+
+    #[test]
+    fn dropped_next_loses_nothing() {
+        let (board, mut matrix, mut delay) = setup(MatrixConfig::default());
+        // Dropped while sleeping on the pins
+        assert!(poll_once(matrix.next(&mut delay)).is_none());
+        board.borrow_mut().keys[0][0] = true;
+        board.borrow_mut().keys[2][1] = true;
+        assert_eq!(
+            poll_once(matrix.next(&mut delay)).unwrap(),
+            Ok(change(0, 0, true))
+        );
+        // The second change is already scanned and survives the drop of the first call
+        assert_eq!(
+            poll_once(matrix.next(&mut delay)).unwrap(),
+            Ok(change(2, 1, true))
+        );
+    }
+
+    #[test]
+    fn read_errors_are_passed_on() {
+        let (board, mut matrix, mut delay) = setup(MatrixConfig::default());
+        board.borrow_mut().fail_reads = true;
+        assert_eq!(
+            poll_once(matrix.scan(&mut delay)).unwrap(),
+            Err(Error::Input(ErrorKind::Other))
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Pointer motion from the halves, for trackpoints, trackballs and encoders.
+//!
+//! Motion is relative and piles up in a [`Motion`] until whoever carries it has room: a half only
+//! writes the next pointer payload once the previous one has left, and the dongle only takes the
+//! next report once the previous one was written. However fast a sensor reports, there's then at
+//! most one pointer payload in the radio queue and nothing is lost, only merged. Buttons are sent
+//! as they are in every payload.
+
+/// Relative motion, summed up until it's taken
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Motion {
+    pub x: i32,
+    pub y: i32,
+    pub wheel: i32,
+    /// Horizontal scroll
+    pub pan: i32,
+}
+
+impl Motion {
+    pub const ZERO: Self = Self {
+        x: 0,
+        y: 0,
+        wheel: 0,
+        pan: 0,
+    };
+
+    pub fn add(&mut self, other: Motion) {
+        self.x = self.x.saturating_add(other.x);
+        self.y = self.y.saturating_add(other.y);
+        self.wheel = self.wheel.saturating_add(other.wheel);
+        self.pan = self.pan.saturating_add(other.pan);
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+
+    /// Takes at most `xy_max` of each pointer axis and `scroll_max` of each scroll axis either
+    /// way, what's left over stays for the next take
+    pub fn take(&mut self, xy_max: i32, scroll_max: i32) -> Motion {
+        fn take(axis: &mut i32, max: i32) -> i32 {
+            let taken = (*axis).clamp(-max, max);
+            *axis -= taken;
+            taken
+        }
+        Motion {
+            x: take(&mut self.x, xy_max),
+            y: take(&mut self.y, xy_max),
+            wheel: take(&mut self.wheel, scroll_max),
+            pan: take(&mut self.pan, scroll_max),
+        }
+    }
+}
+
+/// Half side, merges motion until the next pointer payload goes out
+#[derive(Default)]
+pub struct PointerSender {
+    buttons: u8,
+    motion: Motion,
+    seq: u8,
+    /// Buttons changed since the last payload
+    buttons_changed: bool,
+}
+
+impl PointerSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons_changed |= buttons != self.buttons;
+        self.buttons = buttons;
+    }
+
+    pub fn add(&mut self, motion: Motion) {
+        self.motion.add(motion);
+    }
+
+    /// Whether there's anything to send
+    pub fn is_pending(&self) -> bool {
+        self.buttons_changed || !self.motion.is_zero()
+    }
+
+    /// Writes the buttons and as much of the motion as one payload holds, the rest goes with the
+    /// next one. Call again on a timer while buttons are held so a lost release doesn't leave
+    /// them stuck. `None` if `buf` is too small.
+    pub fn encode(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let mut rest = self.motion;
+        let motion = rest.take(i16::MAX as i32, i8::MAX as i32);
+        let seq = self.seq.wrapping_add(1);
+        let len = crate::event::encode_pointer(seq, self.buttons, &motion, buf)?;
+        self.motion = rest;
+        self.seq = seq;
+        self.buttons_changed = false;
+        Some(len)
+    }
+}
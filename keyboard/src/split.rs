@@ -0,0 +1,270 @@
+//! Merging both halves into one keyboard on the dongle.
+//!
+//! Each half reports its own matrix through an [`event::Receiver`]. [`Split`] maps the local
+//! positions into one global matrix and stamps every change with when it happened on its half,
+//! from the receive time and the age the delta carries. Changes are held until [`Split::flush`],
+//! called once per usb frame, which hands them out in that order. Two keys pressed on different
+//! halves within a frame then come out in the order they were pressed rather than the order their
+//! packets made it through. A half that stays quiet for longer than the timeout counts as gone and
+//! all of its keys are released, so halves have to refresh their full state while idle.
+//!
+//! Pointer motion of both halves is summed up until [`Split::take_motion`], while their pointer
+//! buttons and consumer usages are kept as last sent and released along with the keys. So is the
+//! battery level, which is unknown again once a half is gone.
+
+use crate::{
+    battery::Level,
+    event::{self, DecodeError, Message, Receiver, MAX_CONSUMER_USAGES},
+    matrix::{KeyChange, KeyPos},
+    pointer::Motion,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Half {
+    Left,
+    Right,
+}
+
+/// Where a half's matrix sits in the global one
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HalfLayout {
+    pub row_offset: u8,
+    pub col_offset: u8,
+    /// Local columns count from the other side, for a half wired as a mirror image of the other
+    pub mirrored: bool,
+}
+
+/// A change in global coordinates and the time it happened in the receiver's clock
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimedChange {
+    pub change: KeyChange,
+    pub at_us: u64,
+}
+
+const EMPTY: TimedChange = TimedChange {
+    change: KeyChange {
+        pos: KeyPos { row: 0, col: 0 },
+        pressed: false,
+    },
+    at_us: 0,
+};
+
+/// Changes held for ordering, more than that in one frame come out early
+const PENDING: usize = 64;
+
+/// Changes sorted by time, ties in arrival order
+struct Queue {
+    changes: [TimedChange; PENDING],
+    len: usize,
+}
+
+impl Queue {
+    /// Queues a change, flushing everything queued so far into `on_overflow` if there's no room
+    fn push(&mut self, change: TimedChange, on_overflow: &mut impl FnMut(TimedChange)) {
+        if self.len == PENDING {
+            self.flush(&mut *on_overflow);
+        }
+        let at = self.changes[..self.len].partition_point(|c| c.at_us <= change.at_us);
+        self.changes.copy_within(at..self.len, at + 1);
+        self.changes[at] = change;
+        self.len += 1;
+    }
+
+    fn flush(&mut self, mut on_change: impl FnMut(TimedChange)) {
+        for &change in &self.changes[..self.len] {
+            on_change(change);
+        }
+        self.len = 0;
+    }
+}
+
+/// Pointer buttons, consumer usages and battery level of one half
+#[derive(Clone, Copy)]
+struct Extras {
+    /// Last pointer payload applied, repeats carry motion that's already counted
+    pointer_seq: Option<u8>,
+    buttons: u8,
+    consumer: [u16; MAX_CONSUMER_USAGES],
+    consumer_len: usize,
+    battery: Option<Level>,
+}
+
+const NO_EXTRAS: Extras = Extras {
+    pointer_seq: None,
+    buttons: 0,
+    consumer: [0; MAX_CONSUMER_USAGES],
+    consumer_len: 0,
+    battery: None,
+};
+
+/// Both halves, each with a `ROWS` by `COLS` matrix
+pub struct Split<const ROWS: usize, const COLS: usize> {
+    receivers: [Receiver<ROWS, COLS>; 2],
+    layouts: [HalfLayout; 2],
+    /// When each half was last heard from, `None` while it's gone
+    last_seen: [Option<u64>; 2],
+    /// Latest change time of each half, its changes never go out of order among themselves
+    last_at: [u64; 2],
+    timeout_us: u64,
+    queue: Queue,
+    extras: [Extras; 2],
+    motion: Motion,
+}
+
+impl<const ROWS: usize, const COLS: usize> Split<ROWS, COLS> {
+    pub fn new(left: HalfLayout, right: HalfLayout, timeout_us: u64) -> Self {
+        Self {
+            receivers: [Receiver::new(), Receiver::new()],
+            layouts: [left, right],
+            last_seen: [None; 2],
+            last_at: [0; 2],
+            timeout_us,
+            queue: Queue {
+                changes: [EMPTY; PENDING],
+                len: 0,
+            },
+            extras: [NO_EXTRAS; 2],
+            motion: Motion::ZERO,
+        }
+    }
+
+    pub fn is_connected(&self, half: Half) -> bool {
+        self.last_seen[half as usize].is_some()
+    }
+
+    /// Whether `half` should be asked for its full state, see [`Split::encode_request`]
+    pub fn needs_full_state(&self, half: Half) -> bool {
+        self.receivers[half as usize].needs_full_state()
+    }
+
+    pub fn encode_request(&self, half: Half, buf: &mut [u8]) -> Option<usize> {
+        self.receivers[half as usize].encode_request(buf)
+    }
+
+    /// Marks the next change of `half` as happening at `at_us`, or right after its previous
+    /// one if that's later
+    fn stamp(&mut self, half: Half, at_us: u64) -> u64 {
+        let last = &mut self.last_at[half as usize];
+        *last = at_us.max(*last);
+        *last
+    }
+
+    /// Applies a payload `half` sent, received at `now_us`. Its changes are queued for
+    /// [`Split::flush`] and only come out through `on_overflow` if too many pile up in one frame.
+    pub fn receive(
+        &mut self,
+        half: Half,
+        payload: &[u8],
+        now_us: u64,
+        mut on_overflow: impl FnMut(TimedChange),
+    ) -> Result<(), DecodeError> {
+        let h = half as usize;
+        let age_us = match event::decode(payload)? {
+            Message::Delta { age_us, .. } => age_us,
+            Message::Pointer {
+                seq,
+                buttons,
+                motion,
+            } => {
+                let extras = &mut self.extras[h];
+                if extras.pointer_seq != Some(seq) {
+                    self.motion.add(motion);
+                }
+                extras.pointer_seq = Some(seq);
+                extras.buttons = buttons;
+                self.last_seen[h] = Some(now_us);
+                return Ok(());
+            }
+            Message::Consumer { usages } => {
+                let extras = &mut self.extras[h];
+                extras.consumer_len = 0;
+                for (usage, slot) in usages.zip(&mut extras.consumer) {
+                    *slot = usage;
+                    extras.consumer_len += 1;
+                }
+                self.last_seen[h] = Some(now_us);
+                return Ok(());
+            }
+            Message::Battery { level } => {
+                self.extras[h].battery = Some(level);
+                self.last_seen[h] = Some(now_us);
+                return Ok(());
+            }
+            _ => 0,
+        };
+        let at_us = self.stamp(half, now_us.saturating_sub(age_us as u64));
+        let layout = self.layouts[h];
+        let queue = &mut self.queue;
+        self.receivers[h].receive(payload, |change| {
+            queue.push(global::<COLS>(&layout, change, at_us), &mut on_overflow)
+        })?;
+        self.last_seen[h] = Some(now_us);
+        Ok(())
+    }
+
+    /// Releases the keys of every half that hasn't been heard from within the timeout. They're
+    /// queued like any other change.
+    pub fn release_lost(&mut self, now_us: u64, mut on_overflow: impl FnMut(TimedChange)) {
+        for half in [Half::Left, Half::Right] {
+            let h = half as usize;
+            if self.last_seen[h].is_none_or(|seen| now_us.saturating_sub(seen) <= self.timeout_us) {
+                continue;
+            }
+            self.last_seen[h] = None;
+            self.extras[h] = NO_EXTRAS;
+            let at_us = self.stamp(half, now_us);
+            let layout = self.layouts[h];
+            let queue = &mut self.queue;
+            self.receivers[h].release_all(|change| {
+                queue.push(global::<COLS>(&layout, change, at_us), &mut on_overflow)
+            });
+        }
+    }
+
+    /// Pointer buttons held on either half
+    pub fn buttons(&self) -> u8 {
+        self.extras[0].buttons | self.extras[1].buttons
+    }
+
+    /// Consumer usages held on either half
+    pub fn consumer(&self) -> impl Iterator<Item = u16> + '_ {
+        self.extras
+            .iter()
+            .flat_map(|extras| &extras.consumer[..extras.consumer_len])
+            .copied()
+    }
+
+    /// Latest battery level `half` sent, `None` if it never did or is gone
+    pub fn battery(&self, half: Half) -> Option<Level> {
+        self.extras[half as usize].battery
+    }
+
+    /// Motion of both halves since the last take
+    pub fn take_motion(&mut self) -> Motion {
+        core::mem::take(&mut self.motion)
+    }
+
+    /// Hands out every queued change, oldest first
+    pub fn flush(&mut self, on_change: impl FnMut(TimedChange)) {
+        self.queue.flush(on_change);
+    }
+}
+
+/// Maps a change of a half with `COLS` columns into the global matrix
+fn global<const COLS: usize>(layout: &HalfLayout, change: KeyChange, at_us: u64) -> TimedChange {
+    let col = if layout.mirrored {
+        COLS as u8 - 1 - change.pos.col
+    } else {
+        change.pos.col
+    };
+    TimedChange {
+        change: KeyChange {
+            pos: KeyPos {
+                row: layout.row_offset + change.pos.row,
+                col: layout.col_offset + col,
+            },
+            pressed: change.pressed,
+        },
+        at_us,
+    }
+}
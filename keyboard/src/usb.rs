@@ -0,0 +1,523 @@
+//! The dongle's composite usb device.
+//!
+//! [`build`] puts the boot keyboard, NKRO keyboard and mouse and consumer control interfaces from
+//! [`crate::hid`] together with a CDC ACM serial port for telemetry and config, and optionally a
+//! second one for logs. With the log port that's seven IN endpoints, all the nrf52840 has next to
+//! the control one, which is why mouse and consumer control share an interface.
+//!
+//! It works on any embassy-usb driver, so the descriptors can be built and checked against a mock
+//! driver on the host, where running out of interfaces, handlers or descriptor space panics the
+//! same as it would on the device.
+
+use embassy_usb::{
+    class::{
+        cdc_acm::{self, CdcAcmClass},
+        hid::{self, HidReaderWriter, HidWriter, RequestHandler},
+    },
+    driver::Driver,
+    Builder, Config, UsbDevice,
+};
+
+use crate::hid::{
+    BOOT_DESCRIPTOR, BOOT_REPORT_LEN, CONSUMER_REPORT_LEN, MOUSE_CONSUMER_DESCRIPTOR,
+    MOUSE_REPORT_LEN, NKRO_DESCRIPTOR, NKRO_REPORT_LEN,
+};
+
+/// pid.codes test ids, until the project has ids of its own
+pub const VID: u16 = 0x1209;
+pub const PID: u16 = 0x0001;
+
+/// Interfaces [`build`] adds at most, each CDC ACM port takes two
+pub const MAX_INTERFACES: usize = 7;
+/// Control request handlers [`build`] registers at most, one per class
+pub const MAX_HANDLERS: usize = 5;
+
+const SERIAL_PACKET_SIZE: u16 = 64;
+/// Room for either report of the mouse and consumer control interface
+const REPORT_LEN: usize = if MOUSE_REPORT_LEN > CONSUMER_REPORT_LEN {
+    MOUSE_REPORT_LEN
+} else {
+    CONSUMER_REPORT_LEN
+};
+
+/// Hex digits of a 64 bit device id
+pub struct SerialNumber([u8; 16]);
+
+impl SerialNumber {
+    /// From the two words of the nrf FICR DEVICEID, high word first in the string
+    pub fn from_device_id(device_id: [u32; 2]) -> Self {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+        let id = (device_id[1] as u64) << 32 | device_id[0] as u64;
+        let mut digits = [0; 16];
+        for (i, digit) in digits.iter_mut().enumerate() {
+            *digit = HEX[(id >> (60 - 4 * i)) as usize & 0xF];
+        }
+        Self(digits)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever holds ascii hex digits
+        core::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+/// Device descriptor fields, the serial number tells dongles apart on one host
+pub fn config<'d>(product: &'d str, serial: &'d SerialNumber) -> Config<'d> {
+    let mut config = Config::new(VID, PID);
+    config.manufacturer = Some("bruh78");
+    config.product = Some(product);
+    config.serial_number = Some(serial.as_str());
+    config.max_packet_size_0 = 64;
+    config
+}
+
+/// Descriptor buffers and class state, has to outlive the device
+pub struct State<'d> {
+    config_descriptor: [u8; 256],
+    bos_descriptor: [u8; 256],
+    msos_descriptor: [u8; 256],
+    control_buf: [u8; 64],
+    boot: hid::State<'d>,
+    nkro: hid::State<'d>,
+    mouse_consumer: hid::State<'d>,
+    serial: cdc_acm::State<'d>,
+    log: cdc_acm::State<'d>,
+}
+
+impl Default for State<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State<'_> {
+    pub const fn new() -> Self {
+        Self {
+            config_descriptor: [0; 256],
+            bos_descriptor: [0; 256],
+            msos_descriptor: [0; 256],
+            control_buf: [0; 64],
+            boot: hid::State::new(),
+            nkro: hid::State::new(),
+            mouse_consumer: hid::State::new(),
+            serial: cdc_acm::State::new(),
+            log: cdc_acm::State::new(),
+        }
+    }
+}
+
+pub struct Device<'d, D: Driver<'d>> {
+    pub usb: UsbDevice<'d, D>,
+    pub boot: HidReaderWriter<'d, D, 1, BOOT_REPORT_LEN>,
+    pub nkro: HidReaderWriter<'d, D, 1, NKRO_REPORT_LEN>,
+    pub mouse_consumer: HidWriter<'d, D, REPORT_LEN>,
+    /// Telemetry out, config in
+    pub serial: CdcAcmClass<'d, D>,
+    /// Only there if `log` was set
+    pub log: Option<CdcAcmClass<'d, D>>,
+}
+
+/// Builds the device. Both keyboard interfaces hand their led output reports to `leds`, one
+/// handler each.
+pub fn build<'d, D: Driver<'d>>(
+    driver: D,
+    config: Config<'d>,
+    state: &'d mut State<'d>,
+    leds: [&'d mut dyn RequestHandler; 2],
+    log: bool,
+) -> Device<'d, D> {
+    let [boot_leds, nkro_leds] = leds;
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut state.config_descriptor,
+        &mut state.bos_descriptor,
+        &mut state.msos_descriptor,
+        &mut state.control_buf,
+    );
+    let boot = HidReaderWriter::new(
+        &mut builder,
+        &mut state.boot,
+        hid::Config {
+            report_descriptor: BOOT_DESCRIPTOR,
+            request_handler: Some(boot_leds),
+            poll_ms: 1,
+            max_packet_size: BOOT_REPORT_LEN as u16,
+        },
+    );
+    let nkro = HidReaderWriter::new(
+        &mut builder,
+        &mut state.nkro,
+        hid::Config {
+            report_descriptor: NKRO_DESCRIPTOR,
+            request_handler: Some(nkro_leds),
+            poll_ms: 1,
+            max_packet_size: 32,
+        },
+    );
+    let mouse_consumer = HidWriter::new(
+        &mut builder,
+        &mut state.mouse_consumer,
+        hid::Config {
+            report_descriptor: MOUSE_CONSUMER_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 1,
+            max_packet_size: 16,
+        },
+    );
+    let serial = CdcAcmClass::new(&mut builder, &mut state.serial, SERIAL_PACKET_SIZE);
+    let log = log.then(|| CdcAcmClass::new(&mut builder, &mut state.log, SERIAL_PACKET_SIZE));
+    Device {
+        usb: builder.build(),
+        boot,
+        nkro,
+        mouse_consumer,
+        serial,
+        log,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::{
+        cell::RefCell,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{collections::VecDeque, rc::Rc, string::String, vec::Vec};
+
+    use embassy_usb::driver::{
+        Bus, ControlPipe, Direction, Endpoint, EndpointAddress, EndpointAllocError, EndpointError,
+        EndpointIn, EndpointInfo, EndpointOut, EndpointType, Event, Unsupported,
+    };
+
+    use super::*;
+
+    /// Setup packets the host sends and what came back for each
+    #[derive(Default)]
+    struct Host {
+        requests: VecDeque<[u8; 8]>,
+        responses: Vec<Vec<u8>>,
+    }
+
+    type Shared = Rc<RefCell<Host>>;
+
+    /// Driver with the endpoints of the nrf52840, answering requests queued in [`Host`]
+    struct MockDriver {
+        host: Shared,
+        outs: u8,
+        ins: u8,
+    }
+
+    struct MockEndpoint(EndpointInfo);
+    struct MockBus;
+    struct MockControl(Shared);
+
+    impl Endpoint for MockEndpoint {
+        fn info(&self) -> &EndpointInfo {
+            &self.0
+        }
+
+        async fn wait_enabled(&mut self) {}
+    }
+
+    impl EndpointOut for MockEndpoint {
+        async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EndpointError> {
+            Err(EndpointError::Disabled)
+        }
+    }
+
+    impl EndpointIn for MockEndpoint {
+        async fn write(&mut self, _buf: &[u8]) -> Result<(), EndpointError> {
+            Err(EndpointError::Disabled)
+        }
+    }
+
+    impl Bus for MockBus {
+        async fn enable(&mut self) {}
+
+        async fn disable(&mut self) {}
+
+        async fn poll(&mut self) -> Event {
+            core::future::pending().await
+        }
+
+        fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {}
+
+        fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+        fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+            false
+        }
+
+        async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+            Err(Unsupported)
+        }
+    }
+
+    impl ControlPipe for MockControl {
+        fn max_packet_size(&self) -> usize {
+            64
+        }
+
+        async fn setup(&mut self) -> [u8; 8] {
+            let host = self.0.clone();
+            core::future::poll_fn(move |_| match host.borrow_mut().requests.pop_front() {
+                Some(req) => Poll::Ready(req),
+                None => Poll::Pending,
+            })
+            .await
+        }
+
+        async fn data_out(
+            &mut self,
+            _buf: &mut [u8],
+            _first: bool,
+            _last: bool,
+        ) -> Result<usize, EndpointError> {
+            Err(EndpointError::Disabled)
+        }
+
+        async fn data_in(
+            &mut self,
+            data: &[u8],
+            first: bool,
+            _last: bool,
+        ) -> Result<(), EndpointError> {
+            let mut host = self.0.borrow_mut();
+            if first {
+                host.responses.push(Vec::new());
+            }
+            host.responses.last_mut().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        async fn accept(&mut self) {}
+
+        async fn reject(&mut self) {
+            self.0.borrow_mut().responses.push(Vec::new());
+        }
+
+        async fn accept_set_address(&mut self, _addr: u8) {}
+    }
+
+    impl<'d> Driver<'d> for MockDriver {
+        type EndpointOut = MockEndpoint;
+        type EndpointIn = MockEndpoint;
+        type ControlPipe = MockControl;
+        type Bus = MockBus;
+
+        fn alloc_endpoint_out(
+            &mut self,
+            ep_type: EndpointType,
+            _ep_addr: Option<EndpointAddress>,
+            max_packet_size: u16,
+            interval_ms: u8,
+        ) -> Result<MockEndpoint, EndpointAllocError> {
+            self.outs += 1;
+            endpoint(
+                self.outs,
+                Direction::Out,
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            )
+        }
+
+        fn alloc_endpoint_in(
+            &mut self,
+            ep_type: EndpointType,
+            _ep_addr: Option<EndpointAddress>,
+            max_packet_size: u16,
+            interval_ms: u8,
+        ) -> Result<MockEndpoint, EndpointAllocError> {
+            self.ins += 1;
+            endpoint(
+                self.ins,
+                Direction::In,
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            )
+        }
+
+        fn start(self, _control_max_packet_size: u16) -> (MockBus, MockControl) {
+            (MockBus, MockControl(self.host))
+        }
+    }
+
+    /// Endpoints 1 to 7 of either direction, the nrf52840 has no more next to the control one
+    fn endpoint(
+        index: u8,
+        direction: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<MockEndpoint, EndpointAllocError> {
+        if index > 7 || max_packet_size > 64 {
+            return Err(EndpointAllocError);
+        }
+        Ok(MockEndpoint(EndpointInfo {
+            addr: EndpointAddress::from_parts(index as usize, direction),
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        }))
+    }
+
+    struct Leds;
+
+    impl RequestHandler for Leds {}
+
+    const DEVICE: u8 = 1;
+    const CONFIGURATION: u8 = 2;
+    const STRING: u8 = 3;
+    const INTERFACE: u8 = 4;
+    const ENDPOINT: u8 = 5;
+    const HID: u8 = 0x21;
+    const REPORT: u8 = 0x22;
+
+    fn get_descriptor(recipient: u8, kind: u8, index: u8, w_index: u16, len: u16) -> [u8; 8] {
+        let [value_0, value_1] = u16::from_le_bytes([index, kind]).to_le_bytes();
+        let [index_0, index_1] = w_index.to_le_bytes();
+        let [len_0, len_1] = len.to_le_bytes();
+        [
+            0x80 | recipient,
+            6,
+            value_0,
+            value_1,
+            index_0,
+            index_1,
+            len_0,
+            len_1,
+        ]
+    }
+
+    /// Builds the device and runs it until it answered every request
+    fn request(log: bool, requests: &[[u8; 8]]) -> Vec<Vec<u8>> {
+        let host = Shared::default();
+        host.borrow_mut().requests.extend(requests);
+        let serial = SerialNumber::from_device_id([0x89AB_CDEF, 0x0123_4567]);
+        let mut state = State::new();
+        let (mut boot_leds, mut nkro_leds) = (Leds, Leds);
+        let driver = MockDriver {
+            host: host.clone(),
+            outs: 0,
+            ins: 0,
+        };
+        let mut device = build(
+            driver,
+            config("Keyboard dongle", &serial),
+            &mut state,
+            [&mut boot_leds, &mut nkro_leds],
+            log,
+        );
+        assert_eq!(device.log.is_some(), log);
+        let mut run = pin!(device.usb.run());
+        let mut cx = Context::from_waker(Waker::noop());
+        for _ in 0..requests.len() * 4 {
+            assert!(run.as_mut().poll(&mut cx).is_pending());
+        }
+        let responses = core::mem::take(&mut host.borrow_mut().responses);
+        assert_eq!(responses.len(), requests.len());
+        responses
+    }
+
+    /// Descriptors of a configuration descriptor, as type and bytes
+    fn descriptors(config: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut res = Vec::new();
+        let mut rest = config;
+        while let [len, kind, ..] = *rest {
+            let (descriptor, tail) = rest.split_at(len as usize);
+            res.push((kind, descriptor));
+            rest = tail;
+        }
+        res
+    }
+
+    #[test]
+    fn serial_number_is_the_device_id_in_hex() {
+        let serial = SerialNumber::from_device_id([0x89AB_CDEF, 0x0123_4567]);
+        assert_eq!(serial.as_str(), "0123456789ABCDEF");
+        assert_eq!(
+            SerialNumber::from_device_id([0, 0]).as_str(),
+            "0000000000000000"
+        );
+    }
+
+    #[test]
+    fn device_descriptor() {
+        let responses = request(false, &[get_descriptor(0, DEVICE, 0, 0, 18)]);
+        let device = &responses[0];
+        assert_eq!(device.len(), 18);
+        assert_eq!(device[7], 64);
+        assert_eq!(u16::from_le_bytes([device[8], device[9]]), VID);
+        assert_eq!(u16::from_le_bytes([device[10], device[11]]), PID);
+    }
+
+    #[test]
+    fn serial_number_string() {
+        let responses = request(false, &[get_descriptor(0, STRING, 3, 0x0409, 255)]);
+        let utf16: Vec<u16> = responses[0][2..]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&utf16).unwrap(), "0123456789ABCDEF");
+    }
+
+    #[test]
+    fn configuration_descriptor() {
+        for (log, interfaces) in [(false, 5), (true, MAX_INTERFACES)] {
+            let responses = request(log, &[get_descriptor(0, CONFIGURATION, 0, 0, 512)]);
+            let config = &responses[0];
+            assert_eq!(
+                u16::from_le_bytes([config[2], config[3]]) as usize,
+                config.len()
+            );
+            assert_eq!(config[4] as usize, interfaces);
+            let descriptors = descriptors(config);
+            let count = |kind| descriptors.iter().filter(|(k, _)| *k == kind).count();
+            assert_eq!(count(INTERFACE), interfaces);
+            let ins = descriptors
+                .iter()
+                .filter(|(k, d)| *k == ENDPOINT && d[2] & 0x80 != 0)
+                .count();
+            assert_eq!(ins, if log { 7 } else { 5 });
+
+            // embassy-usb's HID class leaves subclass and protocol at 0, so only the class is set
+            let classes: Vec<u8> = descriptors
+                .iter()
+                .filter(|(k, _)| *k == INTERFACE)
+                .map(|(_, d)| d[5])
+                .collect();
+            assert_eq!(classes[..3], [3, 3, 3]);
+            let report_lens: Vec<usize> = descriptors
+                .iter()
+                .filter(|(k, _)| *k == HID)
+                .map(|(_, d)| u16::from_le_bytes([d[7], d[8]]) as usize)
+                .collect();
+            assert_eq!(
+                report_lens,
+                [
+                    BOOT_DESCRIPTOR.len(),
+                    NKRO_DESCRIPTOR.len(),
+                    MOUSE_CONSUMER_DESCRIPTOR.len()
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn report_descriptors() {
+        let requests: Vec<_> = (0..3)
+            .map(|interface| get_descriptor(1, REPORT, 0, interface, 512))
+            .collect();
+        let responses = request(true, &requests);
+        assert_eq!(responses[0], BOOT_DESCRIPTOR);
+        assert_eq!(responses[1], NKRO_DESCRIPTOR);
+        assert_eq!(responses[2], MOUSE_CONSUMER_DESCRIPTOR);
+    }
+}
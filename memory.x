@@ -1,6 +1,7 @@
 MEMORY {
 
   /* These values correspond to the NRF52840 with Softdevices S140 7.3.0 */
-     FLASH : ORIGIN = 0x00026000, LENGTH = 868K
+  /* The last 8K before 0xFF000 are left out for the macros in src/macro_store.rs */
+     FLASH : ORIGIN = 0x00026000, LENGTH = 860K
      RAM : ORIGIN = 0x20020000, LENGTH = 128K
 }
@@ -0,0 +1,444 @@
+//! Stop and wait retransmission as used on the radio link.
+//!
+//! Every data frame carries a one byte id and is sent again until the receiver acks that id. The
+//! receiver acks every copy but only hands out the first one. Kept apart from the radio drivers
+//! so dropping a send or receive halfway can be exercised on the host against a simulated radio.
+
+use crate::control::MAX_PAYLOAD_LEN;
+
+/// Logical addresses a radio can listen on
+pub const NUM_PIPES: usize = 8;
+/// Bytes the length field of a frame covers besides the payload, the id and the type
+pub const META_LEN: u8 = 2;
+/// Length field of the largest frame, the radio's MAXLEN has to be at least this or it cuts the
+/// end of large payloads off
+pub const MAX_LEN_FIELD: u8 = MAX_PAYLOAD_LEN + META_LEN;
+
+/// Hands out the ids of data frames. An id is taken before the first attempt and never handed
+/// out twice in a row, so a frame that got through on a dropped send can't make the receiver
+/// discard the next one as its duplicate.
+#[derive(Clone, Copy, Debug)]
+pub struct Sender {
+    id: u8,
+}
+
+impl Sender {
+    pub const fn new() -> Self {
+        Self { id: 0 }
+    }
+
+    pub fn next_id(&mut self) -> u8 {
+        self.id = self.id.wrapping_add(1);
+        self.id
+    }
+}
+
+impl Default for Sender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receive side state of the link, shared by every call to [`receive`]
+#[derive(Clone, Copy, Debug)]
+pub struct Receiver<P> {
+    /// Id of the last data frame accepted on each pipe
+    last_ids: [u8; NUM_PIPES],
+    /// Frame that was accepted but not handed out yet
+    pending: Option<P>,
+}
+
+impl<P: Copy> Receiver<P> {
+    pub const fn new() -> Self {
+        Self {
+            last_ids: [0; NUM_PIPES],
+            pending: None,
+        }
+    }
+
+    /// Checks a data frame before it's acked. A new frame is kept as pending and `true` is
+    /// returned. A copy of the last frame on the pipe returns `false`, it still needs an ack since
+    /// the sender evidently missed the previous one.
+    pub fn accept(&mut self, pipe: u8, id: u8, packet: &P) -> bool {
+        let last = &mut self.last_ids[pipe as usize % NUM_PIPES];
+        if *last == id {
+            return false;
+        }
+        *last = id;
+        self.pending = Some(*packet);
+        true
+    }
+
+    /// Frame left behind by a receive that was dropped after accepting it
+    pub fn take_pending(&mut self) -> Option<P> {
+        self.pending.take()
+    }
+}
+
+impl<P: Copy> Default for Receiver<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The radio as seen by [`receive`]
+#[allow(async_fn_in_trait)]
+pub trait Phy {
+    type Packet: Copy;
+
+    /// Listens for the next frame and writes it into `packet`. Returns the pipe and id of an
+    /// intact data frame and `None` for anything else. Has to turn the radio off when dropped.
+    async fn receive_frame(&mut self, packet: &mut Self::Packet) -> Option<(u8, u8)>;
+
+    /// Acks the data frame `id` on `pipe`. Has to turn the radio off when dropped.
+    async fn transmit_ack(&mut self, pipe: u8, id: u8);
+
+    /// Called for every copy of a frame that was already accepted
+    fn duplicate(&mut self, _pipe: u8) {}
+}
+
+/// The radio as seen by [`send`]
+#[allow(async_fn_in_trait)]
+pub trait SendPhy {
+    type Packet;
+    /// What an attempt that got acked hands back, like the rssi of the ack
+    type Ack;
+
+    fn set_id(packet: &mut Self::Packet, id: u8);
+
+    /// Puts the data frame on the air. Has to turn the radio off when dropped.
+    async fn transmit_frame(&mut self, packet: &Self::Packet);
+
+    /// Listens until the ack for `id` arrives or the ack deadline passes, `None` means the frame
+    /// has to be sent again. Has to turn the radio off when dropped.
+    async fn await_ack(&mut self, id: u8) -> Option<Self::Ack>;
+}
+
+/// Sends `packet` until it's acked, returning the ack and how many retransmissions it took.
+///
+/// Cancel safe: the id is taken before the first attempt and written into `packet`. If the send is
+/// dropped the frame may or may not have reached the receiver, but as the next send takes a new id
+/// it's never discarded as a duplicate of this one.
+pub async fn send<P: SendPhy>(
+    tx: &mut Sender,
+    phy: &mut P,
+    packet: &mut P::Packet,
+) -> (P::Ack, u32) {
+    let id = tx.next_id();
+    P::set_id(packet, id);
+    let mut retranmisisons = 0;
+    loop {
+        phy.transmit_frame(packet).await;
+        if let Some(ack) = phy.await_ack(id).await {
+            return (ack, retranmisisons);
+        }
+        retranmisisons += 1;
+    }
+}
+
+/// Waits for the next data frame that isn't a duplicate and acks it.
+///
+/// Cancel safe: a frame is accepted before its ack goes out. If the receive is dropped before
+/// that, the sender never saw an ack and sends the frame again. If it's dropped while or after the
+/// ack goes out, the frame stays in `rx` and the next call returns it right away. `packet` may
+/// hold partial data after a drop.
+pub async fn receive<P: Phy>(rx: &mut Receiver<P::Packet>, phy: &mut P, packet: &mut P::Packet) {
+    if let Some(pending) = rx.take_pending() {
+        *packet = pending;
+        return;
+    }
+    loop {
+        let Some((pipe, id)) = phy.receive_frame(packet).await else {
+            continue;
+        };
+        let new = rx.accept(pipe, id, packet);
+        phy.transmit_ack(pipe, id).await;
+        if new {
+            rx.pending = None;
+            return;
+        }
+        phy.duplicate(pipe);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Returns `Pending` once before completing, like a radio event that isn't there yet
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: core::pin::Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Polls `fut` at most `polls` times, dropping it if it didn't finish by then
+    fn poll_at_most<F: Future>(fut: F, polls: usize) -> Option<F::Output> {
+        let mut fut = pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        for _ in 0..polls {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return Some(out);
+            }
+        }
+        None
+    }
+
+    /// Sender on the other end of the air. It repeats its current frame until the ack for it
+    /// goes out, every few frames the frame is corrupted.
+    struct SimPhy {
+        sender: Sender,
+        payloads: Vec<u8>,
+        /// Index of the payload on the air and the id it's sent with
+        current: Option<(usize, u8)>,
+        frames: u32,
+        duplicates: u32,
+    }
+
+    impl SimPhy {
+        fn new(payloads: Vec<u8>) -> Self {
+            Self {
+                sender: Sender::new(),
+                payloads,
+                current: None,
+                frames: 0,
+                duplicates: 0,
+            }
+        }
+
+        fn frame(&mut self) -> (usize, u8) {
+            let sender = &mut self.sender;
+            *self.current.get_or_insert_with(|| (0, sender.next_id()))
+        }
+    }
+
+    impl Phy for SimPhy {
+        type Packet = u8;
+
+        async fn receive_frame(&mut self, packet: &mut u8) -> Option<(u8, u8)> {
+            YieldOnce(false).await;
+            self.frames += 1;
+            let (index, id) = self.frame();
+            *packet = *self.payloads.get(index)?;
+            (!self.frames.is_multiple_of(5)).then_some((2, id))
+        }
+
+        async fn transmit_ack(&mut self, _pipe: u8, id: u8) {
+            YieldOnce(false).await;
+            // The ack is on the air from here on, dropping the future doesn't take it back
+            if let Some((index, current)) = self.current {
+                if current == id {
+                    self.current = Some((index + 1, self.sender.next_id()));
+                }
+            }
+            YieldOnce(false).await;
+        }
+
+        fn duplicate(&mut self, _pipe: u8) {
+            self.duplicates += 1;
+        }
+    }
+
+    /// Receives all payloads, dropping the first receive after `drop_after` polls
+    fn run(drop_after: usize) -> (Vec<u8>, SimPhy) {
+        let payloads: Vec<u8> = (10..20).collect();
+        let mut phy = SimPhy::new(payloads.clone());
+        let mut rx = Receiver::new();
+        let mut packet = 0;
+        let mut received = Vec::new();
+        if let Some(()) = poll_at_most(receive(&mut rx, &mut phy, &mut packet), drop_after) {
+            received.push(packet);
+        }
+        while received.len() < payloads.len() {
+            poll_at_most(receive(&mut rx, &mut phy, &mut packet), 1000).unwrap();
+            received.push(packet);
+        }
+        (received, phy)
+    }
+
+    #[test]
+    fn receives_everything_once_in_order() {
+        let (received, phy) = run(1000);
+        assert_eq!(received, (10..20).collect::<Vec<_>>());
+        assert_eq!(phy.duplicates, 0);
+    }
+
+    #[test]
+    fn drop_at_every_await_loses_nothing() {
+        for drop_after in 0..12 {
+            let (received, _) = run(drop_after);
+            assert_eq!(
+                received,
+                (10..20).collect::<Vec<_>>(),
+                "dropped after {drop_after} polls"
+            );
+        }
+    }
+
+    #[test]
+    fn drop_before_ack_gets_a_retransmission() {
+        // Listening finishes on the second poll, which then stops in the ack before it's on the
+        // air. The frame is accepted but the sender will repeat it.
+        let (received, phy) = run(2);
+        assert_eq!(received, (10..20).collect::<Vec<_>>());
+        assert_eq!(phy.duplicates, 1);
+    }
+
+    #[test]
+    fn duplicates_are_per_pipe() {
+        let mut rx = Receiver::new();
+        assert!(rx.accept(1, 7, &'a'));
+        assert!(!rx.accept(1, 7, &'a'));
+        assert!(rx.accept(2, 7, &'b'));
+        assert_eq!(rx.take_pending(), Some('b'));
+        assert_eq!(rx.take_pending(), None);
+    }
+
+    #[test]
+    fn dropped_send_does_not_reuse_its_id() {
+        // A send takes its id and is dropped after the receiver accepted the frame
+        let mut sender = Sender::new();
+        let mut rx = Receiver::new();
+        assert!(rx.accept(0, sender.next_id(), &1));
+        // The next send has to get through even though the ack never arrived
+        assert!(rx.accept(0, sender.next_id(), &2));
+    }
+
+    #[test]
+    fn ids_wrap() {
+        let mut sender = Sender::new();
+        let ids: Vec<u8> = (0..300).map(|_| sender.next_id()).collect();
+        assert!(ids.windows(2).all(|w| w[0] != w[1]));
+    }
+
+    /// Receiver on the other end of the air with its own [`Receiver`]. Every third data frame and
+    /// every fourth ack is lost.
+    struct SimPeer {
+        rx: Receiver<u8>,
+        delivered: Vec<u8>,
+        /// Id of the last ack that made it back
+        ack: Option<u8>,
+        frames: u32,
+        acks: u32,
+    }
+
+    impl SimPeer {
+        fn new() -> Self {
+            Self {
+                rx: Receiver::new(),
+                delivered: Vec::new(),
+                ack: None,
+                frames: 0,
+                acks: 0,
+            }
+        }
+    }
+
+    impl SendPhy for SimPeer {
+        /// Payload and id
+        type Packet = (u8, u8);
+        type Ack = ();
+
+        fn set_id(packet: &mut (u8, u8), id: u8) {
+            packet.1 = id;
+        }
+
+        async fn transmit_frame(&mut self, &(payload, id): &(u8, u8)) {
+            self.ack = None;
+            YieldOnce(false).await;
+            // On the air from here on, dropping the future doesn't take it back
+            self.frames += 1;
+            if self.frames.is_multiple_of(3) {
+                return;
+            }
+            if self.rx.accept(0, id, &payload) {
+                self.delivered.extend(self.rx.take_pending());
+            }
+            self.acks += 1;
+            if !self.acks.is_multiple_of(4) {
+                self.ack = Some(id);
+            }
+            YieldOnce(false).await;
+        }
+
+        async fn await_ack(&mut self, id: u8) -> Option<()> {
+            YieldOnce(false).await;
+            (self.ack == Some(id)).then_some(())
+        }
+    }
+
+    #[test]
+    fn sends_everything_once_in_order() {
+        let mut tx = Sender::new();
+        let mut peer = SimPeer::new();
+        let mut retranmisisons = 0;
+        for payload in 0..20 {
+            let mut packet = (payload, 0);
+            let ((), r) = poll_at_most(send(&mut tx, &mut peer, &mut packet), 1000).unwrap();
+            retranmisisons += r;
+        }
+        assert_eq!(peer.delivered, (0..20).collect::<Vec<_>>());
+        assert_eq!(retranmisisons, peer.frames - 20);
+    }
+
+    #[test]
+    fn dropped_send_at_every_await_never_blocks_the_next() {
+        for drop_after in 0..12 {
+            let mut tx = Sender::new();
+            let mut peer = SimPeer::new();
+            let mut expected = Vec::new();
+            for payload in 0..10u8 {
+                // Whatever part of the dropped send made it through is delivered at most once
+                let mut packet = (100 + payload, 0);
+                if poll_at_most(send(&mut tx, &mut peer, &mut packet), drop_after).is_some()
+                    || peer.delivered.last() == Some(&(100 + payload))
+                {
+                    expected.push(100 + payload);
+                }
+                let mut packet = (payload, 0);
+                poll_at_most(send(&mut tx, &mut peer, &mut packet), 1000).unwrap();
+                expected.push(payload);
+                assert_eq!(peer.delivered, expected, "dropped after {drop_after} polls");
+            }
+        }
+    }
+
+    /// What the radio stores of a received frame: the length field and at most `maxlen` bytes
+    /// after it, with the length field as sent
+    fn radio_receive(frame: &[u8], maxlen: u8) -> Vec<u8> {
+        let len = frame[0].min(maxlen) as usize;
+        frame[..1 + len].to_vec()
+    }
+
+    #[test]
+    fn largest_payload_fits_maxlen() {
+        let payload: Vec<u8> = (0..MAX_PAYLOAD_LEN).collect();
+        let mut frame = std::vec![MAX_PAYLOAD_LEN + META_LEN, 7, 0];
+        frame.extend_from_slice(&payload);
+        assert_eq!(frame[0], MAX_LEN_FIELD);
+
+        let received = radio_receive(&frame, MAX_LEN_FIELD);
+        assert_eq!(received[1 + META_LEN as usize..], payload);
+        // A MAXLEN of just the payload length loses the last bytes
+        let received = radio_receive(&frame, MAX_PAYLOAD_LEN);
+        assert_eq!(received.len(), frame.len() - META_LEN as usize);
+    }
+}
@@ -0,0 +1,130 @@
+//! Consistent overhead byte stuffing. Encoded data never contains a zero byte so a zero can be
+//! used to delimit frames in a byte stream.
+
+/// Worst case size of `len` bytes after encoding, not counting the delimiter
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Encodes `src` into `dst` and returns the encoded length, or `None` if `dst` is too small
+pub fn encode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut code_idx = 0;
+    let mut out = 1;
+    let mut code = 1u8;
+    if dst.is_empty() {
+        return None;
+    }
+    for &b in src {
+        if b == 0 {
+            dst[code_idx] = code;
+            code_idx = out;
+            *dst.get_mut(out)? = 0;
+            out += 1;
+            code = 1;
+        } else {
+            *dst.get_mut(out)? = b;
+            out += 1;
+            code += 1;
+            if code == 0xFF {
+                dst[code_idx] = code;
+                code_idx = out;
+                *dst.get_mut(out)? = 0;
+                out += 1;
+                code = 1;
+            }
+        }
+    }
+    dst[code_idx] = code;
+    Some(out)
+}
+
+/// Decodes `src` (without the delimiter) into `dst` and returns the decoded length, or `None` if
+/// the input is malformed or `dst` is too small
+pub fn decode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut i = 0;
+    let mut out = 0;
+    while i < src.len() {
+        let code = src[i] as usize;
+        if code == 0 || i + code > src.len() {
+            return None;
+        }
+        i += 1;
+        for _ in 1..code {
+            *dst.get_mut(out)? = src[i];
+            out += 1;
+            i += 1;
+        }
+        if code != 0xFF && i < src.len() {
+            *dst.get_mut(out)? = 0;
+            out += 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![0u8; max_encoded_len(data.len())];
+        let len = encode(data, &mut encoded).unwrap();
+        assert!(
+            !encoded[..len].contains(&0),
+            "zero in the encoding of {data:?}"
+        );
+        let mut decoded = vec![0u8; data.len()];
+        assert_eq!(decode(&encoded[..len], &mut decoded), Some(data.len()));
+        assert_eq!(decoded, data);
+        encoded.truncate(len);
+        encoded
+    }
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(round_trip(&[]), [1]);
+        assert_eq!(round_trip(&[0]), [1, 1]);
+        assert_eq!(round_trip(&[0, 0]), [1, 1, 1]);
+        assert_eq!(round_trip(&[0x11, 0x22, 0, 0x33]), [3, 0x11, 0x22, 2, 0x33]);
+        assert_eq!(
+            round_trip(&[0x11, 0x22, 0x33, 0x44]),
+            [5, 0x11, 0x22, 0x33, 0x44]
+        );
+        assert_eq!(round_trip(&[0x11, 0, 0, 0]), [2, 0x11, 1, 1, 1]);
+    }
+
+    #[test]
+    fn long_runs() {
+        for len in [253, 254, 255, 508, 600] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 255) as u8 + 1).collect();
+            let encoded = round_trip(&data);
+            assert!(encoded.len() <= max_encoded_len(len));
+            let mut zeros = data.clone();
+            zeros[len / 2] = 0;
+            round_trip(&zeros);
+        }
+    }
+
+    #[test]
+    fn every_byte_value() {
+        let data: Vec<u8> = (0..=255).chain((0..=255).rev()).collect();
+        round_trip(&data);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut buf = [0u8; 16];
+        // Block runs past the end
+        assert_eq!(decode(&[5, 1, 2], &mut buf), None);
+        // A delimiter inside the frame
+        assert_eq!(decode(&[2, 1, 0, 1], &mut buf), None);
+        // Output doesn't fit
+        assert_eq!(decode(&[5, 1, 2, 3, 4], &mut buf[..3]), None);
+        assert_eq!(encode(&[1, 2, 3], &mut buf[..3]), None);
+        assert_eq!(encode(&[1], &mut []), None);
+    }
+}
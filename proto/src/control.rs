@@ -0,0 +1,216 @@
+//! Commands the host sends to the firmware to drive experiments. Replies come back as
+//! [`Record`](crate::record::Record)s on the telemetry stream.
+
+use crate::{
+    frame::{DecodeError, Message},
+    plan::Plan,
+    wire::{Reader, Writer},
+};
+
+/// Which radio driver the firmware was built with
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Driver {
+    Polled = 0,
+    Trad = 1,
+}
+
+impl TryFrom<u8> for Driver {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Driver::Polled),
+            1 => Ok(Driver::Trad),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DataRate {
+    Nrf1Mbit = 0,
+    Nrf2Mbit = 1,
+    Ble1Mbit = 2,
+    Ble2Mbit = 3,
+}
+
+impl TryFrom<u8> for DataRate {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DataRate::Nrf1Mbit),
+            1 => Ok(DataRate::Nrf2Mbit),
+            2 => Ok(DataRate::Ble1Mbit),
+            3 => Ok(DataRate::Ble2Mbit),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+}
+
+/// Largest payload a packet carries
+pub const MAX_PAYLOAD_LEN: u8 = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RunParams {
+    pub payload_len: u8,
+    /// Delay between the end of one send and the start of the next
+    pub interval_us: u32,
+    /// Number of packets to send, 0 runs until stopped
+    pub count: u32,
+    pub driver: Driver,
+    pub data_rate: DataRate,
+    /// Frequency offset from 2400 MHz in MHz
+    pub channel: u8,
+    /// Fill payloads with [`pattern`](crate::pattern)s for the receiver to verify instead of
+    /// stamping them with their capture time
+    pub pattern: bool,
+}
+
+impl Default for RunParams {
+    fn default() -> Self {
+        Self {
+            payload_len: 3,
+            interval_us: 1_000_000,
+            count: 0,
+            driver: Driver::Polled,
+            data_rate: DataRate::Nrf1Mbit,
+            channel: 80,
+            pattern: false,
+        }
+    }
+}
+
+impl RunParams {
+    pub(crate) fn encode(&self, w: &mut Writer) -> Option<()> {
+        w.u8(self.payload_len)?;
+        w.u32(self.interval_us)?;
+        w.u32(self.count)?;
+        w.u8(self.driver as u8)?;
+        w.u8(self.data_rate as u8)?;
+        w.u8(self.channel)?;
+        w.u8(self.pattern as u8)
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let t = DecodeError::Truncated;
+        Ok(Self {
+            payload_len: r.u8().ok_or(t)?,
+            interval_us: r.u32().ok_or(t)?,
+            count: r.u32().ok_or(t)?,
+            driver: r.u8().ok_or(t)?.try_into()?,
+            data_rate: r.u8().ok_or(t)?.try_into()?,
+            channel: r.u8().ok_or(t)?,
+            pattern: r.u8().ok_or(t)? != 0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// Asks for a [`Record::Status`](crate::record::Record::Status)
+    Status,
+    Configure(RunParams),
+    Start,
+    Stop,
+    /// Runs every cell of the plan and reports a
+    /// [`Record::CellResult`](crate::record::Record::CellResult) for each at the end
+    RunPlan(Plan),
+    /// Like [`Command::Start`] but every packet is a ping the receiver echoes back, reported as
+    /// [`Record::Ping`](crate::record::Record::Ping)s
+    Ping,
+    /// Asks for a [`Record::Counters`](crate::record::Record::Counters) of every peer that saw
+    /// traffic, followed by the ack
+    Counters,
+    /// Sends packets of the configured payload length back to back for `duration_ms` and reports
+    /// a [`Record::StreamDone`](crate::record::Record::StreamDone). Individual sends aren't
+    /// reported so the telemetry doesn't slow the link down
+    Stream {
+        duration_ms: u32,
+    },
+}
+
+impl Command {
+    pub const STATUS: u8 = 0;
+    pub const CONFIGURE: u8 = 1;
+    pub const START: u8 = 2;
+    pub const STOP: u8 = 3;
+    pub const RUN_PLAN: u8 = 4;
+    pub const PING: u8 = 5;
+    pub const COUNTERS: u8 = 6;
+    pub const STREAM: u8 = 7;
+}
+
+impl Message for Command {
+    fn kind(&self) -> u8 {
+        match self {
+            Command::Status => Self::STATUS,
+            Command::Configure(_) => Self::CONFIGURE,
+            Command::Start => Self::START,
+            Command::Stop => Self::STOP,
+            Command::RunPlan(_) => Self::RUN_PLAN,
+            Command::Ping => Self::PING,
+            Command::Counters => Self::COUNTERS,
+            Command::Stream { .. } => Self::STREAM,
+        }
+    }
+
+    fn encode_body(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut w = Writer::new(buf);
+        match self {
+            Command::Configure(params) => params.encode(&mut w)?,
+            Command::RunPlan(plan) => plan.encode(&mut w)?,
+            Command::Stream { duration_ms } => w.u32(*duration_ms)?,
+            _ => {}
+        }
+        Some(w.len())
+    }
+
+    fn decode_body(kind: u8, body: &[u8]) -> Result<Self, DecodeError> {
+        match kind {
+            Self::STATUS => Ok(Command::Status),
+            Self::CONFIGURE => RunParams::decode(&mut Reader::new(body)).map(Command::Configure),
+            Self::START => Ok(Command::Start),
+            Self::STOP => Ok(Command::Stop),
+            Self::RUN_PLAN => Plan::decode(&mut Reader::new(body)).map(Command::RunPlan),
+            Self::PING => Ok(Command::Ping),
+            Self::COUNTERS => Ok(Command::Counters),
+            Self::STREAM => Reader::new(body)
+                .u32()
+                .map(|duration_ms| Command::Stream { duration_ms })
+                .ok_or(DecodeError::Truncated),
+            _ => Err(DecodeError::UnknownKind(kind)),
+        }
+    }
+}
+
+/// Why a command was rejected
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NackReason {
+    /// A run is in progress and only [`Command::Stop`] is accepted
+    Busy = 0,
+    /// The requested driver isn't the one the firmware was built with
+    WrongDriver = 1,
+    InvalidParams = 2,
+    NotRunning = 3,
+    /// The plan has more cells than the firmware can keep results for
+    PlanTooLarge = 4,
+}
+
+impl TryFrom<u8> for NackReason {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(NackReason::Busy),
+            1 => Ok(NackReason::WrongDriver),
+            2 => Ok(NackReason::InvalidParams),
+            3 => Ok(NackReason::NotRunning),
+            4 => Ok(NackReason::PlanTooLarge),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Framing for messages sent over a byte stream such as usb serial.
+//!
+//! A frame is `seq: u16 | kind: u8 | body | crc16` (little endian), cobs encoded and terminated
+//! by a zero byte. The sequence number lets the receiver detect dropped frames, the crc catches
+//! corruption cobs can't.
+
+use crate::{cobs, crc::crc16};
+
+/// Largest message body a frame can carry
+pub const MAX_BODY_SIZE: usize = 96;
+const HEADER_SIZE: usize = 3;
+const CRC_SIZE: usize = 2;
+const MAX_RAW_SIZE: usize = HEADER_SIZE + MAX_BODY_SIZE + CRC_SIZE;
+/// Largest encoded frame including the delimiter
+pub const MAX_FRAME_SIZE: usize = cobs::max_encoded_len(MAX_RAW_SIZE) + 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The frame wasn't valid cobs or was longer than [`MAX_FRAME_SIZE`]
+    Cobs,
+    Crc,
+    /// The frame or the message body ended early
+    Truncated,
+    UnknownKind(u8),
+    /// The body decoded but contained a value that isn't valid for its field
+    Invalid,
+}
+
+/// A message that can be carried in a frame
+pub trait Message: Sized {
+    fn kind(&self) -> u8;
+
+    /// Writes the body into `buf` and returns its length, or `None` if it doesn't fit
+    fn encode_body(&self, buf: &mut [u8]) -> Option<usize>;
+
+    fn decode_body(kind: u8, body: &[u8]) -> Result<Self, DecodeError>;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Frame<M> {
+    pub seq: u16,
+    pub message: M,
+}
+
+/// Encodes `message` into `out` including the trailing delimiter and returns the length
+pub fn encode<M: Message>(seq: u16, message: &M, out: &mut [u8]) -> Option<usize> {
+    let mut raw = [0u8; MAX_RAW_SIZE];
+    raw[..2].copy_from_slice(&seq.to_le_bytes());
+    raw[2] = message.kind();
+    let body_len = message.encode_body(&mut raw[HEADER_SIZE..][..MAX_BODY_SIZE])?;
+    let len = HEADER_SIZE + body_len;
+    let crc = crc16(&raw[..len]);
+    raw[len..][..CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+    let encoded = cobs::encode(&raw[..len + CRC_SIZE], out)?;
+    *out.get_mut(encoded)? = 0;
+    Some(encoded + 1)
+}
+
+/// Decodes a single frame, `encoded` must not include the delimiter
+pub fn decode<M: Message>(encoded: &[u8]) -> Result<Frame<M>, DecodeError> {
+    let mut raw = [0u8; MAX_RAW_SIZE];
+    let len = cobs::decode(encoded, &mut raw).ok_or(DecodeError::Cobs)?;
+    if len < HEADER_SIZE + CRC_SIZE {
+        return Err(DecodeError::Truncated);
+    }
+    let (data, crc) = raw[..len].split_at(len - CRC_SIZE);
+    if crc16(data) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(DecodeError::Crc);
+    }
+    Ok(Frame {
+        seq: u16::from_le_bytes([data[0], data[1]]),
+        message: M::decode_body(data[2], &data[HEADER_SIZE..])?,
+    })
+}
+
+/// Splits a byte stream into frames. Bytes are fed in one at a time as they arrive
+pub struct FrameDecoder {
+    buf: [u8; MAX_FRAME_SIZE],
+    len: usize,
+    overflow: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_SIZE],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Returns a decoded frame or an error once a delimiter is seen, `None` while a frame is
+    /// still being received. Empty frames between back to back delimiters are skipped.
+    pub fn push<M: Message>(&mut self, byte: u8) -> Option<Result<Frame<M>, DecodeError>> {
+        if byte != 0 {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return Some(Err(DecodeError::Cobs));
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(decode(&self.buf[..len]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::record::Record;
+
+    /// Captured from the usb serial port: an ack, a drop notice and the end of a plan
+    const CAPTURE: [u8; 30] = [
+        2, 7, 5, 4, 3, 74, 41, 0, //
+        2, 8, 2, 2, 1, 1, 1, 3, 224, 38, 0, //
+        2, 9, 3, 8, 2, 1, 1, 3, 71, 53, 0,
+    ];
+
+    fn decode_all(stream: &[u8]) -> Vec<Result<Frame<Record>, DecodeError>> {
+        let mut decoder = FrameDecoder::new();
+        stream.iter().filter_map(|&b| decoder.push(b)).collect()
+    }
+
+    fn frame(seq: u16, message: Record) -> Result<Frame<Record>, DecodeError> {
+        Ok(Frame { seq, message })
+    }
+
+    #[test]
+    fn decodes_capture() {
+        assert_eq!(
+            decode_all(&CAPTURE),
+            [
+                frame(7, Record::Ack(3)),
+                frame(8, Record::Dropped(0)),
+                frame(
+                    9,
+                    Record::PlanDone {
+                        cells: 2,
+                        skipped: 0
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_capture() {
+        let mut stream = Vec::new();
+        for (seq, message) in [
+            (7, Record::Ack(3)),
+            (8, Record::Dropped(0)),
+            (
+                9,
+                Record::PlanDone {
+                    cells: 2,
+                    skipped: 0,
+                },
+            ),
+        ] {
+            let mut buf = [0u8; MAX_FRAME_SIZE];
+            let len = encode(seq, &message, &mut buf).unwrap();
+            stream.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(stream, CAPTURE);
+    }
+
+    #[test]
+    fn resyncs_after_corruption() {
+        let mut stream = CAPTURE;
+        // Flip a bit in the second frame's crc
+        stream[16] ^= 0x10;
+        let frames = decode_all(&stream);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1], Err(DecodeError::Crc));
+        assert_eq!(
+            frames[2],
+            frame(
+                9,
+                Record::PlanDone {
+                    cells: 2,
+                    skipped: 0
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn joined_mid_frame() {
+        // The host opened the port halfway through the first frame
+        let frames = decode_all(&CAPTURE[4..]);
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].is_err());
+        assert_eq!(frames[1], frame(8, Record::Dropped(0)));
+    }
+
+    #[test]
+    fn truncated_and_empty_frames() {
+        assert_eq!(decode_all(&[0, 0, 0]), []);
+        assert_eq!(decode_all(&[1, 1, 0]), [Err(DecodeError::Truncated)]);
+        // Every prefix of a frame is rejected
+        for len in 1..7 {
+            let mut stream = CAPTURE[..len].to_vec();
+            stream.push(0);
+            assert!(decode_all(&stream)[0].is_err(), "prefix of {len} bytes");
+        }
+    }
+
+    #[test]
+    fn oversize_frame() {
+        let mut stream = [0x55u8; MAX_FRAME_SIZE + 10].to_vec();
+        stream.push(0);
+        stream.extend_from_slice(&CAPTURE[..8]);
+        let frames = decode_all(&stream);
+        assert_eq!(frames, [Err(DecodeError::Cobs), frame(7, Record::Ack(3))]);
+    }
+
+    #[test]
+    fn largest_body_fits() {
+        struct Raw([u8; MAX_BODY_SIZE]);
+
+        impl Message for Raw {
+            fn kind(&self) -> u8 {
+                0xAA
+            }
+
+            fn encode_body(&self, buf: &mut [u8]) -> Option<usize> {
+                buf.get_mut(..MAX_BODY_SIZE)?.copy_from_slice(&self.0);
+                Some(MAX_BODY_SIZE)
+            }
+
+            fn decode_body(kind: u8, body: &[u8]) -> Result<Self, DecodeError> {
+                assert_eq!(kind, 0xAA);
+                Ok(Raw(body.try_into().map_err(|_| DecodeError::Truncated)?))
+            }
+        }
+
+        let mut body = [0u8; MAX_BODY_SIZE];
+        for (i, b) in body.iter_mut().enumerate() {
+            *b = i as u8 % 3;
+        }
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode(0xFFFF, &Raw(body), &mut buf).unwrap();
+        assert!(len <= MAX_FRAME_SIZE);
+        let decoded: Frame<Raw> = decode(&buf[..len - 1]).unwrap();
+        assert_eq!(decoded.seq, 0xFFFF);
+        assert_eq!(decoded.message.0, body);
+    }
+}
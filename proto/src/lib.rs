@@ -0,0 +1,16 @@
+//! Wire formats shared between the firmware and the host tools. Kept free of any embassy or std
+//! dependency so it builds for both sides.
+#![no_std]
+
+pub mod arq;
+pub mod cobs;
+pub mod control;
+pub mod crc;
+pub mod frame;
+pub mod pattern;
+pub mod plan;
+pub mod record;
+pub mod stats;
+pub mod stream;
+pub mod sync;
+mod wire;
@@ -0,0 +1,222 @@
+//! Test patterns for checking payloads end to end.
+//!
+//! A pattern payload starts with a marker, the sequence number, the length the sender meant to
+//! send and the logical address it sent to. The rest is a pseudo random sequence seeded by the
+//! sequence number, so the receiver can regenerate it and catch corruption that got past the
+//! crc, frames that came out with the wrong length and frames delivered to the wrong address. A
+//! frame whose marker itself got corrupted isn't recognised as a pattern and goes uncounted.
+
+use crate::wire::{Reader, Writer};
+
+const MARKER: u8 = 0xD0;
+/// Marker, sequence number, length and address, a payload needs at least this much room
+pub const HEADER_SIZE: usize = 1 + 4 + 1 + 1;
+
+/// Why a pattern payload didn't check out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mismatch {
+    /// The frame is shorter or longer than the sender wrote it
+    WrongLength,
+    /// The frame arrived on a different logical address than it was sent to
+    Misdelivered,
+    /// The pseudo random part differs from what the sequence number generates
+    Corrupt,
+}
+
+/// xorshift32 seeded from the sequence number, never seeded with 0 since it would stay there
+fn fill(seq: u32, buf: &mut [u8]) {
+    let mut state = seq.wrapping_mul(0x9E37_79B9) ^ 0x5A5A_5A5A;
+    if state == 0 {
+        state = 1;
+    }
+    for b in buf {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        *b = state as u8;
+    }
+}
+
+/// Fills the whole of `payload` with the pattern for `seq` sent to logical address `peer`.
+/// Returns false and leaves it alone if it's shorter than [`HEADER_SIZE`] or longer than a
+/// length byte can describe.
+pub fn write(payload: &mut [u8], seq: u32, peer: u8) -> bool {
+    let Ok(len) = u8::try_from(payload.len()) else {
+        return false;
+    };
+    if payload.len() < HEADER_SIZE {
+        return false;
+    }
+    let (header, body) = payload.split_at_mut(HEADER_SIZE);
+    header[0] = MARKER;
+    header[1..5].copy_from_slice(&seq.to_le_bytes());
+    header[5] = len;
+    header[6] = peer;
+    fill(seq, body);
+    true
+}
+
+/// Checks a payload received on logical address `peer`. Returns `None` if it isn't a pattern
+/// payload, otherwise its sequence number or the first thing found wrong with it.
+pub fn verify(payload: &[u8], peer: u8) -> Option<Result<u32, Mismatch>> {
+    let mut r = Reader::new(payload);
+    if r.u8()? != MARKER {
+        return None;
+    }
+    let seq = r.u32()?;
+    let len = r.u8()?;
+    let sent_to = r.u8()?;
+    if len as usize != payload.len() {
+        return Some(Err(Mismatch::WrongLength));
+    }
+    if sent_to != peer {
+        return Some(Err(Mismatch::Misdelivered));
+    }
+    let body = &payload[HEADER_SIZE..];
+    // The length byte caps what a sender can write, anything longer got caught above
+    let mut expected = [0u8; u8::MAX as usize - HEADER_SIZE];
+    let Some(expected) = expected.get_mut(..body.len()) else {
+        return Some(Err(Mismatch::WrongLength));
+    };
+    fill(seq, expected);
+    if body != expected {
+        return Some(Err(Mismatch::Corrupt));
+    }
+    Some(Ok(seq))
+}
+
+/// Verification results for one logical address
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct PatternCounters {
+    /// Pattern payloads that checked out
+    pub verified: u32,
+    pub wrong_length: u32,
+    pub misdelivered: u32,
+    pub corrupt: u32,
+}
+
+impl PatternCounters {
+    pub const fn new() -> Self {
+        Self {
+            verified: 0,
+            wrong_length: 0,
+            misdelivered: 0,
+            corrupt: 0,
+        }
+    }
+
+    /// Counts the result of [`verify`], payloads that weren't patterns are ignored
+    pub fn record(&mut self, result: Option<Result<u32, Mismatch>>) {
+        match result {
+            None => {}
+            Some(Ok(_)) => self.verified += 1,
+            Some(Err(Mismatch::WrongLength)) => self.wrong_length += 1,
+            Some(Err(Mismatch::Misdelivered)) => self.misdelivered += 1,
+            Some(Err(Mismatch::Corrupt)) => self.corrupt += 1,
+        }
+    }
+
+    pub fn mismatches(&self) -> u32 {
+        self.wrong_length + self.misdelivered + self.corrupt
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub(crate) fn encode(&self, w: &mut Writer) -> Option<()> {
+        w.u32(self.verified)?;
+        w.u32(self.wrong_length)?;
+        w.u32(self.misdelivered)?;
+        w.u32(self.corrupt)
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            verified: r.u32()?,
+            wrong_length: r.u32()?,
+            misdelivered: r.u32()?,
+            corrupt: r.u32()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    fn pattern(len: usize, seq: u32, peer: u8) -> Vec<u8> {
+        let mut payload = vec![0u8; len];
+        assert!(write(&mut payload, seq, peer));
+        payload
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [HEADER_SIZE, HEADER_SIZE + 1, 32, 255] {
+            for seq in [0, 1, 0x1234_5678, u32::MAX] {
+                assert_eq!(verify(&pattern(len, seq, 2), 2), Some(Ok(seq)));
+            }
+        }
+    }
+
+    #[test]
+    fn write_rejects_bad_lengths() {
+        assert!(!write(&mut [0u8; HEADER_SIZE - 1], 0, 0));
+        assert!(!write(&mut [0u8; 256], 0, 0));
+    }
+
+    #[test]
+    fn not_a_pattern() {
+        assert_eq!(verify(&[], 0), None);
+        assert_eq!(verify(&[0x55; 32], 0), None);
+        // Marker but cut off in the header
+        assert_eq!(verify(&pattern(32, 9, 0)[..4], 0), None);
+    }
+
+    #[test]
+    fn mismatches() {
+        let payload = pattern(32, 7, 1);
+        assert_eq!(verify(&payload[..31], 1), Some(Err(Mismatch::WrongLength)));
+        assert_eq!(verify(&payload, 3), Some(Err(Mismatch::Misdelivered)));
+        for i in HEADER_SIZE..payload.len() {
+            let mut corrupt = payload.clone();
+            corrupt[i] ^= 0x04;
+            assert_eq!(verify(&corrupt, 1), Some(Err(Mismatch::Corrupt)));
+        }
+        // A corrupted sequence number regenerates a different body
+        let mut corrupt = payload.clone();
+        corrupt[2] ^= 0x80;
+        assert_eq!(verify(&corrupt, 1), Some(Err(Mismatch::Corrupt)));
+    }
+
+    #[test]
+    fn oversize_payloads() {
+        for len in [256, 255 + HEADER_SIZE, 255 + HEADER_SIZE + 1, 1000] {
+            let mut payload = vec![0u8; len];
+            payload[..32].copy_from_slice(&pattern(32, 1, 0));
+            // A length byte that happens to match the low byte of the real length
+            payload[5] = len as u8;
+            assert_eq!(verify(&payload, 0), Some(Err(Mismatch::WrongLength)));
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let mut c = PatternCounters::new();
+        assert!(c.is_empty());
+        c.record(None);
+        c.record(Some(Ok(1)));
+        c.record(Some(Err(Mismatch::Corrupt)));
+        c.record(Some(Err(Mismatch::WrongLength)));
+        assert_eq!(c.verified, 1);
+        assert_eq!(c.mismatches(), 2);
+        let mut buf = [0u8; 16];
+        c.encode(&mut Writer::new(&mut buf)).unwrap();
+        assert_eq!(PatternCounters::decode(&mut Reader::new(&buf)), Some(c));
+    }
+}
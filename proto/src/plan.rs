@@ -0,0 +1,526 @@
+//! Benchmark plans: a set of parameter sweeps whose cartesian product gives the cells to run.
+//!
+//! Cells are numbered so that the driver changes slowest and the burst length fastest. That
+//! keeps reconfiguring the radio to a minimum and groups cells that need a different firmware
+//! build together.
+
+use crate::{
+    control::{DataRate, Driver, MAX_PAYLOAD_LEN},
+    frame::DecodeError,
+    wire::{Reader, Writer},
+};
+
+/// Values from `start` to `end` inclusive. Each step adds `step`, or multiplies by it if
+/// `geometric` is set. A step that doesn't move the value yields just `start`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Sweep {
+    pub start: u32,
+    pub end: u32,
+    pub step: u32,
+    pub geometric: bool,
+}
+
+impl Sweep {
+    pub const fn single(value: u32) -> Self {
+        Self {
+            start: value,
+            end: value,
+            step: 0,
+            geometric: false,
+        }
+    }
+
+    pub const fn linear(start: u32, end: u32, step: u32) -> Self {
+        Self {
+            start,
+            end,
+            step,
+            geometric: false,
+        }
+    }
+
+    pub const fn geometric(start: u32, end: u32, factor: u32) -> Self {
+        Self {
+            start,
+            end,
+            step: factor,
+            geometric: true,
+        }
+    }
+
+    fn after(&self, value: u32) -> Option<u32> {
+        let next = if self.geometric {
+            value.checked_mul(self.step)?
+        } else {
+            value.checked_add(self.step)?
+        };
+        (next > value && next <= self.end).then_some(next)
+    }
+
+    pub fn iter(&self) -> SweepIter {
+        SweepIter {
+            sweep: *self,
+            next: (self.start <= self.end).then_some(self.start),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        let steps = if self.geometric {
+            // start * step^k <= end is the same as step^k <= end / start for whole numbers
+            match (self.start, self.step) {
+                (0, _) | (_, 0 | 1) => 0,
+                (start, step) => (self.end / start).ilog(step),
+            }
+        } else {
+            match self.step {
+                0 => 0,
+                step => (self.end - self.start) / step,
+            }
+        };
+        usize::try_from(steps as u64 + 1).unwrap_or(usize::MAX)
+    }
+
+    /// The value at `index`, same as `iter().nth(index)`
+    pub fn get(&self, index: usize) -> Option<u32> {
+        if index >= self.len() {
+            return None;
+        }
+        let index = u32::try_from(index).ok()?;
+        if self.geometric {
+            self.start.checked_mul(self.step.checked_pow(index)?)
+        } else {
+            self.start.checked_add(self.step.checked_mul(index)?)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start > self.end
+    }
+
+    pub(crate) fn encode(&self, w: &mut Writer) -> Option<()> {
+        w.u32(self.start)?;
+        w.u32(self.end)?;
+        w.u32(self.step)?;
+        w.u8(self.geometric as u8)
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            start: r.u32()?,
+            end: r.u32()?,
+            step: r.u32()?,
+            geometric: r.u8()? != 0,
+        })
+    }
+}
+
+pub struct SweepIter {
+    sweep: Sweep,
+    next: Option<u32>,
+}
+
+impl Iterator for SweepIter {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let res = self.next?;
+        self.next = self.sweep.after(res);
+        Some(res)
+    }
+}
+
+/// One combination of parameters from a [`Plan`]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cell {
+    pub index: u16,
+    pub driver: Driver,
+    pub data_rate: DataRate,
+    pub payload_len: u8,
+    pub interval_us: u32,
+    /// Packets sent back to back before waiting `interval_us`
+    pub burst_len: u16,
+}
+
+impl Cell {
+    pub(crate) fn encode(&self, w: &mut Writer) -> Option<()> {
+        w.u16(self.index)?;
+        w.u8(self.driver as u8)?;
+        w.u8(self.data_rate as u8)?;
+        w.u8(self.payload_len)?;
+        w.u32(self.interval_us)?;
+        w.u16(self.burst_len)
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let t = DecodeError::Truncated;
+        Ok(Self {
+            index: r.u16().ok_or(t)?,
+            driver: r.u8().ok_or(t)?.try_into()?,
+            data_rate: r.u8().ok_or(t)?.try_into()?,
+            payload_len: r.u8().ok_or(t)?,
+            interval_us: r.u32().ok_or(t)?,
+            burst_len: r.u16().ok_or(t)?,
+        })
+    }
+}
+
+/// Aggregated results of one cell. Latencies are in us
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CellSummary {
+    pub cell: Cell,
+    pub count: u32,
+    pub lost: u32,
+    pub min_us: u32,
+    pub mean_us: u32,
+    pub p50_us: u32,
+    pub p90_us: u32,
+    pub p99_us: u32,
+    pub p999_us: u32,
+    pub max_us: u32,
+    pub jitter_us: u32,
+    /// Mean retransmissions per packet times 1000
+    pub retranmisisons_milli: u32,
+}
+
+impl CellSummary {
+    pub(crate) fn encode(&self, w: &mut Writer) -> Option<()> {
+        self.cell.encode(w)?;
+        for v in [
+            self.count,
+            self.lost,
+            self.min_us,
+            self.mean_us,
+            self.p50_us,
+            self.p90_us,
+            self.p99_us,
+            self.p999_us,
+            self.max_us,
+            self.jitter_us,
+            self.retranmisisons_milli,
+        ] {
+            w.u32(v)?;
+        }
+        Some(())
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let cell = Cell::decode(r)?;
+        let mut v = [0u32; 11];
+        for v in v.iter_mut() {
+            *v = r.u32().ok_or(DecodeError::Truncated)?;
+        }
+        Ok(Self {
+            cell,
+            count: v[0],
+            lost: v[1],
+            min_us: v[2],
+            mean_us: v[3],
+            p50_us: v[4],
+            p90_us: v[5],
+            p99_us: v[6],
+            p999_us: v[7],
+            max_us: v[8],
+            jitter_us: v[9],
+            retranmisisons_milli: v[10],
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Plan {
+    /// Bitmask of [`Driver`]s, bit n set for the driver with discriminant n
+    pub drivers: u8,
+    /// Bitmask of [`DataRate`]s, bit n set for the rate with discriminant n
+    pub data_rates: u8,
+    pub payload_len: Sweep,
+    pub interval_us: Sweep,
+    pub burst_len: Sweep,
+    /// How many bursts are sent for every cell
+    pub repeats: u32,
+}
+
+const DRIVERS: [Driver; 2] = [Driver::Polled, Driver::Trad];
+const DATA_RATES: [DataRate; 4] = [
+    DataRate::Nrf1Mbit,
+    DataRate::Nrf2Mbit,
+    DataRate::Ble1Mbit,
+    DataRate::Ble2Mbit,
+];
+
+fn nth_set<T: Copy>(mask: u8, values: &[T], n: usize) -> Option<T> {
+    values
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| mask & (1 << i) != 0)
+        .map(|(_, v)| *v)
+        .nth(n)
+}
+
+fn count_set(mask: u8, len: usize) -> usize {
+    (mask & ((1u16 << len) - 1) as u8).count_ones() as usize
+}
+
+impl Plan {
+    pub const ENCODED_SIZE: usize = 2 + 3 * 13 + 4;
+    /// Most cells a plan can have, their indices and counts are sent as u16
+    pub const MAX_LEN: usize = u16::MAX as usize;
+
+    /// Every payload size in steps of 8 with bursts of 1, 2 and 4 on the nrf data rates
+    pub const fn default_sweep(driver: Driver) -> Self {
+        Self {
+            drivers: 1 << driver as u8,
+            data_rates: (1 << DataRate::Nrf1Mbit as u8) | (1 << DataRate::Nrf2Mbit as u8),
+            payload_len: Sweep::linear(0, MAX_PAYLOAD_LEN as u32, 8),
+            interval_us: Sweep::single(1000),
+            burst_len: Sweep::geometric(1, 4, 2),
+            repeats: 100,
+        }
+    }
+
+    /// Number of cells in the plan, `usize::MAX` if that doesn't fit
+    pub fn len(&self) -> usize {
+        self.checked_len().unwrap_or(usize::MAX)
+    }
+
+    fn checked_len(&self) -> Option<usize> {
+        count_set(self.drivers, DRIVERS.len())
+            .checked_mul(count_set(self.data_rates, DATA_RATES.len()))?
+            .checked_mul(self.payload_len.len())?
+            .checked_mul(self.interval_us.len())?
+            .checked_mul(self.burst_len.len())
+    }
+
+    /// Number of cells that run on `driver`, the rest need a different firmware build
+    pub fn cells_for(&self, driver: Driver) -> usize {
+        if self.drivers & (1 << driver as u8) == 0 {
+            return 0;
+        }
+        self.len() / count_set(self.drivers, DRIVERS.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cell with the given index, `None` past the end of the plan
+    pub fn cell(&self, index: usize) -> Option<Cell> {
+        let mut rest = index;
+        let mut digit = |len: usize| {
+            let d = rest % len.max(1);
+            rest /= len.max(1);
+            d
+        };
+        let burst = digit(self.burst_len.len());
+        let interval = digit(self.interval_us.len());
+        let payload = digit(self.payload_len.len());
+        let rate = digit(count_set(self.data_rates, DATA_RATES.len()));
+        let driver = digit(count_set(self.drivers, DRIVERS.len()));
+        if index >= self.len() {
+            return None;
+        }
+        Some(Cell {
+            index: index as u16,
+            driver: nth_set(self.drivers, &DRIVERS, driver)?,
+            data_rate: nth_set(self.data_rates, &DATA_RATES, rate)?,
+            payload_len: self.payload_len.get(payload)?.min(u8::MAX as u32) as u8,
+            interval_us: self.interval_us.get(interval)?,
+            burst_len: self.burst_len.get(burst)?.min(u16::MAX as u32) as u16,
+        })
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = Cell> + '_ {
+        (0..self.len()).filter_map(|i| self.cell(i))
+    }
+
+    pub(crate) fn encode(&self, w: &mut Writer) -> Option<()> {
+        w.u8(self.drivers)?;
+        w.u8(self.data_rates)?;
+        self.payload_len.encode(w)?;
+        self.interval_us.encode(w)?;
+        self.burst_len.encode(w)?;
+        w.u32(self.repeats)
+    }
+
+    /// Rejects plans with more than [`Self::MAX_LEN`] cells or payloads that don't fit a packet
+    pub(crate) fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let res = (|| {
+            Some(Self {
+                drivers: r.u8()?,
+                data_rates: r.u8()?,
+                payload_len: Sweep::decode(r)?,
+                interval_us: Sweep::decode(r)?,
+                burst_len: Sweep::decode(r)?,
+                repeats: r.u32()?,
+            })
+        })();
+        let plan = res.ok_or(DecodeError::Truncated)?;
+        let payloads = plan.payload_len.len();
+        let too_long = payloads > 0
+            && plan
+                .payload_len
+                .get(payloads - 1)
+                .is_none_or(|len| len > MAX_PAYLOAD_LEN as u32);
+        if too_long || plan.checked_len().is_none_or(|len| len > Self::MAX_LEN) {
+            return Err(DecodeError::Invalid);
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{collections::HashSet, vec::Vec};
+
+    use super::*;
+
+    fn round_trip(plan: &Plan) -> Result<Plan, DecodeError> {
+        let mut buf = [0u8; Plan::ENCODED_SIZE];
+        let mut w = Writer::new(&mut buf);
+        plan.encode(&mut w).unwrap();
+        assert_eq!(w.len(), Plan::ENCODED_SIZE);
+        Plan::decode(&mut Reader::new(&buf))
+    }
+
+    #[test]
+    fn sweep_len_matches_iter() {
+        for start in [0, 1, 2, 3, 7, 100] {
+            for end in [0, 1, 5, 64, 1000, 1024] {
+                for step in [0, 1, 2, 3, 10] {
+                    for sweep in [
+                        Sweep::linear(start, end, step),
+                        Sweep::geometric(start, end, step),
+                    ] {
+                        let values: Vec<u32> = sweep.iter().collect();
+                        assert_eq!(sweep.len(), values.len(), "{sweep:?}");
+                        for (i, &v) in values.iter().enumerate() {
+                            assert_eq!(sweep.get(i), Some(v), "{sweep:?}");
+                        }
+                        assert_eq!(sweep.get(values.len()), None);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sweep_near_the_top() {
+        assert_eq!(Sweep::linear(u32::MAX - 5, u32::MAX, 2).len(), 3);
+        assert_eq!(Sweep::geometric(1, u32::MAX, 2).len(), 32);
+        assert_eq!(Sweep::geometric(1, u32::MAX, 2).get(31), Some(1 << 31));
+        // Would take ages to iterate
+        let huge = Sweep::linear(0, u32::MAX, 1);
+        assert_eq!(huge.len() as u64, u32::MAX as u64 + 1);
+        assert_eq!(huge.get(1_000_000), Some(1_000_000));
+    }
+
+    #[test]
+    fn default_sweep() {
+        let plan = Plan::default_sweep(Driver::Polled);
+        // 2 rates, payloads 0 to 32 in steps of 8, bursts 1, 2 and 4
+        assert_eq!(plan.len(), 2 * 5 * 3);
+        assert_eq!(plan.cells_for(Driver::Polled), plan.len());
+        assert_eq!(plan.cells_for(Driver::Trad), 0);
+        assert_eq!(round_trip(&plan), Ok(plan));
+        // The largest step is the largest payload a frame carries
+        let largest = (0..plan.payload_len.len())
+            .filter_map(|i| plan.payload_len.get(i))
+            .max();
+        assert_eq!(largest, Some(MAX_PAYLOAD_LEN as u32));
+    }
+
+    #[test]
+    fn iteration_order() {
+        let plan = Plan {
+            drivers: 0b11,
+            data_rates: 0b1001,
+            payload_len: Sweep::linear(4, 8, 4),
+            interval_us: Sweep::single(500),
+            burst_len: Sweep::geometric(1, 4, 2),
+            repeats: 1,
+        };
+        let cells: Vec<Cell> = plan.cells().collect();
+        assert_eq!(cells.len(), plan.len());
+        assert_eq!(cells.len(), 2 * 2 * 2 * 3);
+        assert_eq!(plan.cells_for(Driver::Trad), cells.len() / 2);
+        assert!(cells.iter().enumerate().all(|(i, c)| c.index as usize == i));
+        // Burst length changes fastest and the driver slowest
+        assert_eq!(
+            cells[..3].iter().map(|c| c.burst_len).collect::<Vec<_>>(),
+            [1, 2, 4]
+        );
+        assert_eq!(cells[3].payload_len, 8);
+        assert_eq!(cells[6].data_rate, DataRate::Ble2Mbit);
+        assert!(cells[..12].iter().all(|c| c.driver == Driver::Polled));
+        assert!(cells[12..].iter().all(|c| c.driver == Driver::Trad));
+        let unique: HashSet<_> = cells
+            .iter()
+            .map(|c| {
+                (
+                    c.driver as u8,
+                    c.data_rate as u8,
+                    c.payload_len,
+                    c.burst_len,
+                )
+            })
+            .collect();
+        assert_eq!(unique.len(), cells.len());
+        assert_eq!(plan.cell(cells.len()), None);
+    }
+
+    #[test]
+    fn empty_plans() {
+        let mut plan = Plan::default_sweep(Driver::Trad);
+        plan.data_rates = 0;
+        assert!(plan.is_empty());
+        assert_eq!(plan.cells().count(), 0);
+        let mut plan = Plan::default_sweep(Driver::Trad);
+        plan.burst_len = Sweep::linear(5, 1, 1);
+        assert!(plan.is_empty());
+        assert_eq!(round_trip(&plan), Ok(plan));
+    }
+
+    #[test]
+    fn rejects_oversize_plans() {
+        let mut plan = Plan::default_sweep(Driver::Polled);
+        plan.interval_us = Sweep::linear(0, u32::MAX, 1);
+        assert!(plan.len() > Plan::MAX_LEN);
+        assert_eq!(round_trip(&plan), Err(DecodeError::Invalid));
+
+        let mut plan = Plan::default_sweep(Driver::Polled);
+        let intervals = Plan::MAX_LEN / plan.len();
+        plan.interval_us = Sweep::linear(0, intervals as u32, 1);
+        assert_eq!(round_trip(&plan), Err(DecodeError::Invalid));
+        plan.interval_us = Sweep::linear(0, intervals as u32 - 1, 1);
+        assert!(plan.len() <= Plan::MAX_LEN);
+        assert_eq!(round_trip(&plan), Ok(plan));
+    }
+
+    #[test]
+    fn rejects_oversize_payloads() {
+        let mut plan = Plan::default_sweep(Driver::Polled);
+        plan.payload_len = Sweep::linear(0, MAX_PAYLOAD_LEN as u32 + 1, 1);
+        assert_eq!(round_trip(&plan), Err(DecodeError::Invalid));
+        plan.payload_len = Sweep::geometric(1, 1000, 2);
+        assert_eq!(round_trip(&plan), Err(DecodeError::Invalid));
+        plan.payload_len = Sweep::linear(0, MAX_PAYLOAD_LEN as u32, 1);
+        assert_eq!(round_trip(&plan), Ok(plan));
+    }
+
+    #[test]
+    fn truncated() {
+        let plan = Plan::default_sweep(Driver::Polled);
+        let mut buf = [0u8; Plan::ENCODED_SIZE];
+        plan.encode(&mut Writer::new(&mut buf)).unwrap();
+        for len in 0..buf.len() {
+            assert_eq!(
+                Plan::decode(&mut Reader::new(&buf[..len])),
+                Err(DecodeError::Truncated)
+            );
+        }
+    }
+}
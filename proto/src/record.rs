@@ -0,0 +1,471 @@
+//! Telemetry records streamed from the firmware to the host
+
+use crate::{
+    control::{Driver, NackReason, RunParams},
+    frame::{DecodeError, Message},
+    pattern::PatternCounters,
+    plan::CellSummary,
+    stream::StreamSummary,
+    wire::{Reader, Writer},
+};
+
+/// Result of one acked send
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SendRecord {
+    /// Time the ack arrived in us since boot
+    pub timestamp_us: u64,
+    /// Logical address the packet was sent to
+    pub peer: u8,
+    pub channel: u8,
+    /// Rssi of the ack in dBm
+    pub rssi: i8,
+    pub retranmisisons: u16,
+    /// Last attempt only, from its transmission until the ack
+    pub time_elapsed_us: u32,
+    /// From the send call until the ack, including every retransmission
+    pub total_us: u32,
+    /// From the send call until the radio was ready to put the first attempt on air
+    pub first_transmit_us: u32,
+}
+
+/// A data packet that was received and acked
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ReceiveRecord {
+    /// Time the packet was handed to the application in us since boot
+    pub timestamp_us: u64,
+    /// Logical address the packet was received on
+    pub peer: u8,
+    pub channel: u8,
+    pub rssi: i8,
+    pub id: u8,
+    pub len: u8,
+}
+
+/// A ping whose pong came back in time
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PingRecord {
+    /// Time the pong arrived in us since boot
+    pub timestamp_us: u64,
+    pub seq: u32,
+    /// Application level round trip, from sending the ping until the pong was received
+    pub rtt_us: u32,
+    /// Send call to ack time of the ping itself
+    pub ack_us: u32,
+    /// Retransmissions needed to get the ping acked
+    pub retranmisisons: u16,
+    /// Rssi of the pong in dBm
+    pub rssi: i8,
+}
+
+/// Cumulative link counters for one logical address
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct PeerCounters {
+    /// Data frames transmitted, retransmissions included
+    pub frames_sent: u32,
+    /// Data frames received with a valid crc, duplicates included
+    pub frames_received: u32,
+    pub acks_sent: u32,
+    pub acks_received: u32,
+    /// Attempts whose ack deadline passed without the ack
+    pub ack_timeouts: u32,
+    /// Frames that matched the address but failed the crc check
+    pub crc_failures: u32,
+    /// Retransmitted frames that were acked again but not handed to the application
+    pub duplicates: u32,
+    /// Frames with a valid crc but a packet type the driver doesn't know
+    pub unknown_type: u32,
+    /// Bytes transmitted and received including preamble, address and crc
+    pub bytes_on_air: u64,
+    /// Time spent transmitting, waiting for acks and sending acks for this peer
+    pub radio_on_us: u64,
+}
+
+impl PeerCounters {
+    /// Fraction of data frames sent that weren't acked, `None` before anything was sent
+    pub fn packet_error_rate(&self) -> Option<f32> {
+        (self.frames_sent > 0).then(|| {
+            1.0 - self.acks_received.min(self.frames_sent) as f32 / self.frames_sent as f32
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn merge(&mut self, other: &PeerCounters) {
+        self.frames_sent += other.frames_sent;
+        self.frames_received += other.frames_received;
+        self.acks_sent += other.acks_sent;
+        self.acks_received += other.acks_received;
+        self.ack_timeouts += other.ack_timeouts;
+        self.crc_failures += other.crc_failures;
+        self.duplicates += other.duplicates;
+        self.unknown_type += other.unknown_type;
+        self.bytes_on_air += other.bytes_on_air;
+        self.radio_on_us += other.radio_on_us;
+    }
+
+    fn encode(&self, w: &mut Writer) -> Option<()> {
+        for v in [
+            self.frames_sent,
+            self.frames_received,
+            self.acks_sent,
+            self.acks_received,
+            self.ack_timeouts,
+            self.crc_failures,
+            self.duplicates,
+            self.unknown_type,
+        ] {
+            w.u32(v)?;
+        }
+        w.u64(self.bytes_on_air)?;
+        w.u64(self.radio_on_us)
+    }
+
+    fn decode(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            frames_sent: r.u32()?,
+            frames_received: r.u32()?,
+            acks_sent: r.u32()?,
+            acks_received: r.u32()?,
+            ack_timeouts: r.u32()?,
+            crc_failures: r.u32()?,
+            duplicates: r.u32()?,
+            unknown_type: r.u32()?,
+            bytes_on_air: r.u64()?,
+            radio_on_us: r.u64()?,
+        })
+    }
+}
+
+/// A data packet stamped with its capture time in the receiver's clock
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OneWayRecord {
+    /// Time the packet was received in us since boot
+    pub timestamp_us: u64,
+    pub peer: u8,
+    pub id: u8,
+    /// Capture to receive time, off by however far the sender's clock estimate is
+    pub latency_us: i32,
+}
+
+/// Clock estimate of the other side after a sync exchange
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SyncRecord {
+    /// Local time of the exchange in us since boot
+    pub timestamp_us: u64,
+    /// Remote minus local time
+    pub offset_us: i64,
+    /// How much faster the remote clock runs in parts per billion
+    pub drift_ppb: i32,
+    /// Round trip of the exchange without the time spent on the remote side
+    pub delay_us: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Status {
+    pub driver: Driver,
+    pub running: bool,
+    pub params: RunParams,
+}
+
+/// Everything the firmware sends to the host
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Record {
+    Send(SendRecord),
+    Receive(ReceiveRecord),
+    /// Records the firmware had to throw away because the host wasn't reading fast enough
+    Dropped(u32),
+    Status(Status),
+    /// The command with this kind was accepted
+    Ack(u8),
+    Nack {
+        command: u8,
+        reason: NackReason,
+    },
+    /// A run finished or was stopped
+    RunDone {
+        sent: u32,
+        retranmisisons: u32,
+    },
+    CellResult(CellSummary),
+    /// All results of a plan were reported. Cells for a driver other than the one the firmware
+    /// was built with are skipped
+    PlanDone {
+        cells: u16,
+        skipped: u16,
+    },
+    Ping(PingRecord),
+    OneWay(OneWayRecord),
+    Sync(SyncRecord),
+    /// Snapshot of the counters for one logical address
+    Counters {
+        timestamp_us: u64,
+        peer: u8,
+        counters: PeerCounters,
+    },
+    /// A stream run finished or was stopped
+    StreamDone(StreamSummary),
+    /// Cumulative payload verification results for one logical address on the receiving side
+    Pattern {
+        timestamp_us: u64,
+        peer: u8,
+        counters: PatternCounters,
+    },
+    /// Latest battery level one logical address reported
+    Battery {
+        timestamp_us: u64,
+        peer: u8,
+        millivolts: u16,
+        percent: u8,
+    },
+}
+
+impl Record {
+    const SEND: u8 = 0;
+    const RECEIVE: u8 = 1;
+    const DROPPED: u8 = 2;
+    const STATUS: u8 = 3;
+    const ACK: u8 = 4;
+    const NACK: u8 = 5;
+    const RUN_DONE: u8 = 6;
+    const CELL_RESULT: u8 = 7;
+    const PLAN_DONE: u8 = 8;
+    const PING: u8 = 9;
+    const ONE_WAY: u8 = 10;
+    const SYNC: u8 = 11;
+    const COUNTERS: u8 = 12;
+    const STREAM_DONE: u8 = 13;
+    const PATTERN: u8 = 14;
+    const BATTERY: u8 = 15;
+}
+
+impl Message for Record {
+    fn kind(&self) -> u8 {
+        match self {
+            Record::Send(_) => Self::SEND,
+            Record::Receive(_) => Self::RECEIVE,
+            Record::Dropped(_) => Self::DROPPED,
+            Record::Status(_) => Self::STATUS,
+            Record::Ack(_) => Self::ACK,
+            Record::Nack { .. } => Self::NACK,
+            Record::RunDone { .. } => Self::RUN_DONE,
+            Record::CellResult(_) => Self::CELL_RESULT,
+            Record::PlanDone { .. } => Self::PLAN_DONE,
+            Record::Ping(_) => Self::PING,
+            Record::OneWay(_) => Self::ONE_WAY,
+            Record::Sync(_) => Self::SYNC,
+            Record::Counters { .. } => Self::COUNTERS,
+            Record::StreamDone(_) => Self::STREAM_DONE,
+            Record::Pattern { .. } => Self::PATTERN,
+            Record::Battery { .. } => Self::BATTERY,
+        }
+    }
+
+    fn encode_body(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut w = Writer::new(buf);
+        match self {
+            Record::Send(r) => {
+                w.u64(r.timestamp_us)?;
+                w.u8(r.peer)?;
+                w.u8(r.channel)?;
+                w.i8(r.rssi)?;
+                w.u16(r.retranmisisons)?;
+                w.u32(r.time_elapsed_us)?;
+                w.u32(r.total_us)?;
+                w.u32(r.first_transmit_us)?;
+            }
+            Record::Receive(r) => {
+                w.u64(r.timestamp_us)?;
+                w.u8(r.peer)?;
+                w.u8(r.channel)?;
+                w.i8(r.rssi)?;
+                w.u8(r.id)?;
+                w.u8(r.len)?;
+            }
+            Record::Dropped(n) => w.u32(*n)?,
+            Record::Status(status) => {
+                w.u8(status.driver as u8)?;
+                w.u8(status.running as u8)?;
+                status.params.encode(&mut w)?;
+            }
+            Record::Ack(command) => w.u8(*command)?,
+            Record::Nack { command, reason } => {
+                w.u8(*command)?;
+                w.u8(*reason as u8)?;
+            }
+            Record::RunDone {
+                sent,
+                retranmisisons,
+            } => {
+                w.u32(*sent)?;
+                w.u32(*retranmisisons)?;
+            }
+            Record::CellResult(summary) => summary.encode(&mut w)?,
+            Record::PlanDone { cells, skipped } => {
+                w.u16(*cells)?;
+                w.u16(*skipped)?;
+            }
+            Record::Ping(r) => {
+                w.u64(r.timestamp_us)?;
+                w.u32(r.seq)?;
+                w.u32(r.rtt_us)?;
+                w.u32(r.ack_us)?;
+                w.u16(r.retranmisisons)?;
+                w.i8(r.rssi)?;
+            }
+            Record::OneWay(r) => {
+                w.u64(r.timestamp_us)?;
+                w.u8(r.peer)?;
+                w.u8(r.id)?;
+                w.i32(r.latency_us)?;
+            }
+            Record::Sync(r) => {
+                w.u64(r.timestamp_us)?;
+                w.i64(r.offset_us)?;
+                w.i32(r.drift_ppb)?;
+                w.u32(r.delay_us)?;
+            }
+            Record::Counters {
+                timestamp_us,
+                peer,
+                counters,
+            } => {
+                w.u64(*timestamp_us)?;
+                w.u8(*peer)?;
+                counters.encode(&mut w)?;
+            }
+            Record::StreamDone(summary) => summary.encode(&mut w)?,
+            Record::Pattern {
+                timestamp_us,
+                peer,
+                counters,
+            } => {
+                w.u64(*timestamp_us)?;
+                w.u8(*peer)?;
+                counters.encode(&mut w)?;
+            }
+            Record::Battery {
+                timestamp_us,
+                peer,
+                millivolts,
+                percent,
+            } => {
+                w.u64(*timestamp_us)?;
+                w.u8(*peer)?;
+                w.u16(*millivolts)?;
+                w.u8(*percent)?;
+            }
+        }
+        Some(w.len())
+    }
+
+    fn decode_body(kind: u8, body: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(body);
+        let res = match kind {
+            Self::SEND => (|| {
+                Some(Record::Send(SendRecord {
+                    timestamp_us: r.u64()?,
+                    peer: r.u8()?,
+                    channel: r.u8()?,
+                    rssi: r.i8()?,
+                    retranmisisons: r.u16()?,
+                    time_elapsed_us: r.u32()?,
+                    total_us: r.u32()?,
+                    first_transmit_us: r.u32()?,
+                }))
+            })(),
+            Self::RECEIVE => (|| {
+                Some(Record::Receive(ReceiveRecord {
+                    timestamp_us: r.u64()?,
+                    peer: r.u8()?,
+                    channel: r.u8()?,
+                    rssi: r.i8()?,
+                    id: r.u8()?,
+                    len: r.u8()?,
+                }))
+            })(),
+            Self::DROPPED => r.u32().map(Record::Dropped),
+            Self::STATUS => {
+                let t = DecodeError::Truncated;
+                return Ok(Record::Status(Status {
+                    driver: r.u8().ok_or(t)?.try_into()?,
+                    running: r.u8().ok_or(t)? != 0,
+                    params: RunParams::decode(&mut r)?,
+                }));
+            }
+            Self::ACK => r.u8().map(Record::Ack),
+            Self::NACK => {
+                let t = DecodeError::Truncated;
+                return Ok(Record::Nack {
+                    command: r.u8().ok_or(t)?,
+                    reason: r.u8().ok_or(t)?.try_into()?,
+                });
+            }
+            Self::RUN_DONE => (|| {
+                Some(Record::RunDone {
+                    sent: r.u32()?,
+                    retranmisisons: r.u32()?,
+                })
+            })(),
+            Self::CELL_RESULT => return CellSummary::decode(&mut r).map(Record::CellResult),
+            Self::PLAN_DONE => (|| {
+                Some(Record::PlanDone {
+                    cells: r.u16()?,
+                    skipped: r.u16()?,
+                })
+            })(),
+            Self::PING => (|| {
+                Some(Record::Ping(PingRecord {
+                    timestamp_us: r.u64()?,
+                    seq: r.u32()?,
+                    rtt_us: r.u32()?,
+                    ack_us: r.u32()?,
+                    retranmisisons: r.u16()?,
+                    rssi: r.i8()?,
+                }))
+            })(),
+            Self::ONE_WAY => (|| {
+                Some(Record::OneWay(OneWayRecord {
+                    timestamp_us: r.u64()?,
+                    peer: r.u8()?,
+                    id: r.u8()?,
+                    latency_us: r.i32()?,
+                }))
+            })(),
+            Self::SYNC => (|| {
+                Some(Record::Sync(SyncRecord {
+                    timestamp_us: r.u64()?,
+                    offset_us: r.i64()?,
+                    drift_ppb: r.i32()?,
+                    delay_us: r.u32()?,
+                }))
+            })(),
+            Self::COUNTERS => (|| {
+                Some(Record::Counters {
+                    timestamp_us: r.u64()?,
+                    peer: r.u8()?,
+                    counters: PeerCounters::decode(&mut r)?,
+                })
+            })(),
+            Self::STREAM_DONE => return StreamSummary::decode(&mut r).map(Record::StreamDone),
+            Self::PATTERN => (|| {
+                Some(Record::Pattern {
+                    timestamp_us: r.u64()?,
+                    peer: r.u8()?,
+                    counters: PatternCounters::decode(&mut r)?,
+                })
+            })(),
+            Self::BATTERY => (|| {
+                Some(Record::Battery {
+                    timestamp_us: r.u64()?,
+                    peer: r.u8()?,
+                    millivolts: r.u16()?,
+                    percent: r.u8()?,
+                })
+            })(),
+            _ => return Err(DecodeError::UnknownKind(kind)),
+        };
+        res.ok_or(DecodeError::Truncated)
+    }
+}
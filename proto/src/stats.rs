@@ -0,0 +1,408 @@
+//! Constant memory latency statistics.
+//!
+//! Latencies are kept in microseconds in a log scale histogram with 4 buckets per power of two,
+//! so percentile estimates are within ~20% of the real value no matter how many samples are
+//! recorded. Everything is plain integer math so snapshots from different runs or devices can be
+//! merged and shipped over the wire.
+
+use crate::wire::{Reader, Writer};
+
+/// Number of histogram buckets per power of two
+const SUB_BUCKETS: u32 = 4;
+const SUB_BITS: u32 = 2;
+/// Anything at or above 2^22 us (~4.2s) lands in the last bucket
+const MAX_OCTAVE: u32 = 21;
+pub const NUM_BUCKETS: usize = (SUB_BUCKETS * MAX_OCTAVE) as usize;
+/// Retransmission counts at or above this are counted in the last slot
+pub const MAX_RETRANMISISONS: usize = 15;
+
+const VERSION: u8 = 2;
+pub const SERIALIZED_SIZE: usize =
+    1 + 4 * 5 + 8 * 3 + 4 * NUM_BUCKETS + 4 * (MAX_RETRANMISISONS + 1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: u32,
+    pub p90: u32,
+    pub p99: u32,
+    pub p999: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyStats {
+    count: u32,
+    lost: u32,
+    min_us: u32,
+    max_us: u32,
+    sum_us: u64,
+    /// Sum of the absolute difference between consecutive latencies
+    jitter_sum_us: u64,
+    retranmisison_sum: u64,
+    last_us: u32,
+    buckets: [u32; NUM_BUCKETS],
+    retranmisisons: [u32; MAX_RETRANMISISONS + 1],
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyStats {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            lost: 0,
+            min_us: u32::MAX,
+            max_us: 0,
+            sum_us: 0,
+            jitter_sum_us: 0,
+            retranmisison_sum: 0,
+            last_us: 0,
+            buckets: [0; NUM_BUCKETS],
+            retranmisisons: [0; MAX_RETRANMISISONS + 1],
+        }
+    }
+
+    pub fn record_us(&mut self, latency_us: u32, retranmisisons: u32) {
+        if self.count > 0 {
+            self.jitter_sum_us += latency_us.abs_diff(self.last_us) as u64;
+        }
+        self.last_us = latency_us;
+        self.count += 1;
+        self.sum_us += latency_us as u64;
+        self.min_us = self.min_us.min(latency_us);
+        self.max_us = self.max_us.max(latency_us);
+        self.buckets[bucket_index(latency_us)] += 1;
+        self.retranmisison_sum += retranmisisons as u64;
+        self.retranmisisons[(retranmisisons as usize).min(MAX_RETRANMISISONS)] += 1;
+    }
+
+    /// A packet that was never acked
+    pub fn record_loss(&mut self) {
+        self.lost += 1;
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+
+    pub fn min_us(&self) -> Option<u32> {
+        (self.count > 0).then_some(self.min_us)
+    }
+
+    pub fn max_us(&self) -> Option<u32> {
+        (self.count > 0).then_some(self.max_us)
+    }
+
+    pub fn mean_us(&self) -> Option<u32> {
+        (self.count > 0).then(|| (self.sum_us / self.count as u64) as u32)
+    }
+
+    /// Mean absolute difference between consecutive latencies
+    pub fn jitter_us(&self) -> Option<u32> {
+        (self.count > 1).then(|| (self.jitter_sum_us / (self.count as u64 - 1)) as u32)
+    }
+
+    /// Retransmissions summed over every recorded packet
+    pub fn total_retranmisisons(&self) -> u64 {
+        self.retranmisison_sum
+    }
+
+    pub fn mean_retranmisisons(&self) -> Option<f32> {
+        (self.count > 0).then(|| self.retranmisison_sum as f32 / self.count as f32)
+    }
+
+    /// How many packets needed `i` retransmissions, the last entry counts
+    /// [`MAX_RETRANMISISONS`] or more
+    pub fn retranmisisons(&self) -> &[u32; MAX_RETRANMISISONS + 1] {
+        &self.retranmisisons
+    }
+
+    pub fn buckets(&self) -> &[u32; NUM_BUCKETS] {
+        &self.buckets
+    }
+
+    /// Fraction of packets that were lost out of everything attempted
+    pub fn loss_rate(&self) -> f32 {
+        let total = self.count + self.lost;
+        if total == 0 {
+            0.0
+        } else {
+            self.lost as f32 / total as f32
+        }
+    }
+
+    /// Estimates the latency below which `q` (0.0 to 1.0) of the samples fall by interpolating
+    /// inside the histogram bucket the rank lands in
+    pub fn percentile(&self, q: f32) -> Option<u32> {
+        if self.count == 0 {
+            return None;
+        }
+        let exact = q.clamp(0.0, 1.0) * self.count as f32;
+        let mut rank = exact as u32;
+        if (rank as f32) < exact {
+            rank += 1;
+        }
+        let rank = rank.clamp(1, self.count);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            if seen + n >= rank {
+                let lower = bucket_lower(i);
+                let width = bucket_lower(i + 1) - lower;
+                let offset = ((rank - seen) as u64 * width as u64 / n as u64) as u32;
+                return Some((lower + offset).clamp(self.min_us, self.max_us));
+            }
+            seen += n;
+        }
+        Some(self.max_us)
+    }
+
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        Some(Percentiles {
+            p50: self.percentile(0.5)?,
+            p90: self.percentile(0.9)?,
+            p99: self.percentile(0.99)?,
+            p999: self.percentile(0.999)?,
+        })
+    }
+
+    /// Combines the samples from `other` into `self`. The jitter between the last sample of `self`
+    /// and the first of `other` isn't known, it's estimated as the average jitter of both.
+    pub fn merge(&mut self, other: &LatencyStats) {
+        if other.count > 0 {
+            self.last_us = other.last_us;
+        }
+        if self.count > 0 && other.count > 0 {
+            // Count the unknown pair at the seam as the average of both runs so the jitter stays
+            // an average over count - 1 pairs
+            let pairs = (self.count - 1) as u64 + (other.count - 1) as u64;
+            if let Some(avg) = (self.jitter_sum_us + other.jitter_sum_us).checked_div(pairs) {
+                self.jitter_sum_us += avg;
+            }
+        }
+        self.count += other.count;
+        self.lost += other.lost;
+        self.min_us = self.min_us.min(other.min_us);
+        self.max_us = self.max_us.max(other.max_us);
+        self.sum_us += other.sum_us;
+        self.jitter_sum_us += other.jitter_sum_us;
+        self.retranmisison_sum += other.retranmisison_sum;
+        for (a, b) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *a += b;
+        }
+        for (a, b) in self
+            .retranmisisons
+            .iter_mut()
+            .zip(other.retranmisisons.iter())
+        {
+            *a += b;
+        }
+    }
+
+    /// Writes the stats as little endian into `buf`, returning the number of bytes used
+    pub fn serialize(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut w = Writer::new(buf);
+        w.u8(VERSION)?;
+        w.u32(self.count)?;
+        w.u32(self.lost)?;
+        w.u32(self.min_us)?;
+        w.u32(self.max_us)?;
+        w.u64(self.sum_us)?;
+        w.u64(self.jitter_sum_us)?;
+        w.u64(self.retranmisison_sum)?;
+        w.u32(self.last_us)?;
+        for &b in self.buckets.iter().chain(self.retranmisisons.iter()) {
+            w.u32(b)?;
+        }
+        Some(w.len())
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Option<Self> {
+        let mut r = Reader::new(buf);
+        if r.u8()? != VERSION {
+            return None;
+        }
+        let mut res = Self {
+            count: r.u32()?,
+            lost: r.u32()?,
+            min_us: r.u32()?,
+            max_us: r.u32()?,
+            sum_us: r.u64()?,
+            jitter_sum_us: r.u64()?,
+            retranmisison_sum: r.u64()?,
+            last_us: r.u32()?,
+            ..Self::new()
+        };
+        for b in res.buckets.iter_mut().chain(res.retranmisisons.iter_mut()) {
+            *b = r.u32()?;
+        }
+        Some(res)
+    }
+}
+
+/// Index of the histogram bucket `us` falls into
+pub fn bucket_index(us: u32) -> usize {
+    if us < SUB_BUCKETS {
+        return us as usize;
+    }
+    let octave = 31 - us.leading_zeros();
+    if octave > MAX_OCTAVE {
+        return NUM_BUCKETS - 1;
+    }
+    let sub = (us >> (octave - SUB_BITS)) & (SUB_BUCKETS - 1);
+    (SUB_BUCKETS * (octave - 1) + sub) as usize
+}
+
+/// Smallest latency in us that falls into bucket `i`
+pub fn bucket_lower(i: usize) -> u32 {
+    let i = i as u32;
+    if i < SUB_BUCKETS {
+        return i;
+    }
+    let octave = i / SUB_BUCKETS + 1;
+    let sub = i % SUB_BUCKETS;
+    (SUB_BUCKETS + sub) << (octave - SUB_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Deterministic spread of latencies between 100 us and ~13 ms
+    fn samples() -> impl Iterator<Item = u32> {
+        let mut x: u32 = 1;
+        (0..5000).map(move |_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            100 + (x >> 8) % 13_000
+        })
+    }
+
+    #[test]
+    fn buckets_cover_every_value() {
+        for us in (0..100_000).chain([u32::MAX / 2, u32::MAX]) {
+            let i = bucket_index(us);
+            assert!(bucket_lower(i) <= us, "{us} below bucket {i}");
+            if i < NUM_BUCKETS - 1 {
+                assert!(us < bucket_lower(i + 1), "{us} above bucket {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn percentiles_are_close() {
+        let mut stats = LatencyStats::new();
+        let mut sorted: Vec<u32> = samples().collect();
+        for &us in sorted.iter() {
+            stats.record_us(us, 0);
+        }
+        sorted.sort();
+        for q in [0.5, 0.9, 0.99, 0.999] {
+            let exact = sorted[((q * sorted.len() as f32) as usize).min(sorted.len() - 1)];
+            let estimate = stats.percentile(q).unwrap();
+            assert!(
+                estimate.abs_diff(exact) <= exact / 5,
+                "p{q}: {estimate} vs {exact}"
+            );
+        }
+        assert_eq!(stats.min_us(), sorted.first().copied());
+        assert_eq!(stats.max_us(), sorted.last().copied());
+    }
+
+    #[test]
+    fn empty() {
+        let stats = LatencyStats::new();
+        assert_eq!(stats.percentiles(), None);
+        assert_eq!(stats.mean_us(), None);
+        assert_eq!(stats.jitter_us(), None);
+        assert_eq!(stats.loss_rate(), 0.0);
+    }
+
+    #[test]
+    fn jitter_and_retranmisisons() {
+        let mut stats = LatencyStats::new();
+        for (us, r) in [(100, 0), (300, 2), (200, 1), (200, 40)] {
+            stats.record_us(us, r);
+        }
+        // |300 - 100| + |200 - 300| + 0 over 3 pairs
+        assert_eq!(stats.jitter_us(), Some(100));
+        assert_eq!(stats.mean_us(), Some(200));
+        assert_eq!(stats.total_retranmisisons(), 43);
+        assert_eq!(stats.retranmisisons()[MAX_RETRANMISISONS], 1);
+        stats.record_loss();
+        stats.record_loss();
+        assert_eq!(stats.loss_rate(), 2.0 / 6.0);
+    }
+
+    #[test]
+    fn merge_matches_recording_everything() {
+        let all: Vec<u32> = samples().collect();
+        let (a, b) = all.split_at(1234);
+        let mut whole = LatencyStats::new();
+        let mut left = LatencyStats::new();
+        let mut right = LatencyStats::new();
+        for &us in all.iter() {
+            whole.record_us(us, us % 3);
+        }
+        for &us in a {
+            left.record_us(us, us % 3);
+        }
+        for &us in b {
+            right.record_us(us, us % 3);
+        }
+        right.record_loss();
+        left.merge(&right);
+
+        assert_eq!(left.count(), whole.count());
+        assert_eq!(left.lost(), 1);
+        assert_eq!(left.buckets(), whole.buckets());
+        assert_eq!(left.retranmisisons(), whole.retranmisisons());
+        assert_eq!(left.percentiles(), whole.percentiles());
+        assert_eq!(left.min_us(), whole.min_us());
+        assert_eq!(left.max_us(), whole.max_us());
+        assert_eq!(left.mean_us(), whole.mean_us());
+        // Only the pair at the seam is estimated
+        let jitter = left.jitter_us().unwrap();
+        assert!(jitter.abs_diff(whole.jitter_us().unwrap()) <= 13_000 / all.len() as u32 + 1);
+    }
+
+    #[test]
+    fn merge_into_empty() {
+        let mut stats = LatencyStats::new();
+        let mut other = LatencyStats::new();
+        other.record_us(500, 1);
+        other.record_us(700, 0);
+        stats.merge(&other);
+        assert_eq!(stats, other);
+        stats.merge(&LatencyStats::new());
+        assert_eq!(stats, other);
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let mut stats = LatencyStats::new();
+        for us in samples().take(100) {
+            stats.record_us(us, us % 4);
+        }
+        stats.record_loss();
+        let mut buf = [0u8; SERIALIZED_SIZE];
+        assert_eq!(stats.serialize(&mut buf), Some(SERIALIZED_SIZE));
+        assert_eq!(LatencyStats::deserialize(&buf), Some(stats));
+        assert_eq!(stats.serialize(&mut buf[..SERIALIZED_SIZE - 1]), None);
+        assert_eq!(LatencyStats::deserialize(&buf[..SERIALIZED_SIZE - 1]), None);
+        buf[0] += 1;
+        assert_eq!(LatencyStats::deserialize(&buf), None);
+    }
+}
@@ -0,0 +1,97 @@
+//! Saturation runs: packets sent back to back for a fixed time, as fast as the ARQ lets them
+//! through, to see how the link holds up under a burst of traffic.
+
+use crate::{
+    control::{DataRate, Driver},
+    frame::DecodeError,
+    wire::{Reader, Writer},
+};
+
+/// Results of one stream run. Latencies are from the send call until the ack, in us
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StreamSummary {
+    pub driver: Driver,
+    pub data_rate: DataRate,
+    pub payload_len: u8,
+    /// How long the run went, shorter than asked for if it was stopped
+    pub duration_us: u32,
+    /// Packets that were acked
+    pub packets: u32,
+    /// Packets given up on because they weren't acked in time
+    pub lost: u32,
+    pub retranmisisons: u32,
+    pub mean_us: u32,
+    pub p50_us: u32,
+    pub p99_us: u32,
+    pub max_us: u32,
+    pub jitter_us: u32,
+}
+
+impl StreamSummary {
+    /// Acked payload in kbit/s, headers, retransmissions and acks not included
+    pub fn goodput_kbps(&self) -> f32 {
+        if self.duration_us == 0 {
+            return 0.0;
+        }
+        let bits = self.packets as f64 * self.payload_len as f64 * 8.0;
+        (bits * 1000.0 / self.duration_us as f64) as f32
+    }
+
+    pub fn packets_per_sec(&self) -> f32 {
+        if self.duration_us == 0 {
+            return 0.0;
+        }
+        (self.packets as f64 * 1e6 / self.duration_us as f64) as f32
+    }
+
+    /// Fraction of transmissions that were retries, `None` if nothing was sent
+    pub fn retry_rate(&self) -> Option<f32> {
+        let transmissions = self.packets + self.retranmisisons;
+        (transmissions > 0).then(|| self.retranmisisons as f32 / transmissions as f32)
+    }
+
+    pub(crate) fn encode(&self, w: &mut Writer) -> Option<()> {
+        w.u8(self.driver as u8)?;
+        w.u8(self.data_rate as u8)?;
+        w.u8(self.payload_len)?;
+        for v in [
+            self.duration_us,
+            self.packets,
+            self.lost,
+            self.retranmisisons,
+            self.mean_us,
+            self.p50_us,
+            self.p99_us,
+            self.max_us,
+            self.jitter_us,
+        ] {
+            w.u32(v)?;
+        }
+        Some(())
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let t = DecodeError::Truncated;
+        let driver = r.u8().ok_or(t)?.try_into()?;
+        let data_rate = r.u8().ok_or(t)?.try_into()?;
+        let payload_len = r.u8().ok_or(t)?;
+        let mut v = [0u32; 9];
+        for v in v.iter_mut() {
+            *v = r.u32().ok_or(t)?;
+        }
+        Ok(Self {
+            driver,
+            data_rate,
+            payload_len,
+            duration_us: v[0],
+            packets: v[1],
+            lost: v[2],
+            retranmisisons: v[3],
+            mean_us: v[4],
+            p50_us: v[5],
+            p99_us: v[6],
+            max_us: v[7],
+            jitter_us: v[8],
+        })
+    }
+}
@@ -0,0 +1,309 @@
+//! Estimates the offset and drift of a remote clock from NTP style timestamp exchanges.
+//!
+//! Every exchange gives one offset sample with a known round trip delay. Samples whose delay is
+//! far above the smallest one in the window went through extra queueing on one side and are left
+//! out, the rest are fitted with a least squares line like FTSP does, so the slope is the drift
+//! and the line gives the offset at any local time. All times are in us.
+
+/// Samples kept for the fit, older ones are replaced
+pub const WINDOW: usize = 16;
+/// Samples whose delay is more than this above the smallest one in the window are ignored
+const MAX_EXTRA_DELAY_US: u64 = 200;
+
+/// Timestamps of one request/response exchange
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Exchange {
+    /// Local time the request was sent
+    pub t1: u64,
+    /// Remote time the request was received
+    pub t2: u64,
+    /// Remote time the response was sent
+    pub t3: u64,
+    /// Local time the response was received
+    pub t4: u64,
+}
+
+impl Exchange {
+    /// Remote minus local time, assuming both directions took equally long
+    pub fn offset(&self) -> i64 {
+        ((self.t2 as i64 - self.t1 as i64) + (self.t3 as i64 - self.t4 as i64)) / 2
+    }
+
+    /// Round trip time without the time spent on the remote side
+    pub fn delay(&self) -> u64 {
+        (self.t4.saturating_sub(self.t1)).saturating_sub(self.t3.saturating_sub(self.t2))
+    }
+
+    /// Local time the offset applies to
+    pub fn midpoint(&self) -> u64 {
+        self.t1 + self.t4.saturating_sub(self.t1) / 2
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+struct Sample {
+    local: u64,
+    offset: i64,
+    delay: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClockSync {
+    samples: [Sample; WINDOW],
+    len: usize,
+    next: usize,
+    /// Local time the fitted line is anchored at
+    ref_local: u64,
+    ref_offset: f64,
+    /// Remote us gained per local us
+    skew: f64,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSync {
+    pub const fn new() -> Self {
+        Self {
+            samples: [Sample {
+                local: 0,
+                offset: 0,
+                delay: 0,
+            }; WINDOW],
+            len: 0,
+            next: 0,
+            ref_local: 0,
+            ref_offset: 0.0,
+            skew: 0.0,
+        }
+    }
+
+    /// Forgets every sample, for when the remote restarted
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.len > 0
+    }
+
+    pub fn add_exchange(&mut self, exchange: &Exchange) {
+        self.add_sample(exchange.midpoint(), exchange.offset(), exchange.delay());
+    }
+
+    /// Adds an offset measured at local time `local` over a link with round trip `delay`
+    pub fn add_sample(&mut self, local: u64, offset: i64, delay: u64) {
+        self.samples[self.next] = Sample {
+            local,
+            offset,
+            delay,
+        };
+        self.next = (self.next + 1) % WINDOW;
+        self.len = (self.len + 1).min(WINDOW);
+        self.fit();
+    }
+
+    fn fit(&mut self) {
+        let samples = &self.samples[..self.len];
+        let min_delay = samples.iter().map(|s| s.delay).min().unwrap_or(0);
+        let good = || {
+            samples
+                .iter()
+                .filter(move |s| s.delay <= min_delay + MAX_EXTRA_DELAY_US)
+        };
+        let newest = good().map(|s| s.local).max().unwrap_or(0);
+        let n = good().count() as f64;
+        // Relative to the newest sample so the squares stay small enough for an f64
+        let x = |s: &Sample| s.local as f64 - newest as f64;
+        let mean_x = good().map(x).sum::<f64>() / n;
+        let mean_y = good().map(|s| s.offset as f64).sum::<f64>() / n;
+        let var = good()
+            .map(|s| (x(s) - mean_x) * (x(s) - mean_x))
+            .sum::<f64>();
+        let cov = good()
+            .map(|s| (x(s) - mean_x) * (s.offset as f64 - mean_y))
+            .sum::<f64>();
+        // A single sample or samples taken at the same time say nothing about drift, keep the
+        // previous estimate
+        if var > 0.0 {
+            self.skew = cov / var;
+        }
+        self.ref_local = newest;
+        self.ref_offset = mean_y - self.skew * mean_x;
+    }
+
+    /// Estimated remote minus local time at local time `local`
+    pub fn offset_at(&self, local: u64) -> Option<i64> {
+        if !self.is_synced() {
+            return None;
+        }
+        let dt = local as f64 - self.ref_local as f64;
+        Some((self.ref_offset + self.skew * dt) as i64)
+    }
+
+    /// How much faster the remote clock runs in parts per million
+    pub fn drift_ppm(&self) -> Option<f32> {
+        (self.len > 1).then_some((self.skew * 1e6) as f32)
+    }
+
+    pub fn to_remote(&self, local: u64) -> Option<u64> {
+        Some(local.saturating_add_signed(self.offset_at(local)?))
+    }
+
+    pub fn to_local(&self, remote: u64) -> Option<u64> {
+        if !self.is_synced() {
+            return None;
+        }
+        // Solves remote = local + offset_at(local) for local. Guessing with the offset at the
+        // remote time instead is off by the drift over the whole offset, which adds up for
+        // clocks that booted far apart
+        let local = (remote as f64 - self.ref_offset + self.skew * self.ref_local as f64)
+            / (1.0 + self.skew);
+        Some((local + 0.5) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remote clock `offset_us` ahead of the local one and running `drift_ppm` faster
+    struct SimClock {
+        offset_us: f64,
+        drift_ppm: f64,
+    }
+
+    impl SimClock {
+        fn remote(&self, local: u64) -> u64 {
+            (local as f64 * (1.0 + self.drift_ppm * 1e-6) + self.offset_us) as u64
+        }
+
+        fn offset(&self, local: u64) -> i64 {
+            self.remote(local) as i64 - local as i64
+        }
+
+        /// Exchange started at `t1` taking `to_us` there, `processing_us` on the remote and
+        /// `back_us` back
+        fn exchange(&self, t1: u64, to_us: u64, processing_us: u64, back_us: u64) -> Exchange {
+            let t2_local = t1 + to_us;
+            let t3_local = t2_local + processing_us;
+            Exchange {
+                t1,
+                t2: self.remote(t2_local),
+                t3: self.remote(t3_local),
+                t4: t3_local + back_us,
+            }
+        }
+    }
+
+    /// xorshift32, for repeatable jitter
+    fn rng(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    #[test]
+    fn exchange_math() {
+        let clock = SimClock {
+            offset_us: 5000.0,
+            drift_ppm: 0.0,
+        };
+        let e = clock.exchange(1000, 150, 40, 150);
+        assert_eq!(e.offset(), 5000);
+        assert_eq!(e.delay(), 300);
+        assert_eq!(e.midpoint(), 1170);
+        // Asymmetric paths show up as half the difference in the offset
+        assert_eq!(clock.exchange(1000, 250, 40, 50).offset(), 5100);
+    }
+
+    #[test]
+    fn unsynced() {
+        let mut sync = ClockSync::new();
+        assert!(!sync.is_synced());
+        assert_eq!(sync.offset_at(0), None);
+        assert_eq!(sync.to_remote(0), None);
+        sync.add_sample(1000, 42, 300);
+        assert_eq!(sync.offset_at(5000), Some(42));
+        assert_eq!(sync.drift_ppm(), None);
+        sync.reset();
+        assert!(!sync.is_synced());
+    }
+
+    #[test]
+    fn tracks_simulated_drift() {
+        for drift_ppm in [-80.0, -20.0, 0.0, 35.0, 120.0] {
+            let clock = SimClock {
+                offset_us: 123_456_789.0,
+                drift_ppm,
+            };
+            let mut sync = ClockSync::new();
+            let mut state = 0x1234_5678;
+            let mut t = 10_000_000;
+            for _ in 0..40 {
+                let to = 140 + (rng(&mut state) % 20) as u64;
+                let back = 140 + (rng(&mut state) % 20) as u64;
+                sync.add_exchange(&clock.exchange(t, to, 30, back));
+                t += 100_000;
+            }
+            let drift = sync.drift_ppm().unwrap() as f64;
+            assert!(
+                (drift - drift_ppm).abs() < 5.0,
+                "{drift} ppm instead of {drift_ppm}"
+            );
+            // Predicting a bit past the last exchange stays within the jitter
+            for ahead in [0, 50_000, 200_000] {
+                let local = t + ahead;
+                let error = sync.offset_at(local).unwrap() - clock.offset(local);
+                assert!(error.abs() < 20, "{error} us off {ahead} us ahead");
+            }
+            let local = t + 10_000;
+            let remote = sync.to_remote(local).unwrap();
+            assert!(remote.abs_diff(clock.remote(local)) < 20);
+            assert!(sync.to_local(remote).unwrap().abs_diff(local) <= 1);
+        }
+    }
+
+    #[test]
+    fn ignores_queued_exchanges() {
+        let clock = SimClock {
+            offset_us: -2_000_000.0,
+            drift_ppm: 40.0,
+        };
+        let mut sync = ClockSync::new();
+        let mut t = 5_000_000;
+        for i in 0..WINDOW as u64 * 2 {
+            // Every third response sat in a queue for a few ms, which skews its offset by half
+            let back = if i % 3 == 0 { 3000 + i * 100 } else { 150 };
+            sync.add_exchange(&clock.exchange(t, 150, 30, back));
+            t += 50_000;
+        }
+        let drift = sync.drift_ppm().unwrap();
+        assert!((drift - 40.0).abs() < 2.0, "{drift} ppm");
+        let error = sync.offset_at(t).unwrap() - clock.offset(t);
+        assert!(error.abs() < 5, "{error} us off");
+    }
+
+    #[test]
+    fn follows_drift_change() {
+        // Only the last WINDOW samples count, so a temperature change is picked up
+        let mut sync = ClockSync::new();
+        let mut t = 0;
+        for drift_ppm in [10.0, -30.0] {
+            let clock = SimClock {
+                offset_us: 1000.0,
+                drift_ppm,
+            };
+            for _ in 0..WINDOW {
+                sync.add_exchange(&clock.exchange(t, 150, 30, 150));
+                t += 100_000;
+            }
+        }
+        let drift = sync.drift_ppm().unwrap();
+        assert!((drift + 30.0).abs() < 1.0, "{drift} ppm");
+    }
+}
@@ -0,0 +1,101 @@
+//! Little endian cursor helpers used by the message encoders
+
+pub(crate) struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buf
+            .get_mut(self.pos..self.pos + bytes.len())?
+            .copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Some(())
+    }
+
+    pub(crate) fn u8(&mut self, v: u8) -> Option<()> {
+        self.put(&[v])
+    }
+
+    pub(crate) fn i8(&mut self, v: i8) -> Option<()> {
+        self.put(&v.to_le_bytes())
+    }
+
+    pub(crate) fn u16(&mut self, v: u16) -> Option<()> {
+        self.put(&v.to_le_bytes())
+    }
+
+    pub(crate) fn u32(&mut self, v: u32) -> Option<()> {
+        self.put(&v.to_le_bytes())
+    }
+
+    pub(crate) fn u64(&mut self, v: u64) -> Option<()> {
+        self.put(&v.to_le_bytes())
+    }
+
+    pub(crate) fn i32(&mut self, v: i32) -> Option<()> {
+        self.put(&v.to_le_bytes())
+    }
+
+    pub(crate) fn i64(&mut self, v: i64) -> Option<()> {
+        self.put(&v.to_le_bytes())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pos
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let res = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(res)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        Some(self.array::<1>()?[0])
+    }
+
+    pub(crate) fn i8(&mut self) -> Option<i8> {
+        Some(i8::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn i64(&mut self) -> Option<i64> {
+        Some(i64::from_le_bytes(self.array()?))
+    }
+}
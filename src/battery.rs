@@ -0,0 +1,43 @@
+//! Battery voltage of a half, sampled with the SAADC.
+//!
+//! The cell is read through VDDH / 5, which the nrf52840 measures without any pins or divider of
+//! its own, so this only works on boards that run off VDDH. Samples are oversampled and taken in
+//! one shot, the SAADC is stopped in between to keep it from drawing current.
+
+use embassy_nrf::{
+    interrupt::typelevel::{Binding, SAADC},
+    peripherals,
+    saadc::{self, ChannelConfig, InterruptHandler, Oversample, Saadc, VddhDiv5Input},
+    Peri,
+};
+use keyboard::battery::Scale;
+
+pub struct Sampler<'d> {
+    saadc: Saadc<'d, 1>,
+}
+
+impl<'d> Sampler<'d> {
+    /// Calibrates the SAADC offset before the first sample
+    pub async fn new(
+        saadc: Peri<'d, peripherals::SAADC>,
+        irq: impl Binding<SAADC, InterruptHandler> + 'd,
+    ) -> Self {
+        let mut config = saadc::Config::default();
+        config.oversample = Oversample::OVER8X;
+        let saadc = Saadc::new(
+            saadc,
+            irq,
+            config,
+            [ChannelConfig::single_ended(VddhDiv5Input)],
+        );
+        saadc.calibrate().await;
+        Self { saadc }
+    }
+
+    /// Voltage of the cell
+    pub async fn millivolts(&mut self) -> u16 {
+        let mut buf = [0];
+        self.saadc.sample(&mut buf).await;
+        Scale::VDDH_DIV5.millivolts(buf[0])
+    }
+}
@@ -0,0 +1,79 @@
+//! Runs benchmark [`Plan`]s, sweeping payload size, interval, burst length and data rate and
+//! aggregating the send results of every cell.
+
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use latency_proto::plan::{Cell, CellSummary, Plan};
+
+use crate::{
+    control::DRIVER,
+    link::{LinkConfig, LinkLayer, Timeout},
+    radio::{Packet, BUFFER_SIZE},
+    stats::{LatencyStats, RecordSend},
+};
+
+/// Most cells a plan can have, results for all of them are kept until the plan is done
+pub const MAX_CELLS: usize = 128;
+
+pub type Results = Vec<CellSummary, MAX_CELLS>;
+
+/// A send that isn't acked by then is given up and counted as lost
+pub const SEND_TIMEOUT: Duration = Duration::from_millis(20);
+
+pub fn summarize(cell: Cell, stats: &LatencyStats) -> CellSummary {
+    let p = stats.percentiles();
+    CellSummary {
+        cell,
+        count: stats.count(),
+        lost: stats.lost(),
+        min_us: stats.min_us().unwrap_or(0),
+        mean_us: stats.mean_us().unwrap_or(0),
+        p50_us: p.map_or(0, |p| p.p50),
+        p90_us: p.map_or(0, |p| p.p90),
+        p99_us: p.map_or(0, |p| p.p99),
+        p999_us: p.map_or(0, |p| p.p999),
+        max_us: stats.max_us().unwrap_or(0),
+        jitter_us: stats.jitter_us().unwrap_or(0),
+        retranmisisons_milli: (stats.mean_retranmisisons().unwrap_or(0.0) * 1000.0) as u32,
+    }
+}
+
+async fn run_cell<L: LinkLayer>(link: &mut L, cell: &Cell, repeats: u32) -> LatencyStats {
+    let mut payload = [0u8; BUFFER_SIZE];
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let mut packet = Packet::default();
+    packet.copy_from_slice(&payload[..(cell.payload_len as usize).min(BUFFER_SIZE)]);
+    let mut stats = LatencyStats::new();
+    for _ in 0..repeats {
+        for _ in 0..cell.burst_len {
+            match link.send_with_timeout(&mut packet, SEND_TIMEOUT).await {
+                Ok(res) => stats.record(&res),
+                Err(Timeout) => stats.record_loss(),
+            }
+        }
+        Timer::after_micros(cell.interval_us as u64).await;
+    }
+    stats
+}
+
+/// Runs every cell of the plan this build's driver can run. Summaries are pushed into `results`
+/// as each cell finishes, so finished cells are kept if the future is dropped part way through
+pub async fn run_plan<L: LinkLayer>(
+    link: &mut L,
+    mut config: LinkConfig,
+    plan: &Plan,
+    results: &mut Results,
+) {
+    for cell in plan.cells().filter(|c| c.driver == DRIVER) {
+        if config.data_rate != cell.data_rate {
+            config.data_rate = cell.data_rate;
+            link.configure(&config);
+        }
+        let stats = run_cell(link, &cell, plan.repeats).await;
+        if results.push(summarize(cell, &stats)).is_err() {
+            return;
+        }
+    }
+}
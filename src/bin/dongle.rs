@@ -0,0 +1,538 @@
+#![no_std]
+#![no_main]
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use bruh78::{
+    control,
+    link::{Backend, LinkConfig, LinkLayer},
+    macro_store::MacroStore,
+    radio::{self, Addresses, Packet},
+    telemetry,
+};
+use cortex_m_rt::entry;
+use defmt::{info, warn, Debug2Format};
+use embassy_executor::{Executor, InterruptExecutor};
+use embassy_futures::{
+    join::{join, join3, join4},
+    select::{select, Either},
+};
+use embassy_nrf::{
+    bind_interrupts,
+    config::HfclkSource,
+    interrupt,
+    interrupt::InterruptExt,
+    peripherals,
+    usb::{self, vbus_detect::HardwareVbusDetect, Driver},
+    Peri,
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant};
+use embassy_usb::{
+    class::hid::{ReportId, RequestHandler},
+    control::OutResponse,
+};
+use keyboard::{
+    combo::{Combo, ComboConfig, Combos},
+    event,
+    hid::{ConsumerReport, KeyReport, Leds, MouseReport, ReportMode},
+    keycode::*,
+    keymap::{Action, Action::*, Keymap, KeymapConfig, Layer, Output},
+    macros::{self, MacroPlayer, Step, Step::*, MAX_LEN},
+    matrix::KeyPos,
+    split::{Half, HalfLayout, Split, TimedChange},
+    usb::{self as device, Device, SerialNumber},
+};
+
+use defmt_rtt as _; // global logger
+use embassy_nrf as _;
+// time driver
+use panic_probe as _;
+use static_cell::StaticCell;
+
+static RADIO_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+static THREAD_EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+#[cfg(not(feature = "trad"))]
+bind_interrupts!(struct Irqs {
+    USBD => usb::InterruptHandler<peripherals::USBD>;
+    CLOCK_POWER => usb::vbus_detect::InterruptHandler;
+    RADIO  => bruh78::radio::InterruptHandler;
+});
+
+#[cfg(feature = "trad")]
+bind_interrupts!(struct Irqs {
+    USBD => usb::InterruptHandler<peripherals::USBD>;
+    CLOCK_POWER => usb::vbus_detect::InterruptHandler;
+    RADIO  => bruh78::trad_radio::TradInterruptHandler;
+    TIMER0  => bruh78::trad_radio::RadioTimerInterrupt;
+});
+
+#[cfg(not(feature = "trad"))]
+fn new_link(
+    radio: Peri<'static, peripherals::RADIO>,
+    _timer: Peri<'static, peripherals::TIMER0>,
+) -> Backend<'static> {
+    Backend::new(radio, Irqs, Addresses::default())
+}
+
+#[cfg(feature = "trad")]
+fn new_link(
+    radio: Peri<'static, peripherals::RADIO>,
+    timer: Peri<'static, peripherals::TIMER0>,
+) -> Backend<'static> {
+    Backend::new(radio, timer, Irqs, Irqs, Addresses::default())
+}
+
+/// The dongle sends everything on its own address, acks included, and both halves listen there
+const DONGLE: u8 = radio::DONGLE_PIPE;
+/// Logical addresses the halves send on, the dongle listens on both
+const LEFT_HALF: u8 = radio::LEFT_PIPE;
+const RIGHT_HALF: u8 = radio::RIGHT_PIPE;
+/// Matrix of each half
+const ROWS: usize = 5;
+const COLS: usize = 4;
+/// Columns of both halves side by side
+const GLOBAL_COLS: usize = 2 * COLS;
+/// The matrix and a row past it for the combos
+const KEYMAP_ROWS: usize = ROWS + 1;
+const REPORT_MODE: ReportMode = ReportMode::Nkro;
+/// Changes within one usb frame go out together, ordered by when they happened
+const FRAME: Duration = Duration::from_millis(1);
+/// A half quiet for this long is taken as gone and its keys are released
+const HALF_TIMEOUT: Duration = Duration::from_secs(1);
+/// Between changes of a macro, a few frames so none of them is lost to a later report
+const MACRO_STEP: Duration = Duration::from_millis(10);
+/// How often the link counters and battery levels go out as telemetry
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// A state request nobody acked by then is sent again once `REQUEST_INTERVAL` passed, so a half
+/// that doesn't answer only holds up the frame this long
+const REQUEST_TIMEOUT: Duration = Duration::from_micros(500);
+/// Between state requests to the same half
+const REQUEST_INTERVAL: Duration = Duration::from_millis(20);
+
+const LEFT_LAYOUT: HalfLayout = HalfLayout {
+    row_offset: 0,
+    col_offset: 0,
+    mirrored: false,
+};
+/// The right half is wired as a mirror image of the left one
+const RIGHT_LAYOUT: HalfLayout = HalfLayout {
+    row_offset: 0,
+    col_offset: COLS as u8,
+    mirrored: true,
+};
+
+const ___: Action = Trans;
+
+#[rustfmt::skip]
+static LAYERS: [Layer<KEYMAP_ROWS, GLOBAL_COLS>; 2] = [
+    [
+        [Key(Q), Key(W), Key(E), Key(R),    Key(U), Key(I), Key(O), Key(P)],
+        [ModTap { tap: A, hold: LEFT_GUI }, ModTap { tap: S, hold: LEFT_ALT },
+         ModTap { tap: D, hold: LEFT_SHIFT }, ModTap { tap: F, hold: LEFT_CTRL },
+         ModTap { tap: J, hold: RIGHT_CTRL }, ModTap { tap: K, hold: RIGHT_SHIFT },
+         ModTap { tap: L, hold: RIGHT_ALT }, ModTap { tap: SEMICOLON, hold: RIGHT_GUI }],
+        [Key(Z), Key(X), Key(C), Key(V),    Key(M), Key(COMMA), Key(DOT), Key(SLASH)],
+        [Key(ESCAPE), Key(TAB), OneShot(1), Toggle(1),    Key(BACKSPACE), Key(QUOTE), Key(MINUS), Key(EQUAL)],
+        [Key(LEFT_CTRL), Key(LEFT_SHIFT), Momentary(1), LayerTap { tap: SPACE, layer: 1 },
+         Key(ENTER), Key(RIGHT_ALT), Key(RIGHT_SHIFT), Key(RIGHT_CTRL)],
+        [Key(ESCAPE), Macro(0), Macro(1), No,    No, No, No, No],
+    ],
+    [
+        [Key(N1), Key(N2), Key(N3), Key(N4),    Key(N7), Key(N8), Key(N9), Key(N0)],
+        [Key(F1), Key(F2), Key(F3), Key(F4),    Key(LEFT), Key(DOWN), Key(UP), Key(RIGHT)],
+        [Key(F5), Key(F6), Key(F7), Key(F8),    Key(HOME), Key(PAGE_DOWN), Key(PAGE_UP), Key(END)],
+        [___, ___, ___, ___,    Key(DELETE), Key(GRAVE), Key(LEFT_BRACKET), Key(RIGHT_BRACKET)],
+        [___, ___, ___, ___,    ___, ___, ___, ___],
+        [___, ___, ___, ___,    ___, ___, ___, ___],
+    ],
+];
+
+const fn at(row: u8, col: u8) -> KeyPos {
+    KeyPos { row, col }
+}
+
+/// Combos press the keys of the last keymap row
+const COMBO_ROW: u8 = ROWS as u8;
+
+#[rustfmt::skip]
+static COMBOS: [Combo<'static>; 3] = [
+    // W E
+    Combo { keys: &[at(0, 1), at(0, 2)], output: at(COMBO_ROW, 0) },
+    // I O
+    Combo { keys: &[at(0, 5), at(0, 6)], output: at(COMBO_ROW, 1) },
+    // C V
+    Combo { keys: &[at(2, 2), at(2, 3)], output: at(COMBO_ROW, 2) },
+];
+
+/// Stored on first boot, after that the ones in flash are played
+#[rustfmt::skip]
+static DEFAULT_MACROS: [&[Step]; 2] = [
+    // Select all and copy
+    &[Press(LEFT_CTRL), Tap(A), Tap(C), Release(LEFT_CTRL)],
+    // Run git status
+    &[Tap(G), Tap(I), Tap(T), Tap(SPACE), Tap(S), Tap(T), Tap(A), Tap(T), Tap(U), Tap(S),
+      Delay(50), Tap(ENTER)],
+];
+
+/// Latest key state from the radio, only the newest one matters to the usb side
+static REPORT: Signal<CriticalSectionRawMutex, KeyReport> = Signal::new();
+/// Mouse buttons and motion not reported yet, motion piles up here while usb is behind
+static MOUSE: Mutex<CriticalSectionRawMutex, RefCell<MouseReport>> =
+    Mutex::new(RefCell::new(MouseReport::new()));
+/// Something changed in `MOUSE`
+static MOUSE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static CONSUMER: Signal<CriticalSectionRawMutex, ConsumerReport> = Signal::new();
+static LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// Takes the led output report, both from SET_REPORT and the out endpoint
+struct LedHandler;
+
+impl RequestHandler for LedHandler {
+    fn set_report(&mut self, _id: ReportId, data: &[u8]) -> OutResponse {
+        let Some(leds) = Leds::from_report(data) else {
+            return OutResponse::Rejected;
+        };
+        if LEDS.swap(leds.0, Ordering::Relaxed) != leds.0 {
+            info!(
+                "Leds: num {} caps {} scroll {}",
+                leds.num_lock(),
+                leds.caps_lock(),
+                leds.scroll_lock()
+            );
+        }
+        OutResponse::Accepted
+    }
+}
+
+#[embassy_executor::task]
+async fn usb_task(usbd: Peri<'static, peripherals::USBD>) {
+    let driver = Driver::new(usbd, Irqs, HardwareVbusDetect::new(Irqs));
+    let ficr = embassy_nrf::pac::FICR;
+    let serial_number =
+        SerialNumber::from_device_id([ficr.deviceid(0).read(), ficr.deviceid(1).read()]);
+    let mut state = device::State::new();
+    let mut boot_control = LedHandler;
+    let mut nkro_control = LedHandler;
+    let Device {
+        mut usb,
+        boot,
+        nkro,
+        mut mouse_consumer,
+        serial,
+        log: log_class,
+    } = device::build(
+        driver,
+        device::config("Keyboard dongle", &serial_number),
+        &mut state,
+        [&mut boot_control, &mut nkro_control],
+        cfg!(feature = "usb-log"),
+    );
+    let (boot_reader, mut boot_writer) = boot.split();
+    let (nkro_reader, mut nkro_writer) = nkro.split();
+    let (sender, receiver) = serial.split();
+    let mut boot_out = LedHandler;
+    let mut nkro_out = LedHandler;
+    let keys = async {
+        let (mut last_boot, mut last_nkro) = KeyReport::new().reports(REPORT_MODE);
+        loop {
+            let (boot, nkro) = REPORT.wait().await.reports(REPORT_MODE);
+            if boot != last_boot {
+                match boot_writer.write(&boot).await {
+                    Ok(()) => last_boot = boot,
+                    Err(e) => warn!("Boot report not sent: {:?}", e),
+                }
+            }
+            if nkro != last_nkro {
+                match nkro_writer.write(&nkro).await {
+                    Ok(()) => last_nkro = nkro,
+                    Err(e) => warn!("NKRO report not sent: {:?}", e),
+                }
+            }
+        }
+    };
+    let pointer = async {
+        let mut last_consumer = ConsumerReport::new();
+        loop {
+            match select(MOUSE_CHANGED.wait(), CONSUMER.wait()).await {
+                Either::First(()) => {
+                    // Motion that doesn't fit one report goes out in the next ones
+                    loop {
+                        let (report, more) = MOUSE.lock(|mouse| {
+                            let mut mouse = mouse.borrow_mut();
+                            (mouse.take(), mouse.has_motion())
+                        });
+                        if let Err(e) = mouse_consumer.write(&report).await {
+                            warn!("Mouse report not sent: {:?}", e);
+                        }
+                        if !more {
+                            break;
+                        }
+                    }
+                }
+                Either::Second(report) if report != last_consumer => {
+                    match mouse_consumer.write(&report.report()).await {
+                        Ok(()) => last_consumer = report,
+                        Err(e) => warn!("Consumer report not sent: {:?}", e),
+                    }
+                }
+                Either::Second(_) => {}
+            }
+        }
+    };
+    let log = async {
+        match log_class {
+            Some(class) => {
+                embassy_usb_logger::with_class!(1024, log::LevelFilter::Info, class).await
+            }
+            None => core::future::pending().await,
+        }
+    };
+    join4(
+        usb.run(),
+        join3(keys, pointer, log),
+        join(telemetry::run(sender), control::run(receiver)),
+        join(
+            boot_reader.run(false, &mut boot_out),
+            nkro_reader.run(false, &mut nkro_out),
+        ),
+    )
+    .await;
+}
+
+/// Macros read from flash and the one playing
+struct Macros {
+    stored: [([u8; MAX_LEN], usize); DEFAULT_MACROS.len()],
+    player: MacroPlayer,
+    /// Usages the playing macro holds, apart from the keys so neither releases the other's
+    report: KeyReport,
+}
+
+impl Macros {
+    /// Reads every macro from flash, storing the default for any that isn't there yet
+    async fn load(store: &mut MacroStore<'_>) -> Self {
+        let mut stored = [([0; MAX_LEN], 0); DEFAULT_MACROS.len()];
+        for (id, (bytes, len)) in stored.iter_mut().enumerate() {
+            match store.load(id as u8, bytes).await {
+                Ok(Some(stored)) => {
+                    *len = stored;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Macro {} not loaded: {:?}", id, Debug2Format(&e)),
+            }
+            *len = macros::encode(DEFAULT_MACROS[id], bytes).unwrap_or(0);
+            if let Err(e) = store.store(id as u8, &bytes[..*len]).await {
+                warn!("Macro {} not stored: {:?}", id, Debug2Format(&e));
+            }
+        }
+        Self {
+            stored,
+            player: MacroPlayer::new(MACRO_STEP.as_micros()),
+            report: KeyReport::new(),
+        }
+    }
+}
+
+/// Turns merged key changes into the report
+struct Keys {
+    combos: Combos<'static, { COMBOS.len() }>,
+    keymap: Keymap<'static, KEYMAP_ROWS, GLOBAL_COLS, 2>,
+    macros: Macros,
+    /// Usages of the keymap, macros keep theirs in [`Macros::report`]
+    report: KeyReport,
+}
+
+impl Keys {
+    /// What goes to the host, the keymap's and the macro's usages together
+    fn report(&self) -> KeyReport {
+        self.report.union(&self.macros.report)
+    }
+
+    fn apply(&mut self, timed: TimedChange) {
+        let Self {
+            combos,
+            keymap,
+            macros,
+            report,
+        } = self;
+        combos.event(timed.change, timed.at_us, |change, at_us| {
+            keymap.event(change, at_us, |o| take(o, at_us, macros, report))
+        });
+    }
+
+    fn tick(&mut self, now_us: u64) {
+        let Self {
+            combos,
+            keymap,
+            macros,
+            report,
+        } = self;
+        combos.tick(now_us, |change, at_us| {
+            keymap.event(change, at_us, |o| take(o, at_us, macros, report))
+        });
+        keymap.tick(now_us, |o| take(o, now_us, macros, report));
+        let Macros {
+            player,
+            report: macro_report,
+            ..
+        } = macros;
+        player.poll(now_us, |u| macro_report.set(u.usage, u.pressed));
+    }
+}
+
+/// Applies what came out of the keymap at `at_us`
+fn take(output: Output, at_us: u64, macros: &mut Macros, report: &mut KeyReport) {
+    match output {
+        Output::Usage(u) => report.set(u.usage, u.pressed),
+        Output::Macro(id) => {
+            let Some((bytes, len)) = macros.stored.get(id as usize) else {
+                warn!("No macro {}", id);
+                return;
+            };
+            let macro_report = &mut macros.report;
+            let played = macros.player.play(&bytes[..*len], at_us, |u| {
+                macro_report.set(u.usage, u.pressed)
+            });
+            if let Err(e) = played {
+                warn!("Macro {} not played: {:?}", id, Debug2Format(&e));
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn radio_task(
+    radio: Peri<'static, peripherals::RADIO>,
+    timer: Peri<'static, peripherals::TIMER0>,
+    nvmc: Peri<'static, peripherals::NVMC>,
+) {
+    // Flash is only touched here, before the radio is up
+    let macros = Macros::load(&mut MacroStore::new(nvmc)).await;
+    let mut link = new_link(radio, timer);
+    let config = LinkConfig {
+        tx_address: DONGLE,
+        rx_addresses: 1 << LEFT_HALF | 1 << RIGHT_HALF,
+        ..Default::default()
+    };
+    link.configure(&config);
+    let mut split = Split::<ROWS, COLS>::new(LEFT_LAYOUT, RIGHT_LAYOUT, HALF_TIMEOUT.as_micros());
+    let mut keys = Keys {
+        combos: Combos::new(&COMBOS, ComboConfig::default()),
+        keymap: Keymap::new(&LAYERS, KeymapConfig::default()),
+        macros,
+        report: KeyReport::new(),
+    };
+    let mut signalled = keys.report();
+    let (mut buttons, mut consumer) = (0, ConsumerReport::new());
+    let mut packet = Packet::default();
+    let mut buf = [0u8; event::MAX_LEN];
+    let mut next_frame = Instant::now() + FRAME;
+    let mut next_telemetry = Instant::now() + TELEMETRY_INTERVAL;
+    let mut next_request = [Instant::now(); 2];
+    loop {
+        let left = next_frame.saturating_duration_since(Instant::now());
+        if link.receive_with_timeout(&mut packet, left).await.is_err() {
+            let now = Instant::now();
+            split.release_lost(now.as_micros(), |c| keys.apply(c));
+            split.flush(|c| keys.apply(c));
+            keys.tick(now.as_micros());
+            let report = keys.report();
+            if report != signalled {
+                REPORT.signal(report);
+                signalled = report;
+            }
+            let motion = split.take_motion();
+            if split.buttons() != buttons || !motion.is_zero() {
+                buttons = split.buttons();
+                MOUSE.lock(|mouse| {
+                    let mut mouse = mouse.borrow_mut();
+                    mouse.buttons = buttons;
+                    mouse.add(motion);
+                });
+                MOUSE_CHANGED.signal(());
+            }
+            let held = ConsumerReport::from_usages(split.consumer());
+            if held != consumer {
+                CONSUMER.signal(held);
+                consumer = held;
+            }
+            for half in [Half::Left, Half::Right] {
+                let next = &mut next_request[half as usize];
+                if !split.is_connected(half) || !split.needs_full_state(half) || now < *next {
+                    continue;
+                }
+                let Some(len) = split.encode_request(half, &mut buf) else {
+                    continue;
+                };
+                // Both halves listen on the dongle's address, the other one answers with its
+                // full state too which is harmless
+                packet.copy_from_slice(&buf[..len]);
+                if link
+                    .send_with_timeout(&mut packet, REQUEST_TIMEOUT)
+                    .await
+                    .is_err()
+                {
+                    warn!("State request not acked, asking again later");
+                }
+                *next = now + REQUEST_INTERVAL;
+            }
+            if now >= next_telemetry {
+                telemetry::log_counters(&link.counters());
+                for (half, addr) in [(Half::Left, LEFT_HALF), (Half::Right, RIGHT_HALF)] {
+                    if let Some(level) = split.battery(half) {
+                        telemetry::log_battery(addr, &level);
+                    }
+                }
+                next_telemetry = now + TELEMETRY_INTERVAL;
+            }
+            control::poll(&link, &config);
+            next_frame = (next_frame + FRAME).max(now);
+            continue;
+        }
+        let half = match packet.addr {
+            LEFT_HALF => Half::Left,
+            RIGHT_HALF => Half::Right,
+            _ => continue,
+        };
+        let now = Instant::now().as_micros();
+        if let Err(e) = split.receive(half, &packet, now, |c| keys.apply(c)) {
+            warn!("Dropped key event payload: {:?}", Debug2Format(&e));
+        }
+    }
+}
+
+#[interrupt]
+unsafe fn EGU1_SWI1() {
+    RADIO_EXECUTOR.on_interrupt()
+}
+
+#[entry]
+fn main() -> ! {
+    let mut nrf_config = embassy_nrf::config::Config::default();
+    nrf_config.hfclk_source = HfclkSource::ExternalXtal;
+    let p = embassy_nrf::init(nrf_config);
+
+    embassy_nrf::interrupt::EGU1_SWI1.set_priority(embassy_nrf::interrupt::Priority::P1);
+    embassy_nrf::interrupt::RADIO.set_priority(embassy_nrf::interrupt::Priority::P0);
+    embassy_nrf::interrupt::TIMER0.set_priority(embassy_nrf::interrupt::Priority::P0);
+    embassy_nrf::interrupt::USBD.set_priority(embassy_nrf::interrupt::Priority::P2);
+    embassy_nrf::interrupt::CLOCK_POWER.set_priority(embassy_nrf::interrupt::Priority::P2);
+    let spawner = RADIO_EXECUTOR.start(embassy_nrf::interrupt::EGU1_SWI1);
+    spawner
+        .spawn(radio_task(p.RADIO, p.TIMER0, p.NVMC))
+        .unwrap();
+
+    let exectuor = THREAD_EXECUTOR.init_with(Executor::new);
+    exectuor.run(|spawner| {
+        spawner.spawn(usb_task(p.USBD)).unwrap();
+        info!("Hello World!");
+    });
+}
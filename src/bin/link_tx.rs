@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+
+use bruh78::{
+    control,
+    link::{Backend, LinkConfig, LinkLayer},
+    radio::Addresses,
+    telemetry,
+};
+use cortex_m_rt::entry;
+use defmt::info;
+use embassy_executor::{Executor, InterruptExecutor};
+use embassy_futures::join::join3;
+use embassy_nrf::{
+    bind_interrupts,
+    config::HfclkSource,
+    interrupt,
+    interrupt::InterruptExt,
+    peripherals,
+    usb::{self, vbus_detect::HardwareVbusDetect, Driver},
+    Peri,
+};
+
+use defmt_rtt as _; // global logger
+use embassy_nrf as _;
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, State},
+    Builder,
+};
+// time driver
+use panic_probe as _;
+use static_cell::StaticCell;
+
+static RADIO_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+static THREAD_EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+#[cfg(not(feature = "trad"))]
+bind_interrupts!(struct Irqs {
+    USBD => usb::InterruptHandler<peripherals::USBD>;
+    CLOCK_POWER => usb::vbus_detect::InterruptHandler;
+    RADIO  => bruh78::radio::InterruptHandler;
+});
+
+#[cfg(feature = "trad")]
+bind_interrupts!(struct Irqs {
+    USBD => usb::InterruptHandler<peripherals::USBD>;
+    CLOCK_POWER => usb::vbus_detect::InterruptHandler;
+    RADIO  => bruh78::trad_radio::TradInterruptHandler;
+    TIMER0  => bruh78::trad_radio::RadioTimerInterrupt;
+});
+
+#[cfg(not(feature = "trad"))]
+fn new_link(
+    radio: Peri<'static, peripherals::RADIO>,
+    _timer: Peri<'static, peripherals::TIMER0>,
+) -> Backend<'static> {
+    Backend::new(radio, Irqs, Addresses::default())
+}
+
+#[cfg(feature = "trad")]
+fn new_link(
+    radio: Peri<'static, peripherals::RADIO>,
+    timer: Peri<'static, peripherals::TIMER0>,
+) -> Backend<'static> {
+    Backend::new(radio, timer, Irqs, Irqs, Addresses::default())
+}
+
+#[embassy_executor::task]
+async fn usb_task(usbd: Peri<'static, peripherals::USBD>) {
+    let driver = Driver::new(usbd, Irqs, HardwareVbusDetect::new(Irqs));
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("bruh78");
+    config.product = Some("Latency telemetry");
+    config.max_packet_size_0 = 64;
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = State::new();
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    let class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let (sender, receiver) = class.split();
+    let mut usb = builder.build();
+    join3(usb.run(), telemetry::run(sender), control::run(receiver)).await;
+}
+
+#[embassy_executor::task]
+async fn radio_task(
+    radio: Peri<'static, peripherals::RADIO>,
+    timer: Peri<'static, peripherals::TIMER0>,
+) {
+    let mut link = new_link(radio, timer);
+    let config = LinkConfig {
+        tx_address: 0,
+        rx_addresses: 0b110,
+        ..Default::default()
+    };
+    link.configure(&config);
+    control::serve(&mut link, config).await
+}
+
+#[interrupt]
+unsafe fn EGU1_SWI1() {
+    RADIO_EXECUTOR.on_interrupt()
+}
+
+#[entry]
+fn main() -> ! {
+    let mut nrf_config = embassy_nrf::config::Config::default();
+    nrf_config.hfclk_source = HfclkSource::ExternalXtal;
+    let p = embassy_nrf::init(nrf_config);
+    #[cfg(feature = "timeline")]
+    bruh78::timeline::init(p.TIMER3, p.PPI_CH0, p.PPI_CH1, p.PPI_CH2, p.PPI_CH3);
+
+    embassy_nrf::interrupt::EGU1_SWI1.set_priority(embassy_nrf::interrupt::Priority::P1);
+    embassy_nrf::interrupt::RADIO.set_priority(embassy_nrf::interrupt::Priority::P0);
+    embassy_nrf::interrupt::TIMER0.set_priority(embassy_nrf::interrupt::Priority::P0);
+    embassy_nrf::interrupt::USBD.set_priority(embassy_nrf::interrupt::Priority::P2);
+    embassy_nrf::interrupt::CLOCK_POWER.set_priority(embassy_nrf::interrupt::Priority::P2);
+    let spawner = RADIO_EXECUTOR.start(embassy_nrf::interrupt::EGU1_SWI1);
+    spawner.spawn(radio_task(p.RADIO, p.TIMER0)).unwrap();
+
+    let exectuor = THREAD_EXECUTOR.init_with(Executor::new);
+    exectuor.run(|spawner| {
+        spawner.spawn(usb_task(p.USBD)).unwrap();
+        info!("Hello World!");
+    });
+}
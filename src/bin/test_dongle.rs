@@ -3,7 +3,10 @@
 
 use core::{mem, ops::Deref};
 
-use bruh78::radio::{self, Addresses, LogInfo, Packet, Radio};
+use bruh78::{
+    radio::{self, Addresses, Packet, Radio},
+    stats::{LatencyStats, RecordSend},
+};
 use cortex_m_rt::entry;
 use defmt::{info, *};
 use embassy_executor::{Executor, InterruptExecutor, Spawner};
@@ -22,7 +25,7 @@ use embassy_nrf::{
 use defmt_rtt as _; // global logger
 use embassy_nrf as _;
 use embassy_time::Timer;
-use heapless::Vec;
+use latency_proto::pattern;
 // time driver
 use panic_probe as _;
 use static_cell::StaticCell;
@@ -51,35 +54,52 @@ async fn radio_task(radio: Peri<'static, peripherals::RADIO>) {
         w.set_addr1(true);
         w.set_addr2(true);
     });
-    const N: usize = 1000;
-    let mut log_state: Vec<LogInfo, N> = Vec::new();
+    const REPORT_EVERY: u32 = 100;
+    let mut stats = LatencyStats::new();
     let mut packet = Packet::default();
-    packet.copy_from_slice(&[1, 2, 3]);
+    packet.copy_from_slice(&[0; 16]);
+    let mut seq = 0;
     loop {
+        // Addressed to logical address 0, the one the tx address is set to
+        pattern::write(&mut packet, seq, 0);
+        seq += 1;
         let res = radio.send(&mut packet).await;
         log::info!(
-            "Took {} us, {} retranmisisons",
+            "Took {} us ({} us last attempt), {} retranmisisons",
+            res.total_time.as_micros(),
             res.time_elapsed.as_micros(),
             res.retranmisisons
         );
+        for frame in &res.timeline {
+            log::info!(
+                "{:?}: ramp up {:?} ns, address {:?} ns, payload {:?} ns, disable {:?} ns",
+                frame.direction,
+                frame.ramp_up_ns(),
+                frame.address_ns(),
+                frame.payload_ns(),
+                frame.disable_ns()
+            );
+        }
+        if let [data, ack] = &res.timeline[..] {
+            log::info!("Turnaround {:?} ns", data.turnaround_ns(ack));
+        }
+        stats.record(&res);
+        if stats.count().is_multiple_of(REPORT_EVERY) {
+            if let Some(p) = stats.percentiles() {
+                log::info!(
+                    "{} packets | mean {} us, jitter {} us | p50 {} p90 {} p99 {} p99.9 {} us",
+                    stats.count(),
+                    stats.mean_us().unwrap_or(0),
+                    stats.jitter_us().unwrap_or(0),
+                    p.p50,
+                    p.p90,
+                    p.p99,
+                    p.p999
+                );
+            }
+        }
         Timer::after_millis(1000).await;
     }
-    // for _ in 0..N {
-    //     let res = radio.send(&mut packet).await;
-    //     log::info!("Sent one message!");
-    //     log_state.push(res);
-    //     Timer::after_millis(1).await;
-    // }
-    // for log in log_state {
-    //     log::info!(
-    //         "Duration Elapsed: {} | Number of retranmisisons: {}",
-    //         log.time_elapsed.as_micros(),
-    //         log.retranmisisons
-    //     );
-    // }
-    // loop {
-    //     Timer::after_secs(10000).await;
-    // }
 }
 
 #[embassy_executor::task]
@@ -99,6 +119,8 @@ fn main() -> ! {
     let mut nrf_config = embassy_nrf::config::Config::default();
     nrf_config.hfclk_source = HfclkSource::ExternalXtal;
     let p = embassy_nrf::init(nrf_config);
+    #[cfg(feature = "timeline")]
+    bruh78::timeline::init(p.TIMER3, p.PPI_CH0, p.PPI_CH1, p.PPI_CH2, p.PPI_CH3);
 
     embassy_nrf::interrupt::EGU1_SWI1.set_priority(embassy_nrf::interrupt::Priority::P1);
     embassy_nrf::interrupt::RADIO.set_priority(embassy_nrf::interrupt::Priority::P0);
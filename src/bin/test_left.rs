@@ -2,29 +2,67 @@
 #![no_main]
 
 use assign_resources::assign_resources;
-use bruh78::radio::{self, Addresses, Packet, Radio};
+use bruh78::{
+    battery::Sampler,
+    radio::{self, Addresses, Packet, Radio},
+};
 use cortex_m_rt::entry;
 use defmt::{info, *};
 use embassy_executor::{Executor, InterruptExecutor, Spawner};
-use embassy_futures::join::join;
+use embassy_futures::{
+    join::join,
+    select::{select, Either},
+};
 use embassy_nrf::{
     bind_interrupts,
     config::HfclkSource,
-    gpio::{Input, Level, Output, OutputDrive, Pull},
+    gpio::{AnyPin, Input, Level, Output, OutputDrive, Pull},
     interrupt,
     interrupt::InterruptExt,
     peripherals::{self, USBD},
+    saadc,
     usb::{self, vbus_detect::HardwareVbusDetect, Driver},
     Peri,
 };
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
 
 use defmt_rtt as _; // global logger
 use embassy_nrf as _;
-use embassy_time::Timer;
+use embassy_time::{with_timeout, Delay, Duration, Instant, Timer};
+use heapless::Vec;
+use keyboard::{
+    battery::{self, Curve, Monitor, MonitorConfig},
+    debounce::{DebounceConfig, Debounced, Debouncer},
+    event,
+    matrix::{KeyChange, Matrix, MatrixConfig},
+};
+use latency_proto::pattern::{self, PatternCounters};
 // time driver
 use panic_probe as _;
 use static_cell::StaticCell;
 
+const ROWS: usize = 5;
+const COLS: usize = 4;
+/// Debounce strategy until something switches it at run time
+const DEBOUNCE: DebounceConfig = DebounceConfig::ASYM_EAGER_DEFER_PK;
+/// Between battery samples, the monitor decides which of them are sent
+const BATTERY_SAMPLE: Duration = Duration::from_secs(10);
+/// Give up on a battery level nobody acks, the next one is on its way anyway
+const BATTERY_SEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Give up on key changes nobody acks
+const KEY_SEND_TIMEOUT: Duration = Duration::from_millis(20);
+/// Listening after every send for whatever the dongle has for this half, the radio is off
+/// otherwise
+const LISTEN_WINDOW: Duration = Duration::from_millis(3);
+
+/// Level due to be sent to the dongle
+static BATTERY: Signal<CriticalSectionRawMutex, battery::Level> = Signal::new();
+/// Debounced changes due to be sent to the dongle, with when their first raw edge happened
+static CHANGES: Channel<CriticalSectionRawMutex, (KeyChange, u64), 16> = Channel::new();
+
 static RADIO_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
 static THREAD_EXECUTOR: StaticCell<Executor> = StaticCell::new();
 
@@ -32,6 +70,7 @@ bind_interrupts!(struct Irqs {
     USBD => usb::InterruptHandler<peripherals::USBD>;
     CLOCK_POWER => usb::vbus_detect::InterruptHandler;
     RADIO  => radio::InterruptHandler;
+    SAADC => saadc::InterruptHandler;
 });
 
 assign_resources! {
@@ -52,6 +91,9 @@ assign_resources! {
     usbd: UsbdResources {
         usbd: USBD
     }
+    battery: BatteryResources {
+        saadc: SAADC,
+    }
 }
 
 #[embassy_executor::task]
@@ -64,31 +106,176 @@ async fn logger_task(r: UsbdResources) {
 async fn radio_task(r: RadioResources) {
     let addresses = Addresses::default();
     let mut radio = Radio::new(r.rad, Irqs, addresses);
-    radio.set_tx_addresses(|w| w.set_txaddress(1));
-    radio.set_rx_addresses(|w| {
-        w.set_addr0(true);
-    });
+    // Sends on its own address and listens on the dongle's, where the dongle's acks come back
+    radio.set_tx_addresses(|w| w.set_txaddress(radio::LEFT_PIPE));
+    radio.set_rx_addresses(|w| w.0 = 1 << radio::DONGLE_PIPE);
+    let mut sender = event::Sender::<ROWS, COLS>::new(0);
     let mut packet = Packet::default();
+    let mut patterns = PatternCounters::new();
+    // The dongle asked for the full state, it goes out without waiting for a change
+    let mut full_state_due = false;
+    loop {
+        let mut buf = [0u8; event::MAX_LEN];
+        let (len, timeout) = if full_state_due {
+            full_state_due = false;
+            (sender.encode_full_state(&mut buf), KEY_SEND_TIMEOUT)
+        } else {
+            match select(CHANGES.receive(), BATTERY.wait()).await {
+                Either::First((change, at_us)) => {
+                    // Changes debounced in the meantime go out in the same payload
+                    let mut changes = Vec::<KeyChange, { event::MAX_CHANGES }>::new();
+                    let _ = changes.push(change);
+                    while !changes.is_full() {
+                        let Ok((change, _)) = CHANGES.try_receive() else {
+                            break;
+                        };
+                        let _ = changes.push(change);
+                    }
+                    let age_us = Instant::now().as_micros().saturating_sub(at_us);
+                    let age_us = age_us.min(u16::MAX as u64) as u16;
+                    (sender.encode(&changes, age_us, &mut buf), KEY_SEND_TIMEOUT)
+                }
+                Either::Second(level) => (
+                    event::encode_battery(&level, &mut buf),
+                    BATTERY_SEND_TIMEOUT,
+                ),
+            }
+        };
+        let Some(len) = len else {
+            continue;
+        };
+        packet.copy_from_slice(&buf[..len]);
+        if with_timeout(timeout, radio.send(&mut packet))
+            .await
+            .is_err()
+        {
+            log::warn!("Payload not acked");
+            // The dongle may have missed changes, the next payload tells it everything
+            sender.request_full_state();
+        }
+        if with_timeout(LISTEN_WINDOW, radio.receive(&mut packet))
+            .await
+            .is_ok()
+        {
+            if let Ok(event::Message::StateRequest { .. }) = event::decode(&packet) {
+                sender.request_full_state();
+                full_state_due = true;
+            } else {
+                verify(&packet, &mut patterns);
+            }
+        }
+    }
+}
+
+fn verify(packet: &Packet, patterns: &mut PatternCounters) {
+    const REPORT_EVERY: u32 = 100;
+    log::info!("Recevied packet {}", packet.id());
+    let result = pattern::verify(packet, packet.addr);
+    if let Some(Err(mismatch)) = result {
+        log::warn!("Packet {} failed verification: {:?}", packet.id(), mismatch);
+    }
+    patterns.record(result);
+    if result.is_some() && (patterns.verified + patterns.mismatches()).is_multiple_of(REPORT_EVERY)
+    {
+        log::info!(
+            "{} verified | {} wrong length, {} misdelivered, {} corrupt",
+            patterns.verified,
+            patterns.wrong_length,
+            patterns.misdelivered,
+            patterns.corrupt
+        );
+    }
+}
+
+#[embassy_executor::task]
+async fn battery_task(r: BatteryResources) {
+    let mut sampler = Sampler::new(r.saadc, Irqs).await;
+    let mut monitor = Monitor::new(Curve::LIPO, MonitorConfig::default());
     loop {
-        radio.receive(&mut packet).await;
-        log::info!("Recevied packet {}", packet.id());
+        let millivolts = sampler.millivolts().await;
+        if let Some(level) = monitor.sample(millivolts, Instant::now().as_micros()) {
+            log::info!("Battery {} mV, {}%", level.millivolts, level.percent);
+            BATTERY.signal(level);
+        }
+        Timer::after(BATTERY_SAMPLE).await;
     }
-    // for i in 0..1000 {
-    //     radio.receive(&mut packet).await;
-    //     log::info!("Recevied packet {}", i);
-    // }
-    // loop {
-    //     Timer::after_secs(1000).await;
-    // }
 }
 
 #[embassy_executor::task]
 async fn thread_task(k: KeyboardResources) {
+    let config = MatrixConfig::default();
+    let (idle, pull) = if config.active_low() {
+        (Level::High, Pull::Up)
+    } else {
+        (Level::Low, Pull::Down)
+    };
+    let output = |pin: Peri<'static, AnyPin>| Output::new(pin, idle, OutputDrive::Standard);
+    let input = |pin: Peri<'static, AnyPin>| Input::new(pin, pull);
+    let outputs = [
+        output(k.out_0.into()),
+        output(k.out_1.into()),
+        output(k.out_2.into()),
+        output(k.out_3.into()),
+        output(k.out_4.into()),
+    ];
+    let inputs = [
+        input(k.in_0.into()),
+        input(k.in_1.into()),
+        input(k.in_2.into()),
+        input(k.in_3.into()),
+    ];
+    let Ok(mut matrix) = Matrix::new(outputs, inputs, config);
+    let mut debouncer = Debouncer::<ROWS, COLS>::new(DEBOUNCE);
     loop {
-        Timer::after_secs(1000).await;
+        while let Some(debounced) = debouncer.poll(Instant::now().as_micros()) {
+            send_key(debounced).await;
+        }
+        let change = match debouncer.next_deadline() {
+            Some(deadline) => {
+                let res = select(
+                    matrix.next(&mut Delay),
+                    Timer::at(Instant::from_micros(deadline)),
+                )
+                .await;
+                match res {
+                    Either::First(change) => Some(change),
+                    Either::Second(()) => None,
+                }
+            }
+            None => Some(matrix.next(&mut Delay).await),
+        };
+        if let Some(Ok(change)) = change {
+            if let Some(debounced) = debouncer.update(change, Instant::now().as_micros()) {
+                send_key(debounced).await;
+            }
+        }
     }
 }
 
+/// Hands the change to the radio, waiting if it's behind so nothing is lost
+async fn send_key(debounced: Debounced) {
+    log_key(&debounced);
+    let at_us = Instant::now()
+        .as_micros()
+        .saturating_sub(debounced.added_us as u64);
+    CHANGES.send((debounced.change, at_us)).await;
+}
+
+fn log_key(debounced: &Debounced) {
+    let change = debounced.change;
+    log::info!(
+        "Key {},{} {} +{}us",
+        change.pos.row,
+        change.pos.col,
+        if change.pressed {
+            "pressed"
+        } else {
+            "released"
+        },
+        debounced.added_us
+    );
+}
+
 #[interrupt]
 unsafe fn EGU1_SWI1() {
     RADIO_EXECUTOR.on_interrupt()
@@ -112,5 +299,6 @@ fn main() -> ! {
     exectuor.run(|spawner| {
         spawner.spawn(logger_task(r.usbd)).unwrap();
         spawner.spawn(thread_task(r.keyboard)).unwrap();
+        spawner.spawn(battery_task(r.battery)).unwrap();
     });
 }
@@ -4,8 +4,8 @@
 use core::{mem, ops::Deref};
 
 use bruh78::{
-    radio::{self, LogInfo, Packet, Radio},
-    trad_radio::{self, Addresses, TradRadio},
+    radio::{self, Addresses, LogInfo, Packet, Radio},
+    trad_radio::{self, TradRadio},
 };
 use cortex_m_rt::entry;
 use defmt::{info, *};
@@ -24,7 +24,7 @@ use embassy_nrf::{
 
 use defmt_rtt as _; // global logger
 use embassy_nrf as _;
-use embassy_time::Timer;
+use embassy_time::{Duration, Timer};
 use heapless::Vec;
 // time driver
 use panic_probe as _;
@@ -62,7 +62,12 @@ async fn main(spawner: Spawner) {
         w.set_addr0(true);
     });
     loop {
-        let packet = rad.receive_packet().await;
-        log::info!("Packet recevied {}", packet.id());
+        match rad
+            .receive_packet_with_timeout(Duration::from_secs(5))
+            .await
+        {
+            Some(packet) => log::info!("Packet recevied {}", packet.id()),
+            None => log::info!("No packet in the last 5 seconds"),
+        }
     }
 }
@@ -4,8 +4,8 @@
 use core::{mem, ops::Deref};
 
 use bruh78::{
-    radio::{self, LogInfo, Packet, Radio},
-    trad_radio::{self, Addresses, TradRadio},
+    radio::{self, Addresses, LogInfo, Packet, Radio},
+    trad_radio::{self, TradRadio},
 };
 use cortex_m_rt::entry;
 use defmt::{info, *};
@@ -48,6 +48,8 @@ async fn main(spawner: Spawner) {
     let mut nrf_config = embassy_nrf::config::Config::default();
     nrf_config.hfclk_source = HfclkSource::ExternalXtal;
     let p = embassy_nrf::init(nrf_config);
+    #[cfg(feature = "timeline")]
+    bruh78::timeline::init(p.TIMER3, p.PPI_CH0, p.PPI_CH1, p.PPI_CH2, p.PPI_CH3);
 
     embassy_nrf::interrupt::RADIO.set_priority(embassy_nrf::interrupt::Priority::P0);
     embassy_nrf::interrupt::TIMER0.set_priority(embassy_nrf::interrupt::Priority::P0);
@@ -67,10 +69,24 @@ async fn main(spawner: Spawner) {
     loop {
         let res = rad.send_packet(packet).await;
         log::info!(
-            "Took {} us, {} retranmisisons",
+            "Took {} us ({} us last attempt), {} retranmisisons",
+            res.total_time.as_micros(),
             res.time_elapsed.as_micros(),
             res.retranmisisons
         );
+        for frame in &res.timeline {
+            log::info!(
+                "{:?}: ramp up {:?} ns, address {:?} ns, payload {:?} ns, disable {:?} ns",
+                frame.direction,
+                frame.ramp_up_ns(),
+                frame.address_ns(),
+                frame.payload_ns(),
+                frame.disable_ns()
+            );
+        }
+        if let [data, ack] = &res.timeline[..] {
+            log::info!("Turnaround {:?} ns", data.turnaround_ns(ack));
+        }
         Timer::after_millis(1000).await;
     }
 }
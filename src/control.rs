@@ -0,0 +1,274 @@
+//! Lets the host drive runs over the usb serial link. Commands are decoded by [`run`] and acted
+//! on by [`serve`], results and replies go back through [`telemetry`]. Firmware that keeps the
+//! link busy with its own traffic answers with [`poll`] instead, which doesn't start runs.
+
+use embassy_futures::select::select;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::{class::cdc_acm::Receiver, driver::Driver as UsbDriver};
+use latency_proto::{
+    control::{Command, Driver, NackReason, RunParams, MAX_PAYLOAD_LEN},
+    frame::{FrameDecoder, Message},
+    pattern,
+    record::{Record, Status},
+    sync::ClockSync,
+};
+
+use crate::{
+    bench::{self, Results, MAX_CELLS},
+    link::{LinkConfig, LinkLayer},
+    ping,
+    radio::{Packet, BUFFER_SIZE},
+    stats::LatencyStats,
+    stream, sync, telemetry,
+};
+
+/// How long to wait for a pong before counting the ping as lost
+const PING_TIMEOUT: Duration = Duration::from_millis(100);
+/// How often runs with room for a capture stamp resync the receiver's clock
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+/// How often runs stream the link counters
+const COUNTERS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The driver [`Backend`](crate::link::Backend) resolves to
+pub const DRIVER: Driver = if cfg!(feature = "trad") {
+    Driver::Trad
+} else {
+    Driver::Polled
+};
+
+static COMMANDS: Channel<CriticalSectionRawMutex, Command, 4> = Channel::new();
+
+/// Reads commands from the host for as long as the device is running
+pub async fn run<'d, D: UsbDriver<'d>>(mut class: Receiver<'d, D>) -> ! {
+    let mut buf = [0u8; 64];
+    loop {
+        class.wait_connection().await;
+        let mut decoder = FrameDecoder::new();
+        while let Ok(n) = class.read_packet(&mut buf).await {
+            for &b in &buf[..n] {
+                if let Some(Ok(frame)) = decoder.push::<Command>(b) {
+                    COMMANDS.send(frame.message).await;
+                }
+            }
+        }
+    }
+}
+
+fn nack(command: &Command, reason: NackReason) -> Record {
+    Record::Nack {
+        command: command.kind(),
+        reason,
+    }
+}
+
+/// Answers the next queued command, if any, without waiting. Only what doesn't need the link to
+/// itself is served, the rest is nacked as busy.
+pub fn poll<L: LinkLayer>(link: &L, config: &LinkConfig) {
+    let Ok(command) = COMMANDS.try_receive() else {
+        return;
+    };
+    let reply = match command {
+        Command::Status => Record::Status(Status {
+            driver: DRIVER,
+            running: true,
+            params: RunParams {
+                driver: DRIVER,
+                data_rate: config.data_rate,
+                channel: config.channel,
+                ..Default::default()
+            },
+        }),
+        Command::Counters => {
+            telemetry::log_counters(&link.counters());
+            Record::Ack(command.kind())
+        }
+        _ => nack(&command, NackReason::Busy),
+    };
+    telemetry::log(reply);
+}
+
+/// Sends the packets of a run. Pattern runs fill every payload with the pattern for its sequence
+/// number. Otherwise, when the payload is large enough every packet is stamped with its capture
+/// time in the receiver's clock, which is kept in sync with `clock` in between sends.
+async fn send_packets<L: LinkLayer>(
+    link: &mut L,
+    params: &RunParams,
+    config: &LinkConfig,
+    clock: &mut ClockSync,
+) {
+    let mut packet = Packet::default();
+    let mut payload = [0u8; BUFFER_SIZE];
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    packet.copy_from_slice(&payload[..params.payload_len as usize]);
+    let stamped = !params.pattern && params.payload_len as usize >= sync::STAMP_SIZE;
+    let mut last_sync: Option<Instant> = None;
+    let mut last_counters = Instant::now();
+    let mut i = 0;
+    while params.count == 0 || i < params.count {
+        if last_counters.elapsed() >= COUNTERS_INTERVAL {
+            telemetry::log_counters(&link.counters());
+            last_counters = Instant::now();
+        }
+        if stamped && last_sync.is_none_or(|t| t.elapsed() >= SYNC_INTERVAL) {
+            // A lost exchange only means the estimate is a bit older for the next packets
+            if let Ok(exchange) = sync::sync(link, clock, SYNC_TIMEOUT).await {
+                telemetry::log_sync(&exchange, clock);
+            }
+            last_sync = Some(Instant::now());
+        }
+        if params.pattern {
+            pattern::write(&mut packet, i, config.tx_address);
+        } else if stamped {
+            sync::stamp(&mut packet, clock, Instant::now());
+        }
+        let res = link.send(&mut packet).await;
+        telemetry::log_send(&res, config.tx_address, config.channel);
+        Timer::after_micros(params.interval_us as u64).await;
+        i += 1;
+    }
+}
+
+async fn send_pings<L: LinkLayer>(link: &mut L, params: &RunParams) {
+    let mut seq = 0;
+    let mut last_counters = Instant::now();
+    while params.count == 0 || seq < params.count {
+        if last_counters.elapsed() >= COUNTERS_INTERVAL {
+            telemetry::log_counters(&link.counters());
+            last_counters = Instant::now();
+        }
+        if let Ok(info) = ping::ping(link, seq, params.payload_len as usize, PING_TIMEOUT).await {
+            telemetry::log_ping(&info);
+        }
+        Timer::after_micros(params.interval_us as u64).await;
+        seq += 1;
+    }
+}
+
+/// Handles commands that arrive while a run is going, returns once the host asks to stop
+async fn wait_for_stop(params: &RunParams) {
+    loop {
+        let command = COMMANDS.receive().await;
+        match command {
+            Command::Stop => {
+                telemetry::send(Record::Ack(command.kind())).await;
+                return;
+            }
+            Command::Status => {
+                telemetry::send(Record::Status(Status {
+                    driver: DRIVER,
+                    running: true,
+                    params: *params,
+                }))
+                .await
+            }
+            _ => telemetry::send(nack(&command, NackReason::Busy)).await,
+        }
+    }
+}
+
+/// Runs whatever the host asks for on `link`. `config` provides the addresses, the data rate and
+/// channel get replaced by the ones in the run params
+pub async fn serve<L: LinkLayer>(link: &mut L, mut config: LinkConfig) -> ! {
+    let mut params = RunParams {
+        driver: DRIVER,
+        data_rate: config.data_rate,
+        channel: config.channel,
+        ..Default::default()
+    };
+    let mut clock = ClockSync::new();
+    loop {
+        let command = COMMANDS.receive().await;
+        let reply = match command {
+            Command::Status => Record::Status(Status {
+                driver: DRIVER,
+                running: false,
+                params,
+            }),
+            Command::Configure(p) if p.driver != DRIVER => nack(&command, NackReason::WrongDriver),
+            Command::Configure(p)
+                if p.payload_len > MAX_PAYLOAD_LEN
+                    || (p.pattern && (p.payload_len as usize) < pattern::HEADER_SIZE) =>
+            {
+                nack(&command, NackReason::InvalidParams)
+            }
+            Command::Configure(p) => {
+                params = p;
+                config.data_rate = p.data_rate;
+                config.channel = p.channel;
+                link.configure(&config);
+                Record::Ack(command.kind())
+            }
+            Command::Start => {
+                telemetry::send(Record::Ack(command.kind())).await;
+                let before = link.stats();
+                select(
+                    send_packets(link, &params, &config, &mut clock),
+                    wait_for_stop(&params),
+                )
+                .await;
+                let after = link.stats();
+                Record::RunDone {
+                    sent: after.packets_sent - before.packets_sent,
+                    retranmisisons: after.retranmisisons - before.retranmisisons,
+                }
+            }
+            Command::Ping => {
+                telemetry::send(Record::Ack(command.kind())).await;
+                let before = link.stats();
+                select(send_pings(link, &params), wait_for_stop(&params)).await;
+                let after = link.stats();
+                Record::RunDone {
+                    sent: after.packets_sent - before.packets_sent,
+                    retranmisisons: after.retranmisisons - before.retranmisisons,
+                }
+            }
+            Command::Stream { duration_ms } => {
+                telemetry::send(Record::Ack(command.kind())).await;
+                let mut stats = LatencyStats::new();
+                let start = Instant::now();
+                select(
+                    stream::stream(
+                        link,
+                        params.payload_len as usize,
+                        Duration::from_millis(duration_ms as u64),
+                        &mut stats,
+                    ),
+                    wait_for_stop(&params),
+                )
+                .await;
+                Record::StreamDone(stream::summarize(&params, start.elapsed(), &stats))
+            }
+            Command::Counters => {
+                telemetry::send_counters(&link.counters()).await;
+                Record::Ack(command.kind())
+            }
+            Command::Stop => nack(&command, NackReason::NotRunning),
+            Command::RunPlan(plan) if plan.cells_for(DRIVER) > MAX_CELLS => {
+                nack(&command, NackReason::PlanTooLarge)
+            }
+            Command::RunPlan(plan) => {
+                telemetry::send(Record::Ack(command.kind())).await;
+                let mut results = Results::new();
+                select(
+                    bench::run_plan(link, config, &plan, &mut results),
+                    wait_for_stop(&params),
+                )
+                .await;
+                // The plan may have switched data rates
+                link.configure(&config);
+                for summary in results.iter() {
+                    telemetry::send(Record::CellResult(*summary)).await;
+                }
+                Record::PlanDone {
+                    cells: results.len() as u16,
+                    skipped: (plan.len() - plan.cells_for(DRIVER)) as u16,
+                }
+            }
+        };
+        telemetry::send(reply).await;
+    }
+}
@@ -1,4 +1,15 @@
 #![no_std]
 
+pub mod battery;
+pub mod bench;
+pub mod control;
+pub mod link;
+pub mod macro_store;
+pub mod ping;
 pub mod radio;
+pub mod stats;
+pub mod stream;
+pub mod sync;
+pub mod telemetry;
+pub mod timeline;
 pub mod trad_radio;
@@ -0,0 +1,156 @@
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+
+pub use latency_proto::{control::DataRate, record::PeerCounters};
+
+use crate::radio::{LogInfo, Packet};
+
+#[cfg(not(feature = "trad"))]
+pub type Backend<'d> = crate::radio::Radio<'d>;
+#[cfg(feature = "trad")]
+pub type Backend<'d> = crate::trad_radio::TradRadio<'d>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LinkConfig {
+    pub data_rate: DataRate,
+    /// Frequency offset from 2400 MHz in MHz
+    pub channel: u8,
+    /// Logical address used for transmission
+    pub tx_address: u8,
+    /// Bitmask of logical addresses the radio listens on
+    pub rx_addresses: u8,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            data_rate: DataRate::Nrf1Mbit,
+            channel: 80,
+            tx_address: 0,
+            rx_addresses: 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Timeout;
+
+/// Logical address of the other end of a link
+pub type Peer = u8;
+pub const NUM_PEERS: usize = 8;
+
+/// Cumulative counters of a driver, kept for every logical address
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LinkCounters {
+    pub peers: [PeerCounters; NUM_PEERS],
+    /// Time spent listening for data frames, which isn't tied to any peer
+    pub listen_us: u64,
+}
+
+impl LinkCounters {
+    pub const fn new() -> Self {
+        const EMPTY: PeerCounters = PeerCounters {
+            frames_sent: 0,
+            frames_received: 0,
+            acks_sent: 0,
+            acks_received: 0,
+            ack_timeouts: 0,
+            crc_failures: 0,
+            duplicates: 0,
+            unknown_type: 0,
+            bytes_on_air: 0,
+            radio_on_us: 0,
+        };
+        Self {
+            peers: [EMPTY; NUM_PEERS],
+            listen_us: 0,
+        }
+    }
+
+    pub fn peer(&self, peer: Peer) -> &PeerCounters {
+        &self.peers[peer as usize % NUM_PEERS]
+    }
+
+    pub(crate) fn peer_mut(&mut self, peer: Peer) -> &mut PeerCounters {
+        &mut self.peers[peer as usize % NUM_PEERS]
+    }
+
+    /// Counters of every peer added up
+    pub fn total(&self) -> PeerCounters {
+        let mut res = PeerCounters::default();
+        for peer in self.peers.iter() {
+            res.merge(peer);
+        }
+        res
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LinkStats {
+    pub packets_sent: u32,
+    pub packets_received: u32,
+    pub retranmisisons: u32,
+}
+
+/// Common interface over the polled [`Radio`](crate::radio::Radio) and the isr driven
+/// [`TradRadio`](crate::trad_radio::TradRadio) so test code can be written once and run against
+/// either driver
+#[allow(async_fn_in_trait)]
+pub trait LinkLayer {
+    /// Sends the packet and waits until it has been acked by the receiver
+    async fn send(&mut self, packet: &mut Packet) -> LogInfo;
+
+    /// Waits for the next data packet that wasn't already received
+    async fn receive(&mut self, packet: &mut Packet);
+
+    /// Same as [`Self::send`] but gives up after `timeout`, the packet may or may not have
+    /// reached the receiver then. Relies on `send` being cancel safe like `receive`.
+    async fn send_with_timeout(
+        &mut self,
+        packet: &mut Packet,
+        timeout: Duration,
+    ) -> Result<LogInfo, Timeout> {
+        match select(Timer::after(timeout), self.send(packet)).await {
+            Either::First(_) => Err(Timeout),
+            Either::Second(info) => Ok(info),
+        }
+    }
+
+    /// Same as [`Self::receive`] but gives up after `timeout`. Relies on `receive` being cancel
+    /// safe, which both drivers guarantee.
+    async fn receive_with_timeout(
+        &mut self,
+        packet: &mut Packet,
+        timeout: Duration,
+    ) -> Result<(), Timeout> {
+        match select(Timer::after(timeout), self.receive(packet)).await {
+            Either::First(_) => Err(Timeout),
+            Either::Second(_) => Ok(()),
+        }
+    }
+
+    fn configure(&mut self, config: &LinkConfig);
+
+    fn stats(&self) -> LinkStats;
+
+    /// Snapshot of the per peer counters
+    fn counters(&self) -> LinkCounters;
+}
+
+/// Writes the parts of the radio config that can change at runtime. Should only be called while
+/// the radio is disabled
+pub(crate) fn apply_config(config: &LinkConfig) {
+    use embassy_nrf::pac::radio::vals::Mode;
+
+    let r = embassy_nrf::pac::RADIO;
+    let mode = match config.data_rate {
+        DataRate::Nrf1Mbit => Mode::NRF_1MBIT,
+        DataRate::Nrf2Mbit => Mode::NRF_2MBIT,
+        DataRate::Ble1Mbit => Mode::BLE_1MBIT,
+        DataRate::Ble2Mbit => Mode::BLE_2MBIT,
+    };
+    r.mode().write(|w| w.set_mode(mode));
+    r.frequency().write(|w| w.set_frequency(config.channel));
+    r.txaddress().write(|w| w.set_txaddress(config.tx_address));
+    r.rxaddresses().write(|w| w.0 = config.rx_addresses as u32);
+}
@@ -0,0 +1,96 @@
+//! Macros kept in flash.
+//!
+//! Each macro is stored under its id as the bytes [`keyboard::macros`] plays back, in a
+//! [`sequential_storage::map`] over the flash `memory.x` leaves out past the application. Writing
+//! and erasing stall the cpu, radio interrupts included, so macros are read into ram once and
+//! played from there, and only stored while nobody is typing.
+
+use core::ops::Range;
+
+use embassy_embedded_hal::adapter::BlockingAsync;
+use embassy_nrf::{
+    nvmc::{self, Nvmc},
+    peripherals::NVMC,
+    Peri,
+};
+use keyboard::macros::{self, MAX_LEN};
+use sequential_storage::{cache::NoCache, map};
+
+/// Two pages right below 0xFF000, the end of `FLASH` in `memory.x`
+const RANGE: Range<u32> = 0xFD000..0xFF000;
+
+/// Room for the id and the longest macro, rounded up to flash words
+const BUF_LEN: usize = (1 + MAX_LEN).next_multiple_of(4);
+
+/// The nvmc only writes from word aligned buffers
+#[repr(align(4))]
+struct Buffer([u8; BUF_LEN]);
+
+#[derive(Debug)]
+pub enum Error {
+    Flash(sequential_storage::Error<nvmc::Error>),
+    /// What's stored isn't a valid macro
+    Macro(macros::DecodeError),
+}
+
+pub struct MacroStore<'d> {
+    flash: BlockingAsync<Nvmc<'d>>,
+    cache: NoCache,
+    buf: Buffer,
+}
+
+impl<'d> MacroStore<'d> {
+    pub fn new(nvmc: Peri<'d, NVMC>) -> Self {
+        Self {
+            flash: BlockingAsync::new(Nvmc::new(nvmc)),
+            cache: NoCache::new(),
+            buf: Buffer([0; BUF_LEN]),
+        }
+    }
+
+    /// Reads macro `id` into `out`, returning its length, `None` if it was never stored
+    pub async fn load(&mut self, id: u8, out: &mut [u8; MAX_LEN]) -> Result<Option<usize>, Error> {
+        let bytes = map::fetch_item::<u8, &[u8], _>(
+            &mut self.flash,
+            RANGE,
+            &mut self.cache,
+            &mut self.buf.0,
+            &id,
+        )
+        .await
+        .map_err(Error::Flash)?;
+        let Some(bytes) = bytes else {
+            return Ok(None);
+        };
+        macros::decode(bytes).map_err(Error::Macro)?;
+        out[..bytes.len()].copy_from_slice(bytes);
+        Ok(Some(bytes.len()))
+    }
+
+    /// Stores `bytes` as macro `id`, replacing the one stored before
+    pub async fn store(&mut self, id: u8, bytes: &[u8]) -> Result<(), Error> {
+        macros::decode(bytes).map_err(Error::Macro)?;
+        map::store_item(
+            &mut self.flash,
+            RANGE,
+            &mut self.cache,
+            &mut self.buf.0,
+            &id,
+            &bytes,
+        )
+        .await
+        .map_err(Error::Flash)
+    }
+
+    pub async fn remove(&mut self, id: u8) -> Result<(), Error> {
+        map::remove_item(
+            &mut self.flash,
+            RANGE,
+            &mut self.cache,
+            &mut self.buf.0,
+            &id,
+        )
+        .await
+        .map_err(Error::Flash)
+    }
+}
@@ -0,0 +1,85 @@
+//! Application level round trips. The sender puts a ping in a data packet and the application on
+//! the other side hands it back with [`echo`] as a data packet of its own, so the measured time
+//! includes the receiver's task scheduling on top of the link level ack turnaround.
+
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    link::{LinkLayer, Timeout},
+    radio::{LogInfo, Packet, BUFFER_SIZE},
+};
+
+const PING: u8 = 0xB0;
+const PONG: u8 = 0xB1;
+/// Marker, sequence number and send time, anything after that is padding
+pub const PING_SIZE: usize = 1 + 4 + 8;
+
+pub struct PingInfo {
+    pub seq: u32,
+    /// From handing the ping to the link until the pong came back
+    pub rtt: Duration,
+    /// Link level result of sending the ping
+    pub ping: LogInfo,
+    /// Rssi of the pong in dBm
+    pub rssi: i8,
+}
+
+fn write_ping(packet: &mut Packet, marker: u8, seq: u32, sent_ticks: u64, len: usize) {
+    let mut buf = [0u8; BUFFER_SIZE];
+    buf[0] = marker;
+    buf[1..5].copy_from_slice(&seq.to_le_bytes());
+    buf[5..PING_SIZE].copy_from_slice(&sent_ticks.to_le_bytes());
+    packet.copy_from_slice(&buf[..len.clamp(PING_SIZE, BUFFER_SIZE)]);
+}
+
+/// Sequence number and send time in ticks if the packet carries `marker`
+fn read_ping(packet: &Packet, marker: u8) -> Option<(u32, u64)> {
+    if packet.len() < PING_SIZE || packet[0] != marker {
+        return None;
+    }
+    let seq = u32::from_le_bytes(packet[1..5].try_into().unwrap());
+    let sent_ticks = u64::from_le_bytes(packet[5..PING_SIZE].try_into().unwrap());
+    Some((seq, sent_ticks))
+}
+
+pub fn is_ping(packet: &Packet) -> bool {
+    read_ping(packet, PING).is_some()
+}
+
+/// Sends ping `seq` padded to `payload_len` bytes and waits up to `timeout` for its pong. Pongs
+/// of earlier pings that timed out are discarded while waiting.
+pub async fn ping<L: LinkLayer>(
+    link: &mut L,
+    seq: u32,
+    payload_len: usize,
+    timeout: Duration,
+) -> Result<PingInfo, Timeout> {
+    let mut packet = Packet::default();
+    let start = Instant::now();
+    write_ping(&mut packet, PING, seq, start.as_ticks(), payload_len);
+    let info = link.send(&mut packet).await;
+    let deadline = start + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        link.receive_with_timeout(&mut packet, left).await?;
+        if let Some((pong_seq, sent_ticks)) = read_ping(&packet, PONG) {
+            if pong_seq == seq && sent_ticks == start.as_ticks() {
+                return Ok(PingInfo {
+                    seq,
+                    rtt: Instant::now() - start,
+                    ping: info,
+                    rssi: packet.rssi,
+                });
+            }
+        }
+    }
+}
+
+/// Sends `packet` back as a pong if it's a ping, keeping its length. Returns the link level result
+/// of the pong or `None` if the packet wasn't a ping.
+pub async fn echo<L: LinkLayer>(link: &mut L, packet: &mut Packet) -> Option<LogInfo> {
+    let (seq, sent_ticks) = read_ping(packet, PING)?;
+    let len = packet.len();
+    write_ping(packet, PONG, seq, sent_ticks, len);
+    Some(link.send(packet).await)
+}
@@ -1,8 +1,4 @@
-use core::{
-    future::Future,
-    sync::atomic::{compiler_fence, AtomicBool},
-    task::Poll,
-};
+use core::{cell::Cell, future::Future, sync::atomic::compiler_fence, task::Poll};
 
 use defmt::info;
 use embassy_futures::select::select;
@@ -16,49 +12,94 @@ use embassy_nrf::{
     Peri,
 };
 use embassy_sync::{
-    blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
-    channel::Channel,
-    mutex::{Mutex, MutexGuard},
-    signal::Signal,
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
     waitqueue::AtomicWaker,
 };
 use embassy_time::{Duration, Instant, Timer};
 use heapless::Vec;
+use latency_proto::arq;
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
 
+use crate::{
+    link::{LinkConfig, LinkCounters, LinkLayer, LinkStats, PeerCounters},
+    timeline::{self, Direction, FrameTimeline},
+};
+
 pub const DONGLE_ADDRESS: u32 = 0x0A55_0A55;
 pub const DONGLE_PREFIX: u8 = 0x42;
 pub const KEYBOARD_ADDRESS: u32 = 0x0727_0727;
 pub const LEFT_PREFIX: u8 = 0x21;
 pub const RIGHT_PREFIX: u8 = 0x25;
-
-const BUFFER_SIZE: usize = 32;
-const META_SIZE: usize = 3;
+/// Logical addresses of the prefixes above in [`Addresses::default`]. Acks go out on the sender's
+/// own tx address, so every device sends on its own address and listens on its peers'. The dongle
+/// sends on `DONGLE_PIPE` and both halves listen there, each half sends on its own pipe and the
+/// dongle listens on both.
+pub const DONGLE_PIPE: u8 = 0;
+pub const LEFT_PIPE: u8 = 1;
+pub const RIGHT_PIPE: u8 = 2;
+
+/// Largest payload a packet can carry
+pub const BUFFER_SIZE: usize = latency_proto::control::MAX_PAYLOAD_LEN as usize;
+/// Length field, id and type
+const META_SIZE: usize = 1 + arq::META_LEN as usize;
+const _: () = assert!(BUFFER_SIZE + META_SIZE == 1 + arq::MAX_LEN_FIELD as usize);
 
 static STATE: AtomicWaker = AtomicWaker::new();
-
-const NUM_PACKETS: usize = 20;
-
-static DATA: Mutex<CriticalSectionRawMutex, Packet> = Mutex::new(Packet::default());
-
-static REQUESTS: Channel<CriticalSectionRawMutex, Direction, NUM_PACKETS> = Channel::new();
-
-static RECV_CHANNEL: Channel<CriticalSectionRawMutex, Packet, NUM_PACKETS> = Channel::new();
-static SEND_CHANNEL: Channel<CriticalSectionRawMutex, Packet, NUM_PACKETS> = Channel::new();
+/// Ticks when the READY event of the last transmission was handled
+static TX_READY: Mutex<CriticalSectionRawMutex, Cell<u64>> = Mutex::new(Cell::new(0));
 
 pub struct InterruptHandler {}
 
 impl interrupt::typelevel::Handler<typelevel::RADIO> for InterruptHandler {
     unsafe fn on_interrupt() {
         let r = embassy_nrf::pac::RADIO;
+        if r.events_ready().read() != 0 {
+            r.events_ready().write_value(0);
+            TX_READY.lock(|t| t.set(Instant::now().as_ticks()));
+        }
         r.intenclr().write(|w| w.0 = 0xFFFF_FFFF);
         STATE.wake();
     }
 }
 
+/// Attempts whose timing is kept in [`LogInfo::attempts`], later ones are only counted
+pub const MAX_ATTEMPTS: usize = 8;
+
+/// Why an attempt didn't count as acked
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RetryReason {
+    /// Nothing valid arrived before the ack deadline
+    Timeout,
+    /// Something arrived but failed the crc check
+    CrcError,
+    /// A valid packet arrived that wasn't the ack for this packet
+    WrongId,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Attempt {
+    /// From starting the transmission until the ack arrived or the attempt was given up
+    pub duration: Duration,
+    /// `None` for the attempt that got acked
+    pub retry_reason: Option<RetryReason>,
+}
+
+#[derive(Clone, Debug)]
 pub struct LogInfo {
     pub retranmisisons: u32,
+    /// Time of the last attempt only, from its transmission until the ack
     pub time_elapsed: Duration,
+    /// From the send call until the radio was ready to put the first attempt on air
+    pub time_to_first_transmit: Duration,
+    /// From the send call until the ack arrived, including every retransmission
+    pub total_time: Duration,
+    /// The first [`MAX_ATTEMPTS`] attempts in order
+    pub attempts: Vec<Attempt, MAX_ATTEMPTS>,
+    /// Rssi of the ack in dBm
+    pub rssi: i8,
+    /// Hardware timestamps of the acked attempt's data frame and its ack, empty without the
+    /// `timeline` feature
+    pub timeline: Vec<FrameTimeline, 2>,
 }
 
 #[derive(Clone, Copy)]
@@ -86,8 +127,10 @@ pub struct Radio<'d> {
     _radio: Peri<'d, embassy_nrf::peripherals::RADIO>,
     tx_addreses: u8,
     rx_addresses: u32,
-    rx_id: [u8; 8],
-    tx_id: u8,
+    rx: arq::Receiver<Packet>,
+    tx: arq::Sender,
+    stats: LinkStats,
+    counters: LinkCounters,
 }
 
 impl<'d> Radio<'d> {
@@ -116,7 +159,7 @@ impl<'d> Radio<'d> {
         });
 
         r.pcnf1().write(|w| {
-            w.set_maxlen(BUFFER_SIZE as u8);
+            w.set_maxlen(arq::MAX_LEN_FIELD);
             w.set_statlen(0);
             w.set_balen(4);
             w.set_endian(embassy_nrf::pac::radio::vals::Endian::LITTLE);
@@ -156,118 +199,260 @@ impl<'d> Radio<'d> {
             _radio,
             rx_addresses: 0,
             tx_addreses: 0,
-            rx_id: [0u8; 8],
-            tx_id: 0u8,
+            rx: arq::Receiver::new(),
+            tx: arq::Sender::new(),
+            stats: LinkStats::default(),
+            counters: LinkCounters::new(),
         }
     }
 
-    async fn transmit_ack(&mut self, id: u8) {
-        let mut packet = Packet::default();
-        packet.set_type(PacketType::Ack);
-        packet.set_len(1);
-        packet.set_id(id);
-        self.send_inner(&mut packet).await;
+    /// Sends the packet, retransmitting until it's acked.
+    ///
+    /// Cancel safe, see [`arq::send`]: if the future is dropped the radio is disabled before the
+    /// drop returns and `PACKETPTR` no longer refers to `packet` or any other buffer owned by the
+    /// future. The packet may or may not have reached the receiver. Its id is never reused, so a
+    /// following send won't be discarded as a duplicate.
+    pub async fn send(&mut self, packet: &mut Packet) -> LogInfo {
+        let call = Instant::now();
+        packet.set_type(PacketType::Data);
+        let mut phy = RadioSendPhy {
+            counters: self.counters.peer_mut(self.tx_addreses),
+            call,
+            start: call,
+            end: call,
+            first_transmit: None,
+            data_frame: None,
+            attempts: Vec::new(),
+        };
+        let ((rssi, ack_frame), retranmisisons) = arq::send(&mut self.tx, &mut phy, packet).await;
+        self.stats.packets_sent += 1;
+        self.stats.retranmisisons += retranmisisons;
+        LogInfo {
+            retranmisisons,
+            time_elapsed: phy.end - phy.start,
+            time_to_first_transmit: phy.first_transmit.unwrap_or_default(),
+            total_time: phy.end - call,
+            attempts: phy.attempts,
+            rssi,
+            timeline: phy.data_frame.into_iter().chain(ack_frame).collect(),
+        }
     }
 
-    async fn await_ack(&mut self, id: u8) -> Result<(), ()> {
-        let r = embassy_nrf::pac::RADIO;
-        let mut packet = Packet::default();
-        let addr = self.tx_addreses;
-        r.packetptr().write_value(packet.buffer.as_mut_ptr() as u32);
-        let receive_task = async {
-            loop {
-                if ReceiveFuture::new(&mut packet).await.is_ok()
-                    && packet.packet_type().unwrap() == PacketType::Ack
-                    && packet.id() == id
-                {
-                    break;
-                };
-            }
+    /// Waits for the next data packet that isn't a duplicate and acks it.
+    ///
+    /// Cancel safe, see [`arq::receive`]: if the future is dropped the radio is disabled before
+    /// the drop returns. A packet caught by a dropped receive is either retransmitted by the
+    /// sender or, once its ack went out, kept and returned by the next call. `packet` may hold
+    /// partial data after a drop.
+    pub async fn receive(&mut self, packet: &mut Packet) {
+        let mut phy = RadioPhy {
+            counters: &mut self.counters,
         };
-        match select(Timer::after_micros(300), receive_task).await {
-            embassy_futures::select::Either::First(_) => Err(()),
-            embassy_futures::select::Either::Second(_) => Ok(()),
-        }
+        arq::receive(&mut self.rx, &mut phy, packet).await;
+        self.stats.packets_received += 1;
     }
 
-    pub async fn send(&mut self, packet: &mut Packet) -> LogInfo {
-        self.tx_id = self.tx_id.wrapping_add(1);
-        packet.set_id(self.tx_id);
-        packet.set_type(PacketType::Data);
-        let mut i = 0;
-        loop {
-            let start = Instant::now();
-            self.send_inner(packet).await;
-            if self.await_ack(packet.id()).await.is_ok() {
-                let end = Instant::now();
-                return LogInfo {
-                    retranmisisons: i,
-                    time_elapsed: end - start,
-                };
-            } else {
-                i += 1;
-            }
-        }
+    pub fn set_tx_addresses(&mut self, f: impl FnOnce(&mut Txaddress)) {
+        let r = embassy_nrf::pac::RADIO;
+        r.txaddress().write(f);
+        self.tx_addreses = r.txaddress().read().txaddress();
     }
 
-    pub async fn receive(&mut self, packet: &mut Packet) {
+    pub fn set_rx_addresses(&mut self, f: impl FnOnce(&mut Rxaddresses)) {
         let r = embassy_nrf::pac::RADIO;
+        r.rxaddresses().write(f);
+        self.rx_addresses = r.rxaddresses().read().0;
+    }
+}
+
+/// From `call` until the radio was ready to put the first frame on air. Uses the hardware ramp up
+/// of `frame`, started at `start`, when the `timeline` feature captured it, otherwise `ready`,
+/// when the READY event was handled
+pub(crate) fn time_to_first_transmit(
+    call: Instant,
+    start: Instant,
+    ready: Instant,
+    frame: Option<&FrameTimeline>,
+) -> Duration {
+    match frame.and_then(|f| f.ramp_up_ns()) {
+        Some(ns) => start.saturating_duration_since(call) + Duration::from_nanos(ns as u64),
+        None => ready.saturating_duration_since(call),
+    }
+}
+
+async fn transmit(packet: &Packet) -> Option<FrameTimeline> {
+    TransmitFuture::new(packet).await;
+    compiler_fence(core::sync::atomic::Ordering::Acquire);
+    timeline::finish(Direction::Tx)
+}
+
+/// Returns the rssi and timeline of the ack if it arrived in time, otherwise why the last
+/// thing received in the meantime wasn't the ack
+async fn await_ack(
+    counters: &mut PeerCounters,
+    id: u8,
+) -> Result<(i8, Option<FrameTimeline>), RetryReason> {
+    let mut packet = Packet::default();
+    let mut reason = RetryReason::Timeout;
+    let mut frame = None;
+    let receive_task = async {
         loop {
-            let res = ReceiveFuture::new(packet).await;
-            info!("Packet: {} | {}", packet.id(), packet.packet_type().is_ok());
-            if res.is_ok() && packet.packet_type().unwrap() == PacketType::Data {
-                let addr = r.rxmatch().read().rxmatch();
-                self.transmit_ack(packet.id()).await;
-
-                // If packet_id is the same as the previous id, it must mean that the ack hasn't
-                // gone through so we'll discard the packet on the receiving end but send another
-                // ack to make sure the tx side knows the packet was already received
-                if packet.id() != self.rx_id[addr as usize] {
-                    self.rx_id[addr as usize] = packet.id();
-                    packet.addr = addr;
-                    return;
+            let res = ReceiveFuture::new(&mut packet).await;
+            frame = timeline::finish(Direction::Rx);
+            match res {
+                Ok(())
+                    if matches!(packet.packet_type(), Ok(PacketType::Ack)) && packet.id() == id =>
+                {
+                    counters.acks_received += 1;
+                    counters.bytes_on_air += packet.on_air_len();
+                    break;
+                }
+                Ok(()) => {
+                    if packet.packet_type().is_err() {
+                        counters.unknown_type += 1;
+                    }
+                    reason = RetryReason::WrongId
+                }
+                Err(()) => {
+                    counters.crc_failures += 1;
+                    reason = RetryReason::CrcError
                 }
             }
         }
+    };
+    let res = select(Timer::after_micros(300), receive_task).await;
+    match res {
+        embassy_futures::select::Either::First(_) => Err(reason),
+        embassy_futures::select::Either::Second(_) => Ok((packet.rssi, frame)),
     }
+}
 
-    async fn send_inner(&mut self, packet: &mut Packet) {
-        let r = embassy_nrf::pac::RADIO;
+/// The radio registers as seen by [`arq::send`], timing and counting every attempt
+struct RadioSendPhy<'a> {
+    counters: &'a mut PeerCounters,
+    call: Instant,
+    /// When the current attempt started
+    start: Instant,
+    /// When the current attempt was acked or given up
+    end: Instant,
+    first_transmit: Option<Duration>,
+    /// Timeline of the current attempt's data frame
+    data_frame: Option<FrameTimeline>,
+    attempts: Vec<Attempt, MAX_ATTEMPTS>,
+}
 
-        r.packetptr().write_value(packet.buffer.as_ptr() as u32);
-        r.shorts().write(|w| {
-            w.set_ready_start(true);
-            w.set_end_disable(true);
+impl arq::SendPhy for RadioSendPhy<'_> {
+    type Packet = Packet;
+    /// Rssi and timeline of the ack
+    type Ack = (i8, Option<FrameTimeline>);
+
+    fn set_id(packet: &mut Packet, id: u8) {
+        packet.set_id(id);
+    }
+
+    async fn transmit_frame(&mut self, packet: &Packet) {
+        self.start = Instant::now();
+        self.data_frame = transmit(packet).await;
+        let (call, start) = (self.call, self.start);
+        let frame = self.data_frame.as_ref();
+        self.first_transmit.get_or_insert_with(|| {
+            let ready = Instant::from_ticks(TX_READY.lock(|t| t.get()));
+            time_to_first_transmit(call, start, ready, frame)
         });
+        self.counters.frames_sent += 1;
+        self.counters.bytes_on_air += packet.on_air_len();
+    }
 
-        compiler_fence(core::sync::atomic::Ordering::Release);
-        r.tasks_txen().write_value(1);
-        r.intenclr().write(|w| w.0 = 0xFFFF_FFFF);
-        core::future::poll_fn(|cx| {
-            STATE.register(cx.waker());
-            if r.events_disabled().read() != 0 {
-                r.events_disabled().write_value(0);
-                Poll::Ready(())
-            } else {
-                r.intenset().write(|w| w.set_disabled(true));
-                Poll::Pending
+    async fn await_ack(&mut self, id: u8) -> Option<Self::Ack> {
+        let res = await_ack(self.counters, id).await;
+        self.end = Instant::now();
+        let duration = self.end - self.start;
+        self.counters.radio_on_us += duration.as_micros();
+        if res.is_err() {
+            self.counters.ack_timeouts += 1;
+        }
+        // Past MAX_ATTEMPTS the attempts are only counted
+        let _ = self.attempts.push(Attempt {
+            duration,
+            retry_reason: res.err(),
+        });
+        res.ok()
+    }
+}
+
+/// The radio registers as seen by [`arq::receive`], counting every frame on the way
+struct RadioPhy<'a> {
+    counters: &'a mut LinkCounters,
+}
+
+impl arq::Phy for RadioPhy<'_> {
+    type Packet = Packet;
+
+    async fn receive_frame(&mut self, packet: &mut Packet) -> Option<(u8, u8)> {
+        let listen = Instant::now();
+        let res = ReceiveFuture::new(packet).await;
+        timeline::finish(Direction::Rx);
+        self.counters.listen_us += listen.elapsed().as_micros();
+        info!("Packet: {} | {}", packet.id(), packet.packet_type().is_ok());
+        let c = self.counters.peer_mut(packet.addr);
+        if res.is_err() {
+            c.crc_failures += 1;
+            return None;
+        }
+        match packet.packet_type() {
+            Ok(PacketType::Data) => {
+                c.frames_received += 1;
+                c.bytes_on_air += packet.on_air_len();
+                Some((packet.addr, packet.id()))
             }
-        })
-        .await;
+            // A late ack for someone else's packet
+            Ok(PacketType::Ack) => None,
+            Err(_) => {
+                c.unknown_type += 1;
+                None
+            }
+        }
+    }
 
-        compiler_fence(core::sync::atomic::Ordering::Acquire);
+    async fn transmit_ack(&mut self, pipe: u8, id: u8) {
+        let mut packet = Packet::default();
+        packet.set_type(PacketType::Ack);
+        packet.set_len(1);
+        packet.set_id(id);
+        let start = Instant::now();
+        transmit(&packet).await;
+        let c = self.counters.peer_mut(pipe);
+        c.acks_sent += 1;
+        c.bytes_on_air += packet.on_air_len();
+        c.radio_on_us += start.elapsed().as_micros();
     }
 
-    pub fn set_tx_addresses(&mut self, f: impl FnOnce(&mut Txaddress)) {
-        let r = embassy_nrf::pac::RADIO;
-        r.txaddress().write(f);
-        self.tx_addreses = r.txaddress().read().txaddress();
+    fn duplicate(&mut self, pipe: u8) {
+        self.counters.peer_mut(pipe).duplicates += 1;
     }
+}
 
-    pub fn set_rx_addresses(&mut self, f: impl FnOnce(&mut Rxaddresses)) {
-        let r = embassy_nrf::pac::RADIO;
-        r.rxaddresses().write(f);
-        self.rx_addresses = r.rxaddresses().read().0;
+impl<'d> LinkLayer for Radio<'d> {
+    async fn send(&mut self, packet: &mut Packet) -> LogInfo {
+        Radio::send(self, packet).await
+    }
+
+    async fn receive(&mut self, packet: &mut Packet) {
+        Radio::receive(self, packet).await
+    }
+
+    fn configure(&mut self, config: &LinkConfig) {
+        crate::link::apply_config(config);
+        self.tx_addreses = config.tx_address;
+        self.rx_addresses = config.rx_addresses as u32;
+    }
+
+    fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    fn counters(&self) -> LinkCounters {
+        self.counters
     }
 }
 
@@ -282,10 +467,13 @@ impl<'a> ReceiveFuture<'a> {
         r.shorts().write(|w| {
             w.set_ready_start(true);
             w.set_end_disable(true);
+            w.set_address_rssistart(true);
+            w.set_disabled_rssistop(true);
         });
         r.packetptr().write_value(packet.buffer.as_ptr() as u32);
 
         compiler_fence(core::sync::atomic::Ordering::Release);
+        timeline::arm();
         r.tasks_rxen().write_value(1);
         r.intenclr().write(|w| w.0 = 0xFFFF_FFFF);
 
@@ -307,6 +495,7 @@ impl<'a> Future for ReceiveFuture<'a> {
         if r.events_disabled().read() != 0 {
             r.events_disabled().write_value(0);
             self.packet.addr = r.rxmatch().read().rxmatch();
+            self.packet.rssi = read_rssi();
             let res = if r.events_crcok().read() != 0 {
                 r.events_crcok().write_value(0);
                 Ok(())
@@ -325,27 +514,83 @@ impl<'a> Future for ReceiveFuture<'a> {
 impl<'a> Drop for ReceiveFuture<'a> {
     fn drop(&mut self) {
         if !self.complete {
-            let r = embassy_nrf::pac::RADIO;
-            r.tasks_disable().write_value(1);
-            while r.state().read().state() != RadioState::DISABLED {}
+            disable_radio();
+        }
+    }
+}
+
+struct TransmitFuture<'a> {
+    complete: bool,
+    _packet: &'a Packet,
+}
+
+impl<'a> TransmitFuture<'a> {
+    fn new(packet: &'a Packet) -> TransmitFuture<'a> {
+        let r = embassy_nrf::pac::RADIO;
+        r.packetptr().write_value(packet.buffer.as_ptr() as u32);
+        r.shorts().write(|w| {
+            w.set_ready_start(true);
+            w.set_end_disable(true);
+        });
+
+        r.events_ready().write_value(0);
+
+        compiler_fence(core::sync::atomic::Ordering::Release);
+        timeline::arm();
+        r.tasks_txen().write_value(1);
+        r.intenclr().write(|w| w.0 = 0xFFFF_FFFF);
+        r.intenset().write(|w| w.set_ready(true));
+
+        Self {
+            complete: false,
+            _packet: packet,
+        }
+    }
+}
+
+impl<'a> Future for TransmitFuture<'a> {
+    type Output = ();
+    fn poll(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Self::Output> {
+        let r = embassy_nrf::pac::RADIO;
+        STATE.register(cx.waker());
+        if r.events_disabled().read() != 0 {
             r.events_disabled().write_value(0);
+            self.complete = true;
+            Poll::Ready(())
+        } else {
+            r.intenset().write(|w| w.set_disabled(true));
+            Poll::Pending
         }
     }
 }
 
-enum Direction {
-    Tx,
-    Rx,
+impl<'a> Drop for TransmitFuture<'a> {
+    fn drop(&mut self) {
+        if !self.complete {
+            disable_radio();
+        }
+    }
 }
 
-pub async fn send_packet(packet: &Packet) {
-    SEND_CHANNEL.send(*packet).await;
-    REQUESTS.send(Direction::Tx).await;
+/// Last rssi sample in dBm, only valid after a packet was received
+pub(crate) fn read_rssi() -> i8 {
+    let r = embassy_nrf::pac::RADIO;
+    -(r.rssisample().read().rssisample() as i8)
 }
 
-pub async fn receive_packet() -> Packet {
-    REQUESTS.send(Direction::Rx).await;
-    RECV_CHANNEL.receive().await
+/// Stops any ongoing transmission or reception and waits until the radio stopped accessing
+/// memory
+fn disable_radio() {
+    let r = embassy_nrf::pac::RADIO;
+    r.intenclr().write(|w| w.0 = 0xFFFF_FFFF);
+    r.tasks_disable().write_value(1);
+    while r.state().read().state() != RadioState::DISABLED {}
+    r.events_disabled().write_value(0);
+    r.events_crcok().write_value(0);
+    compiler_fence(core::sync::atomic::Ordering::Acquire);
 }
 
 #[repr(u8)]
@@ -358,6 +603,8 @@ pub enum PacketType {
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Packet {
     pub addr: u8,
+    /// Rssi in dBm of the last time this packet was received
+    pub rssi: i8,
     pub buffer: [u8; BUFFER_SIZE + META_SIZE],
 }
 
@@ -369,6 +616,7 @@ impl Packet {
     pub const fn default() -> Self {
         Self {
             addr: 0,
+            rssi: 0,
             buffer: [(META_SIZE - 1) as u8; BUFFER_SIZE + META_SIZE],
         }
     }
@@ -402,6 +650,12 @@ impl Packet {
         self.buffer[Self::TYPE_INDEX] = packet_type as u8;
     }
 
+    /// Bytes the frame takes on air: preamble, 5 byte address, the length field, everything the
+    /// length covers and the crc
+    pub(crate) fn on_air_len(&self) -> u64 {
+        1 + 5 + 1 + self.buffer[Self::LEN_INDEX] as u64 + 2
+    }
+
     pub fn copy_from_slice(&mut self, src: &[u8]) {
         assert!(src.len() <= BUFFER_SIZE);
         self.buffer[META_SIZE..][..src.len()].copy_from_slice(src);
@@ -0,0 +1,19 @@
+//! Feeds sends into the [`LatencyStats`] from `latency_proto::stats`, which the host uses to read
+//! and merge them as well.
+
+pub use latency_proto::stats::*;
+
+use crate::radio::LogInfo;
+
+/// Records [`LogInfo`]s into [`LatencyStats`]
+pub trait RecordSend {
+    /// Records the full latency of the send, from the call until the ack
+    fn record(&mut self, info: &LogInfo);
+}
+
+impl RecordSend for LatencyStats {
+    fn record(&mut self, info: &LogInfo) {
+        let us = info.total_time.as_micros().min(u32::MAX as u64) as u32;
+        self.record_us(us, info.retranmisisons);
+    }
+}
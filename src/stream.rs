@@ -0,0 +1,54 @@
+//! Saturation runs that send packets back to back for a fixed time, see
+//! [`latency_proto::stream`].
+
+use embassy_time::{Duration, Instant};
+use latency_proto::{control::RunParams, stream::StreamSummary};
+
+use crate::{
+    bench::SEND_TIMEOUT,
+    link::{LinkLayer, Timeout},
+    radio::{Packet, BUFFER_SIZE},
+    stats::{LatencyStats, RecordSend},
+};
+
+/// Sends packets of `payload_len` as fast as they get acked until `duration` is over. Every send
+/// is recorded in `stats` as it completes, so the results so far are kept if the future is
+/// dropped part way through. Sends that aren't acked within [`SEND_TIMEOUT`] count as lost.
+pub async fn stream<L: LinkLayer>(
+    link: &mut L,
+    payload_len: usize,
+    duration: Duration,
+    stats: &mut LatencyStats,
+) {
+    let mut payload = [0u8; BUFFER_SIZE];
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let mut packet = Packet::default();
+    packet.copy_from_slice(&payload[..payload_len.min(BUFFER_SIZE)]);
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        match link.send_with_timeout(&mut packet, SEND_TIMEOUT).await {
+            Ok(res) => stats.record(&res),
+            Err(Timeout) => stats.record_loss(),
+        }
+    }
+}
+
+/// `duration` is how long the run actually went
+pub fn summarize(params: &RunParams, duration: Duration, stats: &LatencyStats) -> StreamSummary {
+    StreamSummary {
+        driver: params.driver,
+        data_rate: params.data_rate,
+        payload_len: params.payload_len,
+        duration_us: duration.as_micros().min(u32::MAX as u64) as u32,
+        packets: stats.count(),
+        lost: stats.lost(),
+        retranmisisons: stats.total_retranmisisons().min(u32::MAX as u64) as u32,
+        mean_us: stats.mean_us().unwrap_or(0),
+        p50_us: stats.percentile(0.5).unwrap_or(0),
+        p99_us: stats.percentile(0.99).unwrap_or(0),
+        max_us: stats.max_us().unwrap_or(0),
+        jitter_us: stats.jitter_us().unwrap_or(0),
+    }
+}
@@ -0,0 +1,102 @@
+//! Time sync between the two ends of a link so one way latency can be measured.
+//!
+//! The side that sends data keeps a [`ClockSync`] of the other side's clock, fed by request and
+//! response exchanges carried in data packets the same way [`ping`](crate::ping) is. Data packets
+//! can then be stamped with their capture time in the receiver's clock, which the receiver
+//! subtracts from its own time of reception.
+
+use embassy_time::{Duration, Instant};
+use latency_proto::sync::{ClockSync, Exchange};
+
+use crate::{
+    link::{LinkLayer, Timeout},
+    radio::{LogInfo, Packet, BUFFER_SIZE},
+};
+
+const REQUEST: u8 = 0xC0;
+const RESPONSE: u8 = 0xC1;
+const STAMPED: u8 = 0xC2;
+/// Marker and capture time, a data packet needs at least this much payload to be stamped
+pub const STAMP_SIZE: usize = 1 + 8;
+
+fn write_times(packet: &mut Packet, marker: u8, times: &[u64]) {
+    let mut buf = [0u8; BUFFER_SIZE];
+    buf[0] = marker;
+    for (chunk, t) in buf[1..].chunks_exact_mut(8).zip(times) {
+        chunk.copy_from_slice(&t.to_le_bytes());
+    }
+    packet.copy_from_slice(&buf[..1 + 8 * times.len()]);
+}
+
+fn read_times<const N: usize>(packet: &Packet, marker: u8) -> Option<[u64; N]> {
+    if packet.len() < 1 + 8 * N || packet[0] != marker {
+        return None;
+    }
+    let mut res = [0u64; N];
+    for (t, chunk) in res.iter_mut().zip(packet[1..].chunks_exact(8)) {
+        *t = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    Some(res)
+}
+
+/// Runs one exchange with the other side and adds it to `clock`. Responses to earlier requests
+/// that timed out are discarded while waiting.
+pub async fn sync<L: LinkLayer>(
+    link: &mut L,
+    clock: &mut ClockSync,
+    timeout: Duration,
+) -> Result<Exchange, Timeout> {
+    let mut packet = Packet::default();
+    let start = Instant::now();
+    let t1 = start.as_micros();
+    write_times(&mut packet, REQUEST, &[t1]);
+    link.send(&mut packet).await;
+    let deadline = start + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        link.receive_with_timeout(&mut packet, left).await?;
+        let t4 = Instant::now().as_micros();
+        if let Some([t1_echo, t2, t3]) = read_times(&packet, RESPONSE) {
+            if t1_echo == t1 {
+                let exchange = Exchange { t1, t2, t3, t4 };
+                clock.add_exchange(&exchange);
+                return Ok(exchange);
+            }
+        }
+    }
+}
+
+/// Answers `packet` if it's a sync request. `received` should be taken as soon as the packet was
+/// handed over by the link. Returns the link level result of the response or `None` if the
+/// packet wasn't a request.
+pub async fn respond<L: LinkLayer>(
+    link: &mut L,
+    packet: &mut Packet,
+    received: Instant,
+) -> Option<LogInfo> {
+    let [t1] = read_times(packet, REQUEST)?;
+    let t3 = Instant::now().as_micros();
+    write_times(packet, RESPONSE, &[t1, received.as_micros(), t3]);
+    Some(link.send(packet).await)
+}
+
+/// Puts the capture time in the other side's clock at the start of the payload. Leaves the packet
+/// alone and returns false if it's too short or the clock isn't synced yet.
+pub fn stamp(packet: &mut Packet, clock: &ClockSync, captured: Instant) -> bool {
+    let Some(remote) = clock.to_remote(captured.as_micros()) else {
+        return false;
+    };
+    if packet.len() < STAMP_SIZE {
+        return false;
+    }
+    packet[0] = STAMPED;
+    packet[1..STAMP_SIZE].copy_from_slice(&remote.to_le_bytes());
+    true
+}
+
+/// One way latency in us of a stamped packet received at `received`. Can come out slightly
+/// negative when the sync error is larger than the latency.
+pub fn one_way(packet: &Packet, received: Instant) -> Option<i64> {
+    let [captured] = read_times(packet, STAMPED)?;
+    Some(received.as_micros() as i64 - captured as i64)
+}
@@ -0,0 +1,176 @@
+//! Binary telemetry stream to the host over usb serial.
+//!
+//! Records are queued from anywhere with [`log`] and written out as frames from
+//! [`latency_proto::frame`] by [`run`]. If the host isn't reading, records are dropped instead of
+//! blocking the radio and the number dropped is reported once the host catches up.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Instant;
+use embassy_usb::{class::cdc_acm::Sender, driver::Driver};
+use keyboard::battery::Level;
+use latency_proto::{
+    frame,
+    pattern::PatternCounters,
+    record::{OneWayRecord, PingRecord, ReceiveRecord, Record, SendRecord, SyncRecord},
+    sync::{ClockSync, Exchange},
+};
+
+use crate::{
+    link::{LinkCounters, NUM_PEERS},
+    ping::PingInfo,
+    radio::{LogInfo, Packet},
+};
+
+const NUM_RECORDS: usize = 32;
+
+static RECORDS: Channel<CriticalSectionRawMutex, Record, NUM_RECORDS> = Channel::new();
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Queues a record without waiting, dropping it if the queue is full
+pub fn log(record: Record) {
+    if RECORDS.try_send(record).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Queues a record, waiting for space if the queue is full. Used for replies to the host that
+/// mustn't get lost
+pub async fn send(record: Record) {
+    RECORDS.send(record).await;
+}
+
+pub fn log_send(info: &LogInfo, peer: u8, channel: u8) {
+    log(Record::Send(SendRecord {
+        timestamp_us: Instant::now().as_micros(),
+        peer,
+        channel,
+        rssi: info.rssi,
+        retranmisisons: info.retranmisisons.min(u16::MAX as u32) as u16,
+        time_elapsed_us: info.time_elapsed.as_micros() as u32,
+        total_us: info.total_time.as_micros() as u32,
+        first_transmit_us: info.time_to_first_transmit.as_micros() as u32,
+    }));
+}
+
+pub fn log_receive(packet: &Packet, channel: u8) {
+    log(Record::Receive(ReceiveRecord {
+        timestamp_us: Instant::now().as_micros(),
+        peer: packet.addr,
+        channel,
+        rssi: packet.rssi,
+        id: packet.id(),
+        len: packet.len() as u8,
+    }));
+}
+
+pub fn log_ping(info: &PingInfo) {
+    log(Record::Ping(PingRecord {
+        timestamp_us: Instant::now().as_micros(),
+        seq: info.seq,
+        rtt_us: info.rtt.as_micros() as u32,
+        ack_us: info.ping.total_time.as_micros() as u32,
+        retranmisisons: info.ping.retranmisisons.min(u16::MAX as u32) as u16,
+        rssi: info.rssi,
+    }));
+}
+
+pub fn log_one_way(packet: &Packet, latency_us: i64) {
+    log(Record::OneWay(OneWayRecord {
+        timestamp_us: Instant::now().as_micros(),
+        peer: packet.addr,
+        id: packet.id(),
+        latency_us: latency_us.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+    }));
+}
+
+pub fn log_sync(exchange: &Exchange, clock: &ClockSync) {
+    log(Record::Sync(SyncRecord {
+        timestamp_us: exchange.t4,
+        offset_us: clock.offset_at(exchange.t4).unwrap_or(0),
+        drift_ppb: clock.drift_ppm().map_or(0, |ppm| (ppm * 1000.0) as i32),
+        delay_us: exchange.delay().min(u32::MAX as u64) as u32,
+    }));
+}
+
+fn counter_records(counters: &LinkCounters) -> impl Iterator<Item = Record> + '_ {
+    let timestamp_us = Instant::now().as_micros();
+    counters
+        .peers
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| !c.is_empty())
+        .map(move |(peer, c)| Record::Counters {
+            timestamp_us,
+            peer: peer as u8,
+            counters: *c,
+        })
+}
+
+/// Queues the counters of every peer that saw traffic, dropping them if the queue is full
+pub fn log_counters(counters: &LinkCounters) {
+    for record in counter_records(counters) {
+        log(record);
+    }
+}
+
+/// Same as [`log_counters`] but waits for space in the queue
+pub async fn send_counters(counters: &LinkCounters) {
+    for record in counter_records(counters) {
+        send(record).await;
+    }
+}
+
+/// Queues the battery level a half at logical address `peer` reported
+pub fn log_battery(peer: u8, level: &Level) {
+    log(Record::Battery {
+        timestamp_us: Instant::now().as_micros(),
+        peer,
+        millivolts: level.millivolts,
+        percent: level.percent,
+    });
+}
+
+/// Queues the payload verification results of every peer that sent pattern payloads
+pub fn log_pattern(counters: &[PatternCounters; NUM_PEERS]) {
+    let timestamp_us = Instant::now().as_micros();
+    for (peer, c) in counters.iter().enumerate().filter(|(_, c)| !c.is_empty()) {
+        log(Record::Pattern {
+            timestamp_us,
+            peer: peer as u8,
+            counters: *c,
+        });
+    }
+}
+
+/// Writes queued records to the host for as long as the device is running
+pub async fn run<'d, D: Driver<'d>>(mut class: Sender<'d, D>) -> ! {
+    let mut seq: u16 = 0;
+    let mut buf = [0u8; frame::MAX_FRAME_SIZE];
+    loop {
+        class.wait_connection().await;
+        'connected: loop {
+            let dropped = DROPPED.swap(0, Ordering::Relaxed);
+            let record = if dropped > 0 {
+                Record::Dropped(dropped)
+            } else {
+                RECORDS.receive().await
+            };
+            let Some(len) = frame::encode(seq, &record, &mut buf) else {
+                continue;
+            };
+            seq = seq.wrapping_add(1);
+            let max_packet_size = class.max_packet_size() as usize;
+            for chunk in buf[..len].chunks(max_packet_size) {
+                if class.write_packet(chunk).await.is_err() {
+                    break 'connected;
+                }
+            }
+            // A full last packet doesn't end the transfer, the host would wait for more
+            if len.is_multiple_of(max_packet_size) && class.write_packet(&[]).await.is_err() {
+                break 'connected;
+            }
+        }
+    }
+}
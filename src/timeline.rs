@@ -0,0 +1,178 @@
+//! Hardware timestamps of the radio events of every frame.
+//!
+//! With the `timeline` feature the READY, ADDRESS, END and DISABLED events are routed over PPI to
+//! capture tasks of a free running 16 MHz TIMER3, so the timestamps don't depend on when the
+//! executor or an isr gets around to reading the clock. The drivers [`arm`] the capture right
+//! before enabling the radio and [`finish`] the frame once it's disabled. Finished frames go into
+//! a ring buffer and the frames of an acked attempt are attached to
+//! [`LogInfo::timeline`](crate::radio::LogInfo::timeline). Without the feature nothing is
+//! captured and the timeline stays empty.
+
+#[cfg(feature = "timeline")]
+use core::cell::RefCell;
+
+#[cfg(feature = "timeline")]
+use embassy_nrf::{peripherals::TIMER3, ppi::ConfigurableChannel, Peri};
+#[cfg(feature = "timeline")]
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+#[cfg(feature = "timeline")]
+use heapless::Deque;
+
+const TICKS_PER_US: u64 = 16;
+/// Frames kept in the ring buffer, the oldest is dropped when it's full
+pub const RING_SIZE: usize = 32;
+
+#[cfg(feature = "timeline")]
+const CC_START: usize = 0;
+#[cfg(feature = "timeline")]
+const CC_READY: usize = 1;
+#[cfg(feature = "timeline")]
+const CC_ADDRESS: usize = 2;
+#[cfg(feature = "timeline")]
+const CC_END: usize = 3;
+#[cfg(feature = "timeline")]
+const CC_DISABLED: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+/// Timer ticks at which the events of one frame happened, `None` for events that didn't
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrameTimeline {
+    pub direction: Direction,
+    /// When the driver triggered TXEN or RXEN
+    pub start: u32,
+    pub ready: Option<u32>,
+    pub address: Option<u32>,
+    pub end: Option<u32>,
+    pub disabled: Option<u32>,
+}
+
+fn ns_between(from: Option<u32>, to: Option<u32>) -> Option<u32> {
+    let ticks = to?.wrapping_sub(from?) as u64;
+    Some((ticks * 1000 / TICKS_PER_US).min(u32::MAX as u64) as u32)
+}
+
+impl FrameTimeline {
+    /// From enabling the radio until it's ready
+    pub fn ramp_up_ns(&self) -> Option<u32> {
+        ns_between(Some(self.start), self.ready)
+    }
+
+    /// Preamble and address on air. When receiving this also includes waiting for the frame
+    pub fn address_ns(&self) -> Option<u32> {
+        ns_between(self.ready, self.address)
+    }
+
+    /// Length field, payload and crc on air
+    pub fn payload_ns(&self) -> Option<u32> {
+        ns_between(self.address, self.end)
+    }
+
+    /// From the end of the frame until the radio is disabled again
+    pub fn disable_ns(&self) -> Option<u32> {
+        ns_between(self.end, self.disabled)
+    }
+
+    /// Software overhead between this frame being done and the driver starting `next`
+    pub fn turnaround_ns(&self, next: &FrameTimeline) -> Option<u32> {
+        ns_between(self.disabled, Some(next.start))
+    }
+}
+
+#[cfg(feature = "timeline")]
+static RING: Mutex<CriticalSectionRawMutex, RefCell<Deque<FrameTimeline, RING_SIZE>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Routes the radio events to TIMER3 captures over the given PPI channels and starts the timer.
+/// Has to be called before the radio driver is used.
+#[cfg(feature = "timeline")]
+pub fn init(
+    _timer: Peri<'static, TIMER3>,
+    ready: Peri<'static, impl ConfigurableChannel>,
+    address: Peri<'static, impl ConfigurableChannel>,
+    end: Peri<'static, impl ConfigurableChannel>,
+    disabled: Peri<'static, impl ConfigurableChannel>,
+) {
+    let r = embassy_nrf::pac::RADIO;
+    let t = embassy_nrf::pac::TIMER3;
+    let ppi = embassy_nrf::pac::PPI;
+
+    t.tasks_stop().write_value(1);
+    t.mode()
+        .write(|w| w.set_mode(embassy_nrf::pac::timer::vals::Mode::TIMER));
+    t.bitmode()
+        .write(|w| w.set_bitmode(embassy_nrf::pac::timer::vals::Bitmode::_32BIT));
+    t.prescaler().write(|w| w.set_prescaler(0));
+    t.tasks_clear().write_value(1);
+
+    let routes = [
+        (ready.number(), r.events_ready().as_ptr(), CC_READY),
+        (address.number(), r.events_address().as_ptr(), CC_ADDRESS),
+        (end.number(), r.events_end().as_ptr(), CC_END),
+        (disabled.number(), r.events_disabled().as_ptr(), CC_DISABLED),
+    ];
+    for (ch, event, cc) in routes {
+        ppi.ch(ch).eep().write_value(event as u32);
+        ppi.ch(ch)
+            .tep()
+            .write_value(t.tasks_capture(cc).as_ptr() as u32);
+        ppi.chenset().write(|w| w.set_ch(ch, true));
+    }
+
+    t.tasks_start().write_value(1);
+}
+
+/// Clears the captures of the previous frame and records the start. Called right before
+/// triggering TXEN or RXEN
+#[cfg(feature = "timeline")]
+pub(crate) fn arm() {
+    let t = embassy_nrf::pac::TIMER3;
+    for cc in [CC_READY, CC_ADDRESS, CC_END, CC_DISABLED] {
+        t.cc(cc).write_value(0);
+    }
+    t.tasks_capture(CC_START).write_value(1);
+}
+
+#[cfg(not(feature = "timeline"))]
+pub(crate) fn arm() {}
+
+/// Reads the captures of the frame that just ended and puts it into the ring buffer. Called once
+/// the radio is disabled after the frame
+#[cfg(feature = "timeline")]
+pub(crate) fn finish(direction: Direction) -> Option<FrameTimeline> {
+    let t = embassy_nrf::pac::TIMER3;
+    // Zero is what `arm` left there, a real capture landing exactly on it is rare enough to
+    // ignore
+    let read = |cc| Some(t.cc(cc).read()).filter(|&v| v != 0);
+    let frame = FrameTimeline {
+        direction,
+        start: t.cc(CC_START).read(),
+        ready: read(CC_READY),
+        address: read(CC_ADDRESS),
+        end: read(CC_END),
+        disabled: read(CC_DISABLED),
+    };
+    RING.lock(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.is_full() {
+            ring.pop_front();
+        }
+        let _ = ring.push_back(frame);
+    });
+    Some(frame)
+}
+
+#[cfg(not(feature = "timeline"))]
+pub(crate) fn finish(_direction: Direction) -> Option<FrameTimeline> {
+    None
+}
+
+/// Takes the oldest frame out of the ring buffer
+#[cfg(feature = "timeline")]
+pub fn pop() -> Option<FrameTimeline> {
+    RING.lock(|ring| ring.borrow_mut().pop_front())
+}
@@ -1,35 +1,29 @@
 use core::{
-    cell::{Cell, UnsafeCell},
-    ptr::null,
-    sync::atomic::{compiler_fence, AtomicBool},
-    task::Poll,
+    cell::RefCell,
+    ptr,
+    sync::atomic::{compiler_fence, AtomicPtr, Ordering},
 };
 
-use defmt::info;
+use embassy_futures::select::{select, Either};
 use embassy_nrf::{
-    interrupt::{
-        self,
-        typelevel::{self, Interrupt},
-    },
+    interrupt::typelevel::{self, Interrupt},
     pac::radio::regs::{Rxaddresses, Txaddress},
     Peri,
 };
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, waitqueue::AtomicWaker,
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
 };
-use embassy_time::{Duration, Instant};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+use static_cell::StaticCell;
 
+use crate::link::{LinkConfig, LinkCounters, LinkLayer, LinkStats, Peer};
 use crate::radio::{
-    LogInfo, Packet, PacketType, DONGLE_ADDRESS, DONGLE_PREFIX, KEYBOARD_ADDRESS, LEFT_PREFIX,
-    RIGHT_PREFIX,
+    read_rssi, time_to_first_transmit, Addresses, Attempt, LogInfo, Packet, PacketType,
+    RetryReason, MAX_ATTEMPTS,
 };
-
-pub struct InterruptHandler {}
-
-const BUFFER_SIZE: usize = 32;
-static TRAD_STATE: AtomicWaker = AtomicWaker::new();
-static mut START: u64 = 0;
-static mut COUNT: u32 = 0;
+use crate::timeline::{self, Direction, FrameTimeline};
 
 enum RadioState {
     Disabled,
@@ -39,82 +33,251 @@ enum RadioState {
     RxAck,
 }
 
-static mut CURRENT_PACKET: Packet = Packet::default();
-static mut TX_ID: u8 = 0;
-static mut RX_ID: u8 = 0;
-static ACTIVE: AtomicBool = AtomicBool::new(false);
-pub struct TradInterruptHandler {}
-static mut RADIO_STATE: RadioState = RadioState::Disabled;
+/// Everything the radio and timer isrs share with [`TradRadio`]. Only ever accessed through
+/// [`State::shared`] inside a critical section, so the isrs and the driver can't observe each
+/// other halfway through an update. The packet buffers live in here as well since `PACKETPTR`
+/// needs them to have a fixed address.
+struct Shared {
+    state: RadioState,
+    current: Packet,
+    ack: Packet,
+    tx_id: u8,
+    /// Id of the last packet received on each pipe, so senders don't throw off each other's
+    /// duplicate detection
+    rx_ids: [u8; 8],
+    /// Ticks when the send was requested
+    call: u64,
+    /// From the send call until the radio was ready to put the first attempt on air
+    first_transmit: Duration,
+    /// Ticks when the READY event of the current frame was handled
+    ready: u64,
+    /// Ticks when the current attempt started
+    start: u64,
+    count: u32,
+    attempts: Vec<Attempt, MAX_ATTEMPTS>,
+    /// Why the current attempt will be retried if it ends without an ack
+    retry_reason: RetryReason,
+    /// Logical address the current packet is sent to
+    tx_peer: Peer,
+    /// Ticks when listening started or the ack transmission started while receiving
+    rx_start: u64,
+    counters: LinkCounters,
+    /// Timeline of the current attempt's data frame
+    tx_frame: Option<FrameTimeline>,
+}
 
-static CHAN: Channel<CriticalSectionRawMutex, LogInfo, 5> = Channel::new();
-static P_CHAN: Channel<CriticalSectionRawMutex, Packet, 5> = Channel::new();
+/// State of the one [`TradRadio`], handed to the isrs through [`STATE_PTR`]
+struct State {
+    shared: Mutex<CriticalSectionRawMutex, RefCell<Shared>>,
+    /// Results of sends, filled in by the isr once the ack arrives
+    results: Channel<CriticalSectionRawMutex, LogInfo, 5>,
+    /// Received data packets
+    packets: Channel<CriticalSectionRawMutex, Packet, 5>,
+}
 
-impl typelevel::Handler<typelevel::RADIO> for TradInterruptHandler {
-    unsafe fn on_interrupt() {
-        static mut ACK_PACKET: Packet = Packet::default();
+static STATE: StaticCell<State> = StaticCell::new();
+static STATE_PTR: AtomicPtr<State> = AtomicPtr::new(ptr::null_mut());
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            shared: Mutex::new(RefCell::new(Shared::new())),
+            results: Channel::new(),
+            packets: Channel::new(),
+        }
+    }
+
+    /// Runs `f` on the state of the constructed [`TradRadio`], does nothing before there is one
+    fn with_isr(f: impl FnOnce(&State)) {
+        let state = STATE_PTR.load(Ordering::Acquire);
+        // Only ever set from a `&'static State`
+        if let Some(state) = unsafe { state.as_ref() } {
+            f(state);
+        }
+    }
+}
+
+impl Shared {
+    const fn new() -> Self {
+        Self {
+            state: RadioState::Disabled,
+            current: Packet::default(),
+            ack: Packet::default(),
+            tx_id: 0,
+            rx_ids: [0; 8],
+            call: 0,
+            first_transmit: Duration::from_ticks(0),
+            ready: 0,
+            start: 0,
+            count: 0,
+            attempts: Vec::new(),
+            retry_reason: RetryReason::Timeout,
+            tx_peer: 0,
+            rx_start: 0,
+            counters: LinkCounters::new(),
+            tx_frame: None,
+        }
+    }
+
+    /// Adds the time since `rx_start` to the listening time
+    fn stop_listening(&mut self, now: u64) {
+        self.counters.listen_us += Duration::from_ticks(now - self.rx_start).as_micros();
+        self.rx_start = now;
+    }
+
+    /// Closes the current attempt. Past [`MAX_ATTEMPTS`] the attempts are only counted
+    fn end_attempt(&mut self, now: u64, retry_reason: Option<RetryReason>) {
+        let duration = Duration::from_ticks(now - self.start);
+        let _ = self.attempts.push(Attempt {
+            duration,
+            retry_reason,
+        });
+        self.counters.peer_mut(self.tx_peer).radio_on_us += duration.as_micros();
+    }
+
+    fn retransmit(&mut self) {
+        let r = embassy_nrf::pac::RADIO;
+        let t = embassy_nrf::pac::TIMER0;
+        self.end_attempt(Instant::now().as_ticks(), Some(self.retry_reason));
+        self.retry_reason = RetryReason::Timeout;
+        self.state = RadioState::Tx;
+        r.packetptr()
+            .write_value(self.current.buffer.as_ptr() as u32);
+        self.count += 1;
+        self.start = Instant::now().as_ticks();
+        compiler_fence(core::sync::atomic::Ordering::Release);
+        t.tasks_stop().write_value(1);
+        t.tasks_clear().write_value(1);
+        timeline::arm();
+        r.tasks_txen().write_value(1);
+    }
+
+    fn on_radio_event(
+        &mut self,
+        results: &Channel<CriticalSectionRawMutex, LogInfo, 5>,
+        packets: &Channel<CriticalSectionRawMutex, Packet, 5>,
+    ) {
         let r = embassy_nrf::pac::RADIO;
         let t = embassy_nrf::pac::TIMER0;
-        // info!("Radio Event!!!");
-        match RADIO_STATE {
+        if r.events_ready().read() != 0 {
+            r.events_ready().write_value(0);
+            self.ready = Instant::now().as_ticks();
+        }
+        match self.state {
             RadioState::Disabled => {}
             RadioState::Tx => {
                 if r.events_disabled().read() != 0 {
                     r.events_disabled().write_value(0);
-                    r.packetptr().write_value(ACK_PACKET.buffer.as_ptr() as u32);
-                    RADIO_STATE = RadioState::TxAck;
+                    self.tx_frame = timeline::finish(Direction::Tx);
+                    if self.count == 0 {
+                        self.first_transmit = time_to_first_transmit(
+                            Instant::from_ticks(self.call),
+                            Instant::from_ticks(self.start),
+                            Instant::from_ticks(self.ready),
+                            self.tx_frame.as_ref(),
+                        );
+                    }
+                    let c = self.counters.peer_mut(self.tx_peer);
+                    c.frames_sent += 1;
+                    c.bytes_on_air += self.current.on_air_len();
+                    r.packetptr().write_value(self.ack.buffer.as_ptr() as u32);
+                    self.state = RadioState::TxAck;
                     t.tasks_start().write_value(1);
                     compiler_fence(core::sync::atomic::Ordering::Release);
+                    timeline::arm();
                     r.tasks_rxen().write_value(1);
                 }
             }
             RadioState::TxAck => {
                 if r.events_disabled().read() != 0 {
                     r.events_disabled().write_value(0);
+                    let ack_frame = timeline::finish(Direction::Rx);
                     if r.events_crcok().read() != 0 {
                         r.events_crcok().write_value(0);
-                        if ACK_PACKET.packet_type().unwrap() == PacketType::Ack
-                            && ACK_PACKET.id() == TX_ID
+                        if matches!(self.ack.packet_type(), Ok(PacketType::Ack))
+                            && self.ack.id() == self.current.id()
                         {
-                            TX_ID += 1;
-                            // ACTIVE.store(false, core::sync::atomic::Ordering::Release);
-                            RADIO_STATE = RadioState::Disabled;
-                            // TRAD_STATE.wake();
+                            self.state = RadioState::Disabled;
                             t.tasks_stop().write_value(1);
-                            let _ = CHAN.try_send(LogInfo {
-                                retranmisisons: COUNT,
-                                time_elapsed: Duration::from_ticks(
-                                    Instant::now().as_ticks() - START,
-                                ),
+                            t.tasks_clear().write_value(1);
+                            let now = Instant::now().as_ticks();
+                            let c = self.counters.peer_mut(self.tx_peer);
+                            c.acks_received += 1;
+                            c.bytes_on_air += self.ack.on_air_len();
+                            self.end_attempt(now, None);
+                            let _ = results.try_send(LogInfo {
+                                retranmisisons: self.count,
+                                time_elapsed: Duration::from_ticks(now - self.start),
+                                time_to_first_transmit: self.first_transmit,
+                                total_time: Duration::from_ticks(now - self.call),
+                                attempts: core::mem::take(&mut self.attempts),
+                                rssi: read_rssi(),
+                                timeline: self
+                                    .tx_frame
+                                    .take()
+                                    .into_iter()
+                                    .chain(ack_frame)
+                                    .collect(),
                             });
+                        } else {
+                            if self.ack.packet_type().is_err() {
+                                self.counters.peer_mut(self.tx_peer).unknown_type += 1;
+                            }
+                            // Retransmitted once the ack deadline passes
+                            self.retry_reason = RetryReason::WrongId;
                         }
                     } else {
-                        RADIO_STATE = RadioState::Tx;
-                        r.packetptr()
-                            .write_value(CURRENT_PACKET.buffer.as_ptr() as u32);
-                        COUNT += 1;
-                        START = Instant::now().as_ticks();
-                        compiler_fence(core::sync::atomic::Ordering::Release);
-                        t.tasks_stop().write_value(1);
-                        r.tasks_txen().write_value(1);
+                        self.counters.peer_mut(self.tx_peer).crc_failures += 1;
+                        self.retry_reason = RetryReason::CrcError;
+                        self.retransmit();
                     }
                 }
             }
             RadioState::Rx => {
                 if r.events_disabled().read() != 0 {
                     r.events_disabled().write_value(0);
+                    timeline::finish(Direction::Rx);
+                    let peer = r.rxmatch().read().rxmatch();
                     if r.events_crcok().read() != 0 {
                         r.events_crcok().write_value(0);
-                        if CURRENT_PACKET.packet_type().unwrap() == PacketType::Data {
-                            RADIO_STATE = RadioState::RxAck;
-                            ACK_PACKET.set_len(1);
-                            ACK_PACKET.set_type(PacketType::Ack);
-                            r.packetptr().write_value(ACK_PACKET.buffer.as_ptr() as u32);
+                        let packet_type = self.current.packet_type();
+                        if packet_type.is_err() {
+                            self.counters.peer_mut(peer).unknown_type += 1;
+                        }
+                        let is_new = self.current.id() != self.rx_ids[peer as usize % 8];
+                        if matches!(packet_type, Ok(PacketType::Data))
+                            && is_new
+                            && packets.is_full()
+                        {
+                            // No room to keep it, so no ack either and the sender retransmits
+                            // it once there is
+                            let c = self.counters.peer_mut(peer);
+                            c.frames_received += 1;
+                            c.bytes_on_air += self.current.on_air_len();
+                            timeline::arm();
+                            r.tasks_rxen().write_value(1);
+                        } else if matches!(packet_type, Ok(PacketType::Data)) {
+                            let c = self.counters.peer_mut(peer);
+                            c.frames_received += 1;
+                            c.bytes_on_air += self.current.on_air_len();
+                            self.stop_listening(Instant::now().as_ticks());
+                            self.state = RadioState::RxAck;
+                            self.ack.set_len(1);
+                            self.ack.set_type(PacketType::Ack);
+                            self.ack.set_id(self.current.id());
+                            r.packetptr().write_value(self.ack.buffer.as_ptr() as u32);
+                            t.tasks_clear().write_value(1);
+                            t.tasks_start().write_value(1);
                             compiler_fence(core::sync::atomic::Ordering::Release);
+                            timeline::arm();
                             r.tasks_txen().write_value(1);
                         } else {
+                            timeline::arm();
                             r.tasks_rxen().write_value(1);
                         }
                     } else {
+                        self.counters.peer_mut(peer).crc_failures += 1;
+                        timeline::arm();
                         r.tasks_rxen().write_value(1);
                     }
                 }
@@ -122,96 +285,176 @@ impl typelevel::Handler<typelevel::RADIO> for TradInterruptHandler {
             RadioState::RxAck => {
                 if r.events_disabled().read() != 0 {
                     r.events_disabled().write_value(0);
-                    if CURRENT_PACKET.id() != RX_ID {
-                        RADIO_STATE = RadioState::Disabled;
-                        RX_ID = CURRENT_PACKET.id();
-                        let _ = P_CHAN.try_send(CURRENT_PACKET);
+                    timeline::finish(Direction::Tx);
+                    t.tasks_stop().write_value(1);
+                    t.tasks_clear().write_value(1);
+                    // The ack reused the rx buffer pointer so point it back at the rx packet
+                    // before listening again
+                    r.packetptr()
+                        .write_value(self.current.buffer.as_ptr() as u32);
+                    let now = Instant::now().as_ticks();
+                    let peer = r.rxmatch().read().rxmatch();
+                    let c = self.counters.peer_mut(peer);
+                    c.acks_sent += 1;
+                    c.bytes_on_air += self.ack.on_air_len();
+                    c.radio_on_us += Duration::from_ticks(now - self.rx_start).as_micros();
+                    self.rx_start = now;
+                    let rx_id = &mut self.rx_ids[peer as usize % 8];
+                    if self.current.id() != *rx_id {
+                        *rx_id = self.current.id();
+                        self.state = RadioState::Disabled;
+                        self.current.addr = peer;
+                        self.current.rssi = read_rssi();
+                        let _ = packets.try_send(self.current);
                     } else {
-                        RADIO_STATE = RadioState::Rx;
+                        c.duplicates += 1;
+                        self.state = RadioState::Rx;
+                        timeline::arm();
                         r.tasks_rxen().write_value(1);
                     }
                 }
             }
         }
     }
-}
 
-pub struct RadioTimerInterrupt;
-
-impl typelevel::Handler<typelevel::TIMER0> for RadioTimerInterrupt {
-    unsafe fn on_interrupt() {
+    fn on_timer_event(&mut self) {
         let t = embassy_nrf::pac::TIMER0;
         let r = embassy_nrf::pac::RADIO;
-        match RADIO_STATE {
-            RadioState::Disabled => {
+        match self.state {
+            RadioState::Disabled | RadioState::Tx => {
                 if t.events_compare(0).read() != 0 {
                     t.events_compare(0).write_value(0);
                 }
                 t.tasks_stop().write_value(1);
                 t.tasks_clear().write_value(1);
             }
-            RadioState::Tx => {
-                // info!("TX Timer Fire!");
+            RadioState::TxAck => {
+                t.tasks_stop().write_value(1);
+                t.tasks_clear().write_value(1);
+                if t.events_compare(0).read() != 0 {
+                    t.events_compare(0).write_value(0);
+                    self.counters.peer_mut(self.tx_peer).ack_timeouts += 1;
+                    r.tasks_disable().write_value(1);
+                    self.state = RadioState::Disabled;
+                    while r.state().read().state()
+                        != embassy_nrf::radio::ieee802154::RadioState::DISABLED
+                    {
+                    }
+                    r.events_disabled().write_value(0);
+                    self.retransmit();
+                }
+            }
+            RadioState::Rx => {
+                // Listening has no deadline, a timeout is up to the caller
                 if t.events_compare(0).read() != 0 {
                     t.events_compare(0).write_value(0);
                 }
                 t.tasks_stop().write_value(1);
                 t.tasks_clear().write_value(1);
             }
-            RadioState::TxAck => {
-                // info!("TX Timer Ack Fire!");
+            RadioState::RxAck => {
                 t.tasks_stop().write_value(1);
                 t.tasks_clear().write_value(1);
                 if t.events_compare(0).read() != 0 {
                     t.events_compare(0).write_value(0);
+                    // The ack never finished transmitting. Give up on it and go back to listening,
+                    // the sender will retransmit and we'll ack the duplicate
                     r.tasks_disable().write_value(1);
-                    RADIO_STATE = RadioState::Disabled;
                     while r.state().read().state()
                         != embassy_nrf::radio::ieee802154::RadioState::DISABLED
                     {
                     }
                     r.events_disabled().write_value(0);
-
-                    RADIO_STATE = RadioState::Tx;
+                    self.state = RadioState::Rx;
+                    self.rx_start = Instant::now().as_ticks();
                     r.packetptr()
-                        .write_value(CURRENT_PACKET.buffer.as_ptr() as u32);
-                    COUNT += 1;
-                    START = Instant::now().as_ticks();
+                        .write_value(self.current.buffer.as_ptr() as u32);
                     compiler_fence(core::sync::atomic::Ordering::Release);
-                    r.tasks_txen().write_value(1);
+                    timeline::arm();
+                    r.tasks_rxen().write_value(1);
                 }
             }
-            RadioState::Rx => todo!(),
-            RadioState::RxAck => todo!(),
         }
     }
+
+    /// Stops whatever the radio is doing. Used when a send or receive future is dropped before
+    /// completing
+    fn abort(&mut self) {
+        let r = embassy_nrf::pac::RADIO;
+        let t = embassy_nrf::pac::TIMER0;
+        match self.state {
+            RadioState::Tx | RadioState::TxAck => {
+                // The receiver might have gotten the packet without us seeing the ack, so don't
+                // reuse the id for the next packet or it'll be discarded as a duplicate
+                self.tx_id = self.tx_id.wrapping_add(1);
+            }
+            RadioState::Rx => self.stop_listening(Instant::now().as_ticks()),
+            RadioState::Disabled | RadioState::RxAck => {}
+        }
+        self.state = RadioState::Disabled;
+        t.tasks_stop().write_value(1);
+        t.tasks_clear().write_value(1);
+        t.events_compare(0).write_value(0);
+        r.tasks_disable().write_value(1);
+        while r.state().read().state() != embassy_nrf::radio::ieee802154::RadioState::DISABLED {}
+        r.events_disabled().write_value(0);
+    }
 }
 
-#[derive(Clone, Copy)]
-pub struct Addresses {
-    pub base: [u32; 2],
-    pub prefix: [[u8; 4]; 2],
+/// Aborts the in flight radio operation if the future holding it is dropped
+struct AbortOnDrop {
+    state: &'static State,
+    armed: bool,
 }
 
-impl Default for Addresses {
-    fn default() -> Self {
-        let mut res = Self {
-            base: Default::default(),
-            prefix: Default::default(),
-        };
-        res.base[0] = DONGLE_ADDRESS;
-        res.base[1] = KEYBOARD_ADDRESS;
-        res.prefix[0][0] = DONGLE_PREFIX;
-        res.prefix[0][1] = LEFT_PREFIX;
-        res.prefix[0][2] = RIGHT_PREFIX;
-        res
+impl AbortOnDrop {
+    fn new(state: &'static State) -> Self {
+        Self { state, armed: true }
+    }
+
+    fn defuse(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if self.armed {
+            self.state.shared.lock(|s| s.borrow_mut().abort());
+            // A result belongs to the dropped send. Received packets are kept, they have been
+            // acked and the sender won't send them again
+            self.state.results.clear();
+        }
+    }
+}
+
+pub struct TradInterruptHandler {}
+
+impl typelevel::Handler<typelevel::RADIO> for TradInterruptHandler {
+    unsafe fn on_interrupt() {
+        State::with_isr(|state| {
+            state.shared.lock(|s| {
+                s.borrow_mut()
+                    .on_radio_event(&state.results, &state.packets)
+            })
+        });
+    }
+}
+
+pub struct RadioTimerInterrupt;
+
+impl typelevel::Handler<typelevel::TIMER0> for RadioTimerInterrupt {
+    unsafe fn on_interrupt() {
+        State::with_isr(|state| state.shared.lock(|s| s.borrow_mut().on_timer_event()));
     }
 }
 
 pub struct TradRadio<'d> {
     _radio: Peri<'d, embassy_nrf::peripherals::RADIO>,
+    state: &'static State,
     tx_addreses: u8,
     rx_addresses: u32,
+    stats: LinkStats,
 }
 
 impl<'d> TradRadio<'d> {
@@ -222,6 +465,10 @@ impl<'d> TradRadio<'d> {
         _irq_t: impl typelevel::Binding<embassy_nrf::interrupt::typelevel::TIMER0, RadioTimerInterrupt>,
         addresses: Addresses,
     ) -> Self {
+        // The isrs reach the state through a plain pointer, so there can only ever be one
+        let state: &'static State = STATE.init(State::new());
+        STATE_PTR.store(state as *const State as *mut State, Ordering::Release);
+
         let t = embassy_nrf::pac::TIMER0;
         let r = embassy_nrf::pac::RADIO;
 
@@ -240,7 +487,7 @@ impl<'d> TradRadio<'d> {
         });
 
         r.pcnf1().write(|w| {
-            w.set_maxlen(BUFFER_SIZE as u8);
+            w.set_maxlen(latency_proto::arq::MAX_LEN_FIELD);
             w.set_statlen(0);
             w.set_balen(4);
             w.set_endian(embassy_nrf::pac::radio::vals::Endian::LITTLE);
@@ -272,6 +519,8 @@ impl<'d> TradRadio<'d> {
         r.shorts().write(|w| {
             w.set_ready_start(true);
             w.set_end_disable(true);
+            w.set_address_rssistart(true);
+            w.set_disabled_rssistop(true);
         });
 
         embassy_nrf::interrupt::typelevel::RADIO::unpend();
@@ -279,7 +528,10 @@ impl<'d> TradRadio<'d> {
         unsafe {
             embassy_nrf::interrupt::typelevel::RADIO::enable();
         }
-        r.intenset().write(|w| w.set_disabled(true));
+        r.intenset().write(|w| {
+            w.set_ready(true);
+            w.set_disabled(true);
+        });
 
         t.mode()
             .write(|w| w.set_mode(embassy_nrf::pac::timer::vals::Mode::TIMER));
@@ -296,8 +548,10 @@ impl<'d> TradRadio<'d> {
         t.intenset().write(|w| w.set_compare(0, true));
         Self {
             _radio,
+            state,
             rx_addresses: 0,
             tx_addreses: 0,
+            stats: LinkStats::default(),
         }
     }
 
@@ -313,45 +567,102 @@ impl<'d> TradRadio<'d> {
         self.rx_addresses = r.rxaddresses().read().0;
     }
 
+    /// Waits for the next data packet. Dropping the future turns the radio off again. A packet
+    /// that was already acked stays queued and is returned by the next call, so this is cancel
+    /// safe.
     pub async fn receive_packet(&mut self) -> Packet {
         let r = embassy_nrf::pac::RADIO;
-        cortex_m::interrupt::free(|_cs| unsafe {
-            RADIO_STATE = RadioState::Rx;
-            r.packetptr()
-                .write_value(CURRENT_PACKET.buffer.as_ptr() as u32);
+        // Left over from a receive that was dropped after the ack went out
+        if let Ok(packet) = self.state.packets.try_receive() {
+            self.stats.packets_received += 1;
+            return packet;
+        }
+        self.state.shared.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.state = RadioState::Rx;
+            s.rx_start = Instant::now().as_ticks();
+            r.packetptr().write_value(s.current.buffer.as_ptr() as u32);
             compiler_fence(core::sync::atomic::Ordering::Release);
+            timeline::arm();
             r.tasks_rxen().write_value(1);
         });
-        P_CHAN.receive().await
+        let guard = AbortOnDrop::new(self.state);
+        let packet = self.state.packets.receive().await;
+        guard.defuse();
+        self.stats.packets_received += 1;
+        packet
     }
 
-    pub async fn send_packet(&mut self, packet: Packet) -> LogInfo {
+    /// Same as [`Self::receive_packet`] but gives up after `timeout`, returning `None` if no new
+    /// packet arrived in time
+    pub async fn receive_packet_with_timeout(&mut self, timeout: Duration) -> Option<Packet> {
+        match select(Timer::after(timeout), self.receive_packet()).await {
+            Either::First(_) => None,
+            Either::Second(packet) => Some(packet),
+        }
+    }
+
+    /// Same as [`Self::send`] for callers that don't need the id the packet was sent with
+    pub async fn send_packet(&mut self, mut packet: Packet) -> LogInfo {
+        self.send(&mut packet).await
+    }
+
+    /// Sends the packet and waits for it to be acked, writing the id it was sent with back into
+    /// `packet`. Dropping the future stops the transmission, the packet may or may not have
+    /// reached the receiver at that point.
+    pub async fn send(&mut self, packet: &mut Packet) -> LogInfo {
         let r = embassy_nrf::pac::RADIO;
-        // if ACTIVE.load(core::sync::atomic::Ordering::Acquire) {
-        //     core::future::poll_fn(|cx| {
-        //         TRAD_STATE.register(cx.waker());
-        //         if !ACTIVE.load(core::sync::atomic::Ordering::Acquire) {
-        //             Poll::Ready(())
-        //         } else {
-        //             Poll::Pending
-        //         }
-        //     })
-        //     .await;
-        // }
-        // ACTIVE.store(true, core::sync::atomic::Ordering::Release);
-        cortex_m::interrupt::free(|_cs| unsafe {
-            CURRENT_PACKET = packet;
-            CURRENT_PACKET.set_id(TX_ID);
-            CURRENT_PACKET.set_type(PacketType::Data);
+        let call = Instant::now().as_ticks();
+        self.state.shared.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.tx_id = s.tx_id.wrapping_add(1);
+            let id = s.tx_id;
+            packet.set_id(id);
+            s.current = *packet;
+            s.current.set_type(PacketType::Data);
             compiler_fence(core::sync::atomic::Ordering::Release);
-            r.packetptr()
-                .write_value(CURRENT_PACKET.buffer.as_ptr() as u32);
-            RADIO_STATE = RadioState::Tx;
-            START = Instant::now().as_ticks();
-            COUNT = 0;
+            r.packetptr().write_value(s.current.buffer.as_ptr() as u32);
+            s.state = RadioState::Tx;
+            s.tx_peer = r.txaddress().read().txaddress();
+            s.call = call;
+            s.start = Instant::now().as_ticks();
+            s.ready = s.start;
+            s.count = 0;
+            s.attempts.clear();
+            s.retry_reason = RetryReason::Timeout;
             compiler_fence(core::sync::atomic::Ordering::Release);
+            timeline::arm();
             r.tasks_txen().write_value(1);
         });
-        CHAN.receive().await
+        let guard = AbortOnDrop::new(self.state);
+        let res = self.state.results.receive().await;
+        guard.defuse();
+        self.stats.packets_sent += 1;
+        self.stats.retranmisisons += res.retranmisisons;
+        res
+    }
+}
+
+impl<'d> LinkLayer for TradRadio<'d> {
+    async fn send(&mut self, packet: &mut Packet) -> LogInfo {
+        TradRadio::send(self, packet).await
+    }
+
+    async fn receive(&mut self, packet: &mut Packet) {
+        *packet = self.receive_packet().await;
+    }
+
+    fn configure(&mut self, config: &LinkConfig) {
+        crate::link::apply_config(config);
+        self.tx_addreses = config.tx_address;
+        self.rx_addresses = config.rx_addresses as u32;
+    }
+
+    fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    fn counters(&self) -> LinkCounters {
+        self.state.shared.lock(|s| s.borrow().counters)
     }
 }